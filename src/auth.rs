@@ -0,0 +1,287 @@
+use anyhow::{Context, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use dialoguer::{Input, Password};
+use indicatif::MultiProgress;
+use ssh2::{KeyboardInteractivePrompt, Prompt, Session};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Mutex;
+use tracing::debug;
+use crate::progress;
+use crate::prompt;
+
+const SECURITY_KEY_PREFIX: &str = "sk-";
+const OPENSSH_KEY_MAGIC: &[u8] = b"openssh-key-v1\0";
+const PASSPHRASE_ATTEMPTS: usize = 3;
+
+/// Key passphrase for the rest of the run, so every destination (and
+/// every reconnect) after the first doesn't prompt again.
+static PASSPHRASE: Mutex<Option<CachedPassphrase>> = Mutex::new(None);
+
+struct CachedPassphrase {
+    value: String,
+    /// Given with `--key-passphrase-env`, so a wrong one isn't re-prompted for.
+    from_env: bool,
+}
+
+pub struct PublicKey {
+    pub key_type: String,
+    pub blob: Vec<u8>,
+}
+
+impl PublicKey {
+    /// FIDO2-backed keys (`sk-ssh-ed25519@openssh.com`, `sk-ecdsa-...`)
+    /// can't be used from a key file by libssh2; they have to be signed
+    /// by ssh-agent, which talks to the hardware token.
+    pub fn is_security_key(&self) -> bool {
+        self.key_type.starts_with(SECURITY_KEY_PREFIX)
+    }
+}
+
+/// Reads the public half of a key, preferring the `.pub` file next to it
+/// and falling back to the unencrypted public section of an OpenSSH
+/// private key.
+pub fn read_public_key(private_key_path: &Path) -> Option<PublicKey> {
+    let mut pub_path = PathBuf::from(private_key_path).into_os_string();
+    pub_path.push(".pub");
+
+    if let Ok(content) = fs::read_to_string(&pub_path) {
+        let mut parts = content.split_whitespace();
+        let key_type = parts.next()?.to_string();
+        let blob = STANDARD.decode(parts.next()?).ok()?;
+        return Some(PublicKey { key_type, blob });
+    }
+
+    let content = fs::read_to_string(private_key_path).ok()?;
+    parse_openssh_private_key(&content)
+}
+
+/// Whether the private key needs a passphrase: legacy PEM keys say so in
+/// their headers, OpenSSH keys name the cipher their private part uses.
+pub fn is_encrypted(private_key_path: &Path) -> bool {
+    let Ok(content) = fs::read_to_string(private_key_path) else {
+        return false;
+    };
+    if content.contains("ENCRYPTED") {
+        return true;
+    }
+    openssh_key_data(&content)
+        .and_then(|data| {
+            let mut rest = data.strip_prefix(OPENSSH_KEY_MAGIC)?;
+            read_string(&mut rest).map(|cipher| cipher != b"none")
+        })
+        .unwrap_or(false)
+}
+
+fn openssh_key_data(content: &str) -> Option<Vec<u8>> {
+    let body: String = content.lines()
+        .filter(|line| !line.starts_with("-----"))
+        .collect();
+    STANDARD.decode(body.trim()).ok()
+}
+
+fn parse_openssh_private_key(content: &str) -> Option<PublicKey> {
+    let data = openssh_key_data(content)?;
+    let mut rest = data.strip_prefix(OPENSSH_KEY_MAGIC)?;
+
+    // ciphername, kdfname and kdfoptions precede the key count
+    for _ in 0..3 {
+        read_string(&mut rest)?;
+    }
+    let count = read_u32(&mut rest)?;
+    if count == 0 {
+        return None;
+    }
+
+    let blob = read_string(&mut rest)?.to_vec();
+    let mut blob_rest = blob.as_slice();
+    let key_type = String::from_utf8(read_string(&mut blob_rest)?.to_vec()).ok()?;
+    Some(PublicKey { key_type, blob })
+}
+
+fn read_u32(data: &mut &[u8]) -> Option<u32> {
+    let (head, tail) = data.split_at_checked(4)?;
+    *data = tail;
+    Some(u32::from_be_bytes(head.try_into().ok()?))
+}
+
+fn read_string<'a>(data: &mut &'a [u8]) -> Option<&'a [u8]> {
+    let len = read_u32(data)? as usize;
+    let (head, tail) = data.split_at_checked(len)?;
+    *data = tail;
+    Some(head)
+}
+
+pub fn set_passphrase(value: String) {
+    *PASSPHRASE.lock().unwrap() = Some(CachedPassphrase { value, from_env: true });
+}
+
+/// Unlocks an encrypted key with the cached passphrase, or prompts for
+/// one. The lock is held while prompting so destinations connecting in
+/// parallel wait for the first answer instead of all asking at once.
+pub fn with_passphrase<F>(key_path: &str, progress: &MultiProgress, try_auth: F) -> Result<()>
+where
+    F: Fn(&str) -> std::result::Result<(), ssh2::Error>,
+{
+    let mut cached = PASSPHRASE.lock().unwrap();
+    if let Some(passphrase) = cached.as_ref() {
+        if try_auth(&passphrase.value).is_ok() {
+            return Ok(());
+        }
+        if passphrase.from_env {
+            anyhow::bail!("The passphrase from --key-passphrase-env doesn't unlock {}", key_path);
+        }
+    }
+
+    if !prompt::allowed() {
+        anyhow::bail!(
+            "{} is passphrase-protected. Pass --key-passphrase-env or load it into ssh-agent",
+            key_path
+        );
+    }
+
+    for _ in 0..PASSPHRASE_ATTEMPTS {
+        let passphrase = progress::suspend(progress, || {
+            Password::new()
+                .with_prompt(format!("🔑 Passphrase for {}", key_path))
+                .interact()
+        }).context("Failed to read passphrase")?;
+
+        if try_auth(&passphrase).is_ok() {
+            *cached = Some(CachedPassphrase { value: passphrase, from_env: false });
+            return Ok(());
+        }
+        progress::suspend(progress, || eprintln!("❌ Wrong passphrase"));
+    }
+    anyhow::bail!("Could not unlock {}", key_path)
+}
+
+/// Tries each identity loaded in ssh-agent (including agents such as
+/// 1Password's that stand in for it). Returns false when no agent is
+/// running or none of its keys are accepted, so the key file can be tried.
+pub fn userauth_agent(session: &Session, username: &str) -> Result<bool> {
+    let mut agent = session.agent()
+        .context("Failed to initialize ssh-agent support")?;
+    if let Err(e) = agent.connect() {
+        debug!("No ssh-agent available: {}", e);
+        return Ok(false);
+    }
+    agent.list_identities()
+        .context("Failed to list ssh-agent identities")?;
+
+    let identities = agent.identities()
+        .context("Failed to read ssh-agent identities")?;
+    debug!("ssh-agent offers {} identities", identities.len());
+    for identity in &identities {
+        debug!("Trying ssh-agent identity: {}", identity.comment());
+        if agent.userauth(username, identity).is_ok() && session.authenticated() {
+            let _ = agent.disconnect();
+            return Ok(true);
+        }
+    }
+
+    let _ = agent.disconnect();
+    Ok(false)
+}
+
+/// Authenticates with the agent identity matching `key`. Used for security
+/// keys, which libssh2 can't sign with itself: the agent asks the token
+/// for a touch before signing. A key that isn't in the agent yet is added
+/// with `ssh-add` when there's a terminal to answer it.
+pub fn userauth_agent_key(session: &Session, username: &str, key: &PublicKey, key_path: &str, progress: &MultiProgress) -> Result<()> {
+    let mut agent = session.agent()
+        .context("Failed to initialize ssh-agent support")?;
+    agent.connect()
+        .with_context(|| format!(
+            "Security keys are signed through ssh-agent, and none is running. Start one: eval \"$(ssh-agent)\" && ssh-add {}",
+            key_path
+        ))?;
+
+    let find = |agent: &mut ssh2::Agent| -> Result<Option<ssh2::PublicKey>> {
+        agent.list_identities()
+            .context("Failed to list ssh-agent identities")?;
+        let identities = agent.identities()
+            .context("Failed to read ssh-agent identities")?;
+        Ok(identities.into_iter().find(|identity| identity.blob() == key.blob.as_slice()))
+    };
+    let identity = match find(&mut agent)? {
+        Some(identity) => identity,
+        None if prompt::allowed() => {
+            add_to_agent(key_path, progress)?;
+            find(&mut agent)?
+                .with_context(|| format!("ssh-add ran, but ssh-agent still doesn't hold {}", key_path))?
+        }
+        None => anyhow::bail!("Security key {} is not loaded in ssh-agent. Run: ssh-add {}", key_path, key_path),
+    };
+
+    progress::suspend(progress, || {
+        eprintln!("👆 Touch your security key to authenticate as {}", username);
+        agent.userauth(username, &identity)
+    }).context("Security key authentication failed (was the key touched in time?)")?;
+
+    let _ = agent.disconnect();
+    Ok(())
+}
+
+/// Runs `ssh-add` on the terminal, which asks for the key's passphrase if
+/// it has one.
+fn add_to_agent(key_path: &str, progress: &MultiProgress) -> Result<()> {
+    debug!("Adding {} to ssh-agent", key_path);
+    let status = progress::suspend(progress, || {
+        eprintln!("🔑 Adding security key {} to ssh-agent", key_path);
+        Command::new("ssh-add").arg(key_path).status()
+    }).context("Failed to run ssh-add")?;
+    if !status.success() {
+        anyhow::bail!("ssh-add {} failed ({})", key_path, status);
+    }
+    Ok(())
+}
+
+/// Answers the server's keyboard-interactive challenges (one-time codes
+/// for 2FA, PAM password prompts) by asking the user for each one.
+pub fn userauth_keyboard_interactive(session: &Session, username: &str, progress: &MultiProgress) -> Result<()> {
+    if !prompt::allowed() {
+        anyhow::bail!(
+            "The server asks for keyboard-interactive authentication (e.g. a one-time code), which needs a terminal"
+        );
+    }
+    let mut challenges = Challenges { progress, failed: None };
+    let result = session.userauth_keyboard_interactive(username, &mut challenges);
+    if let Some(e) = challenges.failed {
+        return Err(e);
+    }
+    result.context("Keyboard-interactive authentication failed")
+}
+
+struct Challenges<'a> {
+    progress: &'a MultiProgress,
+    /// Why answers couldn't be read, since libssh2 only takes a list of
+    /// answers back.
+    failed: Option<anyhow::Error>,
+}
+
+impl KeyboardInteractivePrompt for Challenges<'_> {
+    fn prompt<'a>(&mut self, _username: &str, instructions: &str, prompts: &[Prompt<'a>]) -> Vec<String> {
+        debug!("Server sent {} keyboard-interactive prompt(s)", prompts.len());
+        let answers = progress::suspend(self.progress, || {
+            if !instructions.trim().is_empty() {
+                eprintln!("{}", instructions.trim());
+            }
+            prompts.iter()
+                .map(|challenge| {
+                    let text = format!("🔐 {}", challenge.text.trim().trim_end_matches(':'));
+                    match challenge.echo {
+                        true => Input::<String>::new().with_prompt(text).allow_empty(true).interact_text(),
+                        false => Password::new().with_prompt(text).allow_empty_password(true).interact(),
+                    }
+                })
+                .collect::<std::result::Result<Vec<String>, _>>()
+        });
+        answers.unwrap_or_else(|e| {
+            self.failed = Some(anyhow::Error::new(e).context("Failed to read the answer to the server's prompt"));
+            vec![String::new(); prompts.len()]
+        })
+    }
+}
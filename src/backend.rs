@@ -0,0 +1,92 @@
+use anyhow::{Context, Result};
+use std::io::Read;
+use std::path::Path;
+use std::time::Duration;
+use crate::config::{Destination, DestinationKind};
+use crate::ftps::Ftps;
+use crate::local::LocalDir;
+use crate::openssh::OpenSsh;
+use crate::s3::Bucket;
+use crate::transfer::TransferOptions;
+use crate::webdav::WebDav;
+
+/// Storage for destinations that aren't SFTP servers. These only take
+/// uploads; `Transferer` decides what goes where (filters, placeholders,
+/// `--if-exists`, the run log) and hands each file over through this.
+pub trait Backend {
+    /// Whether something is already stored at `path`.
+    fn exists(&self, path: &Path) -> Result<bool>;
+
+    /// Whether the folder `dir` exists. Object stores have no folders, so
+    /// by default every one does.
+    fn is_dir(&self, _dir: &Path) -> Result<bool> {
+        Ok(true)
+    }
+
+    /// Creates the folder `dir`, whose parent exists.
+    fn create_dir(&self, _dir: &Path) -> Result<()> {
+        Ok(())
+    }
+
+    /// Makes sure the folder `dir` exists, creating its parents first.
+    fn create_dir_all(&self, dir: &Path) -> Result<()> {
+        if dir.parent().is_none() || self.is_dir(dir)? {
+            return Ok(());
+        }
+        if let Some(parent) = dir.parent() {
+            self.create_dir_all(parent)?;
+        }
+        self.create_dir(dir)
+    }
+
+    /// Stores `size` bytes from `reader` at `path`, replacing whatever is
+    /// there.
+    fn put(&self, path: &Path, reader: &mut dyn Read, size: u64) -> Result<()>;
+
+    /// Deletes the file at `path`.
+    fn remove(&self, path: &Path) -> Result<()>;
+}
+
+/// The backend for `destination`, or `None` for an SFTP server arkv logs
+/// in to itself.
+pub fn open(destination: &Destination, options: &TransferOptions) -> Result<Option<Box<dyn Backend>>> {
+    let agent = || http_agent(destination.proxy.as_deref(), options.connect_timeout, options.io_timeout);
+    Ok(match destination.kind {
+        DestinationKind::Sftp if destination.gssapi => Some(Box::new(OpenSsh::open(destination, options)?)),
+        DestinationKind::Sftp => None,
+        DestinationKind::S3 => Some(Box::new(Bucket::open(destination, &options.progress, agent()?)?)),
+        DestinationKind::Webdav => Some(Box::new(WebDav::open(destination, &options.progress, agent()?)?)),
+        DestinationKind::Ftps => Some(Box::new(Ftps::open(destination, options)?)),
+        DestinationKind::Local => Some(Box::new(LocalDir::open(destination)?)),
+    })
+}
+
+/// An HTTP client that goes through the destination's proxy, if any, and
+/// gives up after the run's timeouts.
+fn http_agent(proxy: Option<&str>, connect_timeout: Option<Duration>, io_timeout: Option<Duration>) -> Result<ureq::Agent> {
+    let mut agent = ureq::AgentBuilder::new();
+    if let Some(proxy) = proxy {
+        agent = agent.proxy(ureq::Proxy::new(proxy).with_context(|| format!("Invalid proxy: {}", proxy))?);
+    }
+    if let Some(timeout) = connect_timeout {
+        agent = agent.timeout_connect(timeout);
+    }
+    if let Some(timeout) = io_timeout {
+        agent = agent.timeout_read(timeout).timeout_write(timeout);
+    }
+    Ok(agent.build())
+}
+
+/// Percent-encodes everything but unreserved characters, and `/` too
+/// unless it separates path segments.
+pub fn uri_encode(value: &str, encode_slash: bool) -> String {
+    let mut encoded = String::new();
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => encoded.push(byte as char),
+            b'/' if !encode_slash => encoded.push('/'),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
@@ -0,0 +1,174 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::thread;
+use std::time::{Duration, Instant};
+use crate::units;
+
+/// A bandwidth limit that applies during part of the day, e.g.
+/// `{ from = "08:00", to = "22:00", limit = "1M" }`. Windows may wrap past
+/// midnight; outside every window uploads are unlimited.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct BandwidthWindow {
+    pub from: String,
+    pub to: String,
+    /// Bytes per second (`512K`, `1M`, ...) or `unlimited`.
+    pub limit: String,
+}
+
+struct Window {
+    from: u32,
+    to: u32,
+    limit: Option<u64>,
+}
+
+pub struct Schedule {
+    windows: Vec<Window>,
+}
+
+impl Schedule {
+    pub fn new(windows: &[BandwidthWindow]) -> Result<Self> {
+        let windows = windows.iter()
+            .map(|w| {
+                let limit = match w.limit.trim() {
+                    "unlimited" => None,
+                    limit => Some(units::parse_size(limit)
+                        .with_context(|| format!("Invalid bandwidth limit '{}'", w.limit))?),
+                };
+                Ok(Window { from: parse_time(&w.from)?, to: parse_time(&w.to)?, limit })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self { windows })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.windows.is_empty()
+    }
+
+    /// Limit in effect at `minute` minutes past local midnight; the first matching window wins.
+    fn limit_at(&self, minute: u32) -> Option<u64> {
+        self.windows.iter()
+            .find(|w| if w.from <= w.to {
+                (w.from..w.to).contains(&minute)
+            } else {
+                minute >= w.from || minute < w.to
+            })
+            .and_then(|w| w.limit)
+    }
+}
+
+/// Minutes past midnight from `HH:MM`, with `24:00` meaning the end of the day.
+fn parse_time(value: &str) -> Result<u32> {
+    let (hours, minutes) = value.trim().split_once(':')
+        .with_context(|| format!("Invalid time '{}', expected HH:MM", value))?;
+    let hours: u32 = hours.parse().with_context(|| format!("Invalid hour in '{}'", value))?;
+    let minutes: u32 = minutes.parse().with_context(|| format!("Invalid minutes in '{}'", value))?;
+    if minutes > 59 || hours > 24 || (hours == 24 && minutes > 0) {
+        anyhow::bail!("Invalid time '{}', expected HH:MM", value);
+    }
+    Ok(hours * 60 + minutes)
+}
+
+fn local_minute() -> u32 {
+    unsafe {
+        let now = libc::time(std::ptr::null_mut());
+        let mut tm: libc::tm = std::mem::zeroed();
+        if libc::localtime_r(&now, &mut tm).is_null() {
+            return 0;
+        }
+        (tm.tm_hour * 60 + tm.tm_min) as u32
+    }
+}
+
+/// Paces writes to the limit of the current window, re-reading the clock
+/// as it goes so a long transfer speeds up or slows down when it crosses
+/// a window boundary.
+pub struct Throttle {
+    schedule: Schedule,
+    limit: Option<u64>,
+    since: Instant,
+    sent: u64,
+}
+
+impl Throttle {
+    pub fn new(schedule: Schedule) -> Self {
+        Self { schedule, limit: None, since: Instant::now(), sent: 0 }
+    }
+
+    /// Accounts for `bytes` just sent and sleeps if that's ahead of the limit.
+    /// Returns the new limit when it changed.
+    pub fn pace(&mut self, bytes: u64) -> Option<Option<u64>> {
+        if self.schedule.is_empty() {
+            return None;
+        }
+
+        let limit = self.schedule.limit_at(local_minute());
+        let changed = limit != self.limit;
+        if changed {
+            self.limit = limit;
+            self.since = Instant::now();
+            self.sent = 0;
+        }
+
+        if let Some(limit) = limit.filter(|&l| l > 0) {
+            self.sent += bytes;
+            let due = Duration::from_secs_f64(self.sent as f64 / limit as f64);
+            let elapsed = self.since.elapsed();
+            if due > elapsed {
+                thread::sleep(due - elapsed);
+            }
+        }
+
+        changed.then_some(limit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn window(from: &str, to: &str, limit: &str) -> BandwidthWindow {
+        BandwidthWindow { from: from.to_string(), to: to.to_string(), limit: limit.to_string() }
+    }
+
+    fn at(hours: u32, minutes: u32) -> u32 {
+        hours * 60 + minutes
+    }
+
+    #[test]
+    fn window_within_the_day() {
+        let schedule = Schedule::new(&[window("08:00", "22:00", "1M")]).unwrap();
+        assert_eq!(schedule.limit_at(at(7, 59)), None);
+        assert_eq!(schedule.limit_at(at(8, 0)), Some(1024 * 1024));
+        assert_eq!(schedule.limit_at(at(21, 59)), Some(1024 * 1024));
+        assert_eq!(schedule.limit_at(at(22, 0)), None);
+    }
+
+    #[test]
+    fn window_across_midnight() {
+        let schedule = Schedule::new(&[window("22:00", "06:00", "512K")]).unwrap();
+        assert_eq!(schedule.limit_at(at(21, 59)), None);
+        assert_eq!(schedule.limit_at(at(22, 0)), Some(512 * 1024));
+        assert_eq!(schedule.limit_at(at(0, 0)), Some(512 * 1024));
+        assert_eq!(schedule.limit_at(at(5, 59)), Some(512 * 1024));
+        assert_eq!(schedule.limit_at(at(6, 0)), None);
+    }
+
+    #[test]
+    fn first_matching_window_wins() {
+        let schedule = Schedule::new(&[
+            window("12:00", "13:00", "unlimited"),
+            window("00:00", "24:00", "1M"),
+        ]).unwrap();
+        assert_eq!(schedule.limit_at(at(12, 30)), None);
+        assert_eq!(schedule.limit_at(at(13, 0)), Some(1024 * 1024));
+        assert_eq!(schedule.limit_at(at(23, 59)), Some(1024 * 1024));
+    }
+
+    #[test]
+    fn invalid_windows() {
+        assert!(Schedule::new(&[window("8", "22:00", "1M")]).is_err());
+        assert!(Schedule::new(&[window("08:60", "22:00", "1M")]).is_err());
+        assert!(Schedule::new(&[window("08:00", "24:01", "1M")]).is_err());
+        assert!(Schedule::new(&[window("08:00", "22:00", "fast")]).is_err());
+    }
+}
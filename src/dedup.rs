@@ -0,0 +1,79 @@
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Folder in the destination's remote base holding the content of every
+/// `--dedup` upload, one file per SHA-256.
+pub const POOL: &str = "objects";
+pub const INDEX_SUFFIX: &str = ".arkv-index.toml";
+
+/// What a `--dedup` upload was: each file's path and the object holding
+/// its content. It stands in for the upload, e.g. `photos.arkv-index.toml`
+/// for `photos/`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Index {
+    pub version: u32,
+    pub created: u64,
+    pub source: String,
+    pub destination: String,
+    pub files: Vec<IndexEntry>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct IndexEntry {
+    /// Path starting with the upload's remote name, e.g. `photos/2026/a.jpg`.
+    pub path: String,
+    pub size: u64,
+    pub sha256: String,
+}
+
+impl Index {
+    pub fn new(source: &Path, destination: &str, files: Vec<IndexEntry>) -> Self {
+        let created = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        Self {
+            version: 1,
+            created,
+            source: source.display().to_string(),
+            destination: destination.to_string(),
+            files,
+        }
+    }
+}
+
+/// Whether `value` is a SHA-256 as indexes and manifests store it: 64 hex
+/// digits. Anything read back from them is checked before it names a path.
+pub fn is_sha256(value: &str) -> bool {
+    value.len() == 64 && value.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// Where the content with `sha256` is kept in the pool under `base`,
+/// fanned out by its first two digits: `objects/ab/abcdef...`.
+pub fn object_path(base: &Path, sha256: &str) -> PathBuf {
+    base.join(POOL).join(&sha256[..2]).join(sha256)
+}
+
+/// The index of the upload at `upload`, e.g. `backups/photos.arkv-index.toml`.
+pub fn index_path(upload: &Path) -> PathBuf {
+    let mut path = upload.as_os_str().to_owned();
+    path.push(INDEX_SUFFIX);
+    PathBuf::from(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_64_hex_digits_are_a_sha256() {
+        let digest = "ab".repeat(32);
+        assert!(is_sha256(&digest));
+        assert!(is_sha256(&digest.to_ascii_uppercase()));
+        assert!(!is_sha256(&digest[..62]));
+        assert!(!is_sha256(&format!("../../{}", &digest[..58])));
+        assert!(!is_sha256(&format!("{}é", &digest[..62])));
+        assert!(!is_sha256(""));
+    }
+}
@@ -0,0 +1,12 @@
+use notify_rust::Notification;
+use tracing::{debug, warn};
+
+/// Shows a desktop notification (Notification Center, the freedesktop
+/// notification service, or Windows toasts). A desktop that can't show
+/// one only gets a warning in the log; it never fails the run.
+pub fn notify(summary: &str, body: &str) {
+    debug!("Sending desktop notification: {}", summary);
+    if let Err(e) = Notification::new().appname("arkv").summary(summary).body(body).show() {
+        warn!("Failed to show desktop notification: {}", e);
+    }
+}
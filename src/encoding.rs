@@ -0,0 +1,52 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+/// How file names are encoded on the server. Paths are built as UTF-8
+/// locally and converted right before they go over the wire.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RemoteEncoding {
+    #[default]
+    Utf8,
+    /// ISO-8859-1, for old servers that mangle UTF-8 names.
+    Latin1,
+}
+
+impl RemoteEncoding {
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Converts a local (UTF-8) path into the server's encoding.
+    pub fn encode(&self, path: &Path) -> Result<PathBuf> {
+        match self {
+            RemoteEncoding::Utf8 => Ok(path.to_path_buf()),
+            RemoteEncoding::Latin1 => {
+                let text = path.to_string_lossy();
+                let mut bytes = Vec::with_capacity(text.len());
+                for c in text.chars() {
+                    let code = c as u32;
+                    if code > 0xFF {
+                        anyhow::bail!("'{}' in {} can't be represented in Latin-1", c, path.display());
+                    }
+                    bytes.push(code as u8);
+                }
+                Ok(PathBuf::from(OsStr::from_bytes(&bytes)))
+            }
+        }
+    }
+
+    /// Converts a path received from the server back to UTF-8.
+    pub fn decode(&self, path: &Path) -> PathBuf {
+        match self {
+            RemoteEncoding::Utf8 => path.to_path_buf(),
+            RemoteEncoding::Latin1 => {
+                let text: String = path.as_os_str().as_bytes().iter().map(|&b| b as char).collect();
+                PathBuf::from(text)
+            }
+        }
+    }
+}
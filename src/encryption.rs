@@ -0,0 +1,259 @@
+use anyhow::{Context, Result};
+use std::io::{self, BufReader, Read, Write};
+use std::path::Path;
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use crate::config::EncryptionBackend;
+
+const AGE_EXTENSION: &str = "age";
+const GPG_EXTENSION: &str = "gpg";
+const PIPE_BUFFER_SIZE: usize = 65_536;
+
+/// Chunks of gpg's output, read on its own thread.
+type GpgOutput = Receiver<io::Result<Vec<u8>>>;
+
+/// Recipients that `--encrypt` encrypts each file (or tar stream) to, so
+/// only ciphertext reaches the remote disk.
+#[derive(Clone)]
+pub struct Encryption {
+    backend: EncryptionBackend,
+    recipients: Vec<String>,
+    age_keys: Vec<age::x25519::Recipient>,
+}
+
+impl Encryption {
+    pub fn new(backend: EncryptionBackend, recipients: &[String]) -> Result<Self> {
+        if recipients.is_empty() {
+            anyhow::bail!("--encrypt needs at least one recipient; add {} to the destination", match backend {
+                EncryptionBackend::Age => "age_recipients",
+                EncryptionBackend::Gpg => "gpg_recipients",
+            });
+        }
+        let age_keys = match backend {
+            EncryptionBackend::Age => recipients.iter()
+                .map(|r| r.trim().parse::<age::x25519::Recipient>()
+                    .map_err(|e| anyhow::anyhow!("Invalid age recipient '{}': {}", r, e)))
+                .collect::<Result<_>>()?,
+            EncryptionBackend::Gpg => Vec::new(),
+        };
+        Ok(Self { backend, recipients: recipients.to_vec(), age_keys })
+    }
+
+    pub fn backend(&self) -> EncryptionBackend {
+        self.backend
+    }
+
+    pub fn recipients(&self) -> &[String] {
+        &self.recipients
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self.backend {
+            EncryptionBackend::Age => AGE_EXTENSION,
+            EncryptionBackend::Gpg => GPG_EXTENSION,
+        }
+    }
+
+    /// Wraps `output` so everything written to it is encrypted. The
+    /// ciphertext is only complete once `finish` is called.
+    pub fn wrap<'a>(&self, output: Box<dyn Write + 'a>) -> Result<Box<dyn FinishWrite + 'a>> {
+        match self.backend {
+            EncryptionBackend::Age => {
+                let encryptor = age::Encryptor::with_recipients(self.age_keys.iter().map(|k| k as &dyn age::Recipient))
+                    .context("Failed to set up encryption")?;
+                let writer = encryptor.wrap_output(output)
+                    .context("Failed to write the encryption header")?;
+                Ok(Box::new(writer))
+            }
+            EncryptionBackend::Gpg => {
+                let mut args = vec!["--batch", "--yes", "--encrypt", "--output", "-"];
+                for recipient in &self.recipients {
+                    args.extend(["--recipient", recipient.as_str()]);
+                }
+                let (child, stdin, stdout) = spawn_gpg(&args)?;
+                Ok(Box::new(GpgWriter { child, stdin: Some(stdin), stdout, output }))
+            }
+        }
+    }
+}
+
+/// A writer that has trailing data to flush when the stream ends.
+pub trait FinishWrite: Write {
+    fn finish(self: Box<Self>) -> io::Result<()>;
+}
+
+impl<W: Write> FinishWrite for age::stream::StreamWriter<W> {
+    fn finish(self: Box<Self>) -> io::Result<()> {
+        (*self).finish().map(drop)
+    }
+}
+
+/// Passes writes through unchanged.
+pub struct Plain<W>(pub W);
+
+impl<W: Write> Write for Plain<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl<W: Write> FinishWrite for Plain<W> {
+    fn finish(mut self: Box<Self>) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+/// Whether a remote file looks like one `--encrypt` produced.
+pub fn is_encrypted(path: &Path) -> bool {
+    path.extension().is_some_and(|e| e == AGE_EXTENSION || e == GPG_EXTENSION)
+}
+
+/// Decrypts the stream of the encrypted file `path`: `.age` files with
+/// the identities in `identity_file` (as written by `age-keygen`), `.gpg`
+/// files with `gpg --decrypt` and the user's keyring.
+pub fn decrypting_reader<'a>(input: impl Read + 'a, path: &Path, identity_file: &Path) -> Result<Box<dyn Read + 'a>> {
+    if path.extension().is_some_and(|e| e == GPG_EXTENSION) {
+        let (child, stdin, stdout) = spawn_gpg(&["--decrypt", "--quiet"])?;
+        return Ok(Box::new(GpgReader { child: Some(child), stdin: Some(stdin), stdout, input, pending: Vec::new() }));
+    }
+
+    let identities = age::IdentityFile::from_file(identity_file.to_string_lossy().into_owned())
+        .with_context(|| format!("Failed to read age identity file: {}", identity_file.display()))?
+        .into_identities()
+        .context("Failed to load age identities")?;
+    let decryptor = age::Decryptor::new(BufReader::new(input))
+        .context("Not a valid age file")?;
+    let reader = decryptor.decrypt(identities.iter().map(|i| i.as_ref()))
+        .context("Failed to decrypt (is this file encrypted to your age identity?)")?;
+    Ok(Box::new(reader))
+}
+
+/// Starts gpg with its output read on a separate thread, so writing to
+/// its input never blocks on gpg waiting for its output to be read.
+fn spawn_gpg(args: &[&str]) -> Result<(Child, ChildStdin, GpgOutput)> {
+    let mut child = Command::new("gpg")
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context("Failed to run gpg (is GnuPG installed?)")?;
+    let stdin = child.stdin.take().context("Failed to open gpg input")?;
+    let mut stdout = child.stdout.take().context("Failed to open gpg output")?;
+
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut buffer = vec![0; PIPE_BUFFER_SIZE];
+        loop {
+            match stdout.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(n) => {
+                    if tx.send(Ok(buffer[..n].to_vec())).is_err() {
+                        break;
+                    }
+                }
+                Err(e) => {
+                    let _ = tx.send(Err(e));
+                    break;
+                }
+            }
+        }
+    });
+    Ok((child, stdin, rx))
+}
+
+/// Waits for gpg and turns a failure into an error with its message.
+fn gpg_result(child: Child) -> io::Result<()> {
+    let output = child.wait_with_output()?;
+    if output.status.success() {
+        return Ok(());
+    }
+    Err(io::Error::other(format!("gpg failed: {}", String::from_utf8_lossy(&output.stderr).trim())))
+}
+
+struct GpgWriter<'a> {
+    child: Child,
+    stdin: Option<ChildStdin>,
+    stdout: GpgOutput,
+    output: Box<dyn Write + 'a>,
+}
+
+impl GpgWriter<'_> {
+    /// Passes on the ciphertext gpg has produced so far.
+    fn forward(&mut self) -> io::Result<()> {
+        while let Ok(chunk) = self.stdout.try_recv() {
+            self.output.write_all(&chunk?)?;
+        }
+        Ok(())
+    }
+}
+
+impl Write for GpgWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let stdin = self.stdin.as_mut().ok_or(io::ErrorKind::BrokenPipe)?;
+        let n = stdin.write(buf)?;
+        self.forward()?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.forward()?;
+        self.output.flush()
+    }
+}
+
+impl FinishWrite for GpgWriter<'_> {
+    fn finish(mut self: Box<Self>) -> io::Result<()> {
+        drop(self.stdin.take());
+        for chunk in self.stdout.iter() {
+            self.output.write_all(&chunk?)?;
+        }
+        self.output.flush()?;
+        gpg_result(self.child)
+    }
+}
+
+struct GpgReader<R> {
+    child: Option<Child>,
+    stdin: Option<ChildStdin>,
+    stdout: GpgOutput,
+    input: R,
+    pending: Vec<u8>,
+}
+
+impl<R: Read> Read for GpgReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut buffer = vec![0; PIPE_BUFFER_SIZE];
+        while self.pending.is_empty() {
+            if let Ok(chunk) = self.stdout.try_recv() {
+                self.pending = chunk?;
+                continue;
+            }
+            match self.stdin.as_mut() {
+                // Feed gpg more ciphertext until it has all of it
+                Some(stdin) => match self.input.read(&mut buffer)? {
+                    0 => drop(self.stdin.take()),
+                    n => stdin.write_all(&buffer[..n])?,
+                },
+                None => match self.stdout.recv() {
+                    Ok(chunk) => self.pending = chunk?,
+                    Err(_) => {
+                        if let Some(child) = self.child.take() {
+                            gpg_result(child)?;
+                        }
+                        return Ok(0);
+                    }
+                },
+            }
+        }
+        let n = buf.len().min(self.pending.len());
+        buf[..n].copy_from_slice(&self.pending[..n]);
+        self.pending.drain(..n);
+        Ok(n)
+    }
+}
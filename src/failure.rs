@@ -0,0 +1,102 @@
+use anyhow::Result;
+use std::fmt;
+
+/// What kind of failure stopped arkv, told to scripts by its exit code.
+/// Errors that aren't classified exit with 1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+    /// The config file is missing, unreadable or names no such destination.
+    Config,
+    /// The server couldn't be reached or the SSH handshake failed.
+    Connect,
+    /// None of the password, ssh-agent or key file was accepted.
+    Auth,
+    /// The server's host key is unknown and wasn't trusted, or has changed.
+    HostKey,
+    /// Another arkv run holds the destination's lock.
+    Locked,
+    /// Some destinations finished and others failed.
+    Partial,
+    /// A file on the server doesn't match what was sent.
+    Verify,
+}
+
+impl Failure {
+    pub fn exit_code(self) -> i32 {
+        match self {
+            Failure::Config => 3,
+            Failure::Connect => 4,
+            Failure::Auth => 5,
+            Failure::HostKey => 6,
+            Failure::Locked => 7,
+            Failure::Partial => 8,
+            Failure::Verify => 9,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Failure::Config => "config",
+            Failure::Connect => "connect",
+            Failure::Auth => "auth",
+            Failure::HostKey => "host_key",
+            Failure::Locked => "locked",
+            Failure::Partial => "partial",
+            Failure::Verify => "verify",
+        }
+    }
+
+    /// The failure `error` was classified as, if any.
+    pub fn of(error: &anyhow::Error) -> Option<Failure> {
+        error.downcast_ref::<Classified>().map(|c| c.failure)
+    }
+
+    /// The exit code for a run that failed with `error`.
+    pub fn code_for(error: &anyhow::Error) -> i32 {
+        Failure::of(error).map_or(1, Failure::exit_code)
+    }
+}
+
+/// An error tagged with its `Failure`. It displays exactly like the error
+/// it wraps, so tagging doesn't change what the user sees.
+struct Classified {
+    failure: Failure,
+    error: anyhow::Error,
+}
+
+impl fmt::Debug for Classified {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&self.error, f)
+    }
+}
+
+impl fmt::Display for Classified {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.error, f)
+    }
+}
+
+impl std::error::Error for Classified {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.error.source()
+    }
+}
+
+pub trait Classify<T> {
+    /// Tags the error, if any, with `failure` unless it already has one.
+    fn classify(self, failure: Failure) -> Result<T>;
+}
+
+impl<T> Classify<T> for Result<T> {
+    fn classify(self, failure: Failure) -> Result<T> {
+        self.map_err(|error| match Failure::of(&error) {
+            Some(_) => error,
+            None => Classified { failure, error }.into(),
+        })
+    }
+}
+
+/// Returns an error with `message` tagged with `failure`.
+pub fn fail(failure: Failure, message: String) -> anyhow::Error {
+    Classified { failure, error: anyhow::Error::msg(message) }.into()
+}
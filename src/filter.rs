@@ -0,0 +1,61 @@
+use anyhow::{Context, Result};
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use std::path::Path;
+
+/// `--include`/`--exclude` patterns for folder uploads, matched against
+/// paths relative to the uploaded folder. A pattern without a `/` (such
+/// as `node_modules` or `*.jpg`) matches at any depth; one with a `/` is
+/// anchored to the folder.
+#[derive(Clone, Default)]
+pub struct PathFilter {
+    include: Vec<String>,
+    exclude: Vec<String>,
+    include_set: GlobSet,
+    exclude_set: GlobSet,
+}
+
+impl PathFilter {
+    pub fn new(include: &[String], exclude: &[String]) -> Result<Self> {
+        Ok(Self {
+            include: include.to_vec(),
+            exclude: exclude.to_vec(),
+            include_set: build(include)?,
+            exclude_set: build(exclude)?,
+        })
+    }
+
+    pub fn include(&self) -> &[String] {
+        &self.include
+    }
+
+    pub fn exclude(&self) -> &[String] {
+        &self.exclude
+    }
+
+    /// Excluded files are skipped, and excluded folders aren't descended into.
+    pub fn excludes(&self, relative: &Path) -> bool {
+        self.exclude_set.is_match(relative)
+    }
+
+    /// With include patterns, only files matching one are uploaded.
+    pub fn includes(&self, relative: &Path) -> bool {
+        self.include.is_empty() || self.include_set.is_match(relative)
+    }
+}
+
+fn build(patterns: &[String]) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        let anchored = match pattern.strip_prefix('/') {
+            Some(rest) => rest.to_string(),
+            None if pattern.contains('/') => pattern.clone(),
+            None => format!("**/{}", pattern),
+        };
+        let glob = GlobBuilder::new(anchored.trim_end_matches('/'))
+            .literal_separator(true)
+            .build()
+            .with_context(|| format!("Invalid pattern '{}'", pattern))?;
+        builder.add(glob);
+    }
+    builder.build().context("Invalid include/exclude patterns")
+}
@@ -0,0 +1,280 @@
+use anyhow::{Context, Result};
+use rustls::pki_types::ServerName;
+use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::{debug, info, trace};
+use crate::backend::Backend;
+use crate::config::Destination;
+use crate::failure::{self, Failure};
+use crate::net;
+use crate::secret::Password;
+use crate::transfer::TransferOptions;
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct FtpsSettings {
+    pub host: String,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    pub username: String,
+    pub password: Password,
+}
+
+fn default_port() -> u16 {
+    21
+}
+
+type TlsStream = StreamOwned<ClientConnection, TcpStream>;
+
+/// An FTP server reached with explicit TLS (`AUTH TLS`), with both the
+/// commands and every file encrypted.
+pub struct Ftps {
+    control: Mutex<BufReader<TlsStream>>,
+    tls: Arc<ClientConfig>,
+    server_name: ServerName<'static>,
+    /// Where the server put us on login, for resolving relative paths.
+    home: PathBuf,
+    timeouts: Timeouts,
+    folders: Mutex<HashSet<PathBuf>>,
+}
+
+#[derive(Clone, Copy)]
+struct Timeouts {
+    connect: Option<Duration>,
+    io: Option<Duration>,
+}
+
+impl Ftps {
+    pub fn open(destination: &Destination, options: &TransferOptions) -> Result<Self> {
+        let settings = destination.ftps.as_ref()
+            .with_context(|| format!("Destination '{}' has no [destinations.ftps] settings", destination.name))?;
+        let timeouts = Timeouts { connect: options.connect_timeout, io: options.io_timeout };
+        let server_name = ServerName::try_from(settings.host.clone())
+            .with_context(|| format!("Invalid FTPS host: {}", settings.host))?;
+        let roots = RootCertStore { roots: webpki_roots::TLS_SERVER_ROOTS.to_vec() };
+        let tls = Arc::new(ClientConfig::builder().with_root_certificates(roots).with_no_client_auth());
+
+        // The certificate is checked against `host` even when connecting elsewhere
+        let address = destination.connect_address.as_deref().unwrap_or(&settings.host);
+        info!("Connecting to {}:{}", address, settings.port);
+        let addresses = net::resolve(address, settings.port, options.address_family)
+            .map_err(|e| failure::fail(Failure::Connect, format!("Failed to resolve {}: {}", address, e)))?;
+        let tcp = connect(&addresses, timeouts)
+            .map_err(|e| failure::fail(Failure::Connect, format!("Failed to connect to {}:{}: {}", address, settings.port, e)))?;
+
+        // The greeting and AUTH TLS go in the clear, everything after is encrypted
+        let mut plain = BufReader::new(tcp);
+        expect(read_reply(&mut plain)?, 220)?;
+        plain.get_mut().write_all(b"AUTH TLS\r\n").context("Failed to send AUTH TLS")?;
+        expect(read_reply(&mut plain)?, 234)
+            .map_err(|e| failure::fail(Failure::Connect, format!("{} doesn't offer FTPS ({:#})", settings.host, e)))?;
+        let mut connection = ClientConnection::new(tls.clone(), server_name.clone())
+            .context("Failed to start TLS")?;
+        let mut tcp = plain.into_inner();
+        while connection.is_handshaking() {
+            connection.complete_io(&mut tcp).map_err(|e| failure::fail(Failure::Connect, format!(
+                "TLS with {} failed: {}", settings.host, e
+            )))?;
+        }
+        let mut control = BufReader::new(StreamOwned::new(connection, tcp));
+
+        let password = settings.password.resolve(&format!("{}@{}", settings.username, settings.host), &options.progress)?;
+        info!("Logging in as {}", settings.username);
+        let reply = command(&mut control, &format!("USER {}", settings.username))?;
+        let reply = match reply.0 {
+            331 => command(&mut control, &format!("PASS {}", password))?,
+            _ => reply,
+        };
+        expect(reply, 230).map_err(|e| failure::fail(Failure::Auth, format!("FTPS login failed: {:#}", e)))?;
+
+        for setup in ["PBSZ 0", "PROT P", "TYPE I"] {
+            expect(command(&mut control, setup)?, 200)?;
+        }
+        let (_, pwd) = expect(command(&mut control, "PWD")?, 257)?;
+        let home = pwd.split('"').nth(1).map(PathBuf::from).unwrap_or_else(|| PathBuf::from("/"));
+        debug!("FTPS home folder: {}", home.display());
+
+        Ok(Ftps {
+            control: Mutex::new(control),
+            tls,
+            server_name,
+            home,
+            timeouts,
+            folders: Mutex::new(HashSet::new()),
+        })
+    }
+
+    /// `path` as the server takes it: absolute, and without line breaks
+    /// that would end the command early.
+    fn remote(&self, path: &Path) -> Result<String> {
+        let path = self.home.join(path).to_string_lossy().into_owned();
+        if path.contains(['\r', '\n']) {
+            anyhow::bail!("FTP can't handle line breaks in names: {:?}", path);
+        }
+        Ok(path)
+    }
+
+    /// Opens a passive data connection, encrypted like the control one
+    /// (reusing its TLS session, which many servers insist on).
+    fn data_connection(&self, control: &mut BufReader<TlsStream>) -> Result<TlsStream> {
+        let peer = control.get_ref().sock.peer_addr().context("Failed to read server address")?;
+        let port = match command(control, "EPSV")? {
+            (229, text) => text.split('|').nth(3).and_then(|p| p.parse().ok()),
+            _ => match command(control, "PASV")? {
+                (227, text) => pasv_port(&text),
+                reply => return Err(unexpected(reply)),
+            },
+        };
+        let port = port.context("Couldn't read the server's passive port")?;
+        // The server's own idea of its address is often wrong behind NAT
+        let tcp = connect(&[SocketAddr::new(peer.ip(), port)], self.timeouts)
+            .context("Failed to open FTPS data connection")?;
+        let connection = ClientConnection::new(self.tls.clone(), self.server_name.clone())
+            .context("Failed to start TLS")?;
+        Ok(StreamOwned::new(connection, tcp))
+    }
+}
+
+impl Backend for Ftps {
+    fn exists(&self, path: &Path) -> Result<bool> {
+        let remote = self.remote(path)?;
+        match command(&mut self.control.lock().unwrap(), &format!("SIZE {}", remote))? {
+            (213, _) => Ok(true),
+            (550, _) => Ok(false),
+            reply => Err(unexpected(reply)),
+        }
+    }
+
+    fn is_dir(&self, dir: &Path) -> Result<bool> {
+        if self.folders.lock().unwrap().contains(dir) {
+            return Ok(true);
+        }
+        let remote = self.remote(dir)?;
+        let found = match command(&mut self.control.lock().unwrap(), &format!("CWD {}", remote))? {
+            (250, _) => true,
+            (550, _) => false,
+            reply => return Err(unexpected(reply)),
+        };
+        if found {
+            self.folders.lock().unwrap().insert(dir.to_path_buf());
+        }
+        Ok(found)
+    }
+
+    fn create_dir(&self, dir: &Path) -> Result<()> {
+        debug!("Creating directory: {}", dir.display());
+        let remote = self.remote(dir)?;
+        let reply = command(&mut self.control.lock().unwrap(), &format!("MKD {}", remote))?;
+        // 550 as well when another upload created it in the meantime
+        if reply.0 != 257 && !self.is_dir(dir)? {
+            return Err(unexpected(reply)).with_context(|| format!("Failed to create remote directory: {}", dir.display()));
+        }
+        self.folders.lock().unwrap().insert(dir.to_path_buf());
+        Ok(())
+    }
+
+    fn put(&self, path: &Path, reader: &mut dyn Read, size: u64) -> Result<()> {
+        let remote = self.remote(path)?;
+        let mut control = self.control.lock().unwrap();
+        let mut data = self.data_connection(&mut control)?;
+        let reply = command(&mut control, &format!("STOR {}", remote))?;
+        if !matches!(reply.0, 125 | 150) {
+            return Err(unexpected(reply)).with_context(|| format!("Failed to upload {}", path.display()));
+        }
+
+        io::copy(&mut reader.take(size), &mut data)
+            .with_context(|| format!("Failed to write remote file: {}", path.display()))?;
+        data.conn.send_close_notify();
+        data.flush().context("Failed to finish FTPS upload")?;
+        let _ = data.sock.shutdown(Shutdown::Write);
+        drop(data);
+
+        expect(read_reply(&mut control)?, 226)
+            .with_context(|| format!("Failed to upload {}", path.display()))?;
+        Ok(())
+    }
+
+    fn remove(&self, path: &Path) -> Result<()> {
+        let remote = self.remote(path)?;
+        expect(command(&mut self.control.lock().unwrap(), &format!("DELE {}", remote))?, 250)
+            .with_context(|| format!("Failed to delete {}", path.display()))?;
+        Ok(())
+    }
+}
+
+impl Drop for Ftps {
+    fn drop(&mut self) {
+        if let Ok(control) = self.control.get_mut() {
+            let _ = command(control, "QUIT");
+        }
+    }
+}
+
+fn connect(addresses: &[SocketAddr], timeouts: Timeouts) -> io::Result<TcpStream> {
+    let tcp = net::connect_any(addresses, timeouts.connect)?;
+    tcp.set_read_timeout(timeouts.io)?;
+    tcp.set_write_timeout(timeouts.io)?;
+    Ok(tcp)
+}
+
+/// Sends one command and reads its reply.
+fn command<S: Read + Write>(control: &mut BufReader<S>, line: &str) -> Result<(u32, String)> {
+    match line.split_once(' ') {
+        Some(("PASS", _)) => trace!("FTPS > PASS ****"),
+        _ => trace!("FTPS > {}", line),
+    }
+    control.get_mut().write_all(format!("{}\r\n", line).as_bytes())
+        .and_then(|_| control.get_mut().flush())
+        .map_err(|e| anyhow::anyhow!("Failed to send FTP command: {}", e))?;
+    read_reply(control)
+}
+
+/// Reads a reply, joining the lines of a multi-line one (`230-...`
+/// up to `230 ...`).
+fn read_reply<S: Read>(control: &mut BufReader<S>) -> Result<(u32, String)> {
+    let mut text = String::new();
+    loop {
+        let mut line = String::new();
+        let read = control.read_line(&mut line).map_err(|e| anyhow::anyhow!("Failed to read FTP reply: {}", e))?;
+        if read == 0 {
+            anyhow::bail!("The FTP server closed the connection");
+        }
+        let line = line.trim_end();
+        trace!("FTPS < {}", line);
+        if !text.is_empty() {
+            text.push('\n');
+        }
+        text.push_str(line);
+        let code = line.get(..3).and_then(|c| c.parse::<u32>().ok());
+        if let (Some(code), Some(b' ') | None) = (code, line.as_bytes().get(3)) {
+            return Ok((code, text));
+        }
+    }
+}
+
+fn expect(reply: (u32, String), code: u32) -> Result<(u32, String)> {
+    match reply.0 == code {
+        true => Ok(reply),
+        false => Err(unexpected(reply)),
+    }
+}
+
+fn unexpected((code, text): (u32, String)) -> anyhow::Error {
+    anyhow::anyhow!("FTP server replied {}", if text.is_empty() { code.to_string() } else { text })
+}
+
+/// The port in a PASV reply, `227 Entering Passive Mode (h1,h2,h3,h4,p1,p2)`.
+fn pasv_port(text: &str) -> Option<u16> {
+    let start = text.find('(')? + 1;
+    let end = text[start..].find(')')? + start;
+    let numbers: Vec<u16> = text[start..end].split(',').filter_map(|n| n.trim().parse().ok()).collect();
+    match numbers[..] {
+        [_, _, _, _, high, low] if high < 256 && low < 256 => Some(high * 256 + low),
+        _ => None,
+    }
+}
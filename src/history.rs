@@ -0,0 +1,81 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::config::Config;
+
+const HISTORY_FILE: &str = "history.jsonl";
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Upload,
+    Download,
+}
+
+/// One upload to or download from a destination, as kept in the
+/// append-only journal (one JSON object per line).
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct HistoryEntry {
+    /// Unix time the transfer started.
+    pub started: u64,
+    pub direction: Direction,
+    pub destination: String,
+    pub paths: Vec<PathBuf>,
+    pub bytes: u64,
+    pub duration_secs: f64,
+    /// The error that stopped it; `None` for transfers that finished.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub run_id: Option<String>,
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn path() -> Result<PathBuf> {
+    Ok(Config::config_dir()?.join(HISTORY_FILE))
+}
+
+/// Appends `entry` to the journal. Each entry is written with a single
+/// `write` to a file opened for appending, so concurrent runs don't
+/// interleave their lines.
+pub fn record(entry: &HistoryEntry) -> Result<()> {
+    let path = path()?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .context("Failed to create config directory")?;
+    }
+    let mut line = serde_json::to_string(entry).context("Failed to serialize history entry")?;
+    line.push('\n');
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .and_then(|mut file| file.write_all(line.as_bytes()))
+        .with_context(|| format!("Failed to write {}", path.display()))
+}
+
+/// Journal entries, oldest first, for `destination` if given. Lines that
+/// don't parse (e.g. cut short by a crash) are skipped.
+pub fn read(destination: Option<&str>) -> Result<Vec<HistoryEntry>> {
+    let path = path()?;
+    let file = match fs::File::open(&path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    };
+    Ok(BufReader::new(file)
+        .lines()
+        .map_while(Result::ok)
+        .filter_map(|line| serde_json::from_str::<HistoryEntry>(&line).ok())
+        .filter(|entry| destination.is_none_or(|d| entry.destination == d))
+        .collect())
+}
@@ -0,0 +1,135 @@
+use anyhow::{Context, Result};
+use base64::engine::general_purpose::{STANDARD, STANDARD_NO_PAD};
+use base64::Engine;
+use dialoguer::Confirm;
+use indicatif::MultiProgress;
+use ssh2::{CheckResult, HashType, HostKeyType, KnownHostFileKind, Session};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use tracing::{debug, warn};
+use crate::config::Destination;
+use crate::progress;
+use crate::prompt;
+
+/// Serializes trust prompts and writes to known_hosts between
+/// destinations connecting in parallel.
+static PROMPT: Mutex<()> = Mutex::new(());
+
+fn known_hosts_path() -> Result<PathBuf> {
+    let home = dirs::home_dir()
+        .context("Could not find home directory")?;
+    Ok(home.join(".ssh").join("known_hosts"))
+}
+
+/// Name the host is stored under, matching OpenSSH: bare for port 22,
+/// `[host]:port` otherwise.
+fn host_entry(destination: &Destination) -> String {
+    match destination.port {
+        22 => destination.host.clone(),
+        port => format!("[{}]:{}", destination.host, port),
+    }
+}
+
+/// Checks the server's key against `~/.ssh/known_hosts`. Unknown hosts
+/// are trusted on first use after confirming the fingerprint; a changed
+/// key is always an error.
+pub fn verify(session: &Session, destination: &Destination, progress: &MultiProgress) -> Result<()> {
+    let (key, key_type) = session.host_key()
+        .context("Server did not send a host key")?;
+    let fingerprint = session.host_key_hash(HashType::Sha256)
+        .map(|hash| format!("SHA256:{}", STANDARD_NO_PAD.encode(hash)))
+        .unwrap_or_else(|| "unknown".to_string());
+    let path = known_hosts_path()?;
+
+    let _guard = PROMPT.lock().unwrap();
+    let mut known_hosts = session.known_hosts()
+        .context("Failed to initialize known_hosts support")?;
+    if path.exists() {
+        if let Err(e) = known_hosts.read_file(&path, KnownHostFileKind::OpenSSH) {
+            warn!("Some entries in {} could not be read: {}", path.display(), e);
+        }
+    }
+
+    match known_hosts.check_port(&destination.host, destination.port, key) {
+        CheckResult::Match => {
+            debug!("Host key matches {} ({})", path.display(), fingerprint);
+            Ok(())
+        }
+        CheckResult::Mismatch => anyhow::bail!(
+            "HOST KEY FOR {} HAS CHANGED ({}). Someone may be intercepting the connection, \
+             or the server was reinstalled. If you trust the new key, remove the old one with: \
+             ssh-keygen -R {}",
+            host_entry(destination), fingerprint, host_entry(destination)
+        ),
+        CheckResult::NotFound | CheckResult::Failure => {
+            let type_name = key_type_name(key_type)
+                .with_context(|| format!("Unsupported host key type from {}", destination.host))?;
+            if !prompt::allowed() {
+                anyhow::bail!(
+                    "{} is not in {} ({} key {}). Connect once interactively to trust it, or add it with: \
+                     ssh-keyscan -p {} {} >> {}",
+                    host_entry(destination), path.display(), type_name, fingerprint,
+                    destination.port, destination.host, path.display()
+                );
+            }
+
+            let trusted = progress::suspend(progress, || {
+                eprintln!("🔐 {} is not a known host.", host_entry(destination));
+                eprintln!("   {} key fingerprint is {}", type_name, fingerprint);
+                Confirm::new()
+                    .with_prompt("Trust this host and remember its key?")
+                    .default(false)
+                    .interact()
+            }).context("Failed to read answer")?;
+            if !trusted {
+                anyhow::bail!("Host key for {} was not trusted", host_entry(destination));
+            }
+
+            append_entry(&path, &format!("{} {} {}", host_entry(destination), type_name, STANDARD.encode(key)))?;
+            progress::suspend(progress, || eprintln!("✅ Added {} to {}", host_entry(destination), path.display()));
+            Ok(())
+        }
+    }
+}
+
+/// Appends a line rather than letting libssh2 rewrite the file, which
+/// would drop entries and comments it doesn't understand.
+fn append_entry(path: &std::path::Path, line: &str) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(dir)
+            .with_context(|| format!("Failed to create {}", dir.display()))?;
+    }
+
+    let needs_newline = fs::read(path)
+        .map(|content| !content.is_empty() && !content.ends_with(b"\n"))
+        .unwrap_or(false);
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .mode(0o600)
+        .open(path)
+        .with_context(|| format!("Failed to open {}", path.display()))?;
+    if needs_newline {
+        writeln!(file).with_context(|| format!("Failed to write {}", path.display()))?;
+    }
+    writeln!(file, "{}", line)
+        .with_context(|| format!("Failed to write {}", path.display()))
+}
+
+fn key_type_name(key_type: HostKeyType) -> Option<&'static str> {
+    match key_type {
+        HostKeyType::Rsa => Some("ssh-rsa"),
+        HostKeyType::Dss => Some("ssh-dss"),
+        HostKeyType::Ecdsa256 => Some("ecdsa-sha2-nistp256"),
+        HostKeyType::Ecdsa384 => Some("ecdsa-sha2-nistp384"),
+        HostKeyType::Ecdsa521 => Some("ecdsa-sha2-nistp521"),
+        HostKeyType::Ed25519 => Some("ssh-ed25519"),
+        HostKeyType::Unknown => None,
+    }
+}
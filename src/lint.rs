@@ -0,0 +1,218 @@
+use anyhow::{Context, Result};
+use std::collections::HashSet;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::bandwidth;
+use crate::config::{Config, Destination, DestinationKind, EncryptionBackend, Protocol};
+use crate::encryption::Encryption;
+use crate::failure::{self, Classify, Failure};
+use crate::filter::PathFilter;
+use crate::manifest::Signer;
+use crate::schedule::Cron;
+use crate::template;
+use crate::units;
+
+/// Something `arkv config check` found.
+struct Finding {
+    /// Uploads would fail, rather than maybe not do what was meant.
+    error: bool,
+    subject: String,
+    message: String,
+}
+
+#[derive(Default)]
+struct Findings(Vec<Finding>);
+
+impl Findings {
+    fn error(&mut self, subject: &str, message: impl Into<String>) {
+        self.0.push(Finding { error: true, subject: subject.to_string(), message: message.into() });
+    }
+
+    fn warning(&mut self, subject: &str, message: impl Into<String>) {
+        self.0.push(Finding { error: false, subject: subject.to_string(), message: message.into() });
+    }
+
+    /// Records `result`'s error, if any.
+    fn check<T>(&mut self, subject: &str, result: Result<T>) {
+        if let Err(e) = result {
+            self.error(subject, format!("{:#}", e));
+        }
+    }
+}
+
+/// Reads the config file and checks everything in it that can be checked
+/// without connecting, printing each problem with what to do about it.
+/// Fails when any would stop an upload.
+pub fn run() -> Result<()> {
+    let path = Config::config_path()?;
+    let config = Config::read().classify(Failure::Config)?
+        .with_context(|| format!("No configuration found at {}. Run 'arkv --setup' first", path.display()))
+        .classify(Failure::Config)?;
+
+    for finding in &check(&config).0 {
+        let mark = if finding.error { "❌" } else { "⚠️ " };
+        println!("{} {}: {}", mark, finding.subject, finding.message);
+    }
+    let (errors, warnings) = count(&config);
+    if errors > 0 {
+        return Err(failure::fail(Failure::Config, format!(
+            "{} has {} error(s) and {} warning(s)", path.display(), errors, warnings
+        )));
+    }
+    println!(
+        "✓ {} is valid: {} destination(s), {} schedule(s), {} warning(s)",
+        path.display(), config.destinations.len(), config.schedules.len(), warnings
+    );
+    Ok(())
+}
+
+/// How many errors and warnings `arkv config check` would report.
+pub fn count(config: &Config) -> (usize, usize) {
+    let findings = check(config);
+    let errors = findings.0.iter().filter(|f| f.error).count();
+    (errors, findings.0.len() - errors)
+}
+
+fn check(config: &Config) -> Findings {
+    let mut findings = Findings::default();
+    if config.destinations.is_empty() {
+        findings.error("config", "no destinations; run 'arkv --setup' to add one");
+    }
+
+    let mut names = HashSet::new();
+    for destination in &config.destinations {
+        if !names.insert(destination.name.as_str()) {
+            findings.error(&destination.name, "more than one destination has this name; --to can only pick the first");
+        }
+        check_destination(config, destination, &mut findings);
+    }
+
+    let sftp = config.destinations.iter().filter(|d| d.kind == DestinationKind::Sftp && !d.gssapi);
+    if !Path::new(&config.ssh_key_path).exists() {
+        match sftp.clone().any(|d| d.ssh_agent == Some(false) && d.password.is_none() && d.password_command.is_none()) {
+            true => findings.error("ssh_key_path", format!("{} doesn't exist, and a destination has ssh_agent = false and no password", config.ssh_key_path)),
+            false if sftp.clone().next().is_some() => {
+                findings.warning("ssh_key_path", format!("{} doesn't exist; only ssh-agent or a password can log in", config.ssh_key_path))
+            }
+            false => {}
+        }
+    }
+
+    let tags: HashSet<&str> = config.destinations.iter().flat_map(|d| d.tags.iter().map(String::as_str)).collect();
+    let mut schedules = HashSet::new();
+    for schedule in &config.schedules {
+        let subject = format!("schedule '{}'", schedule.name);
+        if !schedules.insert(schedule.name.as_str()) {
+            findings.error(&subject, "more than one schedule has this name, so they share when they last ran");
+        }
+        findings.check(&subject, Cron::parse(&schedule.cron));
+        for name in &schedule.to {
+            if !names.contains(name.as_str()) {
+                findings.error(&subject, format!("uploads to '{}', which isn't a destination", name));
+            }
+        }
+        for tag in &schedule.group {
+            if !tags.contains(tag.as_str()) {
+                findings.error(&subject, format!("uploads to group '{}', but no destination is tagged with it", tag));
+            }
+        }
+        for path in &schedule.paths {
+            if !Path::new(path).exists() {
+                findings.warning(&subject, format!("{} doesn't exist (yet)", path));
+            }
+        }
+    }
+    findings
+}
+
+fn check_destination(config: &Config, destination: &Destination, findings: &mut Findings) {
+    let subject = destination.name.as_str();
+    findings.check(subject, destination.check());
+
+    if destination.kind == DestinationKind::Sftp && destination.port == 0 {
+        findings.error(subject, "port 0 can't be connected to");
+    }
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    findings.check(subject, template::expand(&destination.remote_path, now, "example"));
+    if destination.kind == DestinationKind::Local && !destination.remote_base().exists() {
+        findings.warning(subject, format!("{} doesn't exist right now (is the drive mounted?)", destination.remote_base().display()));
+    }
+
+    findings.check(subject, PathFilter::new(&destination.include, &destination.exclude));
+    findings.check(subject, bandwidth::Schedule::new(&destination.bandwidth));
+
+    let defaults = &destination.defaults;
+    if defaults.encrypt == Some(true) {
+        let recipients = match destination.encryption {
+            EncryptionBackend::Age => &destination.age_recipients,
+            EncryptionBackend::Gpg => &destination.gpg_recipients,
+        };
+        findings.check(subject, Encryption::new(destination.encryption, recipients));
+    } else if !destination.age_recipients.is_empty() {
+        findings.check(subject, Encryption::new(destination.encryption, &destination.age_recipients));
+    }
+    if let Some(split) = &defaults.split {
+        findings.check(subject, units::parse_size(split).with_context(|| format!("Invalid split size '{}'", split)));
+    }
+    for (setting, value) in [("jobs", defaults.jobs), ("chunks", defaults.chunks)] {
+        if value == Some(0) {
+            findings.error(subject, format!("defaults.{} is 0, so nothing would be uploaded", setting));
+        }
+    }
+    if let Some(percent) = defaults.quota_warn_percent.filter(|p| !(0.0..=100.0).contains(p)) {
+        findings.warning(subject, format!("quota_warn_percent = {} is outside 0-100, so it never warns", percent));
+    }
+    match defaults.sign {
+        Some(Signer::Minisign) => {
+            if let Ok(key) = config.minisign_secret_key() {
+                if !key.exists() {
+                    findings.error(subject, format!("signs with minisign, but there's no key at {} (run minisign -G)", key.display()));
+                }
+            }
+        }
+        Some(Signer::Ssh) if !Path::new(&config.ssh_key_path).exists() => {
+            findings.error(subject, format!("signs with the SSH key, but {} doesn't exist", config.ssh_key_path));
+        }
+        _ => {}
+    }
+
+    if let Some(retention) = &destination.retention {
+        if retention.keep_last.is_none() && retention.keep_days.is_none() {
+            findings.warning(subject, "[destinations.retention] sets neither keep_last nor keep_days, so nothing is pruned");
+        }
+    }
+    if destination.gssapi && (destination.password.is_some() || destination.password_command.is_some()) {
+        findings.warning(subject, "gssapi logs in with Kerberos tickets, so the password is never used");
+    }
+    if destination.kind != DestinationKind::Sftp || destination.gssapi {
+        if destination.kind != DestinationKind::Sftp && destination.protocol != Protocol::Sftp {
+            findings.warning(subject, "protocol only applies to SFTP destinations, and is ignored");
+        }
+        let sftp_only = destination.kind != DestinationKind::Local;
+        let needs_sftp: Vec<&str> = [
+            (defaults.resume == Some(true), "resume"),
+            (defaults.sync == Some(true), "sync"),
+            (defaults.archive == Some(true), "archive"),
+            (defaults.incremental == Some(true), "incremental"),
+            (defaults.snapshot == Some(true), "snapshot"),
+            (defaults.checksum == Some(true), "checksum"),
+            (defaults.dedup == Some(true), "dedup"),
+            (defaults.compress.is_some(), "compress"),
+            (defaults.encrypt == Some(true), "encrypt"),
+            (sftp_only && defaults.manifest == Some(true), "manifest"),
+            (sftp_only && defaults.verify == Some(true), "verify"),
+            (defaults.chunks.is_some_and(|chunks| chunks > 1), "chunks"),
+            (defaults.split.is_some(), "split"),
+            (defaults.quota_warn_percent.is_some(), "quota_warn_percent"),
+            (destination.owner.is_some() || destination.group.is_some(), "owner/group"),
+        ]
+        .into_iter()
+        .filter_map(|(used, name)| used.then_some(name))
+        .collect();
+        if !needs_sftp.is_empty() {
+            findings.error(subject, format!(
+                "{} can't be used with {}, which only takes plain uploads", needs_sftp.join(", "), destination.address()
+            ));
+        }
+    }
+}
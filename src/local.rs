@@ -0,0 +1,69 @@
+use anyhow::{Context, Result};
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::Path;
+use crate::backend::Backend;
+use crate::config::Destination;
+use crate::failure::{self, Failure};
+use crate::transfer::partial_path;
+
+/// A folder on this machine, such as an external drive or a mounted NFS
+/// or SMB share, that uploads are copied into.
+pub struct LocalDir;
+
+impl LocalDir {
+    /// Refuses a destination whose folder isn't there, which mostly means
+    /// the drive isn't mounted, rather than filling the system disk.
+    pub fn open(destination: &Destination) -> Result<Self> {
+        let base = destination.remote_base();
+        if !base.is_dir() {
+            return Err(failure::fail(Failure::Connect, format!(
+                "{}: {} doesn't exist (is the drive mounted?)", destination.name, base.display()
+            )));
+        }
+        Ok(LocalDir)
+    }
+}
+
+impl Backend for LocalDir {
+    fn exists(&self, path: &Path) -> Result<bool> {
+        Ok(fs::symlink_metadata(path).is_ok())
+    }
+
+    fn is_dir(&self, dir: &Path) -> Result<bool> {
+        Ok(dir.is_dir())
+    }
+
+    fn create_dir(&self, dir: &Path) -> Result<()> {
+        match fs::create_dir(dir) {
+            Err(e) if e.kind() != io::ErrorKind::AlreadyExists => {
+                Err(e).with_context(|| format!("Failed to create directory: {}", dir.display()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Writes to a hidden partial file that is synced to disk before being
+    /// renamed into place, so a pulled drive never holds a cut-off copy
+    /// under the real name.
+    fn put(&self, path: &Path, reader: &mut dyn Read, size: u64) -> Result<()> {
+        let partial = partial_path(path);
+        let result = (|| {
+            let mut file = File::create(&partial)
+                .with_context(|| format!("Failed to create {}", partial.display()))?;
+            io::copy(&mut reader.take(size), &mut file)
+                .and_then(|_| file.sync_all())
+                .with_context(|| format!("Failed to write {}", path.display()))?;
+            fs::rename(&partial, path)
+                .with_context(|| format!("Failed to move {} into place", path.display()))
+        })();
+        if result.is_err() {
+            let _ = fs::remove_file(&partial);
+        }
+        result
+    }
+
+    fn remove(&self, path: &Path) -> Result<()> {
+        fs::remove_file(path).with_context(|| format!("Failed to delete {}", path.display()))
+    }
+}
@@ -0,0 +1,205 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use ssh2::{OpenFlags, OpenType, Sftp};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{debug, info};
+use crate::failure::{self, Failure};
+
+const LOCK_FILE: &str = ".arkv.lock";
+/// A lock whose holder hasn't refreshed it for this long is considered abandoned.
+const STALE_AFTER: Duration = Duration::from_secs(600);
+const REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Advisory lock on a remote folder, so two arkv instances don't write
+/// into the same target at once. The holder refreshes `updated` while it
+/// works; a lock that stops being refreshed, or whose process is gone on
+/// this machine, is taken over.
+pub struct RemoteLock {
+    path: PathBuf,
+    info: LockInfo,
+    last_refresh: Instant,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct LockInfo {
+    host: String,
+    pid: u32,
+    acquired: u64,
+    updated: u64,
+}
+
+impl LockInfo {
+    fn is_ours(&self) -> bool {
+        self.host == hostname() && self.pid == std::process::id()
+    }
+
+    fn is_stale(&self) -> bool {
+        if self.host == hostname() && !process_alive(self.pid) {
+            return true;
+        }
+        now_secs().saturating_sub(self.updated) > STALE_AFTER.as_secs()
+    }
+}
+
+pub enum Acquired {
+    Locked(RemoteLock),
+    /// Another thread of this same run already holds the lock.
+    Shared,
+}
+
+impl RemoteLock {
+    pub fn acquire(sftp: &Sftp, remote_dir: &Path, wait: Duration) -> Result<Acquired> {
+        let path = remote_dir.join(LOCK_FILE);
+        let deadline = Instant::now() + wait;
+
+        loop {
+            let now = now_secs();
+            let info = LockInfo { host: hostname(), pid: std::process::id(), acquired: now, updated: now };
+
+            match sftp.open_mode(&path, OpenFlags::WRITE | OpenFlags::EXCLUSIVE, 0o644, OpenType::File) {
+                Ok(mut file) => {
+                    write_info(&mut file, &info)?;
+                    debug!("Acquired remote lock: {}", path.display());
+                    return Ok(Acquired::Locked(RemoteLock { path, info, last_refresh: Instant::now() }));
+                }
+                Err(create_error) => {
+                    let holder = read_info(sftp, &path);
+                    if let Some(holder) = &holder {
+                        if holder.is_ours() {
+                            return Ok(Acquired::Shared);
+                        }
+                    }
+
+                    // An unreadable lock may be one another instance is still writing
+                    let stale = match &holder {
+                        Some(holder) => holder.is_stale(),
+                        None => match sftp.stat(&path) {
+                            Ok(stat) => now.saturating_sub(stat.mtime.unwrap_or(0)) > STALE_AFTER.as_secs(),
+                            Err(_) => {
+                                return Err(create_error)
+                                    .with_context(|| format!("Failed to create remote lock: {}", path.display()));
+                            }
+                        },
+                    };
+
+                    if stale {
+                        if let Some(holder) = &holder {
+                            eprintln!("⚠️  Taking over stale lock from {} (pid {})", holder.host, holder.pid);
+                        }
+                        // Someone else may have taken it over first; the next
+                        // exclusive create settles who wins.
+                        let _ = sftp.unlink(&path);
+                        continue;
+                    }
+
+                    let (host, pid) = holder.map(|h| (h.host, h.pid.to_string()))
+                        .unwrap_or_else(|| ("another host".to_string(), "unknown".to_string()));
+                    if Instant::now() >= deadline {
+                        return Err(failure::fail(Failure::Locked, format!(
+                            "{} is locked by arkv on {} (pid {}); retry later or pass --lock-wait",
+                            remote_dir.display(), host, pid
+                        )));
+                    }
+                    info!("Waiting for remote lock held by {} (pid {})", host, pid);
+                    thread::sleep(POLL_INTERVAL);
+                }
+            }
+        }
+    }
+
+    /// Keeps the lock fresh during long transfers. Cheap to call often.
+    pub fn refresh(&mut self, sftp: &Sftp) -> Result<()> {
+        if self.last_refresh.elapsed() < REFRESH_INTERVAL {
+            return Ok(());
+        }
+
+        self.info.updated = now_secs();
+        let mut file = sftp.open_mode(&self.path, OpenFlags::WRITE | OpenFlags::TRUNCATE, 0o644, OpenType::File)
+            .with_context(|| format!("Failed to refresh remote lock: {}", self.path.display()))?;
+        write_info(&mut file, &self.info)?;
+        self.last_refresh = Instant::now();
+        Ok(())
+    }
+
+    pub fn release(self, sftp: &Sftp) -> Result<()> {
+        sftp.unlink(&self.path)
+            .with_context(|| format!("Failed to release remote lock: {}", self.path.display()))
+    }
+}
+
+fn write_info(file: &mut ssh2::File, info: &LockInfo) -> Result<()> {
+    let content = toml::to_string(info)
+        .context("Failed to serialize lock")?;
+    file.write_all(content.as_bytes())
+        .context("Failed to write remote lock")
+}
+
+fn read_info(sftp: &Sftp, path: &Path) -> Option<LockInfo> {
+    let mut content = String::new();
+    sftp.open(path).ok()?.read_to_string(&mut content).ok()?;
+    toml::from_str(&content).ok()
+}
+
+/// Whether `pid` is running on this machine. `EPERM` means it is, just
+/// under another user; only `ESRCH` says it's gone.
+fn process_alive(pid: u32) -> bool {
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return false;
+    };
+    let signalled = unsafe { libc::kill(pid, 0) } == 0;
+    signalled || std::io::Error::last_os_error().raw_os_error() != Some(libc::ESRCH)
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+pub fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    let rc = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+    if rc != 0 {
+        return "unknown".to_string();
+    }
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lock(host: &str, pid: u32, age: Duration) -> LockInfo {
+        let updated = now_secs() - age.as_secs();
+        LockInfo { host: host.to_string(), pid, acquired: updated, updated }
+    }
+
+    #[test]
+    fn live_lock_on_this_host_is_kept() {
+        assert!(!lock(&hostname(), std::process::id(), Duration::ZERO).is_stale());
+    }
+
+    #[test]
+    fn lock_of_finished_process_is_stale() {
+        // Past the kernel's pid_max, so no process has it
+        assert!(lock(&hostname(), 0x7fff_fff0, Duration::ZERO).is_stale());
+    }
+
+    #[test]
+    fn other_hosts_locks_go_stale_by_age() {
+        assert!(!lock("elsewhere.invalid", 1, Duration::from_secs(60)).is_stale());
+        assert!(!lock("elsewhere.invalid", 1, STALE_AFTER).is_stale());
+        assert!(lock("elsewhere.invalid", 1, STALE_AFTER + Duration::from_secs(1)).is_stale());
+    }
+
+    #[test]
+    fn unrefreshed_lock_on_this_host_is_stale() {
+        assert!(lock(&hostname(), std::process::id(), STALE_AFTER * 2).is_stale());
+    }
+}
@@ -0,0 +1,99 @@
+use anyhow::{Context, Result};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tracing::Level;
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::fmt::time::SystemTime;
+use tracing_subscriber::prelude::*;
+use crate::progress;
+
+/// A log file bigger than this is rotated when arkv starts.
+const ROTATE_SIZE: u64 = 10 * 1024 * 1024;
+/// Rotated logs kept next to the current one (`arkv.log.1` ... `arkv.log.5`).
+const ROTATE_KEEP: usize = 5;
+
+/// Sets up diagnostics. `verbose` is how many times `-v` was given: once
+/// shows the main steps of a run on stderr as plain lines, twice adds
+/// each file, three times every remote check (errors are already reported
+/// there). With `log_file`, messages down to per-file ones (or everything
+/// with `-vvv`) are also appended there with timestamps, starting with a
+/// line for the run.
+pub fn init(verbose: u8, log_file: Option<&Path>) -> Result<()> {
+    let level = match verbose {
+        0 => LevelFilter::OFF,
+        1 => LevelFilter::INFO,
+        2 => LevelFilter::DEBUG,
+        _ => LevelFilter::TRACE,
+    };
+    let stderr = (verbose > 0).then(|| tracing_subscriber::fmt::layer()
+        .with_writer(|| Stderr)
+        .without_time()
+        .with_level(false)
+        .with_target(false)
+        .with_filter(level)
+        .with_filter(tracing_subscriber::filter::filter_fn(|meta| *meta.level() != Level::ERROR)));
+
+    let file = match log_file {
+        Some(path) => Some(tracing_subscriber::fmt::layer()
+            .with_writer(Mutex::new(open(path)?))
+            .with_timer(SystemTime)
+            .with_ansi(false)
+            .with_target(false)
+            .with_thread_names(true)
+            .with_filter(level.max(LevelFilter::DEBUG))),
+        None => None,
+    };
+
+    tracing_subscriber::registry()
+        .with(stderr)
+        .with(file)
+        .try_init()
+        .context("Failed to set up logging")?;
+
+    if log_file.is_some() {
+        let args: Vec<String> = std::env::args().skip(1).collect();
+        tracing::info!("arkv {} started (pid {}): {}", env!("CARGO_PKG_VERSION"), std::process::id(), args.join(" "));
+    }
+    Ok(())
+}
+
+/// Writes log lines above the progress bars instead of into them.
+struct Stderr;
+
+impl Write for Stderr {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        progress::suspend(progress::bars(), || io::stderr().write(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stderr().flush()
+    }
+}
+
+/// Opens the log for appending, rotating it first if it has grown too big.
+fn open(path: &Path) -> Result<File> {
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create {}", parent.display()))?;
+    }
+    if fs::metadata(path).is_ok_and(|m| m.len() > ROTATE_SIZE) {
+        for number in (1..ROTATE_KEEP).rev() {
+            let _ = fs::rename(rotated(path, number), rotated(path, number + 1));
+        }
+        fs::rename(path, rotated(path, 1))
+            .with_context(|| format!("Failed to rotate {}", path.display()))?;
+    }
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Failed to open log file: {}", path.display()))
+}
+
+fn rotated(path: &Path, number: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", number));
+    PathBuf::from(name)
+}
@@ -1,142 +1,1525 @@
+mod auth;
+mod backend;
+mod bandwidth;
 mod config;
+mod dedup;
+mod doctor;
+mod desktop;
+mod encoding;
+mod encryption;
+mod failure;
+mod filter;
+mod ftps;
+mod history;
+mod known_hosts;
+mod lint;
+mod local;
+mod lock;
+mod logging;
+mod manifest;
+mod migrate;
+#[cfg(feature = "mount")]
+mod mount;
+mod net;
+mod openssh;
+mod progress;
+mod probe;
+mod prompt;
+mod prune;
+mod queue;
+mod remote;
+mod rsync;
+mod s3;
+mod sanitize;
+mod schedule;
+mod secret;
+mod service;
 mod setup;
+mod snapshot;
+mod state;
+mod template;
 mod transfer;
+mod units;
+mod vault;
+mod watch;
+mod webdav;
+mod webhook;
 
-use anyhow::Result;
-use clap::Parser;
-use config::Config;
-use dialoguer::Select;
-use transfer::{Transferer, TransferStats};
+use anyhow::{Context, Result};
+use clap::{Parser, Subcommand, ValueEnum};
+use config::{AddressFamily, Compression, Config, Destination, DestinationKind, EncryptionBackend, IfExists, RenameScheme};
+use dialoguer::{Confirm, MultiSelect};
+use indicatif::{HumanBytes, MultiProgress};
+use encryption::Encryption;
+use failure::{Classify, Failure};
+use filter::PathFilter;
+use history::{Direction, HistoryEntry};
+use manifest::{Signer, VerifyOptions};
+use remote::Store;
+use schedule::Schedule;
+use service::Service;
+use state::RunLog;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use transfer::{TransferOptions, Transferer, TransferStats};
 
 #[derive(Parser)]
 #[command(name = "arkv")]
 #[command(about = "Archive files to remote servers via SFTP", long_about = None)]
+#[command(args_conflicts_with_subcommands = true)]
 struct Cli {
-    #[arg(help = "File or folder to archive")]
-    path: Option<String>,
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[arg(help = "Files or folders to archive")]
+    paths: Vec<String>,
+
+    #[arg(long, value_name = "FILE", help = "Also upload the paths listed in FILE, one per line ('-' for stdin), keeping their relative layout")]
+    files_from: Option<PathBuf>,
 
     #[arg(long, help = "Re-run the setup wizard")]
     setup: bool,
 
-    #[arg(short, long, help = "Select destination interactively")]
+    #[arg(short, long, conflicts_with_all = ["to", "group"], help = "Choose destinations interactively")]
     interactive: bool,
 
-    #[arg(short, long, help = "Enable verbose logging")]
-    verbose: bool,
+    #[arg(long, value_name = "NAME", help = "Upload to this destination only (repeatable)")]
+    to: Vec<String>,
+
+    #[arg(long, value_name = "TAG", help = "Upload to every destination tagged TAG (repeatable)")]
+    group: Vec<String>,
+
+    #[arg(long, global = true, help = "Never prompt; fail instead (implied without a terminal)")]
+    non_interactive: bool,
+
+    #[arg(long, global = true, value_name = "FILE", env = "ARKV_CONFIG", help = "Use this config file instead of ~/.config/arkv/config.toml")]
+    config: Option<PathBuf>,
+
+    #[arg(short, long, global = true, action = clap::ArgAction::Count,
+          help = "Show what arkv is doing: -v for the main steps, -vv for each file, -vvv for every remote check")]
+    verbose: u8,
+
+    #[arg(long, global = true, value_name = "PATH", help = "Append timestamped diagnostics to this file (rotated past 10 MB)")]
+    log_file: Option<PathBuf>,
+
+    #[arg(long, global = true, value_enum, default_value_t = OutputFormat::Text, value_name = "FORMAT", help = "How upload results are printed on stdout")]
+    output: OutputFormat,
+
+    #[arg(long, global = true, value_name = "VAR", help = "Read the SSH key passphrase from this environment variable")]
+    key_passphrase_env: Option<String>,
+
+    #[arg(long, overrides_with = "no_fast_cipher", help = "Prefer the fastest cipher supported by this CPU and the server")]
+    fast_cipher: bool,
+
+    #[arg(long, overrides_with = "fast_cipher", hide = true)]
+    no_fast_cipher: bool,
+
+    #[arg(long, value_enum, num_args = 0..=1, default_missing_value = "gzip", conflicts_with = "resume",
+        overrides_with = "no_compress", help = "Compress files while uploading (gzip by default, or zstd)")]
+    compress: Option<Compression>,
+
+    #[arg(long, overrides_with = "compress", help = "Don't compress, even if the destination does by default")]
+    no_compress: bool,
+
+    #[arg(long, overrides_with = "no_resume", help = "Continue partially uploaded files instead of starting over")]
+    resume: bool,
+
+    #[arg(long, overrides_with = "resume", hide = true)]
+    no_resume: bool,
+
+    #[arg(long, value_enum, value_name = "POLICY", help = "What to do when a remote file already exists (default: overwrite)")]
+    if_exists: Option<IfExists>,
+
+    #[arg(long, value_enum, value_name = "SCHEME", help = "How --if-exists rename names the new copy (default: number)")]
+    rename_scheme: Option<RenameScheme>,
+
+    #[arg(long, overrides_with = "no_atomic", hide = true)]
+    atomic: bool,
+
+    #[arg(long, overrides_with = "atomic", help = "Write files in place instead of under a temporary name renamed once complete")]
+    no_atomic: bool,
+
+    #[arg(long, overrides_with = "no_sync", help = "Skip files whose remote copy has the same size and modification time")]
+    sync: bool,
+
+    #[arg(long, overrides_with = "sync", hide = true)]
+    no_sync: bool,
+
+    #[arg(long, overrides_with = "no_preserve", help = "Keep each file's permissions and modification time on the remote copy")]
+    preserve: bool,
+
+    #[arg(long, overrides_with = "preserve", hide = true)]
+    no_preserve: bool,
+
+    #[arg(long, overrides_with = "no_archive", help = "Upload each folder as a single .tar file instead of file by file")]
+    archive: bool,
+
+    #[arg(long, overrides_with = "archive", hide = true)]
+    no_archive: bool,
+
+    #[arg(long, overrides_with = "no_incremental", help = "Only upload files changed since the folder's last archive, into a dated delta folder")]
+    incremental: bool,
+
+    #[arg(long, overrides_with = "incremental", hide = true)]
+    no_incremental: bool,
+
+    #[arg(long, overrides_with = "no_snapshot", help = "Upload each folder into a new dated snapshot, hard-linking files unchanged since the last one")]
+    snapshot: bool,
+
+    #[arg(long, overrides_with = "snapshot", hide = true)]
+    no_snapshot: bool,
+
+    #[arg(long, overrides_with = "no_checksum", help = "Skip files the server already has with the same SHA-256, even renamed or with a different mtime")]
+    checksum: bool,
+
+    #[arg(long, overrides_with = "checksum", hide = true)]
+    no_checksum: bool,
+
+    #[arg(long, overrides_with = "no_dedup", help = "Store each file's content once in the destination's objects/ pool, with an index per upload")]
+    dedup: bool,
+
+    #[arg(long, overrides_with = "dedup", hide = true)]
+    no_dedup: bool,
+
+    #[arg(long, help = "Also delete remote files of an uploaded folder that no longer exist locally")]
+    mirror: bool,
+
+    #[arg(long, requires = "mirror", help = "Delete with --mirror without asking")]
+    force: bool,
+
+    #[arg(long, value_name = "FILE", num_args = 0..=1, require_equals = true, default_missing_value = "-",
+          help = "After uploading, list each file's size, time and speed, slowest first (--report=FILE writes it there)")]
+    report: Option<PathBuf>,
+
+    #[arg(long, overrides_with = "no_notify", help = "Show a desktop notification when the upload finishes or fails")]
+    notify: bool,
+
+    #[arg(long, overrides_with = "notify", help = "Don't show a desktop notification, even if the config asks for one")]
+    no_notify: bool,
+
+    #[arg(long, overrides_with = "no_encrypt", help = "Encrypt uploads to the destination's age or gpg recipients")]
+    encrypt: bool,
+
+    #[arg(long, overrides_with = "encrypt", help = "Don't encrypt, even if the destination does by default")]
+    no_encrypt: bool,
+
+    #[arg(long, overrides_with = "no_manifest", help = "Write a manifest (sizes and SHA-256) next to each upload")]
+    manifest: bool,
+
+    #[arg(long, overrides_with = "manifest", hide = true)]
+    no_manifest: bool,
+
+    #[arg(long, overrides_with = "no_verify", help = "Check each upload's SHA-256 on the server afterwards (needs sha256sum or shasum there)")]
+    verify: bool,
+
+    #[arg(long, overrides_with = "verify", hide = true)]
+    no_verify: bool,
+
+    #[arg(long, value_enum, num_args = 0..=1, default_missing_value = "ssh",
+        help = "Sign the manifest with your SSH key or minisign (implies --manifest)")]
+    sign: Option<Signer>,
+
+    #[arg(long, overrides_with = "no_lock", hide = true)]
+    lock: bool,
+
+    #[arg(long, overrides_with = "lock", help = "Don't take the advisory lock on the remote folder")]
+    no_lock: bool,
+
+    #[arg(long, default_value_t = 0, value_name = "SECONDS", help = "How long to wait for another arkv's remote lock")]
+    lock_wait: u64,
+
+    #[arg(long, value_name = "GLOB", help = "Only upload folder files matching this pattern (repeatable)")]
+    include: Vec<String>,
+
+    #[arg(long, value_name = "GLOB", help = "Skip folder files and subfolders matching this pattern (repeatable)")]
+    exclude: Vec<String>,
+
+    #[arg(long, overrides_with = "no_ignore_files", hide = true)]
+    ignore_files: bool,
+
+    #[arg(long, overrides_with = "ignore_files", help = "Upload paths matched by .gitignore and .arkvignore files too")]
+    no_ignore_files: bool,
+
+    #[arg(long, overrides_with = "no_hidden", hide = true)]
+    hidden: bool,
+
+    #[arg(long, overrides_with = "hidden", help = "Skip dotfiles and dot-folders inside folders")]
+    no_hidden: bool,
+
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u16).range(1..), help = "Only upload files up to N levels deep in folders (1 = top level only)")]
+    max_depth: Option<u16>,
+
+    #[arg(short, long, value_parser = clap::value_parser!(u16).range(1..), help = "Upload this many files of a folder at once, each over its own connection")]
+    jobs: Option<u16>,
+
+    #[arg(long, value_parser = clap::value_parser!(u16).range(1..), help = "Split files of 64M or more into this many ranges, each uploaded over its own connection")]
+    chunks: Option<u16>,
+
+    #[arg(long, value_name = "SIZE", value_parser = units::parse_size, help = "Store files bigger than SIZE (e.g. 4G) as numbered parts: name.part0001, ...")]
+    split: Option<u64>,
+
+    #[arg(long, default_value_t = 2, help = "Reconnect and continue this many times if the connection drops")]
+    retries: u32,
+
+    #[arg(long, value_name = "SECONDS", help = "Give up connecting after this long (default 30, 0 = no limit)")]
+    connect_timeout: Option<u64>,
+
+    #[arg(long, value_name = "SECONDS", help = "Fail when a single network read or write stalls this long (default no limit)")]
+    io_timeout: Option<u64>,
+
+    #[arg(long, conflicts_with = "ipv6", help = "Only connect over IPv4")]
+    ipv4: bool,
+
+    #[arg(long, help = "Only connect over IPv6")]
+    ipv6: bool,
+
+    #[arg(long, value_name = "PERCENT", help = "Warn when the remote volume is at least this full after uploading")]
+    quota_warn: Option<f64>,
+
+    #[arg(long, overrides_with = "no_space_check", hide = true)]
+    space_check: bool,
+
+    #[arg(long, overrides_with = "space_check", help = "Don't check that the upload fits in the remote volume's free space first")]
+    no_space_check: bool,
+
+    #[arg(long, help = "Start over instead of continuing an interrupted run of the same paths")]
+    restart: bool,
+
+    #[arg(long, value_parser = units::parse_size, help = "Source size for progress (e.g. 32G), for devices that don't report one")]
+    size: Option<u64>,
+}
+
+/// What upload results look like on stdout.
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    /// Readable summary
+    Text,
+    /// One JSON object with per-destination stats and errors; everything
+    /// else goes to stderr
+    Json,
 }
 
-fn main() -> Result<()> {
+#[derive(Subcommand)]
+enum Command {
+    #[command(about = "Check an upload against its manifest on the destination")]
+    Verify {
+        #[arg(help = "Destination name")]
+        destination: String,
+
+        #[arg(help = "Name of the uploaded file or folder (relative to the remote path)")]
+        path: String,
+
+        #[arg(long, help = "Fail if the manifest has no signature")]
+        require_signature: bool,
+
+        #[arg(long, help = "Check against the local copy of the manifest kept when uploading")]
+        local: bool,
+
+        #[arg(long, help = "Only compare sizes, without checksumming the remote files")]
+        quick: bool,
+    },
+
+    #[command(about = "Delete old timestamped archives beyond the destination's retention settings")]
+    Prune {
+        #[arg(help = "Destination name")]
+        destination: String,
+
+        #[arg(long, help = "Only list what would be deleted")]
+        dry_run: bool,
+    },
+
+    #[command(about = "Check that a destination can be reached, logged in to and written to")]
+    Test {
+        #[arg(help = "Destination name")]
+        destination: String,
+    },
+
+    #[command(about = "List a remote folder on a destination")]
+    Ls {
+        #[arg(value_name = "DESTINATION[:REMOTE_PATH]", help = "Destination and path, relative to its remote path unless absolute")]
+        target: String,
+    },
+
+    #[command(about = "Delete a remote file or folder on a destination")]
+    Rm {
+        #[arg(value_name = "DESTINATION:REMOTE_PATH", help = "Destination and path, relative to its remote path unless absolute")]
+        target: String,
+
+        #[arg(short, long, help = "Delete a folder with everything in it")]
+        recursive: bool,
+
+        #[arg(short, long, help = "Delete without asking")]
+        yes: bool,
+    },
+
+    #[command(about = "Compare a local file or folder with its copy on a destination")]
+    Diff {
+        #[arg(help = "Local file or folder")]
+        local: PathBuf,
+
+        #[arg(value_name = "DESTINATION[:REMOTE_PATH]", help = "Destination, and the remote copy if not where uploading would put it")]
+        target: String,
+
+        #[arg(long, help = "Compare same-sized files with different times by checksum")]
+        checksum: bool,
+    },
+
+    #[command(about = "Download a file or folder from a destination")]
+    Get {
+        #[arg(value_name = "DESTINATION:REMOTE_PATH", help = "Destination and path, relative to its remote path unless absolute")]
+        source: String,
+
+        #[arg(help = "Where to save it (default: the current folder)")]
+        local: Option<PathBuf>,
+
+        #[arg(long, help = "Keep encrypted (.age, .gpg) files as they are instead of decrypting them")]
+        raw: bool,
+    },
+
+    #[cfg(feature = "mount")]
+    #[command(about = "Mount a destination's remote folder with FUSE")]
+    Mount {
+        #[arg(help = "Destination name")]
+        destination: String,
+
+        #[arg(help = "Local folder to mount on")]
+        mountpoint: PathBuf,
+
+        #[arg(long, help = "Allow changes (read-only by default)")]
+        writable: bool,
+    },
+
+    #[command(about = "Show past uploads and downloads, newest last")]
+    History {
+        #[arg(long, value_name = "NAME", help = "Only transfers to or from this destination")]
+        dest: Option<String>,
+
+        #[arg(long, help = "Only transfers that failed")]
+        failed: bool,
+
+        #[arg(long, short = 'n', default_value_t = 20, help = "How many to show")]
+        limit: usize,
+    },
+
+    #[command(about = "Process queued uploads in the background, one at a time")]
+    Daemon,
+
+    #[command(about = "Queue files or folders for the daemon to upload")]
+    Enqueue {
+        #[arg(required_unless_present = "list", help = "Files or folders to archive")]
+        paths: Vec<String>,
+
+        #[arg(long, value_name = "NAME", help = "Upload to this destination only (repeatable)")]
+        to: Vec<String>,
+
+        #[arg(long, value_name = "TAG", help = "Upload to every destination tagged TAG (repeatable)")]
+        group: Vec<String>,
+
+        #[arg(long, help = "Show the queue instead")]
+        list: bool,
+    },
+
+    #[command(about = "Upload new and changed files in a folder as they appear")]
+    Watch {
+        #[arg(help = "Folder to watch")]
+        folder: PathBuf,
+
+        #[arg(long, value_name = "NAME", help = "Upload to this destination only (repeatable)")]
+        to: Vec<String>,
+
+        #[arg(long, value_name = "TAG", help = "Upload to every destination tagged TAG (repeatable)")]
+        group: Vec<String>,
+
+        #[arg(long, default_value_t = 2, value_name = "SECONDS", help = "Wait for the folder to be quiet this long before uploading")]
+        debounce: u64,
+    },
+
+    #[command(about = "Run the uploads scheduled in the config")]
+    Schedule {
+        #[command(subcommand)]
+        action: ScheduleAction,
+    },
+
+    #[command(about = "Run arkv unattended as a systemd (Linux) or launchd (macOS) user service")]
+    Service {
+        #[command(subcommand)]
+        action: ServiceAction,
+    },
+
+    #[command(about = "Join the parts of a --split file (name.part0001, ...) downloaded without arkv")]
+    Join {
+        #[arg(help = "Any part of the file")]
+        part: PathBuf,
+    },
+
+    #[command(about = "Check this machine's setup: config, SSH key and agent, and reaching each destination")]
+    Doctor,
+
+    #[command(about = "Inspect the config file")]
+    Config {
+        #[command(subcommand)]
+        action: ConfigAction,
+    },
+
+    #[command(about = "Continue an interrupted run (the most recent one by default)")]
+    Resume {
+        #[arg(help = "Run ID printed when the run was interrupted")]
+        run_id: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+enum ConfigAction {
+    #[command(about = "Check every setting without connecting or uploading, and say what to fix")]
+    Check,
+}
+
+#[derive(Subcommand)]
+enum ScheduleAction {
+    #[command(about = "Keep running, uploading each schedule as it comes due")]
+    Run,
+
+    #[command(about = "Show each schedule with its last and next run")]
+    List,
+}
+
+#[derive(Subcommand)]
+enum ServiceAction {
+    #[command(about = "Write the service definition, then enable and start it")]
+    Install {
+        #[command(flatten)]
+        service: ServiceArgs,
+
+        #[arg(long, value_name = "NAME", help = "Upload to this destination only (repeatable; watch mode)")]
+        to: Vec<String>,
+
+        #[arg(long, value_name = "TAG", help = "Upload to every destination tagged TAG (repeatable; watch mode)")]
+        group: Vec<String>,
+
+        #[arg(long, help = "Print the service definition instead of installing it")]
+        print: bool,
+    },
+
+    #[command(about = "Stop the service and remove its definition")]
+    Uninstall {
+        #[command(flatten)]
+        service: ServiceArgs,
+    },
+}
+
+#[derive(clap::Args)]
+struct ServiceArgs {
+    #[arg(value_enum, help = "What the service runs")]
+    mode: ServiceMode,
+
+    #[arg(required_if_eq("mode", "watch"), help = "Folder to watch (watch mode)")]
+    folder: Option<PathBuf>,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum ServiceMode {
+    /// `arkv schedule run`
+    Schedule,
+    /// `arkv watch <FOLDER>`
+    Watch,
+    /// `arkv daemon`
+    Daemon,
+}
+
+fn main() {
     let cli = Cli::parse();
+    let output = cli.output;
+    let result = logging::init(cli.verbose, cli.log_file.as_deref()).and_then(|_| run(cli));
+    match &result {
+        Ok(()) => tracing::info!("arkv finished"),
+        Err(e) => tracing::error!("arkv failed: {:#}", e),
+    }
+    if let Err(e) = result {
+        if output == OutputFormat::Json {
+            println!("{}", serde_json::json!({
+                "ok": false,
+                "error": format!("{:#}", e),
+                "failure": Failure::of(&e).map(Failure::name),
+                "destinations": [],
+            }));
+        } else {
+            eprintln!("Error: {:?}", e);
+        }
+        std::process::exit(Failure::code_for(&e));
+    }
+}
+
+fn run(cli: Cli) -> Result<()> {
+    if cli.non_interactive {
+        prompt::disable();
+    }
+    if let Some(path) = &cli.config {
+        Config::use_file(path.clone());
+    }
 
     if cli.setup {
+        if !prompt::allowed() {
+            anyhow::bail!("--setup needs a terminal to ask questions on");
+        }
         setup::run_setup()?;
         return Ok(());
     }
 
-    let config = match Config::load()? {
+    if let Some(Command::History { dest, failed, limit }) = &cli.command {
+        return print_history(dest.as_deref(), *failed, *limit);
+    }
+
+    if let Some(Command::Join { part }) = &cli.command {
+        let (path, size, parts) = transfer::join_parts(part)?;
+        println!("✨ Joined {} parts into {} ({:.2} MB)", parts, path.display(), size as f64 / 1_048_576.0);
+        return Ok(());
+    }
+
+    if let Some(Command::Config { action: ConfigAction::Check }) = &cli.command {
+        return lint::run();
+    }
+
+    if let Some(Command::Doctor) = &cli.command {
+        return doctor::run();
+    }
+
+    let env_destination = Destination::from_env().classify(Failure::Config)?;
+    let mut config = match Config::load().classify(Failure::Config)? {
         Some(cfg) => cfg,
+        None if env_destination.is_some() => Config::empty()?,
+        None if !prompt::allowed() => {
+            return Err(failure::fail(Failure::Config, format!(
+                "No configuration found at {}. Run 'arkv --setup' from a terminal first",
+                Config::config_path()?.display()
+            )));
+        }
         None => {
             println!("No configuration found. Running setup...\n");
             setup::run_setup()?
         }
     };
+    config.add_env_destination(env_destination).classify(Failure::Config)?;
 
     if config.destinations.is_empty() {
-        eprintln!("Error: No destinations configured. Run 'arkv --setup' to add one.");
-        std::process::exit(1);
+        return Err(failure::fail(Failure::Config, "No destinations configured. Run 'arkv --setup' to add one.".to_string()));
     }
 
-    match cli.path {
-        Some(path) => {
-            let destinations = if cli.interactive {
-                let names: Vec<String> = config.destinations.iter()
-                    .map(|d| format!("{} ({})", d.name, d.host))
-                    .collect();
-
-                let selection = Select::new()
-                    .with_prompt("Select destination")
-                    .items(&names)
-                    .default(0)
-                    .interact()?;
-
-                vec![&config.destinations[selection]]
-            } else {
-                config.destinations.iter().collect()
-            };
-
-            if destinations.len() > 1 {
-                println!("\n📦 Archiving to {} destinations\n", destinations.len());
-            } else {
-                println!("\n📦 Archiving to {} ({})\n", destinations[0].name, destinations[0].host);
-            }
+    if let Some(var) = &cli.key_passphrase_env {
+        let passphrase = std::env::var(var)
+            .with_context(|| format!("--key-passphrase-env: {} is not set", var))?;
+        auth::set_passphrase(passphrase);
+    }
 
-            use std::thread;
-            let handles: Vec<_> = destinations.into_iter().map(|destination| {
-                let dest = destination.clone();
-                let path_clone = path.clone();
-                let ssh_key_path = config.ssh_key_path.clone();
-                let verbose = cli.verbose;
-                
-                thread::spawn(move || {
-                    let transferer = Transferer::new(dest.clone(), verbose);
-                    transferer.transfer(&path_clone, &ssh_key_path)
-                        .map(|stats| (dest.name.clone(), stats))
-                })
-            }).collect();
-
-            let mut errors = Vec::new();
-            let mut all_stats: Vec<(String, TransferStats)> = Vec::new();
-            
-            for handle in handles {
-                match handle.join() {
-                    Ok(Ok((name, stats))) => {
-                        println!("✓ Completed upload to {}", name);
-                        all_stats.push((name, stats));
-                    }
-                    Ok(Err(e)) => errors.push(e),
-                    Err(_) => errors.push(anyhow::anyhow!("Thread panicked")),
+    match &cli.command {
+        Some(Command::Verify { destination, path, require_signature, local, quick }) => {
+            let options = VerifyOptions { require_signature: *require_signature, local: *local, quick: *quick };
+            verify(&cli, &config, destination, path, &options)
+        }
+        #[cfg(feature = "mount")]
+        Some(Command::Mount { destination, mountpoint, writable }) => {
+            mount::run(config.destination(destination)?, &config, mountpoint, *writable)
+        }
+        Some(Command::Prune { destination, dry_run }) => prune(&cli, &config, destination, *dry_run),
+        Some(Command::Test { destination }) => {
+            let destination = config.destination(destination)?;
+            probe::run(destination, transfer_options(&cli, destination, progress::bars())?, &config.ssh_key_path)
+        }
+        Some(Command::Ls { target }) => ls(&cli, &config, target),
+        Some(Command::Diff { local, target, checksum }) => diff(&cli, &config, local, target, *checksum),
+        Some(Command::Rm { target, recursive, yes }) => rm(&cli, &config, target, *recursive, *yes),
+        Some(Command::Get { source, local, raw }) => get(&cli, &config, source, local.as_deref(), *raw),
+        Some(Command::Resume { run_id }) => resume(&cli, &config, run_id.as_deref()),
+        Some(Command::Daemon) => {
+            let progress = progress::bars().clone();
+            queue::run_daemon(&config, |destination| transfer_options(&cli, destination, &progress))
+        }
+        Some(Command::Enqueue { list: true, .. }) => list_queue(),
+        Some(Command::Enqueue { paths, to, group, .. }) => enqueue(&config, paths, to, group),
+        Some(Command::Watch { folder, to, group, debounce }) => watch_folder(&cli, &config, folder, to, group, *debounce),
+        Some(Command::Schedule { action: ScheduleAction::Run }) => {
+            schedule::run(&config.schedules, |entry| run_schedule(&cli, &config, entry))
+        }
+        Some(Command::Schedule { action: ScheduleAction::List }) => schedule::list(&config.schedules),
+        Some(Command::Service { action }) => service(action, cli.log_file.as_deref(), cli.config.as_deref()),
+        Some(Command::Join { .. } | Command::History { .. } | Command::Config { .. } | Command::Doctor) => {
+            unreachable!("handled before loading the config")
+        }
+        None if cli.paths.is_empty() && cli.files_from.is_none() => {
+            print_usage();
+            Ok(())
+        }
+        None => upload(&cli, &config),
+    }
+}
+
+/// Destinations named with `--to`, tagged with `--group`, or picked from a
+/// checklist; all of them by default.
+fn select_destinations<'a>(config: &'a Config, to: &[String], group: &[String], interactive: bool) -> Result<Vec<&'a Destination>> {
+    let destinations = if !to.is_empty() || !group.is_empty() {
+        let mut selected: Vec<&Destination> = Vec::new();
+        for name in to {
+            let destination = config.destination(name)?;
+            if !selected.iter().any(|d| d.name == destination.name) {
+                selected.push(destination);
+            }
+        }
+        for tag in group {
+            let tagged: Vec<&Destination> = config.destinations.iter()
+                .filter(|d| d.tags.contains(tag))
+                .collect();
+            if tagged.is_empty() {
+                anyhow::bail!("No destination is tagged '{}'", tag);
+            }
+            for destination in tagged {
+                if !selected.iter().any(|d| d.name == destination.name) {
+                    selected.push(destination);
                 }
             }
+        }
+        selected
+    } else if interactive {
+        if !prompt::allowed() {
+            anyhow::bail!("--interactive can't prompt here; choose the destination with --to <name>");
+        }
+        let names: Vec<String> = config.destinations.iter()
+            .map(|d| format!("{} ({})", d.name, d.address()))
+            .collect();
 
-            if !errors.is_empty() {
-                eprintln!("\n❌ Errors occurred:");
-                for error in errors {
-                    eprintln!("  {}", error);
-                }
-                std::process::exit(1);
+        let selection = MultiSelect::new()
+            .with_prompt("Select destinations (space to toggle, enter to confirm)")
+            .items(&names)
+            .interact()?;
+        if selection.is_empty() {
+            anyhow::bail!("No destinations selected");
+        }
+
+        selection.into_iter().map(|i| &config.destinations[i]).collect()
+    } else {
+        config.destinations.iter().collect()
+    };
+    Ok(destinations)
+}
+
+fn upload(cli: &Cli, config: &Config) -> Result<()> {
+    let mut inputs = cli.paths.clone();
+    if let Some(list) = &cli.files_from {
+        inputs.extend(read_file_list(list)?);
+        if inputs.is_empty() {
+            anyhow::bail!("No paths listed in {}", list.display());
+        }
+    }
+    let paths = transfer::dedupe_paths(&inputs, cli.files_from.is_some())?;
+
+    let destinations = select_destinations(config, &cli.to, &cli.group, cli.interactive)?;
+
+    let message = match destinations.len() {
+        1 => format!("\n📦 Archiving to {} ({})\n", destinations[0].name, destinations[0].address()),
+        n => format!("\n📦 Archiving to {} destinations\n", n),
+    };
+    status(cli, &message);
+
+    let progress = progress::bars().clone();
+
+    let mut jobs = destinations.iter()
+        .map(|&dest| Ok((dest.clone(), transfer_options(cli, dest, &progress)?)))
+        .collect::<Result<Vec<_>>>()?;
+
+    let names: Vec<&str> = destinations.iter().map(|d| d.name.as_str()).collect();
+    let run = match RunLog::find_unfinished(&paths, &names) {
+        Some(run) if !cli.restart => {
+            status(cli, &format!("🔁 Continuing interrupted run {} (pass --restart to start over)\n", run.id()));
+            jobs.retain(|(dest, _)| run.is_pending(&dest.name));
+            for (dest, options) in &mut jobs {
+                options.resume |= options.compress.is_none() && options.encrypt.is_none() && dest.resumable();
             }
+            run
+        }
+        abandoned => {
+            if let Some(old) = abandoned {
+                old.discard()?;
+            }
+            RunLog::create(&paths, &jobs)?
+        }
+    };
+
+    run_jobs(cli, config, paths, jobs, run)
+}
 
-            println!();
-            for (name, stats) in &all_stats {
-                let mb = stats.bytes_transferred as f64 / 1_048_576.0;
-                let speed = mb / stats.duration_secs;
-                println!("📊 {}: {:.2} MB in {:.1}s ({:.2} MB/s)", 
-                    name, mb, stats.duration_secs, speed);
+/// Prints a progress message: on stdout normally, on stderr when stdout
+/// carries JSON.
+fn status(cli: &Cli, message: &str) {
+    progress::suspend(progress::bars(), || match cli.output {
+        OutputFormat::Text => println!("{}", message),
+        OutputFormat::Json => eprintln!("{}", message),
+    });
+}
+
+fn resume(cli: &Cli, config: &Config, run_id: Option<&str>) -> Result<()> {
+    let run = RunLog::load(run_id)?;
+    let paths = run.paths();
+    let progress = progress::bars().clone();
+
+    let jobs = run.pending(&progress).into_iter()
+        .map(|(name, options)| Ok((config.destination(&name)?.clone(), options)))
+        .collect::<Result<Vec<_>>>()?;
+
+    status(cli, &format!("\n🔁 Resuming run {} ({} destination(s) left)\n", run.id(), jobs.len()));
+    run_jobs(cli, config, paths, jobs, run)
+}
+
+fn enqueue(config: &Config, paths: &[String], to: &[String], group: &[String]) -> Result<()> {
+    let paths = transfer::dedupe_paths(paths, false)?;
+    let destinations = select_destinations(config, to, group, false)?.into_iter()
+        .map(|d| d.name.clone())
+        .collect();
+    let (entry, woken) = queue::enqueue(paths, destinations)?;
+    println!("📥 Queued {} for {}", entry.id, entry.pending.join(", "));
+    if !woken {
+        println!("ℹ️  No daemon is running; it will be uploaded when `arkv daemon` starts");
+    }
+    Ok(())
+}
+
+fn run_schedule(cli: &Cli, config: &Config, entry: &Schedule) -> Result<()> {
+    let paths = transfer::dedupe_paths(&entry.paths, false)?;
+    let progress = progress::bars().clone();
+    let mut failed = Vec::new();
+    for destination in select_destinations(config, &entry.to, &entry.group, false)? {
+        let result = transfer_options(cli, destination, &progress)
+            .and_then(|options| Transferer::new(destination.clone(), options).transfer(&paths, config));
+        match result {
+            Ok(stats) => println!("📊 {}: {:.2} MB in {:.1}s",
+                destination.name, stats.bytes_transferred as f64 / 1_048_576.0, stats.duration_secs),
+            Err(e) => {
+                eprintln!("❌ {}: {:#}", destination.name, e);
+                failed.push(destination.name.clone());
             }
+        }
+    }
+    if !failed.is_empty() {
+        anyhow::bail!("Upload failed for {}", failed.join(", "));
+    }
+    Ok(())
+}
 
-            println!("\n✨ Done!\n");
+fn service(action: &ServiceAction, log_file: Option<&Path>, config_file: Option<&Path>) -> Result<()> {
+    let (args, to, group) = match action {
+        ServiceAction::Install { service, to, group, .. } => (service, to.as_slice(), group.as_slice()),
+        ServiceAction::Uninstall { service } => (service, &[][..], &[][..]),
+    };
+    let mut service = match (args.mode, &args.folder) {
+        (ServiceMode::Schedule, _) => Service {
+            name: "schedule".to_string(),
+            description: "arkv scheduled uploads".to_string(),
+            args: vec!["schedule".to_string(), "run".to_string()],
+        },
+        (ServiceMode::Daemon, _) => Service {
+            name: "daemon".to_string(),
+            description: "arkv upload queue".to_string(),
+            args: vec!["daemon".to_string()],
+        },
+        (ServiceMode::Watch, folder) => {
+            let folder = folder.as_deref().context("Watch mode needs a folder")?;
+            let folder = std::fs::canonicalize(folder)
+                .with_context(|| format!("Folder does not exist: {}", folder.display()))?;
+            let name = folder.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+            let mut args = vec!["watch".to_string(), folder.to_string_lossy().into_owned()];
+            for name in to {
+                args.extend(["--to".to_string(), name.clone()]);
+            }
+            for tag in group {
+                args.extend(["--group".to_string(), tag.clone()]);
+            }
+            Service {
+                name: format!("watch-{}", sanitize_unit_name(&name)),
+                description: format!("arkv watching {}", folder.display()),
+                args,
+            }
         }
-        None => {
-            print_usage();
+    };
+
+    service.args.push("--non-interactive".to_string());
+    if let Some(log_file) = log_file {
+        let log_file = std::path::absolute(log_file)
+            .with_context(|| format!("Invalid log file path: {}", log_file.display()))?;
+        service.args.extend(["--log-file".to_string(), log_file.to_string_lossy().into_owned()]);
+    }
+    if let Some(config_file) = config_file {
+        let config_file = std::path::absolute(config_file)
+            .with_context(|| format!("Invalid config file path: {}", config_file.display()))?;
+        service.args.extend(["--config".to_string(), config_file.to_string_lossy().into_owned()]);
+    }
+    match action {
+        ServiceAction::Install { print: true, .. } => {
+            let (path, content) = service.definition()?;
+            eprintln!("# {}", path.display());
+            print!("{}", content);
+        }
+        ServiceAction::Install { .. } => {
+            let path = service.install()?;
+            println!("✅ Installed and started arkv-{} ({})", service.name, path.display());
+        }
+        ServiceAction::Uninstall { .. } => {
+            let path = service.uninstall()?;
+            println!("🗑️  Removed arkv-{} ({})", service.name, path.display());
+        }
+    }
+    Ok(())
+}
+
+/// Keeps service names to characters systemd and launchd both accept.
+fn sanitize_unit_name(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+        .collect()
+}
+
+fn list_queue() -> Result<()> {
+    let entries = queue::list()?;
+    if entries.is_empty() {
+        println!("The upload queue is empty");
+    }
+    for entry in entries {
+        println!("{}  {}  -> {}", entry.id,
+            entry.paths.iter().map(|p| p.display().to_string()).collect::<Vec<_>>().join(" "),
+            entry.pending.join(", "));
+        if let Some(error) = &entry.last_error {
+            println!("    {} attempt(s) failed: {}", entry.attempts, error);
+        }
+    }
+    Ok(())
+}
+
+fn watch_folder(cli: &Cli, config: &Config, folder: &Path, to: &[String], group: &[String], debounce: u64) -> Result<()> {
+    let folder = std::fs::canonicalize(folder)
+        .with_context(|| format!("Folder does not exist: {}", folder.display()))?;
+    if !folder.is_dir() {
+        anyhow::bail!("Not a folder: {}", folder.display());
+    }
+
+    let progress = progress::bars().clone();
+    let jobs = select_destinations(config, to, group, false)?.into_iter()
+        .map(|dest| Ok((dest.clone(), transfer_options(cli, dest, &progress)?)))
+        .collect::<Result<Vec<_>>>()?;
+    watch::run(config, &folder, jobs, Duration::from_secs(debounce))
+}
+
+/// Paths for `--files-from`, one per line, from a file or stdin (`-`).
+fn read_file_list(list: &Path) -> Result<Vec<String>> {
+    let content = if list == Path::new("-") {
+        std::io::read_to_string(std::io::stdin())
+            .context("Failed to read the file list from stdin")?
+    } else {
+        std::fs::read_to_string(list)
+            .with_context(|| format!("Failed to read file list: {}", list.display()))?
+    };
+    Ok(content.lines()
+        .map(|line| line.trim_end_matches('\r'))
+        .filter(|line| !line.is_empty())
+        .map(str::to_string)
+        .collect())
+}
+
+fn run_jobs(cli: &Cli, config: &Config, paths: Vec<PathBuf>, jobs: Vec<(Destination, TransferOptions)>, run: Arc<RunLog>) -> Result<()> {
+    use std::thread;
+    let handles: Vec<_> = jobs.into_iter().map(|(dest, mut options)| {
+        options.run = Some(run.clone());
+        let paths = paths.clone();
+        let config = config.clone();
+        let name = dest.name.clone();
+        
+        let handle = thread::spawn(move || {
+            let transferer = Transferer::new(dest, options);
+            transferer.transfer(&paths, &config)
+        });
+        (name, handle)
+    }).collect();
+
+    let mut errors = Vec::new();
+    let mut all_stats: Vec<(String, TransferStats)> = Vec::new();
+    
+    for (name, handle) in handles {
+        match handle.join() {
+            Ok(Ok(mut stats)) => {
+                status(cli, &format!("✓ Completed upload to {}", name));
+                let (read, sent, duration) = run.finish_destination(&name, stats.duration_secs)?;
+                stats.bytes_transferred = read;
+                stats.bytes_compressed = stats.bytes_compressed.map(|_| sent);
+                stats.duration_secs = duration;
+                all_stats.push((name, stats));
+            }
+            Ok(Err(e)) => errors.push((name, e)),
+            Err(_) => errors.push((name, anyhow::anyhow!("Thread panicked"))),
+        }
+    }
+
+    if let Some(target) = &cli.report {
+        write_report(cli, target, &all_stats)?;
+    }
+    if (cli.notify || config.notify) && !cli.no_notify {
+        notify_finished(&all_stats, &errors);
+    }
+
+    let code = exit_code(&all_stats, &errors);
+    if cli.output == OutputFormat::Json {
+        print_json_summary(&run, &all_stats, &errors);
+        if !errors.is_empty() {
+            run.flush()?;
+            std::process::exit(code);
+        }
+        return run.finish();
+    }
+
+    if !errors.is_empty() {
+        eprintln!("\n❌ Errors occurred:");
+        for (_, error) in errors {
+            eprintln!("  {}", error);
+        }
+        run.flush()?;
+        eprintln!("\n💾 Progress saved. Continue with: arkv resume {}\n", run.id());
+        std::process::exit(code);
+    }
+
+    println!();
+    for (name, stats) in &all_stats {
+        let mb = stats.bytes_transferred as f64 / 1_048_576.0;
+        let speed = mb / stats.duration_secs;
+        println!("📊 {}: {:.2} MB in {:.1}s ({:.2} MB/s)", 
+            name, mb, stats.duration_secs, speed);
+        if let Some(compressed) = stats.bytes_compressed.filter(|_| stats.bytes_transferred > 0) {
+            println!("   {:.2} MB after compression ({:.0}% of the original)",
+                compressed as f64 / 1_048_576.0, compressed as f64 * 100.0 / stats.bytes_transferred as f64);
+        }
+        if stats.unchanged > 0 {
+            println!("   {} unchanged files skipped", stats.unchanged);
+        }
+        if stats.existing > 0 {
+            println!("   {} existing files skipped", stats.existing);
+        }
+        if stats.deleted > 0 {
+            println!("   {} remote files deleted (mirror)", stats.deleted);
+        }
+        if stats.pruned > 0 {
+            println!("   🧹 Pruned {} old archives", stats.pruned);
+        }
+        if stats.special > 0 {
+            println!("   skipped: {} special files (sockets, FIFOs or device nodes; -v lists them)", stats.special);
+        }
+        for warning in &stats.warnings {
+            println!("⚠️  {}: {}", name, warning);
+        }
+    }
+
+    run.finish()?;
+    println!("\n✨ Done! (run {})\n", run.id());
+
+    Ok(())
+}
+
+/// Files in `--report` that are this much slower than their destination's
+/// average are marked.
+const SLOW_FACTOR: f64 = 4.0;
+
+/// The `--report` table: every file sent, slowest first, with the ones far
+/// below their destination's average speed marked. `-` prints it.
+fn write_report(cli: &Cli, target: &Path, all_stats: &[(String, TransferStats)]) -> Result<()> {
+    let mut rows: Vec<(&str, &transfer::FileTiming, bool)> = Vec::new();
+    for (name, stats) in all_stats {
+        let bytes: u64 = stats.files.iter().map(|f| f.bytes).sum();
+        let seconds: f64 = stats.files.iter().map(|f| f.duration_secs).sum();
+        let average = bytes as f64 / seconds.max(f64::EPSILON);
+        for file in &stats.files {
+            let speed = file.bytes as f64 / file.duration_secs.max(f64::EPSILON);
+            rows.push((name, file, stats.files.len() > 1 && speed * SLOW_FACTOR < average));
+        }
+    }
+    rows.sort_by(|a, b| b.1.duration_secs.total_cmp(&a.1.duration_secs));
+
+    let width = rows.iter().map(|(name, _, _)| name.len()).max().unwrap_or(0).max("DESTINATION".len());
+    let mut report = format!("{:>10}  {:>10}  {:>12}  {:<width$}  FILE\n", "SIZE", "TIME", "SPEED", "DESTINATION");
+    for (name, file, slow) in &rows {
+        report.push_str(&format!("{:>10}  {:>9.1}s  {:>12}  {:<width$}  {}{}\n",
+            HumanBytes(file.bytes).to_string(),
+            file.duration_secs,
+            progress::format_rate(file.bytes as f64 / file.duration_secs.max(f64::EPSILON)),
+            name,
+            file.path.display(),
+            if *slow { "  ← slow" } else { "" },
+        ));
+    }
+
+    if target == Path::new("-") {
+        status(cli, &format!("\n📋 {} files sent:\n{}", rows.len(), report.trim_end()));
+        return Ok(());
+    }
+    std::fs::write(target, report)
+        .with_context(|| format!("Failed to write report: {}", target.display()))?;
+    status(cli, &format!("📋 Wrote a report on {} files to {}", rows.len(), target.display()));
+    Ok(())
+}
+
+/// The desktop notification for a finished run: one line per destination.
+fn notify_finished(all_stats: &[(String, TransferStats)], errors: &[(String, anyhow::Error)]) {
+    let summary = match (all_stats.is_empty(), errors.is_empty()) {
+        (_, true) => "✓ arkv upload finished",
+        (true, false) => "❌ arkv upload failed",
+        (false, false) => "⚠️ arkv upload partly failed",
+    };
+    let mut lines: Vec<String> = all_stats.iter()
+        .map(|(name, stats)| format!("{}: {} in {:.1}s", name, HumanBytes(stats.bytes_transferred), stats.duration_secs))
+        .collect();
+    lines.extend(errors.iter().map(|(name, error)| format!("{} failed: {}", name, error)));
+    desktop::notify(summary, &lines.join("\n"));
+}
+
+/// Partial success when some destinations finished; otherwise the kind of
+/// failure the destinations had in common, or 1 if they differ.
+fn exit_code(all_stats: &[(String, TransferStats)], errors: &[(String, anyhow::Error)]) -> i32 {
+    if !all_stats.is_empty() {
+        return Failure::Partial.exit_code();
+    }
+    let mut failures = errors.iter().map(|(_, error)| Failure::of(error));
+    match failures.next().flatten() {
+        Some(failure) if failures.all(|f| f == Some(failure)) => failure.exit_code(),
+        _ => 1,
+    }
+}
+
+/// The `--output json` form of the run summary.
+fn print_json_summary(run: &RunLog, all_stats: &[(String, TransferStats)], errors: &[(String, anyhow::Error)]) {
+    let finished = all_stats.iter().map(|(name, stats)| serde_json::json!({
+        "name": name,
+        "ok": true,
+        "bytes_transferred": stats.bytes_transferred,
+        "bytes_compressed": stats.bytes_compressed,
+        "duration_secs": stats.duration_secs,
+        "bytes_per_sec": stats.bytes_transferred as f64 / stats.duration_secs.max(f64::EPSILON),
+        "unchanged": stats.unchanged,
+        "existing": stats.existing,
+        "deleted": stats.deleted,
+        "pruned": stats.pruned,
+        "special": stats.special,
+        "warnings": stats.warnings,
+    }));
+    let failed = errors.iter().map(|(name, error)| serde_json::json!({
+        "name": name,
+        "ok": false,
+        "error": format!("{:#}", error),
+        "failure": Failure::of(error).map(Failure::name),
+    }));
+    println!("{}", serde_json::json!({
+        "ok": errors.is_empty(),
+        "run_id": run.id(),
+        "destinations": finished.chain(failed).collect::<Vec<_>>(),
+    }));
+}
+
+fn verify(cli: &Cli, config: &Config, destination: &str, path: &str, options: &VerifyOptions) -> Result<()> {
+    let destination = config.destination(destination)?;
+    let name = destination.sanitize.name(path.trim_end_matches('/'));
+    let name = name.as_str();
+    let report = match destination.kind {
+        DestinationKind::Local => manifest::verify(&Store::Local, destination, name, options, config)?,
+        _ => {
+            let transfer = transfer_options(cli, destination, progress::bars())?;
+            let session = Transferer::new(destination.clone(), transfer).connect(&config.ssh_key_path)?;
+            let sftp = session.sftp()?;
+            manifest::verify(&Store::Sftp(&session, &sftp), destination, name, options, config)?
+        }
+    };
+
+    match report.signed_by {
+        Some(signer) => println!("\n🔏 Manifest signature valid ({:?})", signer),
+        None => println!("\n⚠️  Manifest is not signed"),
+    }
+    for path in &report.missing {
+        println!("  missing:  {}", path);
+    }
+    for path in &report.mismatched {
+        println!("  changed:  {}", path);
+    }
+    for path in &report.unexpected {
+        println!("  unlisted: {}", path);
+    }
+
+    if !report.is_ok() {
+        eprintln!("\n❌ {} of {} files failed verification\n",
+            report.missing.len() + report.mismatched.len(), report.checked);
+        std::process::exit(Failure::Verify.exit_code());
+    }
+
+    println!("✓ {} files match the manifest\n", report.checked);
+    Ok(())
+}
+
+fn prune(cli: &Cli, config: &Config, destination: &str, dry_run: bool) -> Result<()> {
+    let destination = config.destination(destination)?;
+    let removed = match destination.kind {
+        DestinationKind::Local => prune::run(&Store::Local, destination, dry_run)?,
+        _ => {
+            let transfer = transfer_options(cli, destination, progress::bars())?;
+            let session = Transferer::new(destination.clone(), transfer).connect(&config.ssh_key_path)?;
+            let sftp = session.sftp()?;
+            prune::run(&Store::Sftp(&session, &sftp), destination, dry_run)?
+        }
+    };
+    match (removed.len(), dry_run) {
+        (0, _) => println!("✓ Nothing to prune on {}", destination.name),
+        (n, true) => println!("\n🧹 Would prune {} old archives from {}", n, destination.name),
+        (n, false) => println!("🧹 Pruned {} old archives from {}", n, destination.name),
+    }
+    Ok(())
+}
+
+fn ls(cli: &Cli, config: &Config, target: &str) -> Result<()> {
+    let (name, remote) = target.split_once(':').unwrap_or((target, ""));
+    let destination = config.destination(name)?;
+    sftp_only(destination, "ls")?;
+    let transfer = transfer_options(cli, destination, progress::bars())?;
+    let session = Transferer::new(destination.clone(), transfer).connect(&config.ssh_key_path)?;
+    let sftp = session.sftp()?;
+
+    let path = destination.remote_base().join(remote.trim_end_matches('/'));
+    let wire = destination.remote_encoding.encode(&path)?;
+    let stat = sftp.stat(&wire)
+        .with_context(|| format!("Remote path not found: {}", path.display()))?;
+    let mut entries = match stat.is_dir() {
+        true => sftp.readdir(&wire)
+            .with_context(|| format!("Failed to list remote folder: {}", path.display()))?
+            .into_iter()
+            .map(|(entry, stat)| (destination.remote_encoding.decode(&entry), stat))
+            .collect(),
+        false => vec![(path.clone(), stat)],
+    };
+    entries.sort_by(|(a, a_stat), (b, b_stat)| b_stat.is_dir().cmp(&a_stat.is_dir()).then(a.cmp(b)));
+
+    for (entry, stat) in &entries {
+        let name = entry.file_name().unwrap_or(entry.as_os_str()).to_string_lossy();
+        let modified = stat.mtime.map(|t| schedule::format_local(t as i64)).unwrap_or_default();
+        match stat.is_dir() {
+            true => println!("{:>10}  {:16}  {}/", "-", modified, name),
+            false => println!("{:>10}  {:16}  {}", HumanBytes(stat.size.unwrap_or(0)).to_string(), modified, name),
         }
     }
+    if entries.is_empty() {
+        println!("(empty) {}", path.display());
+    }
+    Ok(())
+}
+
+fn print_history(destination: Option<&str>, failed: bool, limit: usize) -> Result<()> {
+    let entries: Vec<HistoryEntry> = history::read(destination)?
+        .into_iter()
+        .filter(|entry| !failed || entry.error.is_some())
+        .collect();
+    if entries.is_empty() {
+        println!("No transfers recorded yet");
+        return Ok(());
+    }
 
+    for entry in &entries[entries.len().saturating_sub(limit)..] {
+        let mark = if entry.error.is_none() { "✓" } else { "✗" };
+        let direction = match entry.direction {
+            Direction::Upload => "→",
+            Direction::Download => "←",
+        };
+        let paths: Vec<String> = entry.paths.iter().map(|p| p.display().to_string()).collect();
+        println!("{} {}  {} {} {}  {:.2} MB in {:.1}s",
+            mark, schedule::format_local(entry.started as i64), direction, entry.destination,
+            paths.join(" "), entry.bytes as f64 / 1_048_576.0, entry.duration_secs);
+        if let Some(error) = &entry.error {
+            println!("      {}", error);
+        }
+    }
     Ok(())
 }
 
+fn diff(cli: &Cli, config: &Config, local: &Path, target: &str, checksum: bool) -> Result<()> {
+    let (name, remote) = match target.split_once(':') {
+        Some((name, remote)) => (name, Some(remote)),
+        None => (target, None),
+    };
+    let destination = config.destination(name)?;
+    sftp_only(destination, "diff")?;
+    let transfer = transfer_options(cli, destination, progress::bars())?;
+    let report = Transferer::new(destination.clone(), transfer).diff(local, remote, checksum, config)?;
+
+    for path in &report.only_local {
+        println!("  only local:  {}", path);
+    }
+    for path in &report.only_remote {
+        println!("  only remote: {}", path);
+    }
+    for (path, difference) in &report.changed {
+        println!("  changed:     {} ({})", path, difference);
+    }
+
+    if !report.is_same() {
+        println!("\n⚠️  {} only local, {} only remote, {} changed, {} the same",
+            report.only_local.len(), report.only_remote.len(), report.changed.len(), report.same);
+        std::process::exit(1);
+    }
+    println!("✓ {} files are the same on {}", report.same, destination.name);
+    Ok(())
+}
+
+fn rm(cli: &Cli, config: &Config, target: &str, recursive: bool, yes: bool) -> Result<()> {
+    let (name, remote) = target.split_once(':')
+        .with_context(|| format!("Expected <destination>:<remote-path>, got '{}'", target))?;
+    if remote.trim_end_matches('/').is_empty() {
+        anyhow::bail!("Refusing to delete the remote path of {} itself; name something inside it", name);
+    }
+    let destination = config.destination(name)?;
+    sftp_only(destination, "rm")?;
+    let transfer = transfer_options(cli, destination, progress::bars())?;
+    let session = Transferer::new(destination.clone(), transfer).connect(&config.ssh_key_path)?;
+    let sftp = session.sftp()?;
+
+    let path = destination.remote_base().join(remote.trim_end_matches('/'));
+    let wire = destination.remote_encoding.encode(&path)?;
+    let stat = sftp.lstat(&wire)
+        .with_context(|| format!("Remote path not found: {}", path.display()))?;
+    if stat.is_dir() && !recursive {
+        anyhow::bail!("{} is a folder; pass -r to delete it with everything in it", path.display());
+    }
+
+    if !yes {
+        if !prompt::allowed() {
+            anyhow::bail!("Can't ask for confirmation here; pass --yes to delete {}", path.display());
+        }
+        let what = if stat.is_dir() { "folder and everything in it" } else { "file" };
+        let confirmed = Confirm::new()
+            .with_prompt(format!("Delete the {} {} on {}?", what, path.display(), destination.name))
+            .default(false)
+            .interact()
+            .context("Failed to read answer")?;
+        if !confirmed {
+            println!("Nothing deleted");
+            return Ok(());
+        }
+    }
+
+    let removed = remote::remove_all(&sftp, &wire)?;
+    println!("🗑️  Deleted {} ({} files)", path.display(), removed);
+    Ok(())
+}
+
+fn get(cli: &Cli, config: &Config, source: &str, local: Option<&Path>, raw: bool) -> Result<()> {
+    let (name, remote) = source.split_once(':')
+        .with_context(|| format!("Expected <destination>:<remote-path>, got '{}'", source))?;
+    let destination = config.destination(name)?;
+    sftp_only(destination, "get")?;
+    let local = local.unwrap_or(Path::new("."));
+
+    println!("\n📥 Downloading from {} ({})\n", destination.name, destination.host);
+
+    let mut options = transfer_options(cli, destination, progress::bars())?;
+    if !raw {
+        options.decrypt_identity = Some(config.age_identity()?);
+    }
+    let stats = Transferer::new(destination.clone(), options).download(remote, local, config)?;
+
+    let mb = stats.bytes_transferred as f64 / 1_048_576.0;
+    println!("\n📊 {}: {:.2} MB in {:.1}s ({:.2} MB/s)",
+        destination.name, mb, stats.duration_secs, mb / stats.duration_secs);
+    println!("\n✨ Done!\n");
+    Ok(())
+}
+
+/// Fails before anything starts for commands that browse a destination
+/// over SFTP when `destination` can't be reached that way.
+fn sftp_only(destination: &Destination, command: &str) -> Result<()> {
+    if destination.kind != DestinationKind::Sftp {
+        return Err(failure::fail(Failure::Config, format!(
+            "arkv {} works over SFTP, but {} is a type = \"{}\" destination",
+            command, destination.name, destination.kind.name()
+        )));
+    }
+    if destination.gssapi {
+        return Err(failure::fail(Failure::Config, format!(
+            "arkv {} needs a key or password login, but {} logs in with GSSAPI through ssh, which only takes uploads",
+            command, destination.name
+        )));
+    }
+    Ok(())
+}
+
+/// Layers CLI flags over the destination's configured defaults.
+fn transfer_options(cli: &Cli, destination: &Destination, progress: &MultiProgress) -> Result<TransferOptions> {
+    let defaults = &destination.defaults;
+
+    let compress = if cli.no_compress { None } else { cli.compress.or(defaults.compress) };
+    let resume = flag(cli.resume, cli.no_resume).or(defaults.resume).unwrap_or(false);
+    if compress.is_some() && resume {
+        anyhow::bail!("{}: compressed uploads can't be resumed; pass --no-compress or --no-resume", destination.name);
+    }
+    let encrypt = match flag(cli.encrypt, cli.no_encrypt).or(defaults.encrypt).unwrap_or(false) {
+        true => Some(Encryption::new(destination.encryption, match destination.encryption {
+                EncryptionBackend::Age => &destination.age_recipients,
+                EncryptionBackend::Gpg => &destination.gpg_recipients,
+            })
+            .with_context(|| format!("{}: can't encrypt", destination.name))?),
+        false => None,
+    };
+    if encrypt.is_some() && resume {
+        anyhow::bail!("{}: encrypted uploads can't be resumed; pass --no-encrypt or --no-resume", destination.name);
+    }
+
+    let split = match (cli.split, &defaults.split) {
+        (Some(size), _) => Some(size),
+        (None, Some(size)) => Some(units::parse_size(size)
+            .with_context(|| format!("{}: invalid split size '{}'", destination.name, size))?),
+        (None, None) => None,
+    };
+    if split == Some(0) {
+        anyhow::bail!("{}: --split needs a size above 0", destination.name);
+    }
+
+    let if_exists = cli.if_exists.or(defaults.if_exists).unwrap_or_default();
+    if if_exists != IfExists::Overwrite && resume {
+        anyhow::bail!("{}: --resume continues existing remote files, so it only works with --if-exists overwrite", destination.name);
+    }
+    let archive = flag(cli.archive, cli.no_archive).or(defaults.archive).unwrap_or(false);
+    let incremental = flag(cli.incremental, cli.no_incremental).or(defaults.incremental).unwrap_or(false);
+    if incremental && (archive || compress.is_some() || encrypt.is_some()) {
+        anyhow::bail!("{}: --incremental compares files with the remote checksums, so it can't be combined with --archive, --compress or --encrypt", destination.name);
+    }
+    if cli.mirror && (archive || incremental) {
+        anyhow::bail!("{}: --mirror deletes files from the uploaded folder, so it can't be combined with --archive or --incremental", destination.name);
+    }
+    let snapshot = flag(cli.snapshot, cli.no_snapshot).or(defaults.snapshot).unwrap_or(false);
+    if snapshot && (archive || incremental || cli.mirror) {
+        anyhow::bail!("{}: --snapshot writes a new folder each run, so it can't be combined with --archive, --incremental or --mirror", destination.name);
+    }
+    let manifest = incremental || cli.sign.is_some() || flag(cli.manifest, cli.no_manifest).or(defaults.manifest).unwrap_or(false)
+        || defaults.sign.is_some();
+    let dedup = flag(cli.dedup, cli.no_dedup).or(defaults.dedup).unwrap_or(false);
+    let conflicts: Vec<&str> = [
+        (archive, "--archive"),
+        (incremental, "--incremental"),
+        (snapshot, "--snapshot"),
+        (cli.mirror, "--mirror"),
+        (compress.is_some(), "--compress"),
+        (encrypt.is_some(), "--encrypt"),
+        (split.is_some(), "--split"),
+        (manifest, "--manifest"),
+        (if_exists != IfExists::Overwrite, "--if-exists"),
+    ]
+    .into_iter()
+    .filter_map(|(used, name)| used.then_some(name))
+    .collect();
+    if dedup && !conflicts.is_empty() {
+        anyhow::bail!(
+            "{}: --dedup stores plain files by their checksum and lists them in its index, so it can't be combined with {}",
+            destination.name, conflicts.join(", ")
+        );
+    }
+    let checksum = flag(cli.checksum, cli.no_checksum).or(defaults.checksum).unwrap_or(false);
+    if checksum && (archive || compress.is_some() || encrypt.is_some()) {
+        anyhow::bail!("{}: --checksum compares files with the remote checksums, so it can't be combined with --archive, --compress or --encrypt", destination.name);
+    }
+
+    let include: Vec<String> = destination.include.iter().chain(&cli.include).cloned().collect();
+    let exclude: Vec<String> = destination.exclude.iter().chain(&cli.exclude).cloned().collect();
+    let filter = PathFilter::new(&include, &exclude)
+        .with_context(|| format!("{}: invalid --include/--exclude pattern", destination.name))?;
+
+    Ok(TransferOptions {
+        fast_cipher: flag(cli.fast_cipher, cli.no_fast_cipher).or(defaults.fast_cipher).unwrap_or(false),
+        compress,
+        resume,
+        sync: flag(cli.sync, cli.no_sync).or(defaults.sync).unwrap_or(false),
+        if_exists,
+        rename_scheme: cli.rename_scheme.or(defaults.rename_scheme).unwrap_or_default(),
+        atomic: flag(cli.atomic, cli.no_atomic).or(defaults.atomic).unwrap_or(true),
+        preserve: flag(cli.preserve, cli.no_preserve).or(defaults.preserve).unwrap_or(false),
+        archive,
+        incremental,
+        snapshot,
+        checksum,
+        dedup,
+        mirror: cli.mirror,
+        force: cli.force,
+        encrypt,
+        decrypt_identity: None,
+        filter,
+        ignore_files: flag(cli.ignore_files, cli.no_ignore_files).or(defaults.ignore_files).unwrap_or(true),
+        hidden: flag(cli.hidden, cli.no_hidden).or(defaults.hidden).unwrap_or(true),
+        max_depth: cli.max_depth.map(usize::from).or(defaults.max_depth),
+        relative_to: match cli.files_from {
+            Some(_) => Some(std::fs::canonicalize(".").context("Failed to resolve the current folder")?),
+            None => None,
+        },
+        size: cli.size,
+        manifest,
+        sign: cli.sign.or(defaults.sign),
+        verify: flag(cli.verify, cli.no_verify).or(defaults.verify).unwrap_or(false),
+        lock: flag(cli.lock, cli.no_lock).or(defaults.lock).unwrap_or(true),
+        lock_wait: Duration::from_secs(cli.lock_wait),
+        retries: cli.retries,
+        connect_timeout: timeout(cli.connect_timeout.or(defaults.connect_timeout).unwrap_or(DEFAULT_CONNECT_TIMEOUT)),
+        address_family: match (cli.ipv4, cli.ipv6) {
+            (true, _) => AddressFamily::Ipv4,
+            (_, true) => AddressFamily::Ipv6,
+            _ => destination.address_family,
+        },
+        io_timeout: timeout(cli.io_timeout.or(defaults.io_timeout).unwrap_or(0)),
+        jobs: cli.jobs.map(usize::from).or(defaults.jobs).unwrap_or(1).max(1),
+        chunks: cli.chunks.map(usize::from).or(defaults.chunks).unwrap_or(1).max(1),
+        split,
+        quota_warn_percent: cli.quota_warn.or(defaults.quota_warn_percent),
+        space_check: flag(cli.space_check, cli.no_space_check).or(defaults.space_check).unwrap_or(true),
+        progress: progress.clone(),
+        run: None,
+    })
+}
+
+/// Seconds to wait for a connection unless configured otherwise.
+const DEFAULT_CONNECT_TIMEOUT: u64 = 30;
+
+/// A timeout in seconds, where 0 means none.
+fn timeout(seconds: u64) -> Option<Duration> {
+    (seconds > 0).then(|| Duration::from_secs(seconds))
+}
+
+/// Reads a `--thing` / `--no-thing` pair; `None` means neither was given.
+fn flag(yes: bool, no: bool) -> Option<bool> {
+    match (yes, no) {
+        (true, _) => Some(true),
+        (_, true) => Some(false),
+        _ => None,
+    }
+}
+
 fn print_usage() {
     println!(r#"
 arkv - Archive files to remote servers
 
 USAGE:
-    arkv <FILE_OR_FOLDER>    Upload a file or folder
+    arkv <FILE_OR_FOLDER>... Upload files or folders
+    arkv get <DEST>:<PATH>   Download a file or folder
+    arkv ls <DEST>[:<PATH>]  List a remote folder
+    arkv rm <DEST>:<PATH>    Delete a remote file (or folder, with -r)
+    arkv diff <PATH> <DEST>  Compare a local folder with its remote copy
+    arkv history             Show past uploads and downloads
+    arkv resume [RUN_ID]     Continue an interrupted run
+    arkv watch <FOLDER>      Upload changes in a folder as they appear
+    arkv enqueue <PATH>...   Queue an upload for `arkv daemon`
+    arkv schedule run        Run the uploads scheduled in the config
+    arkv service install     Run schedule, watch or daemon mode as a service
+    arkv join <PART>         Join --split parts downloaded without arkv
+    arkv prune <DEST>        Delete archives beyond the retention settings
     arkv --setup             Run setup wizard
     arkv --help              Show detailed help
 
 EXAMPLES:
     arkv cool-picture.png              Upload a single file
     arkv my_files/tuesday/             Upload a folder and its contents
-    arkv document.pdf --interactive    Choose destination interactively
+    arkv document.pdf --interactive    Choose destinations interactively
+    arkv photos/ --to nas --to vps     Upload to the named destinations only
+    arkv photos/ --group offsite       Upload to destinations tagged offsite
+    find . -type f | arkv --files-from -   Upload a list of files, keeping their layout
 
 Get started by running: arkv --setup
 "#);
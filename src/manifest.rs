@@ -0,0 +1,424 @@
+use anyhow::{Context, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::Read;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::auth;
+use crate::config::{Config, Destination};
+use crate::encoding::RemoteEncoding;
+use crate::remote::Store;
+
+const MANIFEST_SUFFIX: &str = ".arkv-manifest.toml";
+/// Local copies of manifests, under the config directory.
+const LOCAL_DIR: &str = "manifests";
+const SSH_NAMESPACE: &str = "arkv-manifest";
+const SSH_IDENTITY: &str = "arkv";
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Signer {
+    /// `ssh-keygen -Y sign` with the configured SSH key
+    Ssh,
+    /// `minisign -S` with the configured minisign key
+    Minisign,
+}
+
+impl Signer {
+    fn extension(&self) -> &'static str {
+        match self {
+            Signer::Ssh => "sig",
+            Signer::Minisign => "minisig",
+        }
+    }
+}
+
+/// Describes one uploaded file or folder: every remote file it produced,
+/// with the size and SHA-256 of the bytes as stored remotely.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Manifest {
+    pub version: u32,
+    pub created: u64,
+    pub source: String,
+    pub destination: String,
+    pub files: Vec<ManifestEntry>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ManifestEntry {
+    /// Path relative to the destination's `remote_path`.
+    pub path: String,
+    pub size: u64,
+    pub sha256: String,
+    /// Local path, relative to the upload's parent, when sanitizing changed the remote name.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub original: Option<String>,
+}
+
+impl Manifest {
+    pub fn new(source: &Path, destination: &str, files: Vec<ManifestEntry>) -> Self {
+        let created = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        Self {
+            version: 1,
+            created,
+            source: source.display().to_string(),
+            destination: destination.to_string(),
+            files,
+        }
+    }
+}
+
+/// The manifest for an upload named `name` lives next to it, e.g.
+/// `photos/` gets `photos.arkv-manifest.toml`.
+pub fn remote_manifest_path(remote_root: &str, name: &str) -> PathBuf {
+    PathBuf::from(remote_root).join(format!("{}{}", name, MANIFEST_SUFFIX))
+}
+
+/// The local copy of the manifest for `name` uploaded to `destination`.
+pub fn local_manifest_path(destination: &str, name: &str) -> Result<PathBuf> {
+    Ok(Config::config_dir()?.join(LOCAL_DIR).join(destination).join(format!("{}{}", name, MANIFEST_SUFFIX)))
+}
+
+/// Name of the delta folder an incremental upload of `name` goes into
+/// now, e.g. `photos.delta-20261014-030000`.
+pub fn delta_name(name: &str) -> String {
+    let stamp = unsafe {
+        let now = libc::time(std::ptr::null_mut());
+        let mut tm: libc::tm = std::mem::zeroed();
+        libc::localtime_r(&now, &mut tm);
+        format!("{:04}{:02}{:02}-{:02}{:02}{:02}",
+            tm.tm_year + 1900, tm.tm_mon + 1, tm.tm_mday, tm.tm_hour, tm.tm_min, tm.tm_sec)
+    };
+    format!("{}.delta-{}", name, stamp)
+}
+
+/// The state of the upload `name` as of its last archive, by path inside
+/// the upload: the full archive's local manifest with the files of each
+/// later delta laid over it. `None` before the first full archive.
+pub fn previous_state(destination: &str, name: &str) -> Result<Option<HashMap<String, ManifestEntry>>> {
+    let base = local_manifest_path(destination, name)?;
+    if !base.exists() {
+        return Ok(None);
+    }
+
+    let file_name = Path::new(name).file_name().unwrap_or_default().to_string_lossy().into_owned();
+    let delta_prefix = format!("{}.delta-", file_name);
+    let mut deltas: Vec<PathBuf> = match base.parent().map(fs::read_dir) {
+        Some(Ok(entries)) => entries
+            .flatten()
+            .map(|entry| entry.path())
+            .filter(|path| path.file_name()
+                .and_then(|n| n.to_str())
+                .is_some_and(|n| n.starts_with(&delta_prefix) && n.ends_with(MANIFEST_SUFFIX)))
+            .collect(),
+        _ => Vec::new(),
+    };
+    // The stamps sort in time order
+    deltas.sort();
+
+    let mut state = HashMap::new();
+    for path in std::iter::once(base).chain(deltas) {
+        let content = fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let manifest: Manifest = toml::from_str(&content)
+            .with_context(|| format!("Failed to parse {}", path.display()))?;
+
+        // Paths start with the upload's (or delta's) own remote name
+        let upload = path.file_name().unwrap_or_default().to_string_lossy();
+        let upload = Path::new(name).with_file_name(upload.trim_end_matches(MANIFEST_SUFFIX));
+        let prefix = format!("{}/", upload.to_string_lossy());
+        for entry in manifest.files {
+            if let Some(relative) = entry.path.strip_prefix(&prefix).map(str::to_string) {
+                state.insert(relative, entry);
+            }
+        }
+    }
+    Ok(Some(state))
+}
+
+fn signature_path(manifest_path: &Path, signer: Signer) -> PathBuf {
+    let mut path = manifest_path.as_os_str().to_owned();
+    path.push(".");
+    path.push(signer.extension());
+    PathBuf::from(path)
+}
+
+/// Uploads the manifest, and its detached signature when a signer is set,
+/// and keeps a local copy of both for audits that don't trust the remote.
+pub fn write(store: &Store, manifest: &Manifest, remote_path: &Path, destination: &str, name: &str, signer: Option<Signer>, config: &Config) -> Result<()> {
+    let content = toml::to_string_pretty(manifest)
+        .context("Failed to serialize manifest")?;
+    let signature = signer.map(|signer| sign(content.as_bytes(), signer, config)).transpose()?;
+
+    store.write(remote_path, content.as_bytes())?;
+    if let (Some(signer), Some(signature)) = (signer, &signature) {
+        store.write(&signature_path(remote_path, signer), signature)?;
+    }
+
+    let local_path = local_manifest_path(destination, name)?;
+    if let Some(parent) = local_path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create {}", parent.display()))?;
+    }
+    fs::write(&local_path, &content)
+        .with_context(|| format!("Failed to write {}", local_path.display()))?;
+    for other in [Signer::Ssh, Signer::Minisign] {
+        let path = signature_path(&local_path, other);
+        match (signer, &signature) {
+            (Some(signer), Some(signature)) if signer == other => fs::write(&path, signature)
+                .with_context(|| format!("Failed to write {}", path.display()))?,
+            // A signature from an earlier upload no longer matches
+            _ => { let _ = fs::remove_file(&path); }
+        }
+    }
+
+    Ok(())
+}
+
+fn read_remote_file(store: &Store, path: &Path) -> Result<Vec<u8>> {
+    let mut file = store.open(path)?;
+    let mut content = Vec::new();
+    file.read_to_end(&mut content)
+        .with_context(|| format!("Failed to read remote file: {}", path.display()))?;
+    Ok(content)
+}
+
+fn sign(content: &[u8], signer: Signer, config: &Config) -> Result<Vec<u8>> {
+    let dir = scratch_dir()?;
+    let manifest = dir.join("manifest.toml");
+    fs::write(&manifest, content)
+        .context("Failed to write manifest for signing")?;
+
+    let status = match signer {
+        Signer::Ssh => Command::new("ssh-keygen")
+            .args(["-Y", "sign", "-n", SSH_NAMESPACE, "-f"])
+            .arg(&config.ssh_key_path)
+            .arg(&manifest)
+            .stdout(Stdio::null())
+            .status(),
+        Signer::Minisign => Command::new("minisign")
+            .args(["-S", "-s"])
+            .arg(config.minisign_secret_key()?)
+            .arg("-m")
+            .arg(&manifest)
+            .stdout(Stdio::null())
+            .status(),
+    };
+    let status = status.with_context(|| format!("Failed to run the {:?} signer", signer))?;
+    if !status.success() {
+        let _ = fs::remove_dir_all(&dir);
+        anyhow::bail!("Signing the manifest failed ({})", status);
+    }
+
+    let signature = fs::read(signature_path(&manifest, signer))
+        .context("Failed to read manifest signature");
+    let _ = fs::remove_dir_all(&dir);
+    signature
+}
+
+fn verify_signature(content: &[u8], signature: &[u8], signer: Signer, config: &Config) -> Result<()> {
+    let dir = scratch_dir()?;
+    let manifest = dir.join("manifest.toml");
+    let sig = signature_path(&manifest, signer);
+    fs::write(&manifest, content)
+        .context("Failed to write manifest for verification")?;
+    fs::write(&sig, signature)
+        .context("Failed to write signature for verification")?;
+
+    let output = match signer {
+        Signer::Ssh => {
+            let key = auth::read_public_key(Path::new(&config.ssh_key_path))
+                .context("Could not read the public half of the configured SSH key")?;
+            let signers = dir.join("allowed_signers");
+            fs::write(&signers, format!("{} {} {}\n", SSH_IDENTITY, key.key_type, STANDARD.encode(&key.blob)))
+                .context("Failed to write allowed signers file")?;
+
+            Command::new("ssh-keygen")
+                .args(["-Y", "verify", "-n", SSH_NAMESPACE, "-I", SSH_IDENTITY, "-f"])
+                .arg(&signers)
+                .arg("-s")
+                .arg(&sig)
+                .stdin(fs::File::open(&manifest).context("Failed to reopen manifest")?)
+                .output()
+        }
+        Signer::Minisign => Command::new("minisign")
+            .args(["-V", "-p"])
+            .arg(config.minisign_public_key()?)
+            .arg("-m")
+            .arg(&manifest)
+            .output(),
+    };
+    let _ = fs::remove_dir_all(&dir);
+
+    let output = output.with_context(|| format!("Failed to run the {:?} verifier", signer))?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "Manifest signature is INVALID: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(())
+}
+
+fn scratch_dir() -> Result<PathBuf> {
+    let dir = std::env::temp_dir().join(format!("arkv-{}-{}", std::process::id(), unique_suffix()));
+    fs::create_dir_all(&dir)
+        .context("Failed to create temporary directory")?;
+    Ok(dir)
+}
+
+fn unique_suffix() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or(0)
+}
+
+pub struct VerifyOptions {
+    pub require_signature: bool,
+    /// Check against the local copy of the manifest instead of the remote one.
+    pub local: bool,
+    /// Only compare sizes, without reading the remote files.
+    pub quick: bool,
+}
+
+pub struct VerifyReport {
+    pub signed_by: Option<Signer>,
+    pub checked: usize,
+    pub missing: Vec<String>,
+    pub mismatched: Vec<String>,
+    /// Remote files under the upload that the manifest doesn't list.
+    pub unexpected: Vec<String>,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.missing.is_empty() && self.mismatched.is_empty()
+    }
+}
+
+/// Fetches the manifest for `name`, checks its signature (if any) before
+/// reading it, then compares every listed file against the remote tree:
+/// its size and, unless `quick`, its SHA-256 (computed on the server when
+/// it allows shell commands, otherwise by reading the file over SFTP).
+pub fn verify(
+    store: &Store,
+    destination: &Destination,
+    name: &str,
+    options: &VerifyOptions,
+    config: &Config,
+) -> Result<VerifyReport> {
+    let encoding = destination.remote_encoding;
+    let root = destination.remote_base();
+    let (content, signatures) = if options.local {
+        let path = local_manifest_path(&destination.name, name)?;
+        let content = fs::read(&path)
+            .with_context(|| format!("No local manifest found for '{}' ({})", name, path.display()))?;
+        let signatures = [Signer::Ssh, Signer::Minisign].map(|signer| (signer, fs::read(signature_path(&path, signer)).ok()));
+        (content, signatures)
+    } else {
+        let path = encoding.encode(&remote_manifest_path(&root.to_string_lossy(), name))?;
+        let content = read_remote_file(store, &path)
+            .with_context(|| format!("No manifest found for '{}'", name))?;
+        let signatures = [Signer::Ssh, Signer::Minisign].map(|signer| (signer, read_remote_file(store, &signature_path(&path, signer)).ok()));
+        (content, signatures)
+    };
+
+    let mut signed_by = None;
+    for (signer, signature) in signatures {
+        if let Some(signature) = signature {
+            verify_signature(&content, &signature, signer, config)?;
+            signed_by = Some(signer);
+            break;
+        }
+    }
+    if signed_by.is_none() && options.require_signature {
+        anyhow::bail!("Manifest for '{}' is not signed", name);
+    }
+
+    let manifest: Manifest = toml::from_str(&String::from_utf8_lossy(&content))
+        .context("Failed to parse manifest")?;
+
+    let mut report = VerifyReport {
+        signed_by,
+        checked: 0,
+        missing: Vec::new(),
+        mismatched: Vec::new(),
+        unexpected: Vec::new(),
+    };
+    let mut hash_on_server = !options.quick;
+    for entry in &manifest.files {
+        report.checked += 1;
+        let path = encoding.encode(&root.join(&entry.path))?;
+        match store.size(&path) {
+            Some(size) if size == entry.size => {}
+            Some(_) => {
+                report.mismatched.push(entry.path.clone());
+                continue;
+            }
+            None => {
+                report.missing.push(entry.path.clone());
+                continue;
+            }
+        }
+        if options.quick {
+            continue;
+        }
+
+        let mut digest = None;
+        if hash_on_server {
+            digest = store.sha256(&path).unwrap_or(None);
+            // Don't keep trying on servers without sha256sum or a shell
+            hash_on_server = digest.is_some();
+        }
+        let digest = match digest {
+            Some(digest) => digest,
+            None => store.hash_by_reading(&path)?,
+        };
+        if !digest.eq_ignore_ascii_case(&entry.sha256) {
+            report.mismatched.push(entry.path.clone());
+        }
+    }
+
+    let listed: std::collections::HashSet<&str> = manifest.files.iter().map(|e| e.path.as_str()).collect();
+    let upload = root.join(name);
+    if store.is_dir(&encoding.encode(&upload)?) {
+        for file in remote_files(store, &upload, encoding)? {
+            let Ok(relative) = file.strip_prefix(&root) else {
+                continue;
+            };
+            let relative = relative.to_string_lossy();
+            if !listed.contains(relative.as_ref()) {
+                report.unexpected.push(relative.into_owned());
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+/// Every regular file below the logical remote folder `dir`, as logical paths.
+fn remote_files(store: &Store, dir: &Path, encoding: RemoteEncoding) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in store.read_dir(&encoding.encode(dir)?)? {
+        let Some(name) = encoding.decode(&entry.path).file_name().map(PathBuf::from) else {
+            continue;
+        };
+        let path = dir.join(name);
+        if entry.is_dir {
+            files.extend(remote_files(store, &path, encoding)?);
+        } else if entry.is_file {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
@@ -0,0 +1,43 @@
+use anyhow::Result;
+use tracing::info;
+
+/// Upgrades from one version of the config file to the next: the first
+/// entry takes a version 1 file to version 2, and so on. Each works on
+/// the parsed TOML, before it's read into `Config`, so when a field is
+/// renamed or changes shape the old file still loads.
+const MIGRATIONS: &[fn(&mut toml::Table) -> Result<()>] = &[];
+
+/// The version this arkv writes.
+pub const CURRENT: u32 = MIGRATIONS.len() as u32 + 1;
+
+/// Files from before there was a `version` field are version 1.
+pub fn unversioned() -> u32 {
+    1
+}
+
+/// Brings `table` up to the current version, returning the version it
+/// was at when anything had to change.
+pub fn upgrade(table: &mut toml::Table) -> Result<Option<u32>> {
+    let version = match table.get("version") {
+        None => unversioned(),
+        Some(toml::Value::Integer(version)) if *version >= 1 => *version as u32,
+        Some(value) => anyhow::bail!("Invalid config version: {}", value),
+    };
+    if version > CURRENT {
+        anyhow::bail!(
+            "The config file is version {}, but this arkv only understands up to version {}. Upgrade arkv to use it",
+            version, CURRENT
+        );
+    }
+    if version == CURRENT {
+        return Ok(None);
+    }
+
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(version as usize - 1) {
+        let to = index as u32 + 2;
+        migration(table).map_err(|e| anyhow::anyhow!("Failed to upgrade the config file to version {}: {:#}", to, e))?;
+        info!("Upgraded config file to version {}", to);
+    }
+    table.insert("version".to_string(), toml::Value::Integer(CURRENT.into()));
+    Ok(Some(version))
+}
@@ -0,0 +1,499 @@
+use anyhow::{Context, Result};
+use fuser::{
+    Errno, FileAttr, FileHandle, FileType, Filesystem, FopenFlags, Generation, INodeNo, LockOwner,
+    MountOption, OpenAccMode, OpenFlags, RenameFlags, ReplyAttr, ReplyCreate, ReplyData,
+    ReplyDirectory, ReplyEmpty, ReplyEntry, ReplyOpen, ReplyStatfs, ReplyWrite, Request,
+    TimeOrNow, WriteFlags,
+};
+use ssh2::{FileStat, OpenType, Sftp};
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use crate::config::{Config, Destination};
+use crate::encoding::RemoteEncoding;
+use crate::net::Connection;
+use crate::remote;
+use crate::transfer::{TransferOptions, Transferer};
+
+const TTL: Duration = Duration::from_secs(1);
+const BLOCK_SIZE: u64 = 4096;
+
+/// Mounts a destination's remote folder with FUSE until it is unmounted.
+pub fn run(destination: &Destination, config: &Config, mountpoint: &Path, writable: bool) -> Result<()> {
+    let session = Transferer::new(destination.clone(), TransferOptions::default()).connect(&config.ssh_key_path)?;
+    let sftp = session.sftp()
+        .context("Failed to initialize SFTP")?;
+
+    let fs = RemoteFs {
+        _session: session,
+        root: destination.remote_base(),
+        encoding: destination.remote_encoding,
+        writable,
+        uid: unsafe { libc::getuid() },
+        gid: unsafe { libc::getgid() },
+        state: Mutex::new(State {
+            sftp,
+            paths: HashMap::from([(INodeNo::ROOT.0, PathBuf::new())]),
+            inodes: HashMap::from([(PathBuf::new(), INodeNo::ROOT.0)]),
+            next_inode: INodeNo::ROOT.0 + 1,
+            files: HashMap::new(),
+            next_handle: 1,
+        }),
+    };
+
+    let mut mount_config = fuser::Config::default();
+    mount_config.mount_options = vec![
+        MountOption::FSName(format!("arkv:{}", destination.name)),
+        MountOption::Subtype("arkv".to_string()),
+        if writable { MountOption::RW } else { MountOption::RO },
+    ];
+
+    println!("\n📂 Mounted {} ({}) at {}{}", destination.name, destination.remote_base().display(), mountpoint.display(),
+        if writable { "" } else { " read-only" });
+    println!("   Unmount with: fusermount -u {}\n", mountpoint.display());
+
+    fuser::mount(fs, mountpoint, &mount_config)
+        .with_context(|| format!("Failed to mount {}", mountpoint.display()))
+}
+
+/// Exposes the remote tree through a single SFTP session. Inode numbers are
+/// handed out as paths are looked up and stay valid for the whole mount.
+struct RemoteFs {
+    _session: Connection,
+    root: PathBuf,
+    encoding: RemoteEncoding,
+    writable: bool,
+    uid: u32,
+    gid: u32,
+    state: Mutex<State>,
+}
+
+struct State {
+    sftp: Sftp,
+    /// Paths relative to `root`, by inode.
+    paths: HashMap<u64, PathBuf>,
+    inodes: HashMap<PathBuf, u64>,
+    next_inode: u64,
+    files: HashMap<u64, ssh2::File>,
+    next_handle: u64,
+}
+
+impl State {
+    fn inode(&mut self, path: &Path) -> u64 {
+        if let Some(&ino) = self.inodes.get(path) {
+            return ino;
+        }
+        let ino = self.next_inode;
+        self.next_inode += 1;
+        self.paths.insert(ino, path.to_path_buf());
+        self.inodes.insert(path.to_path_buf(), ino);
+        ino
+    }
+
+    fn path(&self, ino: INodeNo) -> Result<PathBuf, Errno> {
+        self.paths.get(&ino.0).cloned().ok_or(Errno::ENOENT)
+    }
+
+    fn child(&self, parent: INodeNo, name: &OsStr) -> Result<PathBuf, Errno> {
+        Ok(self.path(parent)?.join(name))
+    }
+
+    fn add_file(&mut self, file: ssh2::File) -> u64 {
+        let handle = self.next_handle;
+        self.next_handle += 1;
+        self.files.insert(handle, file);
+        handle
+    }
+
+    /// Keeps inode numbers pointing at the right place after a rename.
+    fn renamed(&mut self, from: &Path, to: &Path) {
+        let moved: Vec<(PathBuf, u64)> = self.inodes.iter()
+            .filter(|(path, _)| path.starts_with(from))
+            .map(|(path, &ino)| (path.clone(), ino))
+            .collect();
+        for (path, ino) in moved {
+            let new_path = to.join(path.strip_prefix(from).unwrap_or(Path::new("")));
+            self.inodes.remove(&path);
+            self.inodes.insert(new_path.clone(), ino);
+            self.paths.insert(ino, new_path);
+        }
+    }
+
+    fn forget_path(&mut self, path: &Path) {
+        if let Some(ino) = self.inodes.remove(path) {
+            self.paths.remove(&ino);
+        }
+    }
+}
+
+fn errno(e: ssh2::Error) -> Errno {
+    Errno::from(std::io::Error::from(e))
+}
+
+fn io_errno(e: std::io::Error) -> Errno {
+    Errno::from(e)
+}
+
+fn system_time(secs: Option<u64>) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(secs.unwrap_or(0))
+}
+
+impl RemoteFs {
+    fn wire(&self, path: &Path) -> Result<PathBuf, Errno> {
+        self.encoding.encode(&self.root.join(path)).map_err(|_| Errno::EINVAL)
+    }
+
+    fn check_writable(&self) -> Result<(), Errno> {
+        if self.writable { Ok(()) } else { Err(Errno::EROFS) }
+    }
+
+    fn attr(&self, ino: u64, stat: &FileStat) -> FileAttr {
+        let kind = if stat.is_dir() {
+            FileType::Directory
+        } else if stat.file_type().is_symlink() {
+            FileType::Symlink
+        } else {
+            FileType::RegularFile
+        };
+        let size = stat.size.unwrap_or(0);
+        FileAttr {
+            ino: INodeNo(ino),
+            size,
+            blocks: size.div_ceil(512),
+            atime: system_time(stat.atime),
+            mtime: system_time(stat.mtime),
+            ctime: system_time(stat.mtime),
+            crtime: system_time(stat.mtime),
+            kind,
+            perm: (stat.perm.unwrap_or(0o644) & 0o7777) as u16,
+            nlink: if kind == FileType::Directory { 2 } else { 1 },
+            uid: self.uid,
+            gid: self.gid,
+            rdev: 0,
+            blksize: BLOCK_SIZE as u32,
+            flags: 0,
+        }
+    }
+
+    fn stat_entry(&self, state: &mut State, path: &Path) -> Result<FileAttr, Errno> {
+        let stat = state.sftp.stat(&self.wire(path)?).map_err(errno)?;
+        let ino = state.inode(path);
+        Ok(self.attr(ino, &stat))
+    }
+
+    fn readdir_entries(&self, state: &mut State, ino: INodeNo) -> Result<Vec<(u64, FileType, PathBuf)>, Errno> {
+        let path = state.path(ino)?;
+        let listing = state.sftp.readdir(&self.wire(&path)?).map_err(errno)?;
+
+        let parent = path.parent()
+            .map(|p| state.inode(p))
+            .unwrap_or(ino.0);
+        let mut entries = vec![
+            (ino.0, FileType::Directory, PathBuf::from(".")),
+            (parent, FileType::Directory, PathBuf::from("..")),
+        ];
+        for (remote, stat) in listing {
+            let Some(name) = self.encoding.decode(&remote).file_name().map(PathBuf::from) else {
+                continue;
+            };
+            let kind = if stat.is_dir() { FileType::Directory } else { FileType::RegularFile };
+            entries.push((state.inode(&path.join(&name)), kind, name));
+        }
+        Ok(entries)
+    }
+}
+
+impl Filesystem for RemoteFs {
+    fn lookup(&self, _req: &Request, parent: INodeNo, name: &OsStr, reply: ReplyEntry) {
+        let mut state = self.state.lock().unwrap();
+        match state.child(parent, name).and_then(|path| self.stat_entry(&mut state, &path)) {
+            Ok(attr) => reply.entry(&TTL, &attr, Generation(0)),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn getattr(&self, _req: &Request, ino: INodeNo, _fh: Option<FileHandle>, reply: ReplyAttr) {
+        let mut state = self.state.lock().unwrap();
+        match state.path(ino).and_then(|path| self.stat_entry(&mut state, &path)) {
+            Ok(attr) => reply.attr(&TTL, &attr),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn setattr(
+        &self,
+        _req: &Request,
+        ino: INodeNo,
+        mode: Option<u32>,
+        _uid: Option<u32>,
+        _gid: Option<u32>,
+        size: Option<u64>,
+        atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
+        _ctime: Option<SystemTime>,
+        _fh: Option<FileHandle>,
+        _crtime: Option<SystemTime>,
+        _chgtime: Option<SystemTime>,
+        _bkuptime: Option<SystemTime>,
+        _flags: Option<fuser::BsdFileFlags>,
+        reply: ReplyAttr,
+    ) {
+        let mut state = self.state.lock().unwrap();
+        let result = (|| {
+            self.check_writable()?;
+            let path = state.path(ino)?;
+            let secs = |t: Option<TimeOrNow>| t.map(|t| {
+                let time = match t {
+                    TimeOrNow::SpecificTime(time) => time,
+                    TimeOrNow::Now => SystemTime::now(),
+                };
+                time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+            });
+            let change = FileStat {
+                size,
+                uid: None,
+                gid: None,
+                perm: mode,
+                atime: secs(atime),
+                mtime: secs(mtime),
+            };
+            state.sftp.setstat(&self.wire(&path)?, change).map_err(errno)?;
+            self.stat_entry(&mut state, &path)
+        })();
+        match result {
+            Ok(attr) => reply.attr(&TTL, &attr),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn mkdir(&self, _req: &Request, parent: INodeNo, name: &OsStr, mode: u32, _umask: u32, reply: ReplyEntry) {
+        let mut state = self.state.lock().unwrap();
+        let result = (|| {
+            self.check_writable()?;
+            let path = state.child(parent, name)?;
+            state.sftp.mkdir(&self.wire(&path)?, (mode & 0o7777) as i32).map_err(errno)?;
+            self.stat_entry(&mut state, &path)
+        })();
+        match result {
+            Ok(attr) => reply.entry(&TTL, &attr, Generation(0)),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn unlink(&self, _req: &Request, parent: INodeNo, name: &OsStr, reply: ReplyEmpty) {
+        let mut state = self.state.lock().unwrap();
+        let result = (|| {
+            self.check_writable()?;
+            let path = state.child(parent, name)?;
+            state.sftp.unlink(&self.wire(&path)?).map_err(errno)?;
+            state.forget_path(&path);
+            Ok(())
+        })();
+        match result {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn rmdir(&self, _req: &Request, parent: INodeNo, name: &OsStr, reply: ReplyEmpty) {
+        let mut state = self.state.lock().unwrap();
+        let result = (|| {
+            self.check_writable()?;
+            let path = state.child(parent, name)?;
+            state.sftp.rmdir(&self.wire(&path)?).map_err(errno)?;
+            state.forget_path(&path);
+            Ok(())
+        })();
+        match result {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn rename(
+        &self,
+        _req: &Request,
+        parent: INodeNo,
+        name: &OsStr,
+        newparent: INodeNo,
+        newname: &OsStr,
+        _flags: RenameFlags,
+        reply: ReplyEmpty,
+    ) {
+        let mut state = self.state.lock().unwrap();
+        let result = (|| {
+            self.check_writable()?;
+            let from = state.child(parent, name)?;
+            let to = state.child(newparent, newname)?;
+            let flags = ssh2::RenameFlags::OVERWRITE | ssh2::RenameFlags::ATOMIC | ssh2::RenameFlags::NATIVE;
+            state.sftp.rename(&self.wire(&from)?, &self.wire(&to)?, Some(flags)).map_err(errno)?;
+            state.forget_path(&to);
+            state.renamed(&from, &to);
+            Ok(())
+        })();
+        match result {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn open(&self, _req: &Request, ino: INodeNo, flags: OpenFlags, reply: ReplyOpen) {
+        let mut state = self.state.lock().unwrap();
+        let result = (|| {
+            let mode = match flags.acc_mode() {
+                OpenAccMode::O_RDONLY => ssh2::OpenFlags::READ,
+                OpenAccMode::O_WRONLY => ssh2::OpenFlags::WRITE,
+                OpenAccMode::O_RDWR => ssh2::OpenFlags::READ | ssh2::OpenFlags::WRITE,
+            };
+            if mode.contains(ssh2::OpenFlags::WRITE) {
+                self.check_writable()?;
+            }
+            let mode = if flags.0 & libc::O_TRUNC != 0 { mode | ssh2::OpenFlags::TRUNCATE } else { mode };
+
+            let path = state.path(ino)?;
+            let file = state.sftp.open_mode(&self.wire(&path)?, mode, 0o644, OpenType::File).map_err(errno)?;
+            Ok(state.add_file(file))
+        })();
+        match result {
+            Ok(handle) => reply.opened(FileHandle(handle), FopenFlags::empty()),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn create(
+        &self,
+        _req: &Request,
+        parent: INodeNo,
+        name: &OsStr,
+        mode: u32,
+        _umask: u32,
+        _flags: i32,
+        reply: ReplyCreate,
+    ) {
+        let mut state = self.state.lock().unwrap();
+        let result = (|| {
+            self.check_writable()?;
+            let path = state.child(parent, name)?;
+            let flags = ssh2::OpenFlags::READ | ssh2::OpenFlags::WRITE
+                | ssh2::OpenFlags::CREATE | ssh2::OpenFlags::TRUNCATE;
+            let file = state.sftp.open_mode(&self.wire(&path)?, flags, (mode & 0o7777) as i32, OpenType::File)
+                .map_err(errno)?;
+            let handle = state.add_file(file);
+            Ok((self.stat_entry(&mut state, &path)?, handle))
+        })();
+        match result {
+            Ok((attr, handle)) => reply.created(&TTL, &attr, Generation(0), FileHandle(handle), FopenFlags::empty()),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn read(
+        &self,
+        _req: &Request,
+        _ino: INodeNo,
+        fh: FileHandle,
+        offset: u64,
+        size: u32,
+        _flags: OpenFlags,
+        _lock_owner: Option<LockOwner>,
+        reply: ReplyData,
+    ) {
+        let mut state = self.state.lock().unwrap();
+        let result = (|| {
+            let file = state.files.get_mut(&fh.0).ok_or(Errno::EBADF)?;
+            file.seek(SeekFrom::Start(offset)).map_err(io_errno)?;
+
+            let mut buffer = vec![0; size as usize];
+            let mut filled = 0;
+            while filled < buffer.len() {
+                match file.read(&mut buffer[filled..]).map_err(io_errno)? {
+                    0 => break,
+                    n => filled += n,
+                }
+            }
+            buffer.truncate(filled);
+            Ok(buffer)
+        })();
+        match result {
+            Ok(data) => reply.data(&data),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn write(
+        &self,
+        _req: &Request,
+        _ino: INodeNo,
+        fh: FileHandle,
+        offset: u64,
+        data: &[u8],
+        _write_flags: WriteFlags,
+        _flags: OpenFlags,
+        _lock_owner: Option<LockOwner>,
+        reply: ReplyWrite,
+    ) {
+        let mut state = self.state.lock().unwrap();
+        let result = (|| {
+            self.check_writable()?;
+            let file = state.files.get_mut(&fh.0).ok_or(Errno::EBADF)?;
+            file.seek(SeekFrom::Start(offset)).map_err(io_errno)?;
+            file.write_all(data).map_err(io_errno)?;
+            Ok(data.len() as u32)
+        })();
+        match result {
+            Ok(written) => reply.written(written),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn release(
+        &self,
+        _req: &Request,
+        _ino: INodeNo,
+        fh: FileHandle,
+        _flags: OpenFlags,
+        _lock_owner: Option<LockOwner>,
+        _flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        self.state.lock().unwrap().files.remove(&fh.0);
+        reply.ok();
+    }
+
+    fn readdir(&self, _req: &Request, ino: INodeNo, _fh: FileHandle, offset: u64, mut reply: ReplyDirectory) {
+        let mut state = self.state.lock().unwrap();
+        match self.readdir_entries(&mut state, ino) {
+            Ok(entries) => {
+                for (i, (ino, kind, name)) in entries.into_iter().enumerate().skip(offset as usize) {
+                    if reply.add(INodeNo(ino), i as u64 + 1, kind, &name) {
+                        break;
+                    }
+                }
+                reply.ok();
+            }
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn statfs(&self, _req: &Request, _ino: INodeNo, reply: ReplyStatfs) {
+        let state = self.state.lock().unwrap();
+        let usage = self.wire(Path::new(""))
+            .ok()
+            .and_then(|root| remote::statvfs_usage(&state.sftp, &root).ok());
+        match usage {
+            Some(usage) => {
+                let free = usage.total_bytes.saturating_sub(usage.used_bytes);
+                reply.statfs(
+                    usage.total_bytes / BLOCK_SIZE,
+                    free / BLOCK_SIZE,
+                    usage.available_bytes / BLOCK_SIZE,
+                    0, 0, BLOCK_SIZE as u32, 255, BLOCK_SIZE as u32,
+                );
+            }
+            None => reply.statfs(0, 0, 0, 0, 0, BLOCK_SIZE as u32, 255, BLOCK_SIZE as u32),
+        }
+    }
+}
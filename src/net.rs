@@ -0,0 +1,401 @@
+use anyhow::{Context, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use ssh2::Session;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::ops::Deref;
+use std::os::unix::io::{AsRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use tracing::{debug, info};
+use crate::config::{AddressFamily, Destination};
+use crate::failure::{self, Failure};
+
+const SOCKET_BUFFER_SIZE: libc::c_int = 2_097_152;
+/// Head start each connection attempt gets before the next address is
+/// tried alongside it (RFC 8305's recommended delay).
+const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// An SSH session, with the thread that keeps it alive through idle
+/// periods when the destination sets `keepalive_interval`.
+pub struct Connection {
+    session: Session,
+    _keepalive: Option<Keepalive>,
+}
+
+struct Keepalive {
+    stop: Arc<(Mutex<bool>, Condvar)>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Connection {
+    /// Turns on keepalives for `session` every `interval` seconds of
+    /// silence. libssh2 only sends them when asked, so a thread asks
+    /// regularly; it waits while the session is busy, which is when no
+    /// keepalive is needed.
+    pub fn new(session: Session, interval: Option<u32>) -> Self {
+        let keepalive = interval.filter(|&i| i > 0).map(|interval| {
+            session.set_keepalive(true, interval);
+            let stop = Arc::new((Mutex::new(false), Condvar::new()));
+            let thread = thread::spawn({
+                let session = session.clone();
+                let stop = stop.clone();
+                move || {
+                    let (stopped, wake) = &*stop;
+                    let mut stopped = stopped.lock().unwrap();
+                    let mut wait = Duration::from_secs(interval.into());
+                    while !*stopped {
+                        stopped = wake.wait_timeout(stopped, wait).unwrap().0;
+                        if *stopped {
+                            break;
+                        }
+                        match session.keepalive_send() {
+                            // Seconds until the next one is due
+                            Ok(next) => wait = Duration::from_secs(next.max(1).into()),
+                            Err(e) => {
+                                debug!("Stopped sending keepalives: {}", e);
+                                break;
+                            }
+                        }
+                    }
+                }
+            });
+            Keepalive { stop, thread: Some(thread) }
+        });
+        Self { session, _keepalive: keepalive }
+    }
+}
+
+impl Deref for Connection {
+    type Target = Session;
+
+    fn deref(&self) -> &Session {
+        &self.session
+    }
+}
+
+impl Drop for Keepalive {
+    fn drop(&mut self) {
+        let (stopped, wake) = &*self.stop;
+        *stopped.lock().unwrap() = true;
+        wake.notify_one();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// The byte stream an SSH session runs over: a direct TCP connection, or
+/// the stdio of a `proxy_command` bridged through a socket pair.
+pub enum Transport {
+    Tcp(TcpStream),
+    Proxy(UnixStream),
+}
+
+impl AsRawFd for Transport {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Transport::Tcp(stream) => stream.as_raw_fd(),
+            Transport::Proxy(stream) => stream.as_raw_fd(),
+        }
+    }
+}
+
+/// Connects to the destination, directly or through its HTTP proxy, or
+/// starts its proxy command. `timeout` limits the TCP connection to each
+/// of the host's (or proxy's) addresses.
+pub fn open_transport(destination: &Destination, family: AddressFamily, timeout: Option<Duration>) -> Result<Transport> {
+    if let Some(command) = &destination.proxy_command {
+        return spawn_proxy(command, destination).map(Transport::Proxy);
+    }
+
+    let host = destination.connect_host();
+    let tcp = match &destination.proxy {
+        Some(proxy) => HttpProxy::parse(proxy)?.tunnel(host, destination.port, family, timeout)?,
+        None => {
+            match &destination.connect_address {
+                Some(address) => info!("Connecting to {} at {}:{}", destination.host, address, destination.port),
+                None => info!("Connecting to {}:{}", host, destination.port),
+            }
+            connect(host, destination.port, family, timeout).map_err(|e| anyhow::anyhow!(
+                "Failed to connect to {}:{}: {}", host, destination.port, e
+            ))?
+        }
+    };
+    tune_tcp(&tcp)?;
+    Ok(Transport::Tcp(tcp))
+}
+
+/// Connects to whichever of the host's addresses in `family` answers
+/// first.
+pub fn connect(host: &str, port: u16, family: AddressFamily, timeout: Option<Duration>) -> io::Result<TcpStream> {
+    connect_any(&resolve(host, port, family)?, timeout)
+}
+
+/// Every address `host` resolves to in `family`, alternating between
+/// IPv6 and IPv4 (starting with whichever the resolver put first) so a
+/// broken family only ever delays the other by one attempt.
+pub fn resolve(host: &str, port: u16, family: AddressFamily) -> io::Result<Vec<SocketAddr>> {
+    let (mut first, mut second): (Vec<SocketAddr>, Vec<SocketAddr>) = Default::default();
+    let mut addresses = (host, port).to_socket_addrs()?.filter(|address| family.allows(address)).peekable();
+    let first_is_ipv6 = addresses.peek().is_some_and(|address| address.is_ipv6());
+    for address in addresses {
+        match address.is_ipv6() == first_is_ipv6 {
+            true => first.push(address),
+            false => second.push(address),
+        }
+    }
+    if first.is_empty() {
+        let kind = match family {
+            AddressFamily::Any => "",
+            AddressFamily::Ipv4 => " IPv4",
+            AddressFamily::Ipv6 => " IPv6",
+        };
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("{} has no{} addresses", host, kind)));
+    }
+
+    let mut ordered = Vec::new();
+    let (mut first, mut second) = (first.into_iter(), second.into_iter());
+    loop {
+        match (first.next(), second.next()) {
+            (None, None) => break,
+            (a, b) => ordered.extend(a.into_iter().chain(b)),
+        }
+    }
+    debug!("{} resolves to {:?}", host, ordered);
+    Ok(ordered)
+}
+
+/// Connects to the first of `addresses` to answer, Happy Eyeballs style:
+/// each attempt gets `ATTEMPT_DELAY` before the next address is tried
+/// alongside it, or none once it has failed. Each attempt is limited to
+/// `timeout`.
+pub fn connect_any(addresses: &[SocketAddr], timeout: Option<Duration>) -> io::Result<TcpStream> {
+    let (results, finished) = mpsc::channel();
+    let mut started = 0;
+    let mut pending = 0;
+    let mut last_error = None;
+    loop {
+        if let Some(&address) = addresses.get(started) {
+            debug!("Trying {}", address);
+            let results = results.clone();
+            thread::spawn(move || {
+                // A connection that loses the race is dropped here
+                let _ = results.send(attempt(address, timeout));
+            });
+            started += 1;
+            pending += 1;
+        } else if pending == 0 {
+            break;
+        }
+
+        let result = match started < addresses.len() {
+            true => finished.recv_timeout(ATTEMPT_DELAY),
+            false => finished.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        match result {
+            Ok(Ok(stream)) => return Ok(stream),
+            Ok(Err(e)) => {
+                pending -= 1;
+                last_error = Some(e);
+            }
+            Err(_) => {}
+        }
+    }
+    Err(last_error.unwrap_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no addresses to connect to")))
+}
+
+fn attempt(address: SocketAddr, timeout: Option<Duration>) -> io::Result<TcpStream> {
+    let Some(timeout) = timeout else {
+        return TcpStream::connect(address);
+    };
+    TcpStream::connect_timeout(&address, timeout).map_err(|e| match e.kind() {
+        io::ErrorKind::TimedOut => io::Error::new(io::ErrorKind::TimedOut, format!(
+            "no answer from {} within {}s (see --connect-timeout)", address, timeout.as_secs()
+        )),
+        _ => e,
+    })
+}
+
+/// An HTTP proxy that SSH connections are tunneled through with
+/// `CONNECT`, as corporate networks often require.
+pub struct HttpProxy {
+    pub host: String,
+    pub port: u16,
+    /// `Proxy-Authorization` value, when the URL has a username.
+    authorization: Option<String>,
+}
+
+impl HttpProxy {
+    /// Parses `http://[user[:password]@]host[:port]`; user and password may
+    /// be percent-encoded.
+    pub fn parse(url: &str) -> Result<Self> {
+        let rest = url.strip_prefix("http://")
+            .with_context(|| format!("Proxy must be an http:// URL: {}", url))?;
+        let authority = rest.trim_end_matches('/');
+        let (credentials, address) = match authority.rsplit_once('@') {
+            Some((credentials, address)) => (Some(credentials), address),
+            None => (None, authority),
+        };
+        let (host, port) = match address.rsplit_once(':').filter(|(_, port)| !port.contains(']')) {
+            Some((host, port)) => (host, port.parse().with_context(|| format!("Invalid proxy port in {}", url))?),
+            None => (address, 80),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() {
+            anyhow::bail!("Proxy URL has no host: {}", url);
+        }
+        let authorization = credentials.map(|credentials| {
+            let (user, password) = credentials.split_once(':').unwrap_or((credentials, ""));
+            let pair = format!("{}:{}", percent_decode(user), percent_decode(password));
+            format!("Basic {}", STANDARD.encode(pair))
+        });
+        Ok(HttpProxy { host: host.to_string(), port, authorization })
+    }
+
+    /// Connects to the proxy and asks it for a tunnel to `host:port`,
+    /// returning the connection once the proxy has opened it.
+    fn tunnel(&self, host: &str, port: u16, family: AddressFamily, timeout: Option<Duration>) -> Result<TcpStream> {
+        info!("Connecting to {}:{} through proxy {}:{}", host, port, self.host, self.port);
+        let mut tcp = connect(&self.host, self.port, family, timeout)
+            .with_context(|| format!("Failed to connect to proxy {}:{}", self.host, self.port))?;
+
+        let target = match host.contains(':') {
+            true => format!("[{}]:{}", host, port),
+            false => format!("{}:{}", host, port),
+        };
+        let mut request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", target);
+        if let Some(authorization) = &self.authorization {
+            request.push_str(&format!("Proxy-Authorization: {}\r\n", authorization));
+        }
+        request.push_str("\r\n");
+        tcp.set_read_timeout(timeout).context("Failed to set proxy timeout")?;
+        tcp.write_all(request.as_bytes()).context("Failed to send CONNECT to proxy")?;
+
+        // Byte by byte, so nothing the server sends after the headers is lost
+        let mut response = Vec::new();
+        let mut byte = [0];
+        while !response.ends_with(b"\r\n\r\n") {
+            if response.len() > 16 * 1024 || tcp.read(&mut byte).context("Failed to read proxy response")? == 0 {
+                anyhow::bail!("Proxy {}:{} closed the connection without answering", self.host, self.port);
+            }
+            response.push(byte[0]);
+        }
+        tcp.set_read_timeout(None).context("Failed to set proxy timeout")?;
+
+        let response = String::from_utf8_lossy(&response);
+        let status_line = response.lines().next().unwrap_or_default();
+        debug!("Proxy answered: {}", status_line);
+        match status_line.split_whitespace().nth(1) {
+            Some(status) if status.starts_with('2') => Ok(tcp),
+            Some("407") => Err(failure::fail(Failure::Auth, format!(
+                "Proxy {}:{} wants credentials arkv wasn't given or didn't accept: {}", self.host, self.port, status_line
+            ))),
+            _ => anyhow::bail!("Proxy {}:{} refused to connect to {}: {}", self.host, self.port, target, status_line),
+        }
+    }
+}
+
+pub fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::new();
+    let mut index = 0;
+    while index < bytes.len() {
+        let hex = bytes.get(index + 1..index + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[index], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                index += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                index += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn tune_tcp(tcp: &TcpStream) -> Result<()> {
+    tcp.set_nodelay(true)
+        .context("Failed to set TCP_NODELAY")?;
+
+    let fd = tcp.as_raw_fd();
+    unsafe {
+        let size = SOCKET_BUFFER_SIZE;
+        libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_SNDBUF,
+            &size as *const _ as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        );
+        libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_RCVBUF,
+            &size as *const _ as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        );
+    }
+    Ok(())
+}
+
+/// Runs the proxy command with its stdin and stdout attached to one end
+/// of a socket pair; the SSH session uses the other end, like OpenSSH's
+/// `ProxyCommand`.
+fn spawn_proxy(command: &str, destination: &Destination) -> Result<UnixStream> {
+    let command = expand_proxy_command(command, destination);
+    info!("Starting proxy command: {}", command);
+
+    let (ours, theirs) = UnixStream::pair()
+        .context("Failed to create socket pair for proxy command")?;
+    let stdin = OwnedFd::from(theirs.try_clone().context("Failed to duplicate proxy socket")?);
+    let stdout = OwnedFd::from(theirs);
+
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(&command)
+        .stdin(Stdio::from(stdin))
+        .stdout(Stdio::from(stdout))
+        .spawn()
+        .with_context(|| format!("Failed to start proxy command: {}", command))?;
+
+    // The proxy exits once the session closes its end; reap it then.
+    thread::spawn(move || {
+        let _ = child.wait();
+    });
+
+    Ok(ours)
+}
+
+/// Expands OpenSSH-style tokens: `%h` host, `%p` port, `%r` user, `%%`.
+fn expand_proxy_command(command: &str, destination: &Destination) -> String {
+    let mut expanded = String::new();
+    let mut chars = command.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            expanded.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('h') => expanded.push_str(&destination.host),
+            Some('p') => expanded.push_str(&destination.port.to_string()),
+            Some('r') => expanded.push_str(&destination.username),
+            Some('%') => expanded.push('%'),
+            Some(other) => {
+                expanded.push('%');
+                expanded.push(other);
+            }
+            None => expanded.push('%'),
+        }
+    }
+    expanded
+}
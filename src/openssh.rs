@@ -0,0 +1,535 @@
+use anyhow::{Context, Result};
+use ssh2::Session;
+use std::collections::VecDeque;
+use std::io::{BufReader, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+use tracing::{debug, info, trace};
+use crate::backend::Backend;
+use crate::config::{AddressFamily, Destination};
+use crate::encoding::RemoteEncoding;
+use crate::failure::{self, Classify, Failure};
+use crate::known_hosts;
+use crate::net;
+use crate::transfer::{self, TransferOptions};
+
+/// Data sent per write request; every SFTP server takes at least this much.
+const WRITE_SIZE: usize = 32 * 1024;
+/// Writes sent ahead of their replies, so a file isn't held back by a
+/// round trip for every chunk.
+const WRITES_IN_FLIGHT: usize = 16;
+/// The largest packet taken from the server. Servers send at most 256 KiB
+/// (OpenSSH's own limit), so anything longer is a broken stream.
+const MAX_PACKET: usize = 256 * 1024;
+
+// SFTP version 3 packet types, status codes and open flags
+const FXP_INIT: u8 = 1;
+const FXP_VERSION: u8 = 2;
+const FXP_OPEN: u8 = 3;
+const FXP_CLOSE: u8 = 4;
+const FXP_WRITE: u8 = 6;
+const FXP_REMOVE: u8 = 13;
+const FXP_MKDIR: u8 = 14;
+const FXP_STAT: u8 = 17;
+const FXP_STATUS: u8 = 101;
+const FXP_HANDLE: u8 = 102;
+const FXP_ATTRS: u8 = 105;
+const FX_OK: u32 = 0;
+const FX_NO_SUCH_FILE: u32 = 2;
+const FXF_WRITE: u32 = 0x02;
+const FXF_CREAT: u32 = 0x08;
+const FXF_TRUNC: u32 = 0x10;
+const ATTR_SIZE: u32 = 0x01;
+const ATTR_UIDGID: u32 = 0x02;
+const ATTR_PERMISSIONS: u32 = 0x04;
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+
+/// An SFTP server reached through OpenSSH (`ssh -s sftp`), for
+/// destinations with `gssapi = true`: OpenSSH logs in with
+/// gssapi-with-mic using the Kerberos tickets `kinit` left in the ticket
+/// cache, which libssh2 can't, and arkv speaks SFTP over its stdin and
+/// stdout.
+pub struct OpenSsh {
+    channel: Mutex<Channel>,
+    encoding: RemoteEncoding,
+}
+
+struct Channel {
+    child: Child,
+    input: ChildStdin,
+    output: BufReader<ChildStdout>,
+    /// Collects what ssh prints, for when it exits.
+    errors: Option<thread::JoinHandle<String>>,
+    next_id: u32,
+}
+
+impl OpenSsh {
+    pub fn open(destination: &Destination, options: &TransferOptions) -> Result<Self> {
+        check_host_key(destination, options)?;
+        Self::log_in(destination, options)
+    }
+
+    fn log_in(destination: &Destination, options: &TransferOptions) -> Result<Self> {
+        let mut command = Command::new("ssh");
+        command
+            .args(ssh_options(destination, options.connect_timeout, options.address_family))
+            .args(["-o", "GSSAPIAuthentication=yes", "-o", "PreferredAuthentications=gssapi-with-mic"])
+            .args(["-l", &destination.username, "-s", destination.connect_host(), "sftp"]);
+        debug!("Running {:?}", command);
+        let mut channel = Channel::spawn(command).context("Failed to run ssh, which GSSAPI logins go through")?;
+        info!("Logging in to {} with GSSAPI through ssh", destination.host);
+        channel.send(FXP_INIT, &Packet::new().u32(3).0)
+            .and_then(|_| channel.receive())
+            .and_then(|(kind, _)| match kind {
+                FXP_VERSION => Ok(()),
+                other => anyhow::bail!("Unexpected SFTP reply {} to the greeting", other),
+            })
+            .map_err(|e| failure::fail(Failure::Auth, format!(
+                "ssh couldn't log in to {} with GSSAPI: {:#} (is there a Kerberos ticket? see klist and kinit)",
+                destination.host, e
+            )))?;
+        info!("Successfully authenticated");
+
+        Ok(OpenSsh { channel: Mutex::new(channel), encoding: destination.remote_encoding })
+    }
+
+    fn remote(&self, path: &Path) -> Result<Vec<u8>> {
+        Ok(self.encoding.encode(path)?.as_os_str().as_bytes().to_vec())
+    }
+
+    /// The permission bits of `path`, or `None` if there's nothing there.
+    fn stat(&self, path: &Path) -> Result<Option<u32>> {
+        let remote = self.remote(path)?;
+        let mut channel = self.channel.lock().unwrap();
+        let (kind, mut reply) = channel.request(FXP_STAT, Packet::new().bytes(&remote))?;
+        match kind {
+            FXP_ATTRS => {
+                let flags = reply.u32()?;
+                if flags & ATTR_SIZE != 0 {
+                    reply.u64()?;
+                }
+                if flags & ATTR_UIDGID != 0 {
+                    reply.u32()?;
+                    reply.u32()?;
+                }
+                Ok(Some(match flags & ATTR_PERMISSIONS != 0 {
+                    true => reply.u32()?,
+                    false => 0,
+                }))
+            }
+            FXP_STATUS => match reply.status()? {
+                (FX_NO_SUCH_FILE, _) => Ok(None),
+                status => Err(status_error(status)).with_context(|| format!("Failed to look up {}", path.display())),
+            },
+            other => anyhow::bail!("Unexpected SFTP reply {} to stat", other),
+        }
+    }
+}
+
+impl Channel {
+    /// Runs `command` with SFTP spoken over its input and output.
+    fn spawn(mut command: Command) -> Result<Self> {
+        let mut child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+
+        let input = child.stdin.take().context("Failed to open ssh input")?;
+        let output = BufReader::new(child.stdout.take().context("Failed to open ssh output")?);
+        let mut stderr = child.stderr.take().context("Failed to open ssh errors")?;
+        let errors = thread::spawn(move || {
+            let mut text = String::new();
+            let _ = stderr.read_to_string(&mut text);
+            text
+        });
+        Ok(Channel { child, input, output, errors: Some(errors), next_id: 0 })
+    }
+
+    fn send(&mut self, kind: u8, body: &[u8]) -> Result<()> {
+        let length = u32::try_from(body.len() + 1).context("SFTP request too large")?;
+        let mut packet = Vec::with_capacity(body.len() + 5);
+        packet.extend_from_slice(&length.to_be_bytes());
+        packet.push(kind);
+        packet.extend_from_slice(body);
+        if self.input.write_all(&packet).is_err() {
+            anyhow::bail!("ssh closed the connection: {}", self.exited());
+        }
+        Ok(())
+    }
+
+    /// The next packet from the server: its type and what follows its id.
+    fn receive(&mut self) -> Result<(u8, Reply)> {
+        let mut length = [0; 4];
+        if self.output.read_exact(&mut length).is_err() {
+            anyhow::bail!("ssh closed the connection: {}", self.exited());
+        }
+        let length = u32::from_be_bytes(length) as usize;
+        if length == 0 {
+            anyhow::bail!("Empty SFTP packet");
+        }
+        if length > MAX_PACKET {
+            anyhow::bail!("SFTP packet of {} bytes is over the {} byte limit", length, MAX_PACKET);
+        }
+        let mut data = vec![0; length];
+        if self.output.read_exact(&mut data).is_err() {
+            anyhow::bail!("ssh closed the connection: {}", self.exited());
+        }
+        let kind = data[0];
+        trace!("SFTP reply {} ({} bytes)", kind, length);
+        Ok((kind, Reply { data, at: 1 }))
+    }
+
+    /// Sends a request of `kind` with `body` after its id and waits for
+    /// the reply, which answers it as nothing else is outstanding.
+    fn request(&mut self, kind: u8, body: Packet) -> Result<(u8, Reply)> {
+        let id = self.send_request(kind, body)?;
+        let (reply_kind, mut reply) = self.receive()?;
+        if reply.u32()? != id {
+            anyhow::bail!("SFTP reply out of order");
+        }
+        Ok((reply_kind, reply))
+    }
+
+    fn send_request(&mut self, kind: u8, body: Packet) -> Result<u32> {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        let mut packet = Packet::new().u32(id).0;
+        packet.extend_from_slice(&body.0);
+        self.send(kind, &packet)?;
+        Ok(id)
+    }
+
+    /// Sends a request answered by a status, and fails unless it's OK.
+    fn expect_ok(&mut self, kind: u8, body: Packet) -> Result<()> {
+        match self.request(kind, body)? {
+            (FXP_STATUS, mut reply) => match reply.status()? {
+                (FX_OK, _) => Ok(()),
+                status => Err(status_error(status)),
+            },
+            (other, _) => anyhow::bail!("Unexpected SFTP reply {}", other),
+        }
+    }
+
+    /// Waits for ssh, which has closed its output, to exit and returns
+    /// what it printed, or its exit status if it printed nothing.
+    fn exited(&mut self) -> String {
+        let status = self.child.wait();
+        let errors = self.errors.take().and_then(|errors| errors.join().ok()).unwrap_or_default();
+        let errors = errors.trim().to_string();
+        match (errors.is_empty(), status) {
+            (false, _) => errors,
+            (true, Ok(status)) => format!("ssh exited ({})", status),
+            (true, Err(e)) => e.to_string(),
+        }
+    }
+}
+
+impl Backend for OpenSsh {
+    fn exists(&self, path: &Path) -> Result<bool> {
+        Ok(self.stat(path)?.is_some())
+    }
+
+    fn is_dir(&self, dir: &Path) -> Result<bool> {
+        Ok(self.stat(dir)?.is_some_and(|mode| mode & S_IFMT == S_IFDIR))
+    }
+
+    fn create_dir(&self, dir: &Path) -> Result<()> {
+        debug!("Creating directory: {}", dir.display());
+        let remote = self.remote(dir)?;
+        let created = self.channel.lock().unwrap().expect_ok(FXP_MKDIR, Packet::new().bytes(&remote).u32(0));
+        // Another upload may have created it in the meantime
+        if created.is_err() && !self.is_dir(dir)? {
+            return created.with_context(|| format!("Failed to create remote directory: {}", dir.display()));
+        }
+        Ok(())
+    }
+
+    fn put(&self, path: &Path, reader: &mut dyn Read, _size: u64) -> Result<()> {
+        let remote = self.remote(path)?;
+        let mut channel = self.channel.lock().unwrap();
+        let handle = match channel.request(FXP_OPEN, Packet::new().bytes(&remote).u32(FXF_WRITE | FXF_CREAT | FXF_TRUNC).u32(0))? {
+            (FXP_HANDLE, mut reply) => reply.bytes()?,
+            (FXP_STATUS, mut reply) => {
+                return Err(status_error(reply.status()?)).with_context(|| format!("Failed to create remote file: {}", path.display()));
+            }
+            (other, _) => anyhow::bail!("Unexpected SFTP reply {} to open", other),
+        };
+
+        let written = write_all(&mut channel, &handle, reader);
+        let closed = channel.expect_ok(FXP_CLOSE, Packet::new().bytes(&handle));
+        written.and(closed).with_context(|| format!("Failed to write remote file: {}", path.display()))
+    }
+
+    fn remove(&self, path: &Path) -> Result<()> {
+        let remote = self.remote(path)?;
+        self.channel.lock().unwrap().expect_ok(FXP_REMOVE, Packet::new().bytes(&remote))
+            .with_context(|| format!("Failed to delete {}", path.display()))
+    }
+}
+
+impl Drop for OpenSsh {
+    fn drop(&mut self) {
+        // Every request has been answered by now, so nothing is cut short
+        if let Ok(channel) = self.channel.get_mut() {
+            let _ = channel.child.kill();
+            let _ = channel.child.wait();
+        }
+    }
+}
+
+/// Writes everything from `reader` to the open file `handle`, keeping up
+/// to `WRITES_IN_FLIGHT` writes unanswered. Every write sent is answered
+/// before this returns, even after a failure, so the next reply read is
+/// the one to the caller's next request.
+fn write_all(channel: &mut Channel, handle: &[u8], reader: &mut dyn Read) -> Result<()> {
+    let mut pending = VecDeque::new();
+    let written = send_writes(channel, handle, reader, &mut pending);
+    let mut answered = Ok(());
+    while !pending.is_empty() {
+        if let Err(e) = write_done(channel, &mut pending) {
+            answered = answered.and(Err(e));
+        }
+    }
+    written.and(answered)
+}
+
+fn send_writes(channel: &mut Channel, handle: &[u8], reader: &mut dyn Read, pending: &mut VecDeque<u32>) -> Result<()> {
+    let mut offset = 0u64;
+    let mut buffer = vec![0; WRITE_SIZE];
+    loop {
+        let n = reader.read(&mut buffer).context("Failed to read local file")?;
+        if n == 0 {
+            return Ok(());
+        }
+        let request = Packet::new().bytes(handle).u64(offset).bytes(&buffer[..n]);
+        pending.push_back(channel.send_request(FXP_WRITE, request)?);
+        offset += n as u64;
+        if pending.len() >= WRITES_IN_FLIGHT {
+            write_done(channel, pending)?;
+        }
+    }
+}
+
+/// Takes the reply to the oldest outstanding write, which servers send
+/// in order.
+fn write_done(channel: &mut Channel, pending: &mut VecDeque<u32>) -> Result<()> {
+    let expected = pending.pop_front();
+    let (kind, mut reply) = channel.receive()?;
+    if Some(reply.u32()?) != expected {
+        anyhow::bail!("SFTP reply out of order");
+    }
+    if kind != FXP_STATUS {
+        anyhow::bail!("Unexpected SFTP reply {} to write", kind);
+    }
+    match reply.status()? {
+        (FX_OK, _) => Ok(()),
+        status => Err(status_error(status)),
+    }
+}
+
+fn status_error((code, message): (u32, String)) -> anyhow::Error {
+    match message.is_empty() {
+        true => anyhow::anyhow!("SFTP error {}", code),
+        false => anyhow::anyhow!("{} (SFTP error {})", message, code),
+    }
+}
+
+/// OpenSSH is only told to trust hosts in known_hosts, so the server's
+/// key is checked (or trusted on first use) first, as for every other
+/// connection.
+fn check_host_key(destination: &Destination, options: &TransferOptions) -> Result<()> {
+    let transport = net::open_transport(destination, options.address_family, options.connect_timeout).classify(Failure::Connect)?;
+    let mut session = Session::new().context("Failed to create SSH session")?;
+    session.set_tcp_stream(transport);
+    session.set_timeout(transfer::timeout_millis(options.connect_timeout));
+    session.handshake()
+        .context("SSH handshake failed")
+        .classify(Failure::Connect)?;
+    known_hosts::verify(&session, destination, &options.progress).classify(Failure::HostKey)
+}
+
+/// Options for running OpenSSH's `ssh` against `destination`, set up like
+/// arkv's own connection. Host keys come from the same known_hosts, which
+/// arkv checks first, so OpenSSH never has to ask.
+pub fn ssh_options(destination: &Destination, connect_timeout: Option<Duration>, address_family: AddressFamily) -> Vec<String> {
+    let mut options = vec![
+        "-p".to_string(),
+        destination.port.to_string(),
+        "-o".to_string(),
+        "BatchMode=yes".to_string(),
+        "-o".to_string(),
+        "StrictHostKeyChecking=yes".to_string(),
+    ];
+    let mut option = |value: String| {
+        options.push("-o".to_string());
+        options.push(value);
+    };
+    if let Some(proxy) = &destination.proxy_command {
+        option(format!("ProxyCommand={}", proxy));
+    }
+    if destination.connect_address.is_some() {
+        option(format!("HostKeyAlias={}", destination.host));
+    }
+    if let Some(timeout) = connect_timeout {
+        option(format!("ConnectTimeout={}", timeout.as_secs().max(1)));
+    }
+    match address_family {
+        AddressFamily::Any => {}
+        AddressFamily::Ipv4 => option("AddressFamily=inet".to_string()),
+        AddressFamily::Ipv6 => option("AddressFamily=inet6".to_string()),
+    }
+    if let Some(interval) = destination.keepalive_interval.filter(|&i| i > 0) {
+        option(format!("ServerAliveInterval={}", interval));
+    }
+    options
+}
+
+/// The body of an SFTP request, built field by field.
+struct Packet(Vec<u8>);
+
+impl Packet {
+    fn new() -> Self {
+        Packet(Vec::new())
+    }
+
+    fn u32(mut self, value: u32) -> Self {
+        self.0.extend_from_slice(&value.to_be_bytes());
+        self
+    }
+
+    fn u64(mut self, value: u64) -> Self {
+        self.0.extend_from_slice(&value.to_be_bytes());
+        self
+    }
+
+    fn bytes(self, value: &[u8]) -> Self {
+        let mut packet = self.u32(value.len() as u32);
+        packet.0.extend_from_slice(value);
+        packet
+    }
+}
+
+/// A packet from the server, read field by field.
+struct Reply {
+    data: Vec<u8>,
+    at: usize,
+}
+
+impl Reply {
+    fn take(&mut self, n: usize) -> Result<&[u8]> {
+        let end = self.at.checked_add(n).filter(|&end| end <= self.data.len())
+            .context("SFTP reply too short")?;
+        let field = &self.data[self.at..end];
+        self.at = end;
+        Ok(field)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into()?))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into()?))
+    }
+
+    fn bytes(&mut self) -> Result<Vec<u8>> {
+        let length = self.u32()? as usize;
+        Ok(self.take(length)?.to_vec())
+    }
+
+    /// A status reply's code and message.
+    fn status(&mut self) -> Result<(u32, String)> {
+        let code = self.u32()?;
+        let message = self.bytes().map(|m| String::from_utf8_lossy(&m).into_owned()).unwrap_or_default();
+        Ok((code, message))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn packet_fields_are_big_endian_and_length_prefixed() {
+        let packet = Packet::new().u32(7).bytes(b"ab").u64(1);
+        assert_eq!(packet.0, [0, 0, 0, 7, 0, 0, 0, 2, b'a', b'b', 0, 0, 0, 0, 0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn reply_reads_status() {
+        let mut data = vec![FXP_STATUS];
+        data.extend(Packet::new().u32(9).u32(FX_NO_SUCH_FILE).bytes(b"No such file").bytes(b"").0);
+        let mut reply = Reply { data, at: 1 };
+        assert_eq!(reply.u32().unwrap(), 9);
+        assert_eq!(reply.status().unwrap(), (FX_NO_SUCH_FILE, "No such file".to_string()));
+        // The language tag is left unread
+        assert_eq!(reply.bytes().unwrap(), b"");
+        assert!(reply.u32().is_err());
+    }
+
+    /// A channel to `sh`, which plays back `replies` as SFTP packets and
+    /// reads whatever is sent to it.
+    fn replaying(name: &str, replies: &[(u8, Packet)]) -> Channel {
+        let mut stream = Vec::new();
+        for (kind, body) in replies {
+            stream.extend(((body.0.len() + 1) as u32).to_be_bytes());
+            stream.push(*kind);
+            stream.extend(&body.0);
+        }
+        let file = std::env::temp_dir().join(format!("arkv-openssh-{}-{}", name, std::process::id()));
+        std::fs::write(&file, stream).unwrap();
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(format!("exec 3<'{0}'; rm '{0}'; cat <&3; cat >/dev/null", file.display()));
+        Channel::spawn(command).unwrap()
+    }
+
+    fn status(id: u32, code: u32) -> (u8, Packet) {
+        (FXP_STATUS, Packet::new().u32(id).u32(code).bytes(b"").bytes(b""))
+    }
+
+    /// 10 bytes for every read, so each read is a write of its own.
+    struct Chunks(usize);
+
+    impl Read for Chunks {
+        fn read(&mut self, buffer: &mut [u8]) -> std::io::Result<usize> {
+            let n = self.0.min(10).min(buffer.len());
+            self.0 -= n;
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn failed_write_leaves_no_reply_behind() {
+        let writes = WRITES_IN_FLIGHT as u32 + 1;
+        let mut replies = vec![status(0, 4)];
+        replies.extend((1..writes).map(|id| status(id, FX_OK)));
+        replies.push(status(writes, FX_OK));
+        let mut channel = replaying("write", &replies);
+
+        assert!(write_all(&mut channel, b"h", &mut Chunks(10 * writes as usize)).is_err());
+        // The close gets its own reply, not one left over from the writes
+        channel.expect_ok(FXP_CLOSE, Packet::new().bytes(b"h")).unwrap();
+        let _ = channel.child.kill();
+    }
+
+    #[test]
+    fn oversized_packet_is_refused() {
+        let mut channel = replaying("oversized", &[(FXP_ATTRS, Packet(vec![0; MAX_PACKET]))]);
+        let error = channel.receive().err().unwrap();
+        assert!(error.to_string().contains("over the"), "{}", error);
+        let _ = channel.child.kill();
+    }
+
+    #[test]
+    fn short_reply_is_an_error() {
+        let mut reply = Reply { data: vec![FXP_HANDLE, 0, 0, 0, 1, 0, 0, 0, 8, b'x'], at: 1 };
+        assert_eq!(reply.u32().unwrap(), 1);
+        assert!(reply.bytes().is_err());
+    }
+}
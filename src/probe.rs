@@ -0,0 +1,120 @@
+use anyhow::{Context, Result};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use crate::backend::{self, Backend};
+use crate::config::{Destination, DestinationKind};
+use crate::failure::{self, Failure};
+use crate::transfer::{TransferOptions, Transferer};
+
+/// Requests timed for the round-trip figure.
+const ROUND_TRIPS: usize = 5;
+
+/// `arkv test`: connects and logs in, checks the remote folder, writes and
+/// deletes a small file there and times a few round trips, stopping at the
+/// first step that fails. Nothing is left behind.
+pub fn run(destination: &Destination, options: TransferOptions, ssh_key_path: &str) -> Result<()> {
+    println!("🔌 Testing {} ({})", destination.name, destination.address());
+    match destination.kind {
+        DestinationKind::Sftp if !destination.gssapi => test_sftp(destination, options, ssh_key_path),
+        _ => {
+            let backend = backend::open(destination, &options)?
+                .context("No backend for this destination")?;
+            test_backend(destination, backend.as_ref())
+        }
+    }?;
+    println!("✓ {} is ready for uploads", destination.name);
+    Ok(())
+}
+
+fn test_sftp(destination: &Destination, options: TransferOptions, ssh_key_path: &str) -> Result<()> {
+    let start = Instant::now();
+    let session = Transferer::new(destination.clone(), options).connect(ssh_key_path)?;
+    let sftp = session.sftp()?;
+    println!("✓ Connected and logged in as {} ({})", destination.username, millis(start.elapsed()));
+
+    let encoding = destination.remote_encoding;
+    let base = destination.remote_base();
+    let mut folder = base.clone();
+    loop {
+        match sftp.stat(&encoding.encode(&remote(&folder))?) {
+            Ok(stat) if stat.is_dir() => break,
+            Ok(_) => return Err(failure::fail(Failure::Config, format!(
+                "{}: {} is a file, not a folder", destination.name, folder.display()
+            ))),
+            Err(_) if folder.pop() => {}
+            Err(e) => anyhow::bail!("Can't see any folder of {}: {}", base.display(), e),
+        }
+    }
+    report_folder(&base, &folder);
+
+    let probe = encoding.encode(&remote(&folder).join(probe_name()))?;
+    sftp.create(&probe)
+        .map_err(std::io::Error::from)
+        .and_then(|mut file| file.write_all(PROBE))
+        .map_err(|e| anyhow::anyhow!("Can't write to {}: {}", remote(&folder).display(), e))?;
+    sftp.unlink(&probe)
+        .map_err(|e| anyhow::anyhow!("Wrote {} but couldn't delete it: {}", probe.display(), e))?;
+    println!("✓ Wrote and deleted a test file in {}", remote(&folder).display());
+
+    let wire = encoding.encode(&remote(&folder))?;
+    report_round_trips(|| sftp.stat(&wire).map(|_| ()).context("Failed to read the remote folder"))
+}
+
+/// The first request that has to reach the server is the write, since
+/// object stores have no folders to look at.
+fn test_backend(destination: &Destination, backend: &dyn Backend) -> Result<()> {
+    let base = destination.remote_base();
+    let mut folder = base.clone();
+    while !backend.is_dir(&folder)? && folder.pop() {}
+    report_folder(&base, &folder);
+
+    let start = Instant::now();
+    let probe = folder.join(probe_name());
+    backend.put(&probe, &mut &PROBE[..], PROBE.len() as u64)
+        .map_err(|e| anyhow::anyhow!("Can't write to {}: {:#}", remote(&folder).display(), e))?;
+    backend.remove(&probe)
+        .map_err(|e| anyhow::anyhow!("Wrote {} but couldn't delete it: {:#}", probe.display(), e))?;
+    println!("✓ Wrote and deleted a test file in {} ({})", remote(&folder).display(), millis(start.elapsed()));
+
+    report_round_trips(|| backend.exists(&probe).map(|_| ()))
+}
+
+const PROBE: &[u8] = b"arkv test\n";
+
+fn probe_name() -> String {
+    format!(".arkv-test-{}", std::process::id())
+}
+
+/// Relative remote paths are relative to the login folder, which an empty
+/// path can't name.
+fn remote(path: &Path) -> PathBuf {
+    match path.as_os_str().is_empty() {
+        true => PathBuf::from("."),
+        false => path.to_path_buf(),
+    }
+}
+
+fn report_folder(base: &Path, folder: &Path) {
+    match base == folder {
+        true => println!("✓ {} exists", remote(base).display()),
+        false => println!("⚠️  {} doesn't exist yet; uploads will create it in {}", base.display(), remote(folder).display()),
+    }
+}
+
+fn report_round_trips(mut request: impl FnMut() -> Result<()>) -> Result<()> {
+    let mut times = Vec::with_capacity(ROUND_TRIPS);
+    for _ in 0..ROUND_TRIPS {
+        let start = Instant::now();
+        request()?;
+        times.push(start.elapsed());
+    }
+    let best = times.iter().min().copied().unwrap_or_default();
+    let average = times.iter().sum::<Duration>() / ROUND_TRIPS as u32;
+    println!("✓ Round trip {} (best of {}, average {})", millis(best), ROUND_TRIPS, millis(average));
+    Ok(())
+}
+
+fn millis(duration: Duration) -> String {
+    format!("{:.0} ms", duration.as_secs_f64() * 1000.0)
+}
@@ -0,0 +1,129 @@
+use indicatif::{MultiProgress, ProgressStyle};
+use std::cell::Cell;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+static BARS: OnceLock<MultiProgress> = OnceLock::new();
+
+thread_local! {
+    static SUSPENDED: Cell<bool> = const { Cell::new(false) };
+}
+
+/// The terminal area every destination's progress bars are drawn in, so
+/// bars from concurrent uploads stack instead of overwriting each other.
+/// Log lines and status messages are printed above them with `suspend`.
+pub fn bars() -> &'static MultiProgress {
+    BARS.get_or_init(MultiProgress::new)
+}
+
+/// Runs `f` (which prints or prompts) with `progress`'s bars cleared from
+/// the terminal and redraws them afterwards. Unlike
+/// `MultiProgress::suspend`, which would deadlock, calls can be nested,
+/// e.g. by a log message while a prompt is shown.
+pub fn suspend<R>(progress: &MultiProgress, f: impl FnOnce() -> R) -> R {
+    if SUSPENDED.get() {
+        return f();
+    }
+    struct Resume;
+    impl Drop for Resume {
+        fn drop(&mut self) {
+            SUSPENDED.set(false);
+        }
+    }
+    SUSPENDED.set(true);
+    let _resume = Resume;
+    progress.suspend(f)
+}
+
+/// Tracks bytes sent to one destination so its progress bars can show the
+/// current and average transfer rate while the upload is running.
+pub struct Throughput {
+    start: Instant,
+    bytes: AtomicU64,
+    sample: Mutex<Sample>,
+}
+
+struct Sample {
+    at: Instant,
+    bytes: u64,
+    rate: f64,
+}
+
+impl Throughput {
+    pub fn new() -> Arc<Self> {
+        let now = Instant::now();
+        Arc::new(Self {
+            start: now,
+            bytes: AtomicU64::new(0),
+            sample: Mutex::new(Sample { at: now, bytes: 0, rate: 0.0 }),
+        })
+    }
+
+    pub fn add(&self, bytes: u64) {
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn total(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    /// Average bytes per second since the transfer started.
+    pub fn average(&self) -> f64 {
+        let elapsed = self.start.elapsed().as_secs_f64();
+        if elapsed <= 0.0 {
+            return 0.0;
+        }
+        self.bytes.load(Ordering::Relaxed) as f64 / elapsed
+    }
+
+    /// Bytes per second over roughly the last second.
+    pub fn current(&self) -> f64 {
+        let mut sample = self.sample.lock().unwrap();
+        let elapsed = sample.at.elapsed();
+        if elapsed >= SAMPLE_INTERVAL {
+            let bytes = self.bytes.load(Ordering::Relaxed);
+            sample.rate = (bytes - sample.bytes) as f64 / elapsed.as_secs_f64();
+            sample.bytes = bytes;
+            sample.at = Instant::now();
+        }
+        sample.rate
+    }
+}
+
+/// Style of the bars of uploads and downloads counted in bytes: size
+/// done, speed and ETA. `{rate}` and `{avg}` come from `with_throughput`.
+pub fn bytes_style() -> ProgressStyle {
+    ProgressStyle::default_bar()
+        .template("{spinner:.green} {prefix:.bold} [{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} {rate} (avg {avg}), {eta} {msg}")
+        .unwrap()
+        .progress_chars("#>-")
+}
+
+/// Style of the bars counted in files, for runs whose sizes aren't known
+/// up front.
+pub fn files_style() -> ProgressStyle {
+    ProgressStyle::default_bar()
+        .template("{spinner:.green} {prefix:.bold} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} files {rate} (avg {avg}) {msg}")
+        .unwrap()
+        .progress_chars("#>-")
+}
+
+/// Adds `{rate}` and `{avg}` keys to a style.
+pub fn with_throughput(style: ProgressStyle, throughput: &Arc<Throughput>) -> ProgressStyle {
+    let current = throughput.clone();
+    let average = throughput.clone();
+    style
+        .with_key("rate", move |_: &indicatif::ProgressState, w: &mut dyn std::fmt::Write| {
+            let _ = write!(w, "{}", format_rate(current.current()));
+        })
+        .with_key("avg", move |_: &indicatif::ProgressState, w: &mut dyn std::fmt::Write| {
+            let _ = write!(w, "{}", format_rate(average.average()));
+        })
+}
+
+pub fn format_rate(bytes_per_sec: f64) -> String {
+    format!("{:.2} MB/s", bytes_per_sec / 1_048_576.0)
+}
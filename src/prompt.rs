@@ -0,0 +1,16 @@
+use std::io::IsTerminal;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Set by `--non-interactive`, for scripts that run with a terminal
+/// attached but must never stop to ask.
+static DISABLED: AtomicBool = AtomicBool::new(false);
+
+pub fn disable() {
+    DISABLED.store(true, Ordering::Relaxed);
+}
+
+/// Whether arkv may stop and ask the user something. Never from cron or
+/// other runs without a terminal, where a prompt would hang forever.
+pub fn allowed() -> bool {
+    !DISABLED.load(Ordering::Relaxed) && std::io::stdin().is_terminal()
+}
@@ -0,0 +1,150 @@
+use anyhow::{Context, Result};
+use std::collections::BTreeMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::info;
+use crate::config::{Destination, Retention};
+use crate::remote::Store;
+
+/// A timestamped archive in the destination's remote folder.
+struct Archive {
+    name: String,
+    /// The name with its timestamp taken out, shared by every archive of
+    /// the same upload.
+    series: String,
+    time: libc::time_t,
+}
+
+/// Finds the timestamp in a name: `20261014-030000`, `2026-10-14`,
+/// `2026-10-14T03-00` or `2026-10-14T03-00-00`. Returns where it is and
+/// its local time.
+fn find_stamp(name: &str) -> Option<(usize, usize, libc::time_t)> {
+    let bytes = name.as_bytes();
+    let digits = |at: usize, len: usize| -> Option<i32> {
+        let part = bytes.get(at..at + len)?;
+        part.iter().all(u8::is_ascii_digit).then(|| std::str::from_utf8(part).ok()?.parse().ok())?
+    };
+    let sep = |at: usize, c: u8| bytes.get(at) == Some(&c);
+
+    for start in 0..bytes.len() {
+        // Part of a longer number, e.g. a size or an ID
+        if start > 0 && bytes[start - 1].is_ascii_digit() {
+            continue;
+        }
+        let (date, end) = if let (Some(y), Some(m), Some(d), true, Some(hh), Some(mm), Some(ss)) =
+            (digits(start, 4), digits(start + 4, 2), digits(start + 6, 2), sep(start + 8, b'-'),
+             digits(start + 9, 2), digits(start + 11, 2), digits(start + 13, 2)) {
+            ((y, m, d, hh, mm, ss), start + 15)
+        } else if let (Some(y), true, Some(m), true, Some(d)) =
+            (digits(start, 4), sep(start + 4, b'-'), digits(start + 5, 2), sep(start + 7, b'-'), digits(start + 8, 2)) {
+            match (sep(start + 10, b'T'), digits(start + 11, 2), sep(start + 13, b'-'), digits(start + 14, 2)) {
+                (true, Some(hh), true, Some(mm)) => match (sep(start + 16, b'-'), digits(start + 17, 2)) {
+                    (true, Some(ss)) => ((y, m, d, hh, mm, ss), start + 19),
+                    _ => ((y, m, d, hh, mm, 0), start + 16),
+                },
+                _ => ((y, m, d, 0, 0, 0), start + 10),
+            }
+        } else {
+            continue;
+        };
+        if bytes.get(end).is_some_and(u8::is_ascii_digit) {
+            continue;
+        }
+        if let Some(time) = local_timestamp(date) {
+            return Some((start, end, time));
+        }
+    }
+    None
+}
+
+fn local_timestamp((year, month, day, hour, minute, second): (i32, i32, i32, i32, i32, i32)) -> Option<libc::time_t> {
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+    unsafe {
+        let mut tm: libc::tm = std::mem::zeroed();
+        tm.tm_year = year - 1900;
+        tm.tm_mon = month - 1;
+        tm.tm_mday = day;
+        tm.tm_hour = hour;
+        tm.tm_min = minute;
+        tm.tm_sec = second;
+        tm.tm_isdst = -1;
+        match libc::mktime(&mut tm) {
+            -1 => None,
+            time => Some(time),
+        }
+    }
+}
+
+fn archive(name: &str) -> Option<Archive> {
+    // Deltas of an --incremental upload hold the only copy of the files
+    // changed in them, so they are never pruned
+    if name.contains(".delta-") {
+        return None;
+    }
+    let (start, end, time) = find_stamp(name)?;
+    Some(Archive {
+        name: name.to_string(),
+        series: format!("{}*{}", &name[..start], &name[end..]),
+        time,
+    })
+}
+
+/// The archives `retention` doesn't keep: in each series, everything
+/// that is neither one of the `keep_last` newest nor younger than
+/// `keep_days`.
+fn expired(archives: Vec<Archive>, retention: &Retention, now: libc::time_t) -> Vec<Archive> {
+    let mut series: BTreeMap<String, Vec<Archive>> = BTreeMap::new();
+    for archive in archives {
+        series.entry(archive.series.clone()).or_default().push(archive);
+    }
+
+    let mut expired = Vec::new();
+    for (_, mut archives) in series {
+        archives.sort_by_key(|a| std::cmp::Reverse(a.time));
+        for (index, archive) in archives.into_iter().enumerate() {
+            let recent = retention.keep_last.is_some_and(|keep| index < keep);
+            let young = retention.keep_days.is_some_and(|days| now - archive.time < days as libc::time_t * 86_400);
+            if !recent && !young {
+                expired.push(archive);
+            }
+        }
+    }
+    expired.sort_by(|a, b| a.name.cmp(&b.name));
+    expired
+}
+
+fn now() -> libc::time_t {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as libc::time_t)
+        .unwrap_or(0)
+}
+
+/// Deletes the timestamped archives (files or folders, with their
+/// manifests) in the destination's remote folder that its retention
+/// settings don't keep, returning their names. With `dry_run` nothing
+/// is deleted.
+pub fn run(store: &Store, destination: &Destination, dry_run: bool) -> Result<Vec<String>> {
+    let retention = destination.retention.as_ref()
+        .filter(|r| r.keep_last.is_some() || r.keep_days.is_some())
+        .with_context(|| format!("{}: no retention configured; set keep_last or keep_days under [destinations.retention]", destination.name))?;
+
+    let root = destination.remote_base();
+    let entries = store.read_dir(&destination.remote_encoding.encode(&root)?)?;
+    let archives = entries.iter()
+        .filter_map(|entry| destination.remote_encoding.decode(&entry.path).file_name().map(|n| n.to_string_lossy().into_owned()))
+        .filter_map(|name| archive(&name))
+        .collect();
+
+    let expired = expired(archives, retention, now());
+    for archive in &expired {
+        if dry_run {
+            eprintln!("Would remove: {}", archive.name);
+        } else {
+            info!("Removing: {}", archive.name);
+            store.remove_all(&destination.remote_encoding.encode(&root.join(&archive.name))?)?;
+        }
+    }
+    Ok(expired.into_iter().map(|a| a.name).collect())
+}
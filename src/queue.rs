@@ -0,0 +1,204 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use crate::config::{Config, Destination};
+use crate::transfer::{TransferOptions, Transferer};
+
+const QUEUE_FILE: &str = "queue.toml";
+const LOCK_FILE: &str = "queue.lock";
+const SOCKET_FILE: &str = "daemon.sock";
+/// Attempts per destination before an upload is given up on.
+const MAX_ATTEMPTS: u32 = 5;
+/// Wait before retrying a failed upload, multiplied by the attempt number.
+const RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// Uploads waiting for `arkv daemon`, kept on disk so they survive
+/// restarts of the daemon and of the machine.
+#[derive(Debug, Serialize, Deserialize, Default)]
+struct QueueFile {
+    #[serde(default)]
+    entries: Vec<QueueEntry>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct QueueEntry {
+    pub id: String,
+    pub paths: Vec<PathBuf>,
+    /// Destinations this entry still has to reach.
+    pub pending: Vec<String>,
+    #[serde(default)]
+    pub attempts: u32,
+    /// Unix time before which a failed entry isn't retried.
+    #[serde(default)]
+    pub retry_after: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+}
+
+impl QueueEntry {
+    fn failed(&self) -> bool {
+        self.attempts >= MAX_ATTEMPTS
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn socket_path() -> Result<PathBuf> {
+    Ok(Config::config_dir()?.join(SOCKET_FILE))
+}
+
+/// Runs `f` on the queue with an exclusive lock held, so `arkv enqueue`
+/// and the daemon never overwrite each other's changes.
+fn with_queue<R>(f: impl FnOnce(&mut Vec<QueueEntry>) -> R) -> Result<R> {
+    let dir = Config::config_dir()?;
+    fs::create_dir_all(&dir)
+        .context("Failed to create config directory")?;
+
+    let lock = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(dir.join(LOCK_FILE))
+        .context("Failed to open queue lock")?;
+    if unsafe { libc::flock(lock.as_raw_fd(), libc::LOCK_EX) } != 0 {
+        anyhow::bail!("Failed to lock the upload queue: {}", std::io::Error::last_os_error());
+    }
+
+    let path = dir.join(QUEUE_FILE);
+    let mut queue: QueueFile = match fs::read_to_string(&path) {
+        Ok(content) => toml::from_str(&content)
+            .with_context(|| format!("Failed to parse upload queue: {}", path.display()))?,
+        Err(_) => QueueFile::default(),
+    };
+    let result = f(&mut queue.entries);
+
+    let tmp = path.with_extension("toml.tmp");
+    fs::write(&tmp, toml::to_string(&queue).context("Failed to serialize upload queue")?)
+        .with_context(|| format!("Failed to write upload queue: {}", tmp.display()))?;
+    File::open(&tmp).and_then(|f| f.sync_all())
+        .context("Failed to sync upload queue")?;
+    fs::rename(&tmp, &path)
+        .with_context(|| format!("Failed to save upload queue: {}", path.display()))?;
+    Ok(result)
+}
+
+/// Adds an upload to the queue and wakes the daemon if it is running.
+/// Returns the entry and whether a daemon was there to receive it.
+pub fn enqueue(paths: Vec<PathBuf>, destinations: Vec<String>) -> Result<(QueueEntry, bool)> {
+    let entry = QueueEntry {
+        id: format!("{:x}-{:x}", now(), std::process::id()),
+        paths,
+        pending: destinations,
+        attempts: 0,
+        retry_after: 0,
+        last_error: None,
+    };
+    with_queue(|entries| entries.push(entry.clone()))?;
+
+    let woken = UnixStream::connect(socket_path()?)
+        .and_then(|mut stream| stream.write_all(b"wake\n"))
+        .is_ok();
+    Ok((entry, woken))
+}
+
+/// Queued entries, including ones that gave up after too many failures.
+pub fn list() -> Result<Vec<QueueEntry>> {
+    with_queue(|entries| entries.clone())
+}
+
+/// Processes queued uploads one at a time until killed, retrying failed
+/// destinations with a growing delay. `make_options` builds the transfer
+/// settings for each destination.
+pub fn run_daemon(config: &Config, make_options: impl Fn(&Destination) -> Result<TransferOptions>) -> Result<()> {
+    let socket = socket_path()?;
+    if UnixStream::connect(&socket).is_ok() {
+        anyhow::bail!("arkv daemon is already running ({})", socket.display());
+    }
+    let _ = fs::remove_file(&socket);
+    let listener = UnixListener::bind(&socket)
+        .with_context(|| format!("Failed to listen on {}", socket.display()))?;
+
+    let (wake, woken) = mpsc::channel();
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let mut line = String::new();
+            let _ = BufReader::new(stream).read_line(&mut line);
+            let _ = wake.send(());
+        }
+    });
+
+    println!("🛰️  arkv daemon listening on {}", socket.display());
+    loop {
+        let next = with_queue(|entries| {
+            entries.iter()
+                .find(|e| !e.failed() && e.retry_after <= now())
+                .cloned()
+        })?;
+
+        let Some(entry) = next else {
+            let wait = with_queue(|entries| {
+                entries.iter()
+                    .filter(|e| !e.failed())
+                    .map(|e| e.retry_after.saturating_sub(now()))
+                    .min()
+            })?;
+            let _ = woken.recv_timeout(Duration::from_secs(wait.unwrap_or(3600).max(1)));
+            continue;
+        };
+
+        println!("\n📦 Uploading queued {} ({} path(s))", entry.id, entry.paths.len());
+        let mut remaining = Vec::new();
+        let mut errors = Vec::new();
+        for name in &entry.pending {
+            let result = config.destination(name)
+                .and_then(|destination| {
+                    let mut options = make_options(destination)?;
+                    // Continue partial files left by the failed attempt
+                    if entry.attempts > 0 {
+                        options.resume |= options.compress.is_none() && options.encrypt.is_none();
+                    }
+                    Transferer::new(destination.clone(), options).transfer(&entry.paths, config)
+                });
+            match result {
+                Ok(stats) => println!("📊 {}: {:.2} MB in {:.1}s",
+                    name, stats.bytes_transferred as f64 / 1_048_576.0, stats.duration_secs),
+                Err(e) => {
+                    eprintln!("❌ {}: {:#}", name, e);
+                    errors.push(format!("{}: {:#}", name, e));
+                    remaining.push(name.clone());
+                }
+            }
+        }
+
+        with_queue(|entries| {
+            let Some(index) = entries.iter().position(|e| e.id == entry.id) else {
+                return;
+            };
+            if remaining.is_empty() {
+                println!("✨ Done with {}", entry.id);
+                entries.remove(index);
+                return;
+            }
+            let queued = &mut entries[index];
+            queued.pending = remaining;
+            queued.attempts += 1;
+            queued.retry_after = now() + RETRY_DELAY.as_secs() * u64::from(queued.attempts);
+            queued.last_error = Some(errors.join("; "));
+            if queued.failed() {
+                eprintln!("⚠️  Giving up on {} after {} attempts", queued.id, queued.attempts);
+            }
+        })?;
+    }
+}
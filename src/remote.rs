@@ -0,0 +1,472 @@
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+use ssh2::{FileStat, RenameFlags, Session, Sftp};
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+pub struct ExecOutput {
+    pub status: i32,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+/// Removes a remote file or folder with everything in it, returning the
+/// number of files removed. Works on wire paths, so names are never
+/// re-encoded.
+pub fn remove_all(sftp: &Sftp, path: &Path) -> Result<u64> {
+    let stat = sftp.lstat(path)
+        .with_context(|| format!("Remote path not found: {}", path.display()))?;
+    if !stat.is_dir() {
+        sftp.unlink(path)
+            .with_context(|| format!("Failed to remove {}", path.display()))?;
+        return Ok(1);
+    }
+
+    let mut removed = 0;
+    let entries = sftp.readdir(path)
+        .with_context(|| format!("Failed to list remote folder: {}", path.display()))?;
+    for (child, _) in entries {
+        removed += remove_all(sftp, &child)?;
+    }
+    sftp.rmdir(path)
+        .with_context(|| format!("Failed to remove {}", path.display()))?;
+    Ok(removed)
+}
+
+/// A destination's files as manifests, `verify` and `prune` reach them:
+/// over SFTP, or straight from the filesystem for `type = "local"`.
+/// Paths are wire paths.
+pub enum Store<'a> {
+    Sftp(&'a Session, &'a Sftp),
+    Local,
+}
+
+pub struct StoreEntry {
+    pub path: PathBuf,
+    pub is_dir: bool,
+    pub is_file: bool,
+}
+
+impl Store<'_> {
+    pub fn open(&self, path: &Path) -> Result<Box<dyn Read>> {
+        Ok(match self {
+            Store::Sftp(_, sftp) => Box::new(sftp.open(path)
+                .with_context(|| format!("Failed to open remote file: {}", path.display()))?),
+            Store::Local => Box::new(File::open(path)
+                .with_context(|| format!("Failed to open {}", path.display()))?),
+        })
+    }
+
+    pub fn write(&self, path: &Path, content: &[u8]) -> Result<()> {
+        let mut file: Box<dyn Write> = match self {
+            Store::Sftp(_, sftp) => Box::new(sftp.create(path)
+                .with_context(|| format!("Failed to create remote file: {}", path.display()))?),
+            Store::Local => Box::new(File::create(path)
+                .with_context(|| format!("Failed to create {}", path.display()))?),
+        };
+        file.write_all(content)
+            .with_context(|| format!("Failed to write remote file: {}", path.display()))?;
+        Ok(())
+    }
+
+    /// The size of what is at `path`, or `None` when nothing is.
+    pub fn size(&self, path: &Path) -> Option<u64> {
+        match self {
+            Store::Sftp(_, sftp) => sftp.stat(path).ok().map(|stat| stat.size.unwrap_or(0)),
+            Store::Local => fs::metadata(path).ok().map(|metadata| metadata.len()),
+        }
+    }
+
+    pub fn is_dir(&self, path: &Path) -> bool {
+        match self {
+            Store::Sftp(_, sftp) => sftp.stat(path).is_ok_and(|stat| stat.is_dir()),
+            Store::Local => path.is_dir(),
+        }
+    }
+
+    pub fn read_dir(&self, dir: &Path) -> Result<Vec<StoreEntry>> {
+        let context = || format!("Failed to list remote folder: {}", dir.display());
+        match self {
+            Store::Sftp(_, sftp) => Ok(sftp.readdir(dir).with_context(context)?
+                .into_iter()
+                .map(|(path, stat)| StoreEntry { path, is_dir: stat.is_dir(), is_file: stat.is_file() })
+                .collect()),
+            Store::Local => fs::read_dir(dir).with_context(context)?
+                .map(|entry| {
+                    let entry = entry.with_context(context)?;
+                    let kind = entry.file_type().with_context(context)?;
+                    Ok(StoreEntry { path: entry.path(), is_dir: kind.is_dir(), is_file: kind.is_file() })
+                })
+                .collect(),
+        }
+    }
+
+    pub fn remove_all(&self, path: &Path) -> Result<u64> {
+        match self {
+            Store::Sftp(_, sftp) => remove_all(sftp, path),
+            Store::Local => {
+                if !fs::symlink_metadata(path).is_ok_and(|metadata| metadata.is_dir()) {
+                    fs::remove_file(path).with_context(|| format!("Failed to remove {}", path.display()))?;
+                    return Ok(1);
+                }
+                let mut removed = 0;
+                for entry in self.read_dir(path)? {
+                    removed += self.remove_all(&entry.path)?;
+                }
+                fs::remove_dir(path).with_context(|| format!("Failed to remove {}", path.display()))?;
+                Ok(removed)
+            }
+        }
+    }
+
+    /// SHA-256 of the file at `path`: computed on the server over SFTP
+    /// (`None` when it can't), read directly otherwise.
+    pub fn sha256(&self, path: &Path) -> Result<Option<String>> {
+        match self {
+            Store::Sftp(session, _) => sha256(session, path),
+            Store::Local => self.hash_by_reading(path).map(Some),
+        }
+    }
+
+    /// SHA-256 of the file at `path`, reading all of it.
+    pub fn hash_by_reading(&self, path: &Path) -> Result<String> {
+        let mut hasher = Sha256::new();
+        std::io::copy(&mut self.open(path)?, &mut hasher)
+            .with_context(|| format!("Failed to read remote file: {}", path.display()))?;
+        Ok(hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect())
+    }
+}
+
+/// Runs a command on the server over an exec channel.
+pub fn exec(session: &Session, command: &str) -> Result<ExecOutput> {
+    let mut channel = session.channel_session()
+        .context("Failed to open exec channel")?;
+    channel.exec(command)
+        .with_context(|| format!("Failed to run remote command: {}", command))?;
+
+    let mut stdout = String::new();
+    channel.read_to_string(&mut stdout)
+        .context("Failed to read remote command output")?;
+    let mut stderr = String::new();
+    channel.stderr().read_to_string(&mut stderr)
+        .context("Failed to read remote command errors")?;
+
+    channel.wait_close()
+        .context("Failed to close exec channel")?;
+    let status = channel.exit_status()
+        .context("Failed to read remote exit status")?;
+
+    Ok(ExecOutput { status, stdout, stderr })
+}
+
+/// SHA-256 of a remote file computed on the server with `sha256sum` (or
+/// `shasum -a 256`), or `None` when neither could hash it.
+pub fn sha256(session: &Session, path: &Path) -> Result<Option<String>> {
+    let path = shell_quote_path(path);
+    let output = exec(session, &format!("sha256sum -- {0} 2>/dev/null || shasum -a 256 -- {0}", path))?;
+    let digest = output.stdout.split_whitespace().next().unwrap_or_default();
+    if output.status != 0 || digest.len() != 64 {
+        return Ok(None);
+    }
+    Ok(Some(digest.to_ascii_lowercase()))
+}
+
+/// Quotes a value for a POSIX shell command line.
+pub fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+/// Quotes a path for a POSIX shell command line, byte for byte. Commands
+/// go to the server as UTF-8, so bytes that aren't (a latin1 name, say)
+/// are spelled out for `printf` to produce there.
+pub fn shell_quote_path(path: &Path) -> String {
+    let mut quoted = String::new();
+    for chunk in path.as_os_str().as_bytes().utf8_chunks() {
+        if !chunk.valid().is_empty() {
+            quoted.push_str(&shell_quote(chunk.valid()));
+        }
+        if !chunk.invalid().is_empty() {
+            let octal: String = chunk.invalid().iter().map(|b| format!("\\{:03o}", b)).collect();
+            quoted.push_str(&format!("\"$(printf '{}')\"", octal));
+        }
+    }
+    if quoted.is_empty() {
+        quoted.push_str("''");
+    }
+    quoted
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct DiskUsage {
+    pub total_bytes: u64,
+    pub available_bytes: u64,
+    pub used_bytes: u64,
+}
+
+impl DiskUsage {
+    /// Percentage of the space usable by this user that is taken, as `df` reports it.
+    pub fn used_percent(&self) -> f64 {
+        let usable = self.used_bytes + self.available_bytes;
+        if usable == 0 {
+            return 0.0;
+        }
+        self.used_bytes as f64 * 100.0 / usable as f64
+    }
+}
+
+/// The nearest of `dir` and its parents that `is_dir` finds, since a first
+/// upload's remote folder (a dated one, say) may not exist yet.
+pub fn existing_dir(dir: &Path, is_dir: impl Fn(&Path) -> bool) -> PathBuf {
+    let mut dir = dir.to_path_buf();
+    while !is_dir(&dir) && dir.pop() {}
+    dir
+}
+
+/// Reads the usage of the volume holding `dir`, via the statvfs SFTP
+/// extension, falling back to `df -P` for servers without it.
+pub fn disk_usage(session: &Session, sftp: &Sftp, dir: &Path, caps: &Capabilities) -> Result<DiskUsage> {
+    if caps.statvfs {
+        return statvfs_usage(sftp, dir);
+    }
+    if !caps.exec {
+        anyhow::bail!("Server supports neither the statvfs extension nor shell commands, so free space is unknown");
+    }
+
+    let output = exec(session, &format!("df -Pk {}", shell_quote_path(dir)))?;
+    if output.status != 0 {
+        anyhow::bail!("Could not determine free space on the server: {}", output.stderr.trim());
+    }
+    parse_df(&output.stdout)
+        .context("Could not parse remote df output")
+}
+
+pub fn statvfs_usage(sftp: &Sftp, dir: &Path) -> Result<DiskUsage> {
+    let mut handle = sftp.opendir(dir)
+        .with_context(|| format!("Failed to open remote folder: {}", dir.display()))?;
+    let stat = handle.statvfs()
+        .context("Server doesn't support statvfs")?;
+
+    let block = if stat.f_frsize > 0 { stat.f_frsize } else { stat.f_bsize };
+    Ok(DiskUsage {
+        total_bytes: stat.f_blocks * block,
+        available_bytes: stat.f_bavail * block,
+        used_bytes: stat.f_blocks.saturating_sub(stat.f_bfree) * block,
+    })
+}
+
+fn parse_df(output: &str) -> Option<DiskUsage> {
+    // Filesystem 1024-blocks Used Available Capacity Mounted-on
+    let fields: Vec<&str> = output.lines().nth(1)?.split_whitespace().collect();
+    let kib = |i: usize| fields.get(i)?.parse::<u64>().ok().map(|v| v * 1024);
+    Some(DiskUsage {
+        total_bytes: kib(1)?,
+        used_bytes: kib(2)?,
+        available_bytes: kib(3)?,
+    })
+}
+
+/// What the server's SFTP implementation can do, probed once per session
+/// in the destination folder. libssh2 always negotiates SFTP v3 and
+/// doesn't expose the server's extension list, so each capability is
+/// checked by trying it on a scratch file.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Capabilities {
+    pub statvfs: bool,
+    pub setstat: bool,
+    /// `rename` can replace an existing file (posix-rename semantics).
+    pub rename_overwrite: bool,
+    /// Shell commands can be run over an exec channel.
+    pub exec: bool,
+}
+
+impl Capabilities {
+    pub fn probe(session: &Session, sftp: &Sftp, dir: &Path) -> Self {
+        let probe = dir.join(format!(".arkv-probe-{}", std::process::id()));
+        let other = dir.join(format!(".arkv-probe-{}-2", std::process::id()));
+
+        let mut caps = Capabilities {
+            statvfs: statvfs_usage(sftp, dir).is_ok(),
+            exec: exec(session, "true").map(|o| o.status == 0).unwrap_or(false),
+            ..Default::default()
+        };
+
+        if sftp.create(&probe).is_ok() && sftp.create(&other).is_ok() {
+            let times = FileStat { size: None, uid: None, gid: None, perm: None, atime: Some(0), mtime: Some(0) };
+            caps.setstat = sftp.setstat(&probe, times).is_ok();
+            caps.rename_overwrite = sftp.rename(&probe, &other, Some(RenameFlags::OVERWRITE | RenameFlags::ATOMIC)).is_ok();
+        }
+
+        for path in [&probe, &other] {
+            let _ = sftp.unlink(path);
+        }
+        caps
+    }
+
+    pub fn describe(&self) -> String {
+        let flag = |name: &str, on: bool| format!("{}{}", if on { "+" } else { "-" }, name);
+        [
+            flag("statvfs", self.statvfs),
+            flag("setstat", self.setstat),
+            flag("rename-overwrite", self.rename_overwrite),
+            flag("exec", self.exec),
+        ].join(" ")
+    }
+}
+
+const CHOWN_BATCH: usize = 200;
+/// Kept well under the kernel's limit on one argument, since the whole
+/// batch goes to `sh -c` as a single string.
+const LINK_BATCH: usize = 100;
+
+/// Changes the owner and/or group of uploaded paths. Numeric ids are set
+/// directly with SFTP setstat; names (or `sudo`) go through `chown` over
+/// an exec channel, since SFTP only carries numeric ids.
+pub fn apply_ownership(
+    session: &Session,
+    sftp: &Sftp,
+    caps: &Capabilities,
+    owner: Option<&str>,
+    group: Option<&str>,
+    sudo: bool,
+    paths: &[PathBuf],
+) -> Result<()> {
+    if paths.is_empty() || (owner.is_none() && group.is_none()) {
+        return Ok(());
+    }
+
+    let uid = owner.map(|o| o.parse::<u32>().ok());
+    let gid = group.map(|g| g.parse::<u32>().ok());
+    let numeric = !matches!(uid, Some(None)) && !matches!(gid, Some(None));
+
+    if numeric && !sudo && caps.setstat {
+        for path in paths {
+            let current = sftp.stat(path)
+                .with_context(|| format!("Failed to stat remote path: {}", path.display()))?;
+            let ids = FileStat {
+                size: None,
+                uid: uid.flatten().or(current.uid),
+                gid: gid.flatten().or(current.gid),
+                perm: None,
+                atime: None,
+                mtime: None,
+            };
+            sftp.setstat(path, ids)
+                .with_context(|| format!(
+                    "Failed to change ownership of {} (changing owners usually requires root; \
+                     set chown_sudo = true to run chown via sudo)",
+                    path.display()
+                ))?;
+        }
+        return Ok(());
+    }
+
+    if !caps.exec {
+        anyhow::bail!(
+            "Setting owner/group by name or with sudo needs shell access, which this server doesn't allow; \
+             use numeric uid/gid values instead"
+        );
+    }
+
+    let spec = match (owner, group) {
+        (Some(owner), Some(group)) => format!("{}:{}", owner, group),
+        (Some(owner), None) => owner.to_string(),
+        (None, Some(group)) => format!(":{}", group),
+        (None, None) => unreachable!(),
+    };
+    let prefix = if sudo { "sudo -n chown" } else { "chown" };
+
+    for batch in paths.chunks(CHOWN_BATCH) {
+        let targets: Vec<String> = batch.iter()
+            .map(|p| shell_quote_path(p))
+            .collect();
+        let command = format!("{} {} -- {}", prefix, shell_quote(&spec), targets.join(" "));
+        let output = exec(session, &command)?;
+        if output.status != 0 {
+            let hint = if sudo {
+                "check that the SSH user has passwordless sudo for chown"
+            } else {
+                "changing owners usually requires root; set chown_sudo = true to use sudo"
+            };
+            anyhow::bail!("chown {} failed: {} ({})", spec, output.stderr.trim(), hint);
+        }
+    }
+
+    Ok(())
+}
+
+/// Copies a remote file on the server with `cp -p`, creating the copy's
+/// folder first.
+pub fn copy(session: &Session, from: &Path, to: &Path) -> Result<()> {
+    let mut command = String::new();
+    if let Some(dir) = to.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        command.push_str(&format!("mkdir -p -- {} && ", shell_quote_path(dir)));
+    }
+    command.push_str(&format!("cp -p -- {} {}", shell_quote_path(from), shell_quote_path(to)));
+    let output = exec(session, &command)?;
+    if output.status != 0 {
+        anyhow::bail!("Copying {} to {} on the server failed: {}", from.display(), to.display(), output.stderr.trim());
+    }
+    Ok(())
+}
+
+/// Hard-links each `(existing, link)` pair with `ln` over an exec
+/// channel, creating the links' folders first. libssh2 can't send the
+/// hardlink@openssh.com SFTP extension, so this needs shell access.
+pub fn hard_link(session: &Session, links: &[(PathBuf, PathBuf)]) -> Result<()> {
+    for batch in links.chunks(LINK_BATCH) {
+        let mut dirs: Vec<String> = batch.iter()
+            .filter_map(|(_, link)| link.parent().filter(|dir| !dir.as_os_str().is_empty()))
+            .map(shell_quote_path)
+            .collect();
+        dirs.sort();
+        dirs.dedup();
+
+        let mut commands = Vec::with_capacity(batch.len() + 1);
+        if !dirs.is_empty() {
+            commands.push(format!("mkdir -p -- {}", dirs.join(" ")));
+        }
+        commands.extend(batch.iter().map(|(existing, link)| {
+            format!("ln -f -- {} {}", shell_quote_path(existing), shell_quote_path(link))
+        }));
+        let output = exec(session, &commands.join(" && "))?;
+        if output.status != 0 {
+            anyhow::bail!("Hard-linking files failed: {}", output.stderr.trim());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn first_upload_probes_the_nearest_existing_folder() {
+        let root = std::env::temp_dir().join(format!("arkv-existing-{}", std::process::id()));
+        fs::create_dir_all(root.join("backups")).unwrap();
+        let is_dir = |dir: &Path| dir.is_dir();
+
+        // The dated folder under backups/ is created by the upload itself
+        assert_eq!(existing_dir(&root.join("backups/2026-10-14/photos"), is_dir), root.join("backups"));
+        assert_eq!(existing_dir(&root.join("backups"), is_dir), root.join("backups"));
+        // Nothing exists: the relative path runs out, leaving the login folder
+        assert_eq!(existing_dir(Path::new("no-such-arkv-folder/sub"), is_dir), PathBuf::new());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn paths_are_quoted_byte_for_byte() {
+        use std::ffi::OsStr;
+        for raw in [&b"plain"[..], b"it's here", b"caf\xe9/r\xe9sum\xe9.txt", b"\xff", b"$(rm -rf ~) `x` \"y\"", b""] {
+            let path = Path::new(OsStr::from_bytes(raw));
+            let output = std::process::Command::new("sh")
+                .arg("-c")
+                .arg(format!("printf %s {}", shell_quote_path(path)))
+                .output()
+                .unwrap();
+            assert_eq!(output.stdout, raw, "{}", shell_quote_path(path));
+        }
+    }
+}
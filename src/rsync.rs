@@ -0,0 +1,165 @@
+use anyhow::{Context, Result};
+use ssh2::Session;
+use std::io::{BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::Duration;
+use tracing::{debug, info};
+use crate::config::{AddressFamily, Destination};
+use crate::openssh;
+use crate::remote::{self, shell_quote};
+
+/// How an rsync run ended when it didn't fail outright.
+pub enum Outcome {
+    /// Finished, having put this many bytes on the wire.
+    Sent(u64),
+    /// OpenSSH couldn't connect or log in, so nothing was sent.
+    NoConnection(String),
+}
+
+/// Why rsync can't be used against `destination`, if it can't: it has to
+/// be installed at both ends, and OpenSSH has to log in without a
+/// password, since arkv can't hand one over.
+pub fn unavailable(session: &Session, destination: &Destination) -> Option<String> {
+    if destination.password.is_some() {
+        return Some("rsync can't log in with a password, only with keys or ssh-agent".to_string());
+    }
+    if destination.password_command.is_some() {
+        return Some("rsync can't use password_command for a password or key passphrase".to_string());
+    }
+    if destination.proxy.is_some() {
+        return Some("rsync can't go through an HTTP proxy".to_string());
+    }
+    let local = Command::new("rsync").arg("--version").stdout(Stdio::null()).stderr(Stdio::null()).status();
+    if !local.is_ok_and(|status| status.success()) {
+        return Some("rsync is not installed here".to_string());
+    }
+    match remote::exec(session, "command -v rsync") {
+        Ok(output) if output.status == 0 => None,
+        _ => Some(format!("rsync is not installed on {}", destination.host)),
+    }
+}
+
+/// One rsync run: `files` (relative to `source_dir`) into `remote_dir`,
+/// keeping the same relative layout there.
+pub struct Upload<'a> {
+    pub destination: &'a Destination,
+    pub ssh_key_path: &'a str,
+    pub source_dir: &'a Path,
+    pub files: Vec<PathBuf>,
+    pub remote_dir: &'a Path,
+    /// Copy permission bits as well as mtimes.
+    pub preserve: bool,
+    pub connect_timeout: Option<Duration>,
+    pub io_timeout: Option<Duration>,
+    pub address_family: AddressFamily,
+}
+
+impl Upload<'_> {
+    /// Runs rsync, calling `progress` with the source bytes it has gone
+    /// through so far. Unchanged parts of files that are already on the
+    /// server are matched there instead of being sent again.
+    pub fn run(self, mut progress: impl FnMut(u64) -> Result<()>) -> Result<Outcome> {
+        let mut command = Command::new("rsync");
+        command
+            .args(["--times", "--partial", "--protect-args", "--from0", "--files-from=-"])
+            .args(["--info=progress2", "--no-human-readable", "--stats"])
+            .arg("--rsh")
+            .arg(self.ssh_command());
+        if self.preserve {
+            command.arg("--perms");
+        }
+        if let Some(timeout) = self.io_timeout {
+            command.arg(format!("--timeout={}", timeout.as_secs().max(1)));
+        }
+        let mut source = self.source_dir.as_os_str().to_owned();
+        source.push("/");
+        command.arg(source).arg(self.target());
+        debug!("Running {:?}", command);
+
+        let mut child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .context("Failed to run rsync")?;
+
+        let mut stdin = child.stdin.take().context("Failed to open rsync input")?;
+        let mut list = Vec::new();
+        for file in &self.files {
+            list.extend_from_slice(file.as_os_str().as_encoded_bytes());
+            list.push(0);
+        }
+        let writer = thread::spawn(move || stdin.write_all(&list));
+        let mut stderr = child.stderr.take().context("Failed to open rsync errors")?;
+        let errors = thread::spawn(move || {
+            let mut text = String::new();
+            let _ = stderr.read_to_string(&mut text);
+            text
+        });
+
+        // progress2 rewrites one line with \r; --stats follows at the end
+        let stdout = child.stdout.take().context("Failed to open rsync output")?;
+        let mut line = Vec::new();
+        let mut sent = 0;
+        for byte in BufReader::new(stdout).bytes() {
+            let byte = byte.context("Failed to read rsync output")?;
+            if byte != b'\r' && byte != b'\n' {
+                line.push(byte);
+                continue;
+            }
+            let text = String::from_utf8_lossy(&line);
+            if let Some(bytes) = text.split_whitespace().next().and_then(|n| n.parse().ok())
+                .filter(|_| text.contains('%'))
+            {
+                if let Err(e) = progress(bytes) {
+                    let _ = child.kill();
+                    let _ = child.wait();
+                    return Err(e);
+                }
+            } else if let Some(bytes) = text.strip_prefix("Total bytes sent:") {
+                sent = bytes.trim().parse().unwrap_or(0);
+            }
+            line.clear();
+        }
+
+        let status = child.wait().context("Failed to wait for rsync")?;
+        let _ = writer.join();
+        let errors = errors.join().unwrap_or_default();
+        let errors = errors.trim();
+        match status.code() {
+            Some(0) => {
+                info!("rsync sent {} bytes", sent);
+                Ok(Outcome::Sent(sent))
+            }
+            Some(255) => Ok(Outcome::NoConnection(errors.to_string())),
+            code => anyhow::bail!(
+                "rsync failed ({}): {}",
+                code.map_or("killed".to_string(), |c| format!("exit code {}", c)),
+                errors
+            ),
+        }
+    }
+
+    /// The OpenSSH command rsync connects with, with the same options as
+    /// GSSAPI logins, and arkv's key.
+    fn ssh_command(&self) -> String {
+        let mut ssh = vec!["ssh".to_string(), "-i".to_string(), shell_quote(self.ssh_key_path)];
+        ssh.extend(openssh::ssh_options(self.destination, self.connect_timeout, self.address_family).iter().map(|o| shell_quote(o)));
+        if self.destination.ssh_agent == Some(false) {
+            ssh.extend(["-o".to_string(), "IdentityAgent=none".to_string()]);
+        }
+        ssh.join(" ")
+    }
+
+    /// `user@host:dir/`, with IPv6 addresses in brackets.
+    fn target(&self) -> String {
+        let host = self.destination.connect_host();
+        let host = match host.contains(':') {
+            true => format!("[{}]", host),
+            false => host.to_string(),
+        };
+        format!("{}@{}:{}/", self.destination.username, host, self.remote_dir.display())
+    }
+}
@@ -0,0 +1,267 @@
+use anyhow::{Context, Result};
+use hmac::{Hmac, Mac};
+use indicatif::MultiProgress;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::io::Read;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{debug, trace};
+use crate::backend::{uri_encode, Backend};
+use crate::config::Destination;
+use crate::failure::{self, Failure};
+use crate::secret::Password;
+
+/// Files bigger than this are sent as a multipart upload, in parts of
+/// this size (or bigger, to stay within S3's part limit).
+const PART_SIZE: u64 = 16 * 1024 * 1024;
+const MAX_PARTS: u64 = 10_000;
+const UNSIGNED_PAYLOAD: &str = "UNSIGNED-PAYLOAD";
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct S3Settings {
+    /// e.g. `https://s3.eu-west-1.amazonaws.com`, `https://s3.wasabisys.com`
+    /// or `http://minio.lan:9000`.
+    pub endpoint: String,
+    pub bucket: String,
+    #[serde(default = "default_region")]
+    pub region: String,
+    /// Falls back to `AWS_ACCESS_KEY_ID`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub access_key_id: Option<String>,
+    /// Falls back to `AWS_SECRET_ACCESS_KEY`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub secret_access_key: Option<Password>,
+    /// Address the bucket as `endpoint/bucket` instead of
+    /// `bucket.endpoint`, as MinIO and most self-hosted stores need.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub path_style: bool,
+}
+
+fn default_region() -> String {
+    "us-east-1".to_string()
+}
+
+/// A bucket that uploads are written to as objects, keyed by their
+/// remote path.
+pub struct Bucket {
+    settings: S3Settings,
+    access_key_id: String,
+    secret_access_key: String,
+    agent: ureq::Agent,
+}
+
+impl Bucket {
+    pub fn open(destination: &Destination, progress: &MultiProgress, agent: ureq::Agent) -> Result<Self> {
+        let settings = destination.s3.clone()
+            .with_context(|| format!("Destination '{}' has no [destinations.s3] settings", destination.name))?;
+        let access_key_id = match &settings.access_key_id {
+            Some(id) => id.clone(),
+            None => std::env::var("AWS_ACCESS_KEY_ID").map_err(|_| failure::fail(Failure::Config, format!(
+                "{}: set access_key_id in [destinations.s3] or AWS_ACCESS_KEY_ID", destination.name
+            )))?,
+        };
+        let secret_access_key = match &settings.secret_access_key {
+            Some(secret) => secret.resolve(&format!("{}@{}", access_key_id, settings.bucket), progress)?,
+            None => std::env::var("AWS_SECRET_ACCESS_KEY").map_err(|_| failure::fail(Failure::Config, format!(
+                "{}: set secret_access_key in [destinations.s3] or AWS_SECRET_ACCESS_KEY", destination.name
+            )))?,
+        };
+        Ok(Bucket { settings, access_key_id, secret_access_key, agent })
+    }
+
+    fn put_object(&self, key: &str, reader: &mut dyn Read, size: u64) -> Result<()> {
+        self.request("PUT", key, &[])
+            .set("Content-Length", &size.to_string())
+            .send(reader.take(size))
+            .map_err(|e| self.error(e, key))?;
+        Ok(())
+    }
+
+    /// Sends a big file in parts, aborting the upload if any part fails so
+    /// the bucket isn't left holding them.
+    fn put_multipart(&self, key: &str, reader: &mut dyn Read, size: u64) -> Result<()> {
+        let response = self.request("POST", key, &[("uploads", String::new())])
+            .call()
+            .map_err(|e| self.error(e, key))?;
+        let body = response.into_string().context("Failed to read S3 response")?;
+        let upload_id = xml_value(&body, "UploadId")
+            .with_context(|| format!("S3 didn't start a multipart upload of {}", key))?;
+        debug!("Multipart upload of {} started: {}", key, upload_id);
+
+        let result = self.send_parts(key, &upload_id, reader, size);
+        if result.is_err() {
+            let _ = self.request("DELETE", key, &[("uploadId", upload_id.clone())]).call();
+        }
+        result
+    }
+
+    fn send_parts(&self, key: &str, upload_id: &str, reader: &mut dyn Read, size: u64) -> Result<()> {
+        let part_size = PART_SIZE.max(size.div_ceil(MAX_PARTS));
+        let mut parts = String::new();
+        let mut offset = 0;
+        for number in 1.. {
+            if offset >= size {
+                break;
+            }
+            let length = part_size.min(size - offset);
+            trace!("Sending part {} of {} ({} bytes)", number, key, length);
+            let query = [("partNumber", number.to_string()), ("uploadId", upload_id.to_string())];
+            let response = self.request("PUT", key, &query)
+                .set("Content-Length", &length.to_string())
+                .send((&mut *reader).take(length))
+                .map_err(|e| self.error(e, key))?;
+            let etag = response.header("ETag")
+                .with_context(|| format!("S3 returned no ETag for part {} of {}", number, key))?;
+            parts.push_str(&format!("<Part><PartNumber>{}</PartNumber><ETag>{}</ETag></Part>", number, etag));
+            offset += length;
+        }
+
+        let body = format!("<CompleteMultipartUpload>{}</CompleteMultipartUpload>", parts);
+        let response = self.request("POST", key, &[("uploadId", upload_id.to_string())])
+            .send_string(&body)
+            .map_err(|e| self.error(e, key))?;
+        // Completing can fail after the 200 has been sent
+        let body = response.into_string().context("Failed to read S3 response")?;
+        if body.contains("<Error>") {
+            anyhow::bail!("Failed to complete upload of {}: {}", key, describe_error(&body));
+        }
+        Ok(())
+    }
+
+    /// A request signed with AWS Signature Version 4. Bodies aren't
+    /// hashed, so they can be streamed.
+    fn request(&self, method: &str, key: &str, query: &[(&str, String)]) -> ureq::Request {
+        let (scheme, authority) = self.settings.endpoint.trim_end_matches('/')
+            .split_once("://")
+            .unwrap_or(("https", &self.settings.endpoint));
+        let (host, path) = match self.settings.path_style {
+            true => (authority.to_string(), format!("/{}/{}", self.settings.bucket, key)),
+            false => (format!("{}.{}", self.settings.bucket, authority), format!("/{}", key)),
+        };
+        let path = uri_encode(&path, false);
+
+        let mut query: Vec<(String, String)> = query.iter()
+            .map(|(name, value)| (uri_encode(name, true), uri_encode(value, true)))
+            .collect();
+        query.sort();
+        let query = query.iter().map(|(name, value)| format!("{}={}", name, value)).collect::<Vec<_>>().join("&");
+
+        let (date, time) = amz_date(SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0));
+        let signed_headers = "host;x-amz-content-sha256;x-amz-date";
+        let canonical = format!(
+            "{}\n{}\n{}\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n{}\n{}",
+            method, path, query, host, UNSIGNED_PAYLOAD, time, signed_headers, UNSIGNED_PAYLOAD
+        );
+        let scope = format!("{}/{}/s3/aws4_request", date, self.settings.region);
+        let to_sign = format!("AWS4-HMAC-SHA256\n{}\n{}\n{}", time, scope, hex(&Sha256::digest(canonical.as_bytes())));
+
+        let mut signing_key = hmac(format!("AWS4{}", self.secret_access_key).as_bytes(), date.as_bytes());
+        for part in [self.settings.region.as_str(), "s3", "aws4_request"] {
+            signing_key = hmac(&signing_key, part.as_bytes());
+        }
+        let signature = hex(&hmac(&signing_key, to_sign.as_bytes()));
+
+        let url = match query.is_empty() {
+            true => format!("{}://{}{}", scheme, host, path),
+            false => format!("{}://{}{}?{}", scheme, host, path, query),
+        };
+        trace!("S3 {} {}", method, url);
+        self.agent.request(method, &url)
+            .set("x-amz-content-sha256", UNSIGNED_PAYLOAD)
+            .set("x-amz-date", &time)
+            .set("Authorization", &format!(
+                "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+                self.access_key_id, scope, signed_headers, signature
+            ))
+    }
+
+    /// Turns a failed request into an error that says what S3 objected to.
+    fn error(&self, error: ureq::Error, key: &str) -> anyhow::Error {
+        match error {
+            ureq::Error::Status(status, response) => {
+                let body = response.into_string().unwrap_or_default();
+                let message = format!("S3 refused {} ({}): {}", key, status, describe_error(&body));
+                match status {
+                    401 | 403 => failure::fail(Failure::Auth, message),
+                    _ => anyhow::Error::msg(message),
+                }
+            }
+            ureq::Error::Transport(transport) => failure::fail(Failure::Connect, format!(
+                "Failed to reach {}: {}", self.settings.endpoint, transport
+            )),
+        }
+    }
+}
+
+impl Backend for Bucket {
+    fn exists(&self, path: &Path) -> Result<bool> {
+        let key = object_key(path);
+        match self.request("HEAD", &key, &[]).call() {
+            Ok(_) => Ok(true),
+            Err(ureq::Error::Status(404, _)) => Ok(false),
+            Err(e) => Err(self.error(e, &key)),
+        }
+    }
+
+    fn put(&self, path: &Path, reader: &mut dyn Read, size: u64) -> Result<()> {
+        let key = object_key(path);
+        match size > PART_SIZE {
+            true => self.put_multipart(&key, reader, size),
+            false => self.put_object(&key, reader, size),
+        }
+    }
+
+    fn remove(&self, path: &Path) -> Result<()> {
+        let key = object_key(path);
+        self.request("DELETE", &key, &[]).call().map_err(|e| self.error(e, &key))?;
+        Ok(())
+    }
+}
+
+/// Object keys have no leading `/`.
+fn object_key(path: &Path) -> String {
+    path.to_string_lossy().trim_start_matches('/').to_string()
+}
+
+/// The date (`20261014`) and time (`20261014T030000Z`) stamps requests
+/// are signed with, in UTC.
+fn amz_date(time: u64) -> (String, String) {
+    let tm = unsafe {
+        let time = time as libc::time_t;
+        let mut tm: libc::tm = std::mem::zeroed();
+        libc::gmtime_r(&time, &mut tm);
+        tm
+    };
+    let date = format!("{:04}{:02}{:02}", tm.tm_year + 1900, tm.tm_mon + 1, tm.tm_mday);
+    let time = format!("{}T{:02}{:02}{:02}Z", date, tm.tm_hour, tm.tm_min, tm.tm_sec);
+    (date, time)
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// The text of the first `<tag>` in an XML response.
+fn xml_value(xml: &str, tag: &str) -> Option<String> {
+    let start = xml.find(&format!("<{}>", tag))? + tag.len() + 2;
+    let end = xml[start..].find(&format!("</{}>", tag))?;
+    Some(xml[start..start + end].to_string())
+}
+
+/// S3's error code and message, e.g. `NoSuchBucket: The specified bucket
+/// does not exist`.
+fn describe_error(body: &str) -> String {
+    match (xml_value(body, "Code"), xml_value(body, "Message")) {
+        (Some(code), Some(message)) => format!("{}: {}", code, message),
+        (Some(code), None) => code,
+        _ if body.trim().is_empty() => "no details".to_string(),
+        _ => body.trim().to_string(),
+    }
+}
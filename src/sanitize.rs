@@ -0,0 +1,90 @@
+use serde::{Deserialize, Serialize};
+use std::path::{Component, Path, PathBuf};
+
+/// Characters Windows and Samba can't store in a file name.
+const RESERVED_CHARS: &[char] = &['<', '>', ':', '"', '\\', '|', '?', '*'];
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL",
+    "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9",
+    "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// How remote names are made safe for Windows/Samba consumers of a share.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Sanitize {
+    /// Upload names unchanged.
+    #[default]
+    None,
+    /// Drop unsafe characters.
+    Strip,
+    /// Replace unsafe characters with `_`.
+    Replace,
+    /// Percent-encode unsafe characters (and `%`), so names stay reversible.
+    Percent,
+}
+
+impl Sanitize {
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Sanitizes every component of a relative path.
+    pub fn path(&self, path: &Path) -> PathBuf {
+        if *self == Sanitize::None {
+            return path.to_path_buf();
+        }
+        path.components()
+            .map(|component| match component {
+                Component::Normal(name) => PathBuf::from(self.name(&name.to_string_lossy())),
+                other => PathBuf::from(other.as_os_str()),
+            })
+            .collect()
+    }
+
+    pub fn name(&self, name: &str) -> String {
+        if *self == Sanitize::None {
+            return name.to_string();
+        }
+
+        // Windows silently drops trailing spaces and dots
+        let kept = name.trim_end_matches([' ', '.']);
+        let trailing = &name[kept.len()..];
+
+        let mut out = String::with_capacity(name.len());
+        for c in kept.chars() {
+            self.push(&mut out, c, is_unsafe(c) || (*self == Sanitize::Percent && c == '%'));
+        }
+        for c in trailing.chars() {
+            self.push(&mut out, c, true);
+        }
+
+        let stem = out.split('.').next().unwrap_or("");
+        if out.is_empty() || RESERVED_NAMES.iter().any(|r| r.eq_ignore_ascii_case(stem)) {
+            out.insert(0, '_');
+        }
+        out
+    }
+
+    fn push(&self, out: &mut String, c: char, escape: bool) {
+        if !escape {
+            out.push(c);
+            return;
+        }
+        match self {
+            Sanitize::None => out.push(c),
+            Sanitize::Strip => {}
+            Sanitize::Replace => out.push('_'),
+            Sanitize::Percent => {
+                let mut buf = [0u8; 4];
+                for byte in c.encode_utf8(&mut buf).bytes() {
+                    out.push_str(&format!("%{:02X}", byte));
+                }
+            }
+        }
+    }
+}
+
+fn is_unsafe(c: char) -> bool {
+    c.is_control() || RESERVED_CHARS.contains(&c)
+}
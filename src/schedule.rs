@@ -0,0 +1,244 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use crate::config::Config;
+
+const STATE_FILE: &str = "schedule-state.toml";
+/// Missed runs are only looked for this far back after a long sleep.
+const CATCH_UP_MINUTES: i64 = 31 * 24 * 60;
+/// How far ahead `arkv schedule list` looks for the next run.
+const LOOKAHEAD_MINUTES: i64 = 366 * 24 * 60;
+/// The clock is re-checked this often, so a wake from sleep is noticed quickly.
+const POLL_INTERVAL: Duration = Duration::from_secs(20);
+
+/// A recurring upload, e.g.
+/// `{ name = "docs", cron = "0 3 * * *", paths = ["/home/me/Documents"], to = ["nas"] }`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Schedule {
+    pub name: String,
+    /// Standard five-field cron expression (minute hour day month weekday)
+    /// in local time, or `@hourly`, `@daily`, `@weekly`, `@monthly`.
+    pub cron: String,
+    pub paths: Vec<String>,
+    /// Destinations to upload to; all of them when empty.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub to: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub group: Vec<String>,
+}
+
+/// Last time each schedule ran, so runs missed while the machine was
+/// asleep or arkv wasn't running are caught up on.
+#[derive(Debug, Serialize, Deserialize, Default)]
+struct ScheduleState {
+    #[serde(default)]
+    last_run: BTreeMap<String, i64>,
+}
+
+impl ScheduleState {
+    fn path() -> Result<PathBuf> {
+        Ok(Config::config_dir()?.join(STATE_FILE))
+    }
+
+    fn load() -> Result<Self> {
+        match fs::read_to_string(Self::path()?) {
+            Ok(content) => toml::from_str(&content).context("Failed to parse schedule state"),
+            Err(_) => Ok(Self::default()),
+        }
+    }
+
+    fn save(&self) -> Result<()> {
+        let path = Self::path()?;
+        let tmp = path.with_extension("toml.tmp");
+        fs::write(&tmp, toml::to_string(self).context("Failed to serialize schedule state")?)
+            .with_context(|| format!("Failed to write {}", tmp.display()))?;
+        fs::rename(&tmp, &path)
+            .with_context(|| format!("Failed to save {}", path.display()))
+    }
+}
+
+/// A parsed cron expression. Each field is a bitmask of allowed values.
+pub struct Cron {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    /// Whether day-of-month and weekday were both restricted, in which
+    /// case cron runs when either matches.
+    either_day: bool,
+}
+
+impl Cron {
+    pub fn parse(expr: &str) -> Result<Self> {
+        let expr = match expr.trim() {
+            "@hourly" => "0 * * * *",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            "@monthly" => "0 0 1 * *",
+            "@yearly" | "@annually" => "0 0 1 1 *",
+            expr => expr,
+        };
+        let fields: Vec<&str> = expr.split_whitespace().collect();
+        let [minute, hour, day, month, weekday] = fields[..] else {
+            anyhow::bail!("Invalid cron expression '{}': expected 5 fields", expr);
+        };
+
+        let mut weekdays = parse_field(weekday, 0, 7)
+            .with_context(|| format!("Invalid weekday field in '{}'", expr))?;
+        // 7 is another name for Sunday
+        if weekdays & (1 << 7) != 0 {
+            weekdays |= 1;
+        }
+        Ok(Self {
+            minutes: parse_field(minute, 0, 59).with_context(|| format!("Invalid minute field in '{}'", expr))?,
+            hours: parse_field(hour, 0, 23).with_context(|| format!("Invalid hour field in '{}'", expr))?,
+            days: parse_field(day, 1, 31).with_context(|| format!("Invalid day field in '{}'", expr))?,
+            months: parse_field(month, 1, 12).with_context(|| format!("Invalid month field in '{}'", expr))?,
+            weekdays,
+            either_day: day != "*" && weekday != "*",
+        })
+    }
+
+    /// Whether the expression fires during the local minute containing `time`.
+    fn matches(&self, time: i64) -> bool {
+        let Some(t) = local_time(time) else {
+            return false;
+        };
+        let bit = |mask: u64, value: i32| mask & (1 << value) != 0;
+        let day = bit(self.days, t.tm_mday);
+        let weekday = bit(self.weekdays, t.tm_wday);
+        let day_ok = if self.either_day { day || weekday } else { day && weekday };
+        bit(self.minutes, t.tm_min) && bit(self.hours, t.tm_hour) && bit(self.months, t.tm_mon + 1) && day_ok
+    }
+
+    /// The first minute after `after` that the expression fires, within `limit` minutes.
+    fn next_after(&self, after: i64, limit: i64) -> Option<i64> {
+        let start = after.div_euclid(60) + 1;
+        (start..start + limit).map(|minute| minute * 60).find(|&t| self.matches(t))
+    }
+}
+
+/// One cron field: `*`, `5`, `1-5`, `*/15`, `0-30/10`, or a comma list of those.
+fn parse_field(field: &str, min: u32, max: u32) -> Result<u64> {
+    let mut mask = 0u64;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>().context("Invalid step")?),
+            None => (part, 1),
+        };
+        if step == 0 {
+            anyhow::bail!("Step can't be 0");
+        }
+        let (from, to) = match range {
+            "*" => (min, max),
+            range => match range.split_once('-') {
+                Some((from, to)) => (from.parse()?, to.parse()?),
+                None => {
+                    let value: u32 = range.parse()?;
+                    // `5/10` means from 5 to the end in steps of 10
+                    (value, if step > 1 { max } else { value })
+                }
+            },
+        };
+        if from < min || to > max || from > to {
+            anyhow::bail!("'{}' is outside {}-{}", part, min, max);
+        }
+        for value in (from..=to).step_by(step as usize) {
+            mask |= 1 << value;
+        }
+    }
+    Ok(mask)
+}
+
+fn local_time(time: i64) -> Option<libc::tm> {
+    unsafe {
+        let time = time as libc::time_t;
+        let mut tm: libc::tm = std::mem::zeroed();
+        if libc::localtime_r(&time, &mut tm).is_null() {
+            return None;
+        }
+        Some(tm)
+    }
+}
+
+pub fn format_local(time: i64) -> String {
+    match local_time(time) {
+        Some(t) => format!("{:04}-{:02}-{:02} {:02}:{:02}",
+            t.tm_year + 1900, t.tm_mon + 1, t.tm_mday, t.tm_hour, t.tm_min),
+        None => time.to_string(),
+    }
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+fn parse_all(schedules: &[Schedule]) -> Result<Vec<(&Schedule, Cron)>> {
+    if schedules.is_empty() {
+        anyhow::bail!("No schedules configured; add [[schedules]] entries to the config");
+    }
+    schedules.iter()
+        .map(|s| Ok((s, Cron::parse(&s.cron).with_context(|| format!("Schedule '{}'", s.name))?)))
+        .collect()
+}
+
+/// Prints each schedule with when it last ran and will run next.
+pub fn list(schedules: &[Schedule]) -> Result<()> {
+    let state = ScheduleState::load()?;
+    let now = now();
+    for (schedule, cron) in parse_all(schedules)? {
+        let next = cron.next_after(now, LOOKAHEAD_MINUTES)
+            .map(format_local)
+            .unwrap_or_else(|| "never".to_string());
+        let last = state.last_run.get(&schedule.name)
+            .map(|&t| format_local(t))
+            .unwrap_or_else(|| "never".to_string());
+        println!("{}  {}  {}", schedule.name, schedule.cron, schedule.paths.join(" "));
+        println!("    last run: {}, next run: {}", last, next);
+    }
+    Ok(())
+}
+
+/// Runs schedules as they come due until killed. A schedule that came due
+/// one or more times while the machine slept runs once on wake.
+pub fn run(schedules: &[Schedule], runner: impl Fn(&Schedule) -> Result<()>) -> Result<()> {
+    let parsed = parse_all(schedules)?;
+    let mut state = ScheduleState::load()?;
+    let started = now();
+    for (schedule, _) in &parsed {
+        state.last_run.entry(schedule.name.clone()).or_insert(started);
+    }
+    state.save()?;
+
+    println!("⏰ Running {} schedule(s) (Ctrl-C to stop)", parsed.len());
+    loop {
+        let now = now();
+        for (schedule, cron) in &parsed {
+            let last = state.last_run[&schedule.name];
+            let scan_from = last.max(now - CATCH_UP_MINUTES * 60);
+            let Some(due) = cron.next_after(scan_from, (now - scan_from) / 60) else {
+                continue;
+            };
+
+            if now - due >= 120 {
+                println!("\n⏰ {}: catching up on the run due {}", schedule.name, format_local(due));
+            } else {
+                println!("\n⏰ {}: running", schedule.name);
+            }
+            if let Err(e) = runner(schedule) {
+                eprintln!("❌ Schedule {} failed: {:#}", schedule.name, e);
+            }
+            state.last_run.insert(schedule.name.clone(), now);
+            state.save()?;
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
@@ -0,0 +1,113 @@
+use anyhow::{Context, Result};
+use dialoguer::Password as PasswordPrompt;
+use indicatif::MultiProgress;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::process::{Command, Stdio};
+use std::sync::Mutex;
+use tracing::debug;
+use crate::progress;
+use crate::prompt;
+
+/// Service name arkv's keychain entries are filed under.
+const KEYRING_SERVICE: &str = "arkv";
+
+/// Passwords typed in during this run, by `user@host`, so parallel
+/// sessions and reconnects don't ask again.
+static PROMPTED: Mutex<Option<HashMap<String, String>>> = Mutex::new(None);
+
+/// What each password command printed this run, by command, so it's only
+/// run (and a password manager only asks to be unlocked) once.
+static FETCHED: Mutex<Option<HashMap<String, String>>> = Mutex::new(None);
+
+/// A destination's password: kept in the OS keychain (macOS Keychain,
+/// Secret Service, Windows Credential Manager) with only its account
+/// name in config, printed by a command such as a password manager's CLI
+/// (`password = { command = "pass show nas" }`), asked for on each run
+/// (`password = "prompt"`), or as plaintext in older configs.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum Password {
+    Keyring { keyring: String },
+    Command { command: String },
+    Prompt(PromptMode),
+    Plain(String),
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum PromptMode {
+    Prompt,
+}
+
+impl Password {
+    /// Saves `password` to the keychain under `account` and returns the
+    /// reference to store in config.
+    pub fn store(account: &str, password: &str) -> Result<Self> {
+        entry(account)?
+            .set_password(password)
+            .with_context(|| format!("Failed to save password for {} to the OS keychain", account))?;
+        Ok(Password::Keyring { keyring: account.to_string() })
+    }
+
+    /// The password itself. `account` (`user@host`) labels the prompt and
+    /// keys the answer for the rest of the run.
+    pub fn resolve(&self, account: &str, progress: &MultiProgress) -> Result<String> {
+        match self {
+            Password::Plain(password) => Ok(password.clone()),
+            Password::Keyring { keyring } => entry(keyring)?
+                .get_password()
+                .with_context(|| format!("Failed to read password for {} from the OS keychain", keyring)),
+            Password::Command { command } => run_command(command, progress),
+            Password::Prompt(_) => {
+                let mut prompted = PROMPTED.lock().unwrap();
+                let prompted = prompted.get_or_insert_with(HashMap::new);
+                if let Some(password) = prompted.get(account) {
+                    return Ok(password.clone());
+                }
+                if !prompt::allowed() {
+                    anyhow::bail!("{} asks for its password at runtime, but there is no terminal to ask on", account);
+                }
+                let password = progress::suspend(progress, || {
+                    PasswordPrompt::new()
+                        .with_prompt(format!("🔑 Password for {}", account))
+                        .interact()
+                }).context("Failed to read password")?;
+                prompted.insert(account.to_string(), password.clone());
+                Ok(password)
+            }
+        }
+    }
+}
+
+/// The first line `command` prints, run with `sh -c`. Its stdin and
+/// stderr stay on the terminal, for password managers that ask to be
+/// unlocked.
+pub fn run_command(command: &str, progress: &MultiProgress) -> Result<String> {
+    let mut fetched = FETCHED.lock().unwrap();
+    let fetched = fetched.get_or_insert_with(HashMap::new);
+    if let Some(password) = fetched.get(command) {
+        return Ok(password.clone());
+    }
+
+    debug!("Running password command: {}", command);
+    let output = progress::suspend(progress, || {
+        Command::new("sh").arg("-c").arg(command).stderr(Stdio::inherit()).output()
+    }).with_context(|| format!("Failed to run password command: {}", command))?;
+    if !output.status.success() {
+        anyhow::bail!("Password command `{}` failed ({})", command, output.status);
+    }
+    let stdout = String::from_utf8(output.stdout)
+        .with_context(|| format!("Password command `{}` printed something that isn't UTF-8", command))?;
+    let password = stdout.lines().next().unwrap_or_default().to_string();
+    if password.is_empty() {
+        anyhow::bail!("Password command `{}` printed nothing", command);
+    }
+    fetched.insert(command.to_string(), password.clone());
+    Ok(password)
+}
+
+fn entry(account: &str) -> Result<keyring::Entry> {
+    keyring::Entry::new(KEYRING_SERVICE, account)
+        .with_context(|| format!("Failed to open OS keychain entry for {}", account))
+}
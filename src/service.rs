@@ -0,0 +1,157 @@
+use anyhow::{Context, Result};
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+
+/// An unattended arkv command to run as a user service.
+pub struct Service {
+    /// Short name, used for the unit or agent name (`arkv-<name>`).
+    pub name: String,
+    pub description: String,
+    /// Arguments passed to the arkv binary.
+    pub args: Vec<String>,
+}
+
+impl Service {
+    /// The generated definition and where it is installed.
+    pub fn definition(&self) -> Result<(PathBuf, String)> {
+        let exe = std::env::current_exe()
+            .and_then(fs::canonicalize)
+            .context("Failed to locate the arkv binary")?;
+        let mut command = vec![exe.to_string_lossy().into_owned()];
+        command.extend(self.args.iter().cloned());
+
+        let home = dirs::home_dir().context("Could not find home directory")?;
+        if cfg!(target_os = "macos") {
+            let path = home.join("Library/LaunchAgents").join(format!("{}.plist", self.label()));
+            let log = home.join("Library/Logs").join(format!("arkv-{}.log", self.name));
+            Ok((path, self.launchd_plist(&command, &log.to_string_lossy())))
+        } else if cfg!(target_os = "linux") {
+            let path = home.join(".config/systemd/user").join(format!("arkv-{}.service", self.name));
+            Ok((path, self.systemd_unit(&command)))
+        } else {
+            anyhow::bail!("Services are only supported with systemd (Linux) and launchd (macOS)");
+        }
+    }
+
+    /// Writes the definition and starts the service, now and at every login.
+    pub fn install(&self) -> Result<PathBuf> {
+        let (path, content) = self.definition()?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create {}", parent.display()))?;
+        }
+        fs::write(&path, content)
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+
+        if cfg!(target_os = "macos") {
+            // Reloading picks up changes to an agent that was already installed
+            let _ = Command::new("launchctl").arg("unload").arg(&path).output();
+            run("launchctl", &["load", "-w", &path.to_string_lossy()])?;
+        } else {
+            let unit = format!("arkv-{}.service", self.name);
+            run("systemctl", &["--user", "daemon-reload"])?;
+            run("systemctl", &["--user", "enable", &unit])?;
+            run("systemctl", &["--user", "restart", &unit])?;
+        }
+        Ok(path)
+    }
+
+    /// Stops the service and removes its definition.
+    pub fn uninstall(&self) -> Result<PathBuf> {
+        let (path, _) = self.definition()?;
+        if !path.exists() {
+            anyhow::bail!("No arkv-{} service is installed ({} not found)", self.name, path.display());
+        }
+
+        if cfg!(target_os = "macos") {
+            run("launchctl", &["unload", "-w", &path.to_string_lossy()])?;
+        } else {
+            run("systemctl", &["--user", "disable", "--now", &format!("arkv-{}.service", self.name)])?;
+        }
+        fs::remove_file(&path)
+            .with_context(|| format!("Failed to remove {}", path.display()))?;
+        if !cfg!(target_os = "macos") {
+            run("systemctl", &["--user", "daemon-reload"])?;
+        }
+        Ok(path)
+    }
+
+    fn label(&self) -> String {
+        format!("io.github.ehamiter.arkv.{}", self.name)
+    }
+
+    fn systemd_unit(&self, command: &[String]) -> String {
+        let exec: Vec<String> = command.iter().map(|arg| systemd_quote(arg)).collect();
+        format!(r#"[Unit]
+Description={}
+After=network-online.target
+Wants=network-online.target
+
+[Service]
+ExecStart={}
+Restart=on-failure
+RestartSec=30
+
+[Install]
+WantedBy=default.target
+"#, self.description.replace('%', "%%"), exec.join(" "))
+    }
+
+    fn launchd_plist(&self, command: &[String], log: &str) -> String {
+        let args: String = command.iter()
+            .map(|arg| format!("\n        <string>{}</string>", xml_escape(arg)))
+            .collect();
+        format!(r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+    <key>Label</key>
+    <string>{label}</string>
+    <key>ProgramArguments</key>
+    <array>{args}
+    </array>
+    <key>RunAtLoad</key>
+    <true/>
+    <key>KeepAlive</key>
+    <dict>
+        <key>SuccessfulExit</key>
+        <false/>
+    </dict>
+    <key>ThrottleInterval</key>
+    <integer>30</integer>
+    <key>StandardOutPath</key>
+    <string>{log}</string>
+    <key>StandardErrorPath</key>
+    <string>{log}</string>
+</dict>
+</plist>
+"#, label = xml_escape(&self.label()), args = args, log = xml_escape(log))
+    }
+}
+
+fn run(program: &str, args: &[&str]) -> Result<()> {
+    let output = Command::new(program)
+        .args(args)
+        .output()
+        .with_context(|| format!("Failed to run {}", program))?;
+    if !output.status.success() {
+        anyhow::bail!("{} {} failed: {}", program, args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim());
+    }
+    Ok(())
+}
+
+/// Quotes an ExecStart argument; `%` would otherwise start a specifier.
+fn systemd_quote(arg: &str) -> String {
+    let escaped = arg.replace('\\', "\\\\").replace('"', "\\\"").replace('%', "%%");
+    if escaped.is_empty() || escaped.contains(|c: char| c.is_whitespace() || c == '\'' || c == ';') {
+        format!("\"{}\"", escaped)
+    } else {
+        escaped
+    }
+}
+
+fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
@@ -1,7 +1,13 @@
 use anyhow::{Context, Result};
-use dialoguer::{Input, Password, Confirm, Select};
-use std::path::PathBuf;
-use crate::config::{Config, Destination};
+use dialoguer::{Completion, Input, Password, Confirm, Select};
+use ssh2::Sftp;
+use std::path::{Path, PathBuf};
+use crate::config::{Config, Destination, EncryptionBackend};
+use crate::encoding::RemoteEncoding;
+use crate::migrate;
+use crate::secret;
+use crate::transfer::{TransferOptions, Transferer};
+use crate::vault;
 
 pub fn run_setup() -> Result<Config> {
     // Check if config already exists
@@ -12,6 +18,11 @@ pub fn run_setup() -> Result<Config> {
             "Add a new destination",
             "Edit an existing destination", 
             "Delete a destination",
+            if existing_config.encrypted {
+                "Remove the master passphrase"
+            } else {
+                "Encrypt the config with a master passphrase"
+            },
             "Start fresh (delete all and reconfigure)",
             "Cancel",
         ];
@@ -26,7 +37,8 @@ pub fn run_setup() -> Result<Config> {
             0 => add_destination(existing_config),
             1 => edit_destination(existing_config),
             2 => delete_destination(existing_config),
-            3 => {
+            3 => toggle_encryption(existing_config),
+            4 => {
                 let confirm = Confirm::new()
                     .with_prompt("⚠️  This will delete all your existing settings. Are you sure?")
                     .default(false)
@@ -60,7 +72,7 @@ fn setup_fresh() -> Result<Config> {
     
     loop {
         println!("Setting up a remote destination...\n");
-        let destination = setup_destination()?;
+        let destination = setup_destination(&ssh_key_path)?;
         destinations.push(destination);
 
         let add_more = Confirm::new()
@@ -75,8 +87,17 @@ fn setup_fresh() -> Result<Config> {
     }
 
     let config = Config {
+        version: migrate::CURRENT,
         ssh_key_path,
+        minisign_secret_key: None,
+        minisign_public_key: None,
+        age_identity: None,
+        notify: false,
+        webhook_url: None,
+        progress_webhook: None,
         destinations,
+        schedules: Vec::new(),
+        encrypted: false,
     };
 
     config.save()?;
@@ -89,7 +110,7 @@ fn setup_fresh() -> Result<Config> {
 fn add_destination(mut config: Config) -> Result<Config> {
     println!("\n📦 Adding a new destination...\n");
     
-    let destination = setup_destination()?;
+    let destination = setup_destination(&config.ssh_key_path)?;
     config.destinations.push(destination);
     
     config.save()?;
@@ -105,7 +126,7 @@ fn edit_destination(mut config: Config) -> Result<Config> {
     }
     
     let names: Vec<String> = config.destinations.iter()
-        .map(|d| format!("{} ({})", d.name, d.host))
+        .map(|d| format!("{} ({})", d.name, d.address()))
         .collect();
     
     let selection = Select::new()
@@ -116,7 +137,7 @@ fn edit_destination(mut config: Config) -> Result<Config> {
     
     println!("\n📝 Editing {}...\n", config.destinations[selection].name);
     
-    let new_dest = setup_destination()?;
+    let new_dest = setup_destination(&config.ssh_key_path)?;
     config.destinations[selection] = new_dest;
     
     config.save()?;
@@ -132,7 +153,7 @@ fn delete_destination(mut config: Config) -> Result<Config> {
     }
     
     let names: Vec<String> = config.destinations.iter()
-        .map(|d| format!("{} ({})", d.name, d.host))
+        .map(|d| format!("{} ({})", d.name, d.address()))
         .collect();
     
     let selection = Select::new()
@@ -159,6 +180,20 @@ fn delete_destination(mut config: Config) -> Result<Config> {
     Ok(config)
 }
 
+fn toggle_encryption(mut config: Config) -> Result<Config> {
+    if config.encrypted {
+        config.encrypted = false;
+        config.save()?;
+        println!("\n✓ Config is stored as plain TOML again.\n");
+    } else {
+        vault::set_new_passphrase()?;
+        config.encrypted = true;
+        config.save()?;
+        println!("\n✓ Config encrypted. Set {} to use arkv from scripts.\n", vault::PASSPHRASE_ENV);
+    }
+    Ok(config)
+}
+
 fn get_ssh_key_path() -> Result<String> {
     let home = dirs::home_dir().context("Could not find home directory")?;
     let default_key = home.join(".ssh").join("id_ed25519");
@@ -178,7 +213,7 @@ fn get_ssh_key_path() -> Result<String> {
     Ok(path)
 }
 
-fn setup_destination() -> Result<Destination> {
+fn setup_destination(ssh_key_path: &str) -> Result<Destination> {
     let name: String = Input::new()
         .with_prompt("Name for this connection")
         .interact_text()?;
@@ -196,29 +231,222 @@ fn setup_destination() -> Result<Destination> {
         .with_prompt("Username")
         .interact_text()?;
 
-    let remote_path: String = Input::new()
-        .with_prompt("Remote folder path (e.g., /home/user/uploads)")
-        .interact_text()?;
-
     let use_password = Confirm::new()
         .with_prompt("Use password authentication? (otherwise SSH key will be used)")
         .default(false)
         .interact()?;
 
     let password = if use_password {
-        Some(Password::new()
-            .with_prompt("Password")
-            .interact()?)
+        let ask_each_run = Confirm::new()
+            .with_prompt("Ask for the password on each run instead of saving it?")
+            .default(false)
+            .interact()?;
+        if ask_each_run {
+            Some(secret::Password::Prompt(secret::PromptMode::Prompt))
+        } else {
+            let password = Password::new()
+                .with_prompt("Password")
+                .interact()?;
+            Some(store_password(&format!("{}@{}", username, host), password)?)
+        }
     } else {
         None
     };
 
-    Ok(Destination {
+    let mut destination = Destination {
         name,
+        kind: Default::default(),
         host,
         port,
         username,
-        remote_path,
+        remote_path: String::new(),
         password,
-    })
+        password_command: None,
+        tags: Vec::new(),
+        proxy_command: None,
+        proxy: None,
+        connect_address: None,
+        address_family: Default::default(),
+        ssh_agent: None,
+        gssapi: false,
+        keepalive_interval: None,
+        protocol: Default::default(),
+        remote_encoding: Default::default(),
+        sanitize: Default::default(),
+        include: Vec::new(),
+        exclude: Vec::new(),
+        owner: None,
+        group: None,
+        chown_sudo: false,
+        bandwidth: Vec::new(),
+        encryption: EncryptionBackend::default(),
+        age_recipients: Vec::new(),
+        gpg_recipients: Vec::new(),
+        webhook_url: None,
+        defaults: Default::default(),
+        retention: None,
+        s3: None,
+        webdav: None,
+        ftps: None,
+    };
+
+    let browse = Confirm::new()
+        .with_prompt("Connect now to browse for the remote folder?")
+        .default(true)
+        .interact()?;
+
+    destination.remote_path = if browse {
+        match browse_remote_path(&destination, ssh_key_path) {
+            Ok(path) => path,
+            Err(e) => {
+                println!("\n⚠️  Could not browse the server: {:#}\n", e);
+                prompt_remote_path()?
+            }
+        }
+    } else {
+        prompt_remote_path()?
+    };
+
+    Ok(destination)
+}
+
+/// Keeps the password in the OS keychain, falling back to config.toml
+/// only if the user agrees to it being stored as plaintext.
+fn store_password(account: &str, password: String) -> Result<secret::Password> {
+    match secret::Password::store(account, &password) {
+        Ok(stored) => {
+            println!("🔐 Password saved to the OS keychain");
+            Ok(stored)
+        }
+        Err(e) => {
+            println!("\n⚠️  {:#}\n", e);
+            let plaintext = Confirm::new()
+                .with_prompt("Save the password in config.toml as plaintext instead?")
+                .default(false)
+                .interact()?;
+            if !plaintext {
+                anyhow::bail!("No place to store the password; use an SSH key instead");
+            }
+            Ok(secret::Password::Plain(password))
+        }
+    }
+}
+
+fn prompt_remote_path() -> Result<String> {
+    Ok(Input::new()
+        .with_prompt("Remote folder path (e.g., /home/user/uploads)")
+        .interact_text()?)
+}
+
+/// Lets the user walk the remote directory tree and pick a folder, so the
+/// configured path is one that actually exists on the server.
+fn browse_remote_path(destination: &Destination, ssh_key_path: &str) -> Result<String> {
+    println!("\n🔌 Connecting to {}...\n", destination.host);
+    let transferer = Transferer::new(destination.clone(), TransferOptions::default());
+    let session = transferer.connect(ssh_key_path)?;
+    let sftp = session.sftp()
+        .context("Failed to initialize SFTP")?;
+
+    let encoding = destination.remote_encoding;
+    let mut current = sftp.realpath(Path::new("."))
+        .map(|home| encoding.decode(&home))
+        .context("Failed to resolve remote home directory")?;
+
+    loop {
+        let subdirs = list_remote_dirs(&sftp, &current, encoding)?;
+
+        let mut items = vec![
+            format!("✓ Use {}", current.display()),
+            "⬆️  ..".to_string(),
+            "✏️  Type a path (Tab completes folders)".to_string(),
+        ];
+        items.extend(subdirs.iter().map(|d| format!("📁 {}/", d)));
+
+        let choice = Select::new()
+            .with_prompt(format!("Remote folder: {}", current.display()))
+            .items(&items)
+            .default(0)
+            .interact()?;
+
+        match choice {
+            0 => return Ok(current.to_string_lossy().to_string()),
+            1 => {
+                if let Some(parent) = current.parent() {
+                    current = parent.to_path_buf();
+                }
+            }
+            2 => {
+                let completion = RemoteDirCompletion { sftp: &sftp, base: current.clone(), encoding };
+                let typed: String = Input::new()
+                    .with_prompt("Remote folder path")
+                    .with_initial_text(format!("{}/", current.display()))
+                    .completion_with(&completion)
+                    .interact_text()?;
+                let typed = current.join(typed);
+
+                match sftp.stat(&encoding.encode(&typed)?) {
+                    Ok(stat) if stat.is_dir() => current = typed,
+                    Ok(_) => println!("\n⚠️  {} is not a folder\n", typed.display()),
+                    Err(_) => {
+                        let create = Confirm::new()
+                            .with_prompt(format!("{} doesn't exist yet. Use it anyway (it will be created on upload)?", typed.display()))
+                            .default(true)
+                            .interact()?;
+                        if create {
+                            return Ok(typed.to_string_lossy().to_string());
+                        }
+                    }
+                }
+            }
+            n => current = current.join(&subdirs[n - 3]),
+        }
+    }
+}
+
+fn list_remote_dirs(sftp: &Sftp, dir: &Path, encoding: RemoteEncoding) -> Result<Vec<String>> {
+    let entries = sftp.readdir(&encoding.encode(dir)?)
+        .with_context(|| format!("Failed to list remote folder: {}", dir.display()))?;
+
+    let mut dirs: Vec<String> = entries.into_iter()
+        .filter(|(_, stat)| stat.is_dir())
+        .filter_map(|(path, _)| encoding.decode(&path).file_name().map(|n| n.to_string_lossy().to_string()))
+        .filter(|name| !name.starts_with('.'))
+        .collect();
+    dirs.sort();
+    Ok(dirs)
+}
+
+/// Completes the last segment of a typed remote path to the longest
+/// prefix shared by the matching remote folders.
+struct RemoteDirCompletion<'a> {
+    sftp: &'a Sftp,
+    base: PathBuf,
+    encoding: RemoteEncoding,
+}
+
+impl Completion for RemoteDirCompletion<'_> {
+    fn get(&self, input: &str) -> Option<String> {
+        let (parent, partial) = match input.rfind('/') {
+            Some(i) => (&input[..=i], &input[i + 1..]),
+            None => ("", input),
+        };
+
+        let dirs = list_remote_dirs(self.sftp, &self.base.join(parent), self.encoding).ok()?;
+        let matches: Vec<&String> = dirs.iter()
+            .filter(|d| d.starts_with(partial))
+            .collect();
+
+        let first = matches.first()?;
+        let mut common = first.len();
+        for other in &matches[1..] {
+            common = first.bytes()
+                .zip(other.bytes())
+                .take(common)
+                .take_while(|(a, b)| a == b)
+                .count();
+        }
+
+        let suffix = if matches.len() == 1 { "/" } else { "" };
+        Some(format!("{}{}{}", parent, &first[..common], suffix))
+    }
 }
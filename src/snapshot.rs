@@ -0,0 +1,67 @@
+/// What separates an upload's name from its snapshot's timestamp.
+const MARKER: &str = ".snapshot-";
+
+/// Name of the snapshot folder of `name` for a run started at `time`,
+/// e.g. `photos.snapshot-20261014-030000`. Resumed runs keep theirs.
+pub fn name(name: &str, time: u64) -> String {
+    let stamp = unsafe {
+        let time = time as libc::time_t;
+        let mut tm: libc::tm = std::mem::zeroed();
+        libc::localtime_r(&time, &mut tm);
+        format!("{:04}{:02}{:02}-{:02}{:02}{:02}",
+            tm.tm_year + 1900, tm.tm_mon + 1, tm.tm_mday, tm.tm_hour, tm.tm_min, tm.tm_sec)
+    };
+    format!("{}{}{}", name, MARKER, stamp)
+}
+
+/// The newest snapshot of `name` among the folder names `entries`, other
+/// than `current`, the one being written. The timestamps sort by name.
+pub fn previous(entries: impl IntoIterator<Item = String>, name: &str, current: &str) -> Option<String> {
+    let prefix = format!("{}{}", name, MARKER);
+    entries.into_iter()
+        .filter(|entry| entry != current)
+        .filter(|entry| entry.strip_prefix(&prefix).is_some_and(is_stamp))
+        .max()
+}
+
+fn is_stamp(stamp: &str) -> bool {
+    stamp.len() == 15 && stamp.char_indices().all(|(i, c)| if i == 8 { c == '-' } else { c.is_ascii_digit() })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entries(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn newest_other_snapshot_is_previous() {
+        let entries = entries(&[
+            "photos.snapshot-20261012-030000",
+            "photos.snapshot-20261013-030000",
+            "photos.snapshot-20261014-030000",
+        ]);
+        let previous = previous(entries, "photos", "photos.snapshot-20261014-030000");
+        assert_eq!(previous.as_deref(), Some("photos.snapshot-20261013-030000"));
+    }
+
+    #[test]
+    fn other_uploads_and_stray_names_are_ignored() {
+        let entries = entries(&[
+            "photos",
+            "photos-old.snapshot-20261013-030000",
+            "photos.snapshot-latest",
+            "photos.snapshot-20261013-0300",
+        ]);
+        assert_eq!(previous(entries, "photos", "photos.snapshot-20261014-030000"), None);
+    }
+
+    #[test]
+    fn name_has_a_stamp_previous_accepts() {
+        let current = name("photos", 1_760_000_000);
+        assert!(current.starts_with("photos.snapshot-"));
+        assert_eq!(previous(vec![current.clone()], "photos", "photos").as_deref(), Some(current.as_str()));
+    }
+}
@@ -0,0 +1,460 @@
+use anyhow::{Context, Result};
+use indicatif::MultiProgress;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use crate::config::{AddressFamily, Compression, Config, Destination, EncryptionBackend, IfExists, RenameScheme};
+use crate::encryption::Encryption;
+use crate::filter::PathFilter;
+use crate::manifest::Signer;
+use crate::transfer::TransferOptions;
+
+const RUNS_DIR: &str = "runs";
+/// Completed files are flushed to disk at most this often.
+const SAVE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The plan and progress of one run, kept on disk while it is running so
+/// `arkv resume` can continue it after a crash, suspend or reboot.
+#[derive(Debug, Serialize, Deserialize)]
+struct RunState {
+    id: String,
+    started: u64,
+    paths: Vec<PathBuf>,
+    destinations: Vec<DestinationRun>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct DestinationRun {
+    name: String,
+    #[serde(default)]
+    done: bool,
+    /// Bytes read from the sources, across every session of the run.
+    #[serde(default)]
+    bytes_transferred: u64,
+    /// Bytes written to the remote (less than read when compressing).
+    #[serde(default)]
+    bytes_sent: u64,
+    #[serde(default)]
+    duration_secs: f64,
+    options: PlannedOptions,
+    /// Finished remote files, keyed by remote path.
+    #[serde(default)]
+    files: BTreeMap<String, CompletedFile>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct CompletedFile {
+    /// Size as stored remotely.
+    pub size: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+}
+
+/// The resolved transfer settings for a destination, so a resumed run
+/// behaves like the original one regardless of the flags `resume` is given.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct PlannedOptions {
+    fast_cipher: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    compress: Option<Compression>,
+    resume: bool,
+    #[serde(default)]
+    sync: bool,
+    #[serde(default)]
+    preserve: bool,
+    #[serde(default)]
+    archive: bool,
+    #[serde(default, skip_serializing_if = "IfExists::is_default")]
+    if_exists: IfExists,
+    #[serde(default, skip_serializing_if = "RenameScheme::is_default")]
+    rename_scheme: RenameScheme,
+    #[serde(default = "default_true")]
+    atomic: bool,
+    #[serde(default)]
+    incremental: bool,
+    #[serde(default)]
+    snapshot: bool,
+    #[serde(default)]
+    checksum: bool,
+    #[serde(default)]
+    dedup: bool,
+    #[serde(default)]
+    mirror: bool,
+    #[serde(default)]
+    force: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    encrypt_with: Option<EncryptionBackend>,
+    /// Recipients, when encrypting.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    encrypt_to: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    include: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    exclude: Vec<String>,
+    #[serde(default = "default_true")]
+    ignore_files: bool,
+    #[serde(default = "default_true")]
+    hidden: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_depth: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    relative_to: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    size: Option<u64>,
+    manifest: bool,
+    #[serde(default)]
+    verify: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sign: Option<Signer>,
+    lock: bool,
+    lock_wait_secs: u64,
+    #[serde(default)]
+    retries: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    connect_timeout_secs: Option<u64>,
+    #[serde(default, skip_serializing_if = "AddressFamily::is_default")]
+    address_family: AddressFamily,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    io_timeout_secs: Option<u64>,
+    #[serde(default = "default_jobs")]
+    jobs: usize,
+    #[serde(default = "default_jobs")]
+    chunks: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    split: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    quota_warn_percent: Option<f64>,
+    #[serde(default)]
+    space_check: bool,
+}
+
+fn default_jobs() -> usize {
+    1
+}
+
+fn default_true() -> bool {
+    true
+}
+
+impl PlannedOptions {
+    fn from_options(options: &TransferOptions) -> Self {
+        Self {
+            fast_cipher: options.fast_cipher,
+            compress: options.compress,
+            resume: options.resume,
+            sync: options.sync,
+            preserve: options.preserve,
+            archive: options.archive,
+            if_exists: options.if_exists,
+            rename_scheme: options.rename_scheme,
+            atomic: options.atomic,
+            incremental: options.incremental,
+            snapshot: options.snapshot,
+            checksum: options.checksum,
+            dedup: options.dedup,
+            mirror: options.mirror,
+            force: options.force,
+            encrypt_with: options.encrypt.as_ref().map(|e| e.backend()),
+            encrypt_to: options.encrypt.as_ref().map(|e| e.recipients().to_vec()).unwrap_or_default(),
+            include: options.filter.include().to_vec(),
+            exclude: options.filter.exclude().to_vec(),
+            ignore_files: options.ignore_files,
+            hidden: options.hidden,
+            max_depth: options.max_depth,
+            relative_to: options.relative_to.clone(),
+            size: options.size,
+            manifest: options.manifest,
+            verify: options.verify,
+            sign: options.sign,
+            lock: options.lock,
+            lock_wait_secs: options.lock_wait.as_secs(),
+            retries: options.retries,
+            connect_timeout_secs: options.connect_timeout.map(|t| t.as_secs()),
+            address_family: options.address_family,
+            io_timeout_secs: options.io_timeout.map(|t| t.as_secs()),
+            jobs: options.jobs,
+            chunks: options.chunks,
+            split: options.split,
+            quota_warn_percent: options.quota_warn_percent,
+            space_check: options.space_check,
+        }
+    }
+
+    fn to_options(&self, progress: &MultiProgress) -> TransferOptions {
+        TransferOptions {
+            fast_cipher: self.fast_cipher,
+            compress: self.compress,
+            // Partial files from the interrupted session are continued
+            // unless they are compressed or encrypted, which can't be appended to.
+            resume: self.resume || (self.compress.is_none() && self.encrypt_to.is_empty()),
+            sync: self.sync,
+            preserve: self.preserve,
+            archive: self.archive,
+            if_exists: self.if_exists,
+            rename_scheme: self.rename_scheme,
+            atomic: self.atomic,
+            incremental: self.incremental,
+            snapshot: self.snapshot,
+            checksum: self.checksum,
+            dedup: self.dedup,
+            mirror: self.mirror,
+            force: self.force,
+            encrypt: (!self.encrypt_to.is_empty())
+                .then(|| Encryption::new(self.encrypt_with.unwrap_or_default(), &self.encrypt_to).expect("recipients were validated when the run was created")),
+            decrypt_identity: None,
+            // Validated when the run was created
+            filter: PathFilter::new(&self.include, &self.exclude).unwrap_or_default(),
+            ignore_files: self.ignore_files,
+            hidden: self.hidden,
+            max_depth: self.max_depth,
+            relative_to: self.relative_to.clone(),
+            size: self.size,
+            manifest: self.manifest,
+            verify: self.verify,
+            sign: self.sign,
+            lock: self.lock,
+            lock_wait: Duration::from_secs(self.lock_wait_secs),
+            retries: self.retries,
+            connect_timeout: self.connect_timeout_secs.map(Duration::from_secs),
+            address_family: self.address_family,
+            io_timeout: self.io_timeout_secs.map(Duration::from_secs),
+            jobs: self.jobs,
+            chunks: self.chunks,
+            split: self.split,
+            quota_warn_percent: self.quota_warn_percent,
+            space_check: self.space_check,
+            progress: progress.clone(),
+            run: None,
+        }
+    }
+}
+
+/// Shared handle to the state file of the current run.
+pub struct RunLog {
+    path: PathBuf,
+    state: Mutex<RunState>,
+    last_save: Mutex<Instant>,
+    dirty: AtomicBool,
+}
+
+impl RunLog {
+    fn runs_dir() -> Result<PathBuf> {
+        Ok(Config::config_dir()?.join(RUNS_DIR))
+    }
+
+    /// Records a new run before anything is uploaded.
+    pub fn create(paths: &[PathBuf], jobs: &[(Destination, TransferOptions)]) -> Result<Arc<Self>> {
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let id = format!("{:x}-{:x}", started, std::process::id());
+
+        let state = RunState {
+            id: id.clone(),
+            started,
+            paths: paths.to_vec(),
+            destinations: jobs.iter()
+                .map(|(destination, options)| DestinationRun {
+                    name: destination.name.clone(),
+                    done: false,
+                    bytes_transferred: 0,
+                    bytes_sent: 0,
+                    duration_secs: 0.0,
+                    options: PlannedOptions::from_options(options),
+                    files: BTreeMap::new(),
+                })
+                .collect(),
+        };
+
+        let log = RunLog {
+            path: Self::runs_dir()?.join(format!("{}.toml", id)),
+            state: Mutex::new(state),
+            last_save: Mutex::new(Instant::now()),
+            dirty: AtomicBool::new(false),
+        };
+        log.save()?;
+        Ok(Arc::new(log))
+    }
+
+    /// Loads an unfinished run by id, or the most recent one.
+    pub fn load(id: Option<&str>) -> Result<Arc<Self>> {
+        let dir = Self::runs_dir()?;
+        let path = match id {
+            Some(id) => dir.join(format!("{}.toml", id)),
+            None => {
+                let mut runs: Vec<(SystemTime, PathBuf)> = fs::read_dir(&dir)
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|e| e.ok())
+                    .filter(|e| e.path().extension().is_some_and(|ext| ext == "toml"))
+                    .filter_map(|e| Some((e.metadata().ok()?.modified().ok()?, e.path())))
+                    .collect();
+                runs.sort();
+                runs.pop()
+                    .map(|(_, path)| path)
+                    .context("No interrupted runs to resume")?
+            }
+        };
+
+        Self::read(path).map(Arc::new)
+    }
+
+    /// An unfinished run of exactly these paths to these destinations, so
+    /// re-running the same command continues it instead of starting over.
+    pub fn find_unfinished(paths: &[PathBuf], destinations: &[&str]) -> Option<Arc<Self>> {
+        let mut wanted: Vec<&str> = destinations.to_vec();
+        wanted.sort();
+
+        fs::read_dir(Self::runs_dir().ok()?).ok()?
+            .filter_map(|e| e.ok())
+            .filter(|e| e.path().extension().is_some_and(|ext| ext == "toml"))
+            .filter_map(|e| Self::read(e.path()).ok())
+            .find(|run| {
+                let state = run.state.lock().unwrap();
+                let mut names: Vec<&str> = state.destinations.iter().map(|d| d.name.as_str()).collect();
+                names.sort();
+                state.paths == paths && names == wanted
+            })
+            .map(Arc::new)
+    }
+
+    fn read(path: PathBuf) -> Result<Self> {
+        let content = fs::read_to_string(&path)
+            .with_context(|| format!("No unfinished run found at {}", path.display()))?;
+        let state: RunState = toml::from_str(&content)
+            .with_context(|| format!("Failed to parse run state: {}", path.display()))?;
+
+        Ok(RunLog {
+            path,
+            state: Mutex::new(state),
+            last_save: Mutex::new(Instant::now()),
+            dirty: AtomicBool::new(false),
+        })
+    }
+
+    pub fn is_pending(&self, destination: &str) -> bool {
+        self.state.lock().unwrap().destinations.iter()
+            .any(|d| d.name == destination && !d.done)
+    }
+
+    pub fn id(&self) -> String {
+        self.state.lock().unwrap().id.clone()
+    }
+
+    /// Unix time the run was first started.
+    pub fn started(&self) -> u64 {
+        self.state.lock().unwrap().started
+    }
+
+    pub fn paths(&self) -> Vec<PathBuf> {
+        self.state.lock().unwrap().paths.clone()
+    }
+
+    /// Destinations still to be finished, with the options they were started with.
+    pub fn pending(&self, progress: &MultiProgress) -> Vec<(String, TransferOptions)> {
+        self.state.lock().unwrap().destinations.iter()
+            .filter(|d| !d.done)
+            .map(|d| (d.name.clone(), d.options.to_options(progress)))
+            .collect()
+    }
+
+    pub fn completed(&self, destination: &str, remote_path: &Path) -> Option<CompletedFile> {
+        let state = self.state.lock().unwrap();
+        let run = state.destinations.iter().find(|d| d.name == destination)?;
+        run.files.get(remote_path.to_string_lossy().as_ref()).cloned()
+    }
+
+    /// Marks a remote file as finished. Saved in batches; `checkpoint` or
+    /// `flush` force it to disk.
+    pub fn record(&self, destination: &str, remote_path: &Path, file: CompletedFile, bytes_read: u64, bytes_sent: u64) -> Result<()> {
+        {
+            let mut state = self.state.lock().unwrap();
+            if let Some(run) = state.destinations.iter_mut().find(|d| d.name == destination) {
+                run.bytes_transferred += bytes_read;
+                run.bytes_sent += bytes_sent;
+                run.files.insert(remote_path.to_string_lossy().to_string(), file);
+            }
+        }
+        self.dirty.store(true, Ordering::Relaxed);
+
+        if self.last_save.lock().unwrap().elapsed() >= SAVE_INTERVAL {
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    /// Marks a destination as done and returns its totals across all
+    /// sessions: bytes read, bytes sent and duration.
+    pub fn finish_destination(&self, destination: &str, duration_secs: f64) -> Result<(u64, u64, f64)> {
+        let totals = {
+            let mut state = self.state.lock().unwrap();
+            match state.destinations.iter_mut().find(|d| d.name == destination) {
+                Some(run) => {
+                    run.done = true;
+                    run.duration_secs += duration_secs;
+                    (run.bytes_transferred, run.bytes_sent, run.duration_secs)
+                }
+                None => (0, 0, duration_secs),
+            }
+        };
+        self.save()?;
+        Ok(totals)
+    }
+
+    /// Saves unsaved progress, e.g. before starting a long file.
+    pub fn checkpoint(&self) -> Result<()> {
+        if self.dirty.load(Ordering::Relaxed) {
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    pub fn flush(&self) -> Result<()> {
+        self.save()
+    }
+
+    /// Deletes the state file once every destination is done.
+    pub fn finish(&self) -> Result<()> {
+        if self.state.lock().unwrap().destinations.iter().all(|d| d.done) {
+            fs::remove_file(&self.path)
+                .with_context(|| format!("Failed to remove run state: {}", self.path.display()))?;
+        }
+        Ok(())
+    }
+
+    /// Deletes the state file of a run that won't be continued.
+    pub fn discard(&self) -> Result<()> {
+        fs::remove_file(&self.path)
+            .with_context(|| format!("Failed to remove run state: {}", self.path.display()))
+    }
+
+    /// Writes the state to a temporary file and renames it into place, so a
+    /// crash mid-write never leaves a truncated state file behind.
+    fn save(&self) -> Result<()> {
+        self.dirty.store(false, Ordering::Relaxed);
+        let content = {
+            let state = self.state.lock().unwrap();
+            toml::to_string(&*state).context("Failed to serialize run state")?
+        };
+
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)
+                .context("Failed to create run state directory")?;
+        }
+        let tmp = self.path.with_extension("toml.tmp");
+        fs::write(&tmp, content)
+            .with_context(|| format!("Failed to write run state: {}", tmp.display()))?;
+        fs::File::open(&tmp).and_then(|f| f.sync_all())
+            .context("Failed to sync run state")?;
+        fs::rename(&tmp, &self.path)
+            .with_context(|| format!("Failed to save run state: {}", self.path.display()))?;
+
+        *self.last_save.lock().unwrap() = Instant::now();
+        Ok(())
+    }
+}
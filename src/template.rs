@@ -0,0 +1,47 @@
+use anyhow::Result;
+use std::path::{Path, PathBuf};
+
+/// Expands the placeholders in a destination's `remote_path`, e.g.
+/// `/backups/{hostname}/{year}/{month}`: `{date}` (`2026-10-14`),
+/// `{time}` (`03-00-00`), `{year}`, `{month}`, `{day}`, `{hostname}` and
+/// `{source_name}`, the name of the file or folder being uploaded. Times
+/// are local, as of `time`.
+pub fn expand(template: &str, time: u64, source_name: &str) -> Result<String> {
+    let tm = unsafe {
+        let time = time as libc::time_t;
+        let mut tm: libc::tm = std::mem::zeroed();
+        libc::localtime_r(&time, &mut tm);
+        tm
+    };
+
+    let mut expanded = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        expanded.push_str(&rest[..start]);
+        let Some(end) = rest[start..].find('}') else {
+            anyhow::bail!("Unclosed '{{' in remote_path: {}", template);
+        };
+        let value = match &rest[start + 1..start + end] {
+            "date" => format!("{:04}-{:02}-{:02}", tm.tm_year + 1900, tm.tm_mon + 1, tm.tm_mday),
+            "time" => format!("{:02}-{:02}-{:02}", tm.tm_hour, tm.tm_min, tm.tm_sec),
+            "year" => format!("{:04}", tm.tm_year + 1900),
+            "month" => format!("{:02}", tm.tm_mon + 1),
+            "day" => format!("{:02}", tm.tm_mday),
+            "hostname" => crate::lock::hostname(),
+            "source_name" => source_name.to_string(),
+            other => anyhow::bail!("Unknown placeholder {{{}}} in remote_path (use date, time, year, month, day, hostname or source_name)", other),
+        };
+        expanded.push_str(&value.replace('/', "_"));
+        rest = &rest[start + end + 1..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+/// The part of `remote_path` before its first placeholder: the folder
+/// every expansion of it lies in.
+pub fn base(template: &str) -> PathBuf {
+    Path::new(template).components()
+        .take_while(|c| !c.as_os_str().to_string_lossy().contains('{'))
+        .collect()
+}
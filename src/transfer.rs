@@ -1,244 +1,3668 @@
 use anyhow::{Context, Result};
-use indicatif::{ProgressBar, ProgressStyle};
-use ssh2::Session;
-use std::fs::File;
-use std::io::Read;
-use std::net::TcpStream;
+use flate2::read::GzEncoder;
+use dialoguer::Confirm;
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use ssh2::{FileStat, MethodType, OpenFlags, OpenType, RenameFlags, Session};
+use std::cell::Cell;
+use std::ffi::OsStr;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt};
 use std::path::{Path, PathBuf};
-use std::time::Instant;
-use walkdir::WalkDir;
-use crate::config::Destination;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{debug, error, info, trace, warn};
+use sha2::{Digest, Sha256};
+use ignore::WalkBuilder;
+use crate::auth;
+use crate::bandwidth::{Schedule, Throttle};
+use crate::backend::{self, Backend};
+use crate::config::{AddressFamily, Compression, Config, Destination, DestinationKind, IfExists, Protocol, RenameScheme};
+use crate::dedup::{self, Index, IndexEntry};
+use crate::encryption::{self, Encryption, FinishWrite, Plain};
+use crate::failure::{self, Classify, Failure};
+use crate::filter::PathFilter;
+use crate::history::{self, Direction, HistoryEntry};
+use crate::known_hosts;
+use crate::lock::{Acquired, RemoteLock};
+use crate::manifest::{self, Manifest, ManifestEntry, Signer};
+use crate::net::{self, Connection};
+use crate::progress::{self, Throughput};
+use crate::prompt;
+use crate::prune;
+use crate::remote::{self, Capabilities, Store};
+use crate::rsync;
+use crate::secret;
+use crate::snapshot;
+use crate::state::{CompletedFile, RunLog};
+use crate::template;
+use crate::webhook::{CompletionEvent, ProgressEvent, ProgressWebhook, RunStatus};
 
 const BUFFER_SIZE: usize = 262_144;
+/// Bytes compared at the end of a partial remote file before appending to it.
+const RESUME_CHECK_SIZE: u64 = 65_536;
+/// Wait before reconnecting, multiplied by the attempt number.
+const RETRY_DELAY: Duration = Duration::from_secs(2);
+/// Run state is saved before starting files at least this big, so an
+/// interruption during them doesn't lose the files finished just before.
+const CHECKPOINT_SIZE: u64 = 64 * 1024 * 1024;
+/// Smallest range a file is split into by `--chunks`.
+const MIN_CHUNK_SIZE: u64 = 32 * 1024 * 1024;
+/// Suffix of the hidden temporary file an upload is written into before
+/// it is renamed into place.
+const PARTIAL_SUFFIX: &str = ".arkv-partial";
+/// Files listed before `--mirror` asks to delete them.
+const MIRROR_PREVIEW: usize = 10;
+
+/// Per-folder ignore file, in `.gitignore` syntax, for paths arkv should skip.
+const IGNORE_FILE: &str = ".arkvignore";
+
+/// Ciphers in the order they are fastest on CPUs with AES instructions.
+const AES_NI_CIPHERS: &[&str] = &[
+    "aes128-gcm@openssh.com",
+    "aes256-gcm@openssh.com",
+    "aes128-ctr",
+    "chacha20-poly1305@openssh.com",
+    "aes256-ctr",
+];
+
+/// Ciphers in the order they are fastest without hardware AES (many ARM boards).
+const SOFTWARE_CIPHERS: &[&str] = &[
+    "chacha20-poly1305@openssh.com",
+    "aes128-ctr",
+    "aes128-gcm@openssh.com",
+    "aes256-ctr",
+];
+
+/// How a local file or folder and its remote copy differ, by path
+/// relative to the compared folder.
+#[derive(Default)]
+pub struct DiffReport {
+    pub only_local: Vec<String>,
+    pub only_remote: Vec<String>,
+    /// Files on both sides, with what differs: `size`, `mtime` or `content`.
+    pub changed: Vec<(String, &'static str)>,
+    pub same: usize,
+}
+
+impl DiffReport {
+    pub fn is_same(&self) -> bool {
+        self.only_local.is_empty() && self.only_remote.is_empty() && self.changed.is_empty()
+    }
+}
 
 pub struct TransferStats {
+    /// Bytes read from the sources.
     pub bytes_transferred: u64,
+    /// Bytes written to the remote, when compressing.
+    pub bytes_compressed: Option<u64>,
     pub duration_secs: f64,
+    /// Files skipped by `--sync` because the remote copy is current.
+    pub unchanged: u64,
+    /// Files skipped by `--if-exists skip` because they were already there.
+    pub existing: u64,
+    /// Remote files `--mirror` deleted because they are gone locally.
+    pub deleted: u64,
+    /// Old archives removed by the destination's retention settings.
+    pub pruned: u64,
+    /// Sockets, FIFOs and device nodes in uploaded folders, which can't
+    /// be uploaded and were skipped.
+    pub special: u64,
+    pub warnings: Vec<String>,
+    /// How long each file that was sent took, in upload order.
+    pub files: Vec<FileTiming>,
+}
+
+pub struct FileTiming {
+    /// Remote path, relative to the destination's remote path.
+    pub path: PathBuf,
+    /// Source bytes sent for it during this run.
+    pub bytes: u64,
+    pub duration_secs: f64,
+}
+
+/// Settings for one run against one destination, after CLI flags have
+/// been layered over the destination's configured defaults.
+#[derive(Clone, Default)]
+pub struct TransferOptions {
+    pub fast_cipher: bool,
+    pub compress: Option<Compression>,
+    pub resume: bool,
+    /// Skip files whose remote copy has the same size and mtime.
+    pub sync: bool,
+    /// Write each file under a temporary name and rename it into place
+    /// once complete, so it is never seen half-written.
+    pub atomic: bool,
+    /// What to do with remote files that are already there.
+    pub if_exists: IfExists,
+    /// How files are renamed with `IfExists::Rename`.
+    pub rename_scheme: RenameScheme,
+    /// Copy each file's permission bits and mtime to the remote copy.
+    pub preserve: bool,
+    /// Which files of a folder to upload.
+    pub filter: PathFilter,
+    /// Skip paths matched by `.gitignore` and `.arkvignore` files.
+    pub ignore_files: bool,
+    /// Upload dotfiles and dot-folders inside folders.
+    pub hidden: bool,
+    /// Upload each folder as a single tar file.
+    pub archive: bool,
+    /// Only upload files changed since the folder's last archive, into a
+    /// dated delta folder.
+    pub incremental: bool,
+    /// Upload each folder into a new dated snapshot, hard-linking the
+    /// files unchanged since the previous one instead of sending them.
+    pub snapshot: bool,
+    /// Skip files the server already has with the same SHA-256, at the
+    /// same path or, renamed, elsewhere in the upload's manifests.
+    pub checksum: bool,
+    /// Store each file's content once in the destination's `objects/`
+    /// pool, with an index per upload.
+    pub dedup: bool,
+    /// Delete remote files of an uploaded folder that are gone locally.
+    pub mirror: bool,
+    /// Delete them without asking.
+    pub force: bool,
+    /// Recipients to encrypt uploads to.
+    pub encrypt: Option<Encryption>,
+    /// Decrypt `.age` and `.gpg` downloads; `.age` ones with this age identity file.
+    pub decrypt_identity: Option<PathBuf>,
+    /// Only descend this many levels into folders (1 = just the top level).
+    pub max_depth: Option<usize>,
+    /// Upload each path at its location relative to this folder instead of
+    /// under its own name, as `--files-from` does.
+    pub relative_to: Option<PathBuf>,
+    /// Overrides the detected size of device sources, used for progress.
+    pub size: Option<u64>,
+    pub manifest: bool,
+    pub sign: Option<Signer>,
+    /// Compare each upload's SHA-256 with one computed on the server.
+    pub verify: bool,
+    /// Take an advisory lock on the remote folder for the whole run.
+    pub lock: bool,
+    pub lock_wait: Duration,
+    /// Files uploaded at once within a folder, each over its own session.
+    pub jobs: usize,
+    /// Ranges a large file is split into, each uploaded over its own session.
+    pub chunks: usize,
+    /// Store files (and archives) bigger than this as numbered part files.
+    pub split: Option<u64>,
+    /// Reconnect this many times when the connection drops mid-transfer.
+    pub retries: u32,
+    /// Limit on connecting and the SSH handshake.
+    pub connect_timeout: Option<Duration>,
+    /// Which of the host's addresses to connect to.
+    pub address_family: AddressFamily,
+    /// Limit on each network operation once connected.
+    pub io_timeout: Option<Duration>,
+    /// Warn when the remote volume is at least this full after the upload.
+    pub quota_warn_percent: Option<f64>,
+    /// Stop before uploading when the remote volume is too full to take it.
+    pub space_check: bool,
+    pub progress: MultiProgress,
+    /// Persistent state of the run, used to skip files finished in an earlier session.
+    pub run: Option<Arc<RunLog>>,
+}
+
+/// What the local copies of an upload's earlier manifests say is on the
+/// remote, for `--checksum`.
+#[derive(Default)]
+struct KnownFiles {
+    by_path: HashMap<PathBuf, ManifestEntry>,
+    /// Remote paths by size and SHA-256, to find renamed files.
+    by_content: HashMap<(u64, String), PathBuf>,
+    sizes: HashSet<u64>,
+}
+
+/// One file of a folder upload, with where it goes remotely.
+struct FileJob {
+    local: PathBuf,
+    remote: PathBuf,
+    original: Option<PathBuf>,
+    label: String,
+}
+
+/// Hard links to a file earlier in the same upload that were sent as
+/// copies of their own, and why they couldn't be linked.
+#[derive(Default)]
+struct CopiedLinks {
+    files: u64,
+    bytes: u64,
+    why: Option<&'static str>,
+}
+
+/// The progress bar an upload's bytes are counted on: the file's own,
+/// spanning its size, or its folder's, spanning every file in it.
+#[derive(Clone, Copy)]
+enum Bar<'a> {
+    File(&'a ProgressBar),
+    Folder(&'a ProgressBar),
+}
+
+impl<'a> Bar<'a> {
+    fn progress(self) -> &'a ProgressBar {
+        match self {
+            Bar::File(pb) | Bar::Folder(pb) => pb,
+        }
+    }
+
+    /// The size of the file, once it is known.
+    fn set_size(self, size: u64) {
+        if let Bar::File(pb) = self {
+            pb.set_length(size);
+        }
+    }
+
+    /// Counts the first `bytes` of the file as done without sending them.
+    fn mark_done(self, bytes: u64) {
+        match self {
+            Bar::File(pb) => pb.set_position(bytes),
+            Bar::Folder(pb) => pb.inc(bytes),
+        }
+    }
+}
+
+/// A folder upload's bar, counting the bytes of all its files, with how
+/// many of them are done in its message.
+struct FolderBar<'a> {
+    pb: &'a ProgressBar,
+    total: usize,
+    done: AtomicUsize,
+}
+
+impl FolderBar<'_> {
+    fn uploading(&self, label: &str) {
+        self.pb.set_message(format!("{}/{} files, uploading {}", self.done.load(Ordering::Relaxed), self.total, label));
+    }
+}
+
+struct UploadedFile {
+    /// Remote path in local (UTF-8) form, before any remote encoding.
+    remote_path: PathBuf,
+    /// Bytes read from the source during this run.
+    bytes_read: u64,
+    /// Size of the file as stored remotely (after compression).
+    size: u64,
+    sha256: Option<String>,
+    /// Local name (relative to the upload's parent) when it had to be
+    /// sanitized for the remote.
+    original: Option<PathBuf>,
+    /// The part files a `--split` upload was stored as.
+    parts: Vec<UploadedPart>,
+}
+
+struct UploadedPart {
+    remote_path: PathBuf,
+    size: u64,
+    sha256: Option<String>,
 }
 
 pub struct Transferer {
     destination: Destination,
-    verbose: bool,
+    options: TransferOptions,
+    throughput: Arc<Throughput>,
+    lock: Mutex<Option<RemoteLock>>,
+    capabilities: OnceLock<Capabilities>,
+    /// Remote folders created during this run, for applying ownership.
+    created_dirs: Mutex<Vec<PathBuf>>,
+    throttle: Mutex<Option<Throttle>>,
+    /// Source bytes counted as done without being read: files finished
+    /// earlier and the already-uploaded part of resumed files.
+    skipped_bytes: AtomicU64,
+    unchanged: AtomicU64,
+    existing: AtomicU64,
+    deleted: AtomicU64,
+    /// Sockets, FIFOs and device nodes left out of folder uploads.
+    special: Mutex<HashSet<PathBuf>>,
+    copied_links: Mutex<CopiedLinks>,
+    /// Bytes written to the remote, after compression.
+    sent_bytes: AtomicU64,
+    /// Part of `sent_bytes` not yet added to the run log.
+    unrecorded_sent: AtomicU64,
+    /// Set once a dropped connection has been re-established.
+    reconnected: AtomicBool,
+    /// Unix time the transfer was set up, for remote path placeholders.
+    started: u64,
+    timings: Mutex<Vec<FileTiming>>,
 }
 
 impl Transferer {
-    pub fn new(destination: Destination, verbose: bool) -> Self {
-        Self { destination, verbose }
+    pub fn new(destination: Destination, options: TransferOptions) -> Self {
+        Self {
+            destination,
+            options,
+            throughput: Throughput::new(),
+            lock: Mutex::new(None),
+            capabilities: OnceLock::new(),
+            created_dirs: Mutex::new(Vec::new()),
+            throttle: Mutex::new(None),
+            skipped_bytes: AtomicU64::new(0),
+            unchanged: AtomicU64::new(0),
+            existing: AtomicU64::new(0),
+            deleted: AtomicU64::new(0),
+            special: Mutex::new(HashSet::new()),
+            copied_links: Mutex::new(CopiedLinks::default()),
+            sent_bytes: AtomicU64::new(0),
+            unrecorded_sent: AtomicU64::new(0),
+            reconnected: AtomicBool::new(false),
+            started: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+            timings: Mutex::new(Vec::new()),
+        }
     }
 
-    pub fn transfer(&self, local_path: &str, ssh_key_path: &str) -> Result<TransferStats> {
+    pub fn transfer(&self, local_paths: &[PathBuf], config: &Config) -> Result<TransferStats> {
+        let started = history::now();
         let start_time = Instant::now();
-        let path = PathBuf::from(local_path);
-        
-        if !path.exists() {
-            anyhow::bail!("Path does not exist: {}", local_path);
+        let result = self.transfer_with_progress(local_paths, config);
+        let entry = self.record_history(Direction::Upload, local_paths.to_vec(), started, start_time, &result);
+        self.post_completion(config, &entry);
+        result
+    }
+
+    /// Tells the destination's `webhook_url`, or the config's, how the
+    /// upload went.
+    fn post_completion(&self, config: &Config, entry: &HistoryEntry) {
+        let Some(url) = self.destination.webhook_url.as_ref().or(config.webhook_url.as_ref()) else {
+            return;
+        };
+        let event = CompletionEvent {
+            run_id: entry.run_id.as_deref(),
+            destination: &entry.destination,
+            status: if entry.error.is_none() { RunStatus::Finished } else { RunStatus::Failed },
+            paths: &entry.paths,
+            bytes: entry.bytes,
+            duration_secs: entry.duration_secs,
+            error: entry.error.as_deref(),
+        };
+        if let Err(e) = event.post(url) {
+            warn!("{:#}", e);
+        }
+    }
+
+    fn transfer_with_progress(&self, local_paths: &[PathBuf], config: &Config) -> Result<TransferStats> {
+        let Some(webhook) = &config.progress_webhook else {
+            return self.run_transfer(local_paths, config);
+        };
+
+        let done = AtomicBool::new(false);
+        thread::scope(|scope| {
+            let reporter = scope.spawn(|| self.report_progress(webhook, local_paths, &done));
+            let result = self.run_transfer(local_paths, config);
+            done.store(true, Ordering::Relaxed);
+
+            let total = reporter.join().unwrap_or(0);
+            let status = if result.is_ok() { RunStatus::Finished } else { RunStatus::Failed };
+            self.post_progress(webhook, total, status);
+            result
+        })
+    }
+
+    /// Posts progress to the webhook every `interval_secs`, and whenever
+    /// another `every_percent` is done, until `done` is set.
+    fn report_progress(&self, webhook: &ProgressWebhook, local_paths: &[PathBuf], done: &AtomicBool) -> u64 {
+        let total = self.planned_bytes(local_paths);
+        let interval = Duration::from_secs(webhook.interval_secs.max(1));
+        let step = webhook.every_percent.filter(|p| *p > 0.0);
+
+        let mut last_post: Option<Instant> = None;
+        let mut last_step = 0;
+        while !done.load(Ordering::Relaxed) {
+            let current_step = step.map(|p| (self.percent_done(total) / p) as u64).unwrap_or(0);
+            if last_post.is_none_or(|t| t.elapsed() >= interval) || current_step > last_step {
+                self.post_progress(webhook, total, RunStatus::Running);
+                last_post = Some(Instant::now());
+                last_step = current_step;
+            }
+            thread::sleep(Duration::from_millis(250));
         }
+        total
+    }
 
-        let session = self.connect(ssh_key_path)?;
+    fn reconnect(&self, config: &Config) -> Result<(Connection, ssh2::Sftp)> {
+        let session = self.connect(&config.ssh_key_path)?;
         let sftp = session.sftp()
             .context("Failed to initialize SFTP")?;
+        Ok((session, sftp))
+    }
 
-        let mut total_bytes = 0u64;
+    /// Whether the session still answers, to tell a dropped connection
+    /// apart from errors that retrying won't fix.
+    fn connection_alive(&self, sftp: &ssh2::Sftp) -> bool {
+        sftp.stat(Path::new(".")).is_ok()
+    }
 
-        if path.is_file() {
-            let pb = ProgressBar::new_spinner();
-            pb.set_style(
-                ProgressStyle::default_spinner()
-                    .template("{spinner:.green} [{elapsed_precise}] {msg}")
-                    .unwrap()
-            );
-            pb.set_message(format!("Uploading {}", path.file_name().unwrap().to_string_lossy()));
-            pb.enable_steady_tick(std::time::Duration::from_millis(100));
+    /// Resume is on when asked for, and after a reconnect for anything
+    /// that can be appended to.
+    fn resume_enabled(&self) -> bool {
+        self.options.resume || (self.reconnected.load(Ordering::Relaxed) && self.stored_as_is())
+    }
 
-            let remote_file_path = PathBuf::from(&self.destination.remote_path)
-                .join(path.file_name().unwrap());
-            total_bytes = self.upload_file(&sftp, &path, remote_file_path.to_str().unwrap())?;
-            
-            pb.finish_with_message(format!("✓ Uploaded {}", path.file_name().unwrap().to_string_lossy()));
+    fn special_count(&self) -> u64 {
+        self.special.lock().unwrap().len() as u64
+    }
+
+    /// Why further hard links to a file can't be recreated on the server,
+    /// if they can't.
+    fn hard_links_unsupported(&self, session: &Session, sftp: &ssh2::Sftp) -> Option<&'static str> {
+        if self.options.split.is_some() {
+            Some("--split stores them as parts")
+        } else if self.options.if_exists != IfExists::Overwrite {
+            Some("with --if-exists, the first of them may be skipped or renamed")
+        } else if !self.capabilities(session, sftp).exec {
+            Some("linking them with ln needs shell access this server doesn't allow")
         } else {
-            let files: Vec<_> = WalkDir::new(&path)
-                .into_iter()
-                .filter_map(|e| e.ok())
-                .filter(|e| e.file_type().is_file())
-                .collect();
+            None
+        }
+    }
 
-            let total_files = files.len();
-            
-            let pb = ProgressBar::new(total_files as u64);
-            pb.set_style(
-                ProgressStyle::default_bar()
-                    .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} files {msg}")
-                    .unwrap()
-                    .progress_chars("#>-")
-            );
-            pb.enable_steady_tick(std::time::Duration::from_millis(100));
+    /// Counts hard links about to be sent as copies, for the summary.
+    fn copy_links(&self, copies: &[(PathBuf, FileJob)], why: &'static str) {
+        let mut copied = self.copied_links.lock().unwrap();
+        for (_, job) in copies {
+            debug!("Uploading hard link as a copy: {}", job.local.display());
+            copied.files += 1;
+            copied.bytes += fs::metadata(&job.local).map_or(0, |m| m.len());
+        }
+        copied.why = copied.why.or(Some(why));
+    }
 
-            for entry in files {
-                let file_path = entry.path();
-                let relative = file_path.strip_prefix(&path)
-                    .context("Failed to compute relative path")?;
-                
-                let remote_file_path = PathBuf::from(&self.destination.remote_path)
-                    .join(path.file_name().unwrap())
-                    .join(relative);
+    fn copied_links_warning(&self) -> Option<String> {
+        let copied = self.copied_links.lock().unwrap();
+        copied.why.filter(|_| copied.files > 0).map(|why| format!(
+            "{} hard-linked files were uploaded as separate copies ({:.2} MB more than needed): {}",
+            copied.files, copied.bytes as f64 / 1_048_576.0, why
+        ))
+    }
 
-                pb.set_message(format!("Uploading {}", relative.display()));
-                
-                total_bytes += self.upload_file(&sftp, file_path, remote_file_path.to_str().unwrap())?;
-                pb.inc(1);
+    fn bytes_done(&self) -> u64 {
+        self.throughput.total() + self.skipped_bytes.load(Ordering::Relaxed)
+    }
+
+    fn percent_done(&self, total: u64) -> f64 {
+        if total == 0 {
+            return 0.0;
+        }
+        (self.bytes_done() as f64 * 100.0 / total as f64).min(100.0)
+    }
+
+    fn post_progress(&self, webhook: &ProgressWebhook, total: u64, status: RunStatus) {
+        let run_id = self.options.run.as_ref().map(|run| run.id()).unwrap_or_default();
+        let bytes_done = self.bytes_done();
+        let rate = self.throughput.average();
+        let eta_secs = (status == RunStatus::Running && rate > 0.0 && total > bytes_done)
+            .then(|| ((total - bytes_done) as f64 / rate) as u64);
+
+        let event = ProgressEvent {
+            run_id: &run_id,
+            destination: &self.destination.name,
+            status,
+            bytes_done,
+            bytes_total: total,
+            percent: self.percent_done(total),
+            bytes_per_sec: rate,
+            eta_secs,
+        };
+        if let Err(e) = webhook.post(&event) {
+            warn!("{:#}", e);
+        }
+    }
+
+    fn run_transfer(&self, local_paths: &[PathBuf], config: &Config) -> Result<TransferStats> {
+        let start_time = Instant::now();
+
+        if !self.destination.bandwidth.is_empty() {
+            let schedule = Schedule::new(&self.destination.bandwidth)
+                .with_context(|| format!("Invalid bandwidth schedule for {}", self.destination.name))?;
+            *self.throttle.lock().unwrap() = Some(Throttle::new(schedule));
+        }
+
+        if self.destination.kind != DestinationKind::Sftp || self.destination.gssapi {
+            self.check_backend(local_paths)?;
+        }
+        if let Some(backend) = backend::open(&self.destination, &self.options)? {
+            return self.upload_to_backend(backend.as_ref(), local_paths, start_time, config);
+        }
+
+        let scp = self.destination.protocol == Protocol::Scp;
+        if scp {
+            self.check_scp(local_paths)?;
+        }
+        let mut session = self.connect(&config.ssh_key_path)?;
+        if scp {
+            return self.upload_scp(&session, local_paths, start_time);
+        }
+        let mut sftp = session.sftp()
+            .context("Failed to initialize SFTP")?;
+        let rsync = self.destination.protocol == Protocol::Rsync && self.rsync_usable(&session, local_paths);
+
+        // Checked before locking, so a run that stops here leaves no lock behind
+        if self.options.space_check && !rsync {
+            self.check_space(&session, &sftp, local_paths)?;
+        }
+        if !rsync {
+            // Probed up front, as upload workers move finished files into
+            // place without a session to probe with
+            self.capabilities(&session, &sftp);
+        }
+
+        if self.options.lock {
+            let remote_dir = self.destination.remote_base();
+            self.ensure_remote_dir(&sftp, &remote_dir)?;
+            let remote_dir = self.wire(&remote_dir)?;
+            let acquired = progress::suspend(&self.options.progress, || {
+                RemoteLock::acquire(&sftp, &remote_dir, self.options.lock_wait)
+            })?;
+            if let Acquired::Locked(lock) = acquired {
+                *self.lock.lock().unwrap() = Some(lock);
+            }
+        }
+
+        // A dropped connection is retried on a fresh session; files finished
+        // so far are skipped and the interrupted one continues where the
+        // remote copy stops.
+        let mut attempt = 0;
+        let result = loop {
+            let uploaded = match rsync {
+                true => self.upload_rsync(&session, &sftp, local_paths, config),
+                false => self.upload_all(&session, &sftp, local_paths, config),
+            };
+            match uploaded {
+                Err(e) if attempt < self.options.retries && !self.connection_alive(&sftp) => {
+                    attempt += 1;
+                    progress::suspend(&self.options.progress, || eprintln!(
+                        "⚠️  {}: connection lost ({:#}), reconnecting ({}/{})",
+                        self.destination.name, e, attempt, self.options.retries
+                    ));
+                    thread::sleep(RETRY_DELAY * attempt);
+                    if let Ok((new_session, new_sftp)) = self.reconnect(config) {
+                        session = new_session;
+                        sftp = new_sftp;
+                        self.reconnected.store(true, Ordering::Relaxed);
+                    }
+                }
+                other => break other,
+            }
+        };
+
+        // Prune while the lock is still held, so another run never sees
+        // a half-deleted archive
+        let mut warnings = Vec::new();
+        let mut pruned = 0;
+        let retention = self.destination.retention.as_ref()
+            .filter(|r| r.auto_prune && (r.keep_last.is_some() || r.keep_days.is_some()));
+        if let (Ok(_), Some(_)) = (&result, retention) {
+            match prune::run(&Store::Sftp(&session, &sftp), &self.destination, false) {
+                Ok(removed) => pruned = removed.len() as u64,
+                Err(e) => warnings.push(format!("pruning old archives failed: {:#}", e)),
             }
+        }
 
-            pb.finish_with_message(format!("✓ Uploaded {} files", total_files));
+        if let Some(lock) = self.lock.lock().unwrap().take() {
+            lock.release(&sftp)?;
         }
+        result?;
+        let total_bytes = self.throughput.total();
+
+        if let Some(threshold) = self.options.quota_warn_percent {
+            warnings.extend(self.check_quota(&session, &sftp, threshold));
+        }
+        warnings.extend(self.copied_links_warning());
 
         let duration = start_time.elapsed();
         Ok(TransferStats {
             bytes_transferred: total_bytes,
+            bytes_compressed: self.options.compress.map(|_| self.sent_bytes.load(Ordering::Relaxed)),
             duration_secs: duration.as_secs_f64(),
+            unchanged: self.unchanged.load(Ordering::Relaxed),
+            existing: self.existing.load(Ordering::Relaxed),
+            deleted: self.deleted.load(Ordering::Relaxed),
+            pruned,
+            special: self.special_count(),
+            warnings,
+            files: std::mem::take(&mut *self.timings.lock().unwrap()),
         })
     }
 
-    fn connect(&self, ssh_key_path: &str) -> Result<Session> {
-        if self.verbose {
-            eprintln!("Connecting to {}:{}", self.destination.host, self.destination.port);
+    /// SCP can only write single files into a folder that already exists,
+    /// so anything that needs to look at the remote side is refused before
+    /// connecting.
+    fn check_scp(&self, local_paths: &[PathBuf]) -> Result<()> {
+        let unsupported = self.needs_sftp();
+        if !unsupported.is_empty() {
+            return Err(failure::fail(Failure::Config, format!(
+                "{}: {} can't be used with protocol = \"scp\", which can't read or change the remote side",
+                self.destination.name, unsupported.join(", ")
+            )));
         }
-        let tcp = TcpStream::connect(format!("{}:{}", self.destination.host, self.destination.port))
-            .context("Failed to connect to server")?;
+        if let Some(path) = local_paths.iter().find(|path| !path.is_file()) {
+            return Err(failure::fail(Failure::Config, format!(
+                "{}: {} is not a regular file, and protocol = \"scp\" only uploads single files (it can't create remote folders); use SFTP for folders and devices",
+                self.destination.name, path.display()
+            )));
+        }
+        Ok(())
+    }
 
-        tcp.set_nodelay(true)
-            .context("Failed to set TCP_NODELAY")?;
-        
-        use std::os::unix::io::AsRawFd;
-        let fd = tcp.as_raw_fd();
-        unsafe {
-            let size: libc::c_int = 2_097_152;
-            libc::setsockopt(
-                fd,
-                libc::SOL_SOCKET,
-                libc::SO_SNDBUF,
-                &size as *const _ as *const libc::c_void,
-                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
-            );
-            libc::setsockopt(
-                fd,
-                libc::SOL_SOCKET,
-                libc::SO_RCVBUF,
-                &size as *const _ as *const libc::c_void,
-                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
-            );
+    /// Uploads each file with SCP, for servers that don't offer SFTP.
+    /// There is no lock, atomic rename or pruning.
+    fn upload_scp(&self, session: &Session, local_paths: &[PathBuf], start_time: Instant) -> Result<TransferStats> {
+        let mut warnings = Vec::new();
+        if self.destination.retention.as_ref().is_some_and(|r| r.auto_prune) {
+            warnings.push("old archives were not pruned: pruning needs SFTP".to_string());
         }
+        for path in local_paths {
+            let file_name = path.file_name()
+                .with_context(|| format!("Cannot archive path without a name: {}", path.display()))?;
+            let name = self.upload_name(path).unwrap_or_else(|| PathBuf::from(file_name));
+            let remote_file_path = self.remote_root(file_name)?.join(self.destination.sanitize.path(&name));
+            let pb = self.progress_bar(ProgressBar::new(0), progress::bytes_style());
+            pb.set_message(format!("Uploading {}", name.display()));
 
-        if self.verbose {
-            eprintln!("Creating SSH session");
+            let started = Instant::now();
+            let file = self.scp_file(session, path, &remote_file_path, &pb)?;
+            self.record_timing(&file, started);
+            pb.finish_with_message(format!("✓ Uploaded {}", name.display()));
         }
-        let mut session = Session::new()
-            .context("Failed to create SSH session")?;
-        
-        session.set_tcp_stream(tcp);
-        if self.verbose {
-            eprintln!("Performing SSH handshake");
+
+        Ok(TransferStats {
+            bytes_transferred: self.throughput.total(),
+            bytes_compressed: None,
+            duration_secs: start_time.elapsed().as_secs_f64(),
+            unchanged: 0,
+            existing: 0,
+            deleted: 0,
+            pruned: 0,
+            special: 0,
+            warnings,
+            files: std::mem::take(&mut *self.timings.lock().unwrap()),
+        })
+    }
+
+    /// The options in effect that SCP can't provide.
+    fn needs_sftp(&self) -> Vec<&'static str> {
+        let options = &self.options;
+        let mut names: Vec<&str> = [
+            (options.resume, "--resume"),
+            (options.sync, "--sync"),
+            (options.quota_warn_percent.is_some(), "--quota-warn"),
+        ]
+        .into_iter()
+        .filter_map(|(used, name)| used.then_some(name))
+        .collect();
+        names.extend(self.remote_side_options());
+        names
+    }
+
+    /// The options in effect that arkv carries out itself over SFTP, which
+    /// neither SCP nor rsync can do the same way.
+    fn remote_side_options(&self) -> Vec<&'static str> {
+        let options = &self.options;
+        [
+            (options.if_exists != IfExists::Overwrite, "--if-exists"),
+            (options.archive, "--archive"),
+            (options.incremental, "--incremental"),
+            (options.snapshot, "--snapshot"),
+            (options.checksum, "--checksum"),
+            (options.dedup, "--dedup"),
+            (options.mirror, "--mirror"),
+            (options.compress.is_some(), "--compress"),
+            (options.encrypt.is_some(), "--encrypt"),
+            (options.manifest, "--manifest"),
+            (options.verify, "--verify"),
+            (options.chunks > 1, "--chunks"),
+            (options.split.is_some(), "--split"),
+            (self.destination.owner.is_some() || self.destination.group.is_some(), "owner/group"),
+        ]
+        .into_iter()
+        .filter_map(|(used, name)| used.then_some(name))
+        .collect()
+    }
+
+    fn scp_file(&self, session: &Session, local_path: &Path, logical_path: &Path, pb: &ProgressBar) -> Result<UploadedFile> {
+        debug!("Uploading over SCP: {} -> {}", local_path.display(), logical_path.display());
+        let metadata = fs::metadata(local_path)
+            .with_context(|| format!("Failed to read {}", local_path.display()))?;
+        let mode = match self.options.preserve {
+            true => metadata.permissions().mode() & 0o777,
+            false => 0o644,
+        };
+        let times = self.options.preserve
+            .then(|| unix_mtime(&metadata))
+            .flatten()
+            .map(|mtime| (mtime, mtime));
+        pb.set_length(metadata.len());
+
+        let mut channel = session.scp_send(&self.wire(logical_path)?, mode as i32, metadata.len(), times)
+            .with_context(|| format!(
+                "Failed to start SCP upload of {} (SCP can't create folders, so {} must already exist)",
+                logical_path.display(),
+                logical_path.parent().unwrap_or(Path::new("/")).display()
+            ))?;
+
+        let bytes_read = Rc::new(Cell::new(0));
+        let mut reader = CountingReader {
+            inner: File::open(local_path).context("Failed to open local file")?,
+            count: bytes_read.clone(),
+            throughput: self.throughput.clone(),
+            pb: Some(pb.clone()),
+        };
+        let mut buffer = vec![0; BUFFER_SIZE];
+        loop {
+            let chunk = reader.read(&mut buffer)
+                .with_context(|| format!("Failed to read local file: {}", local_path.display()))?;
+            if chunk == 0 {
+                break;
+            }
+            channel.write_all(&buffer[..chunk])
+                .with_context(|| format!("Failed to write remote file: {}", logical_path.display()))?;
+            self.pace(chunk);
         }
-        session.handshake()
-            .context("SSH handshake failed")?;
 
-        if let Some(ref password) = self.destination.password {
-            if self.verbose {
-                eprintln!("Authenticating with password for user: {}", self.destination.username);
+        channel.send_eof()
+            .and_then(|_| channel.wait_eof())
+            .and_then(|_| channel.close())
+            .and_then(|_| channel.wait_close())
+            .with_context(|| format!("Failed to finish SCP upload of {}", logical_path.display()))?;
+
+        Ok(UploadedFile {
+            remote_path: logical_path.to_path_buf(),
+            bytes_read: bytes_read.get(),
+            size: metadata.len(),
+            sha256: None,
+            original: None,
+            parts: Vec::new(),
+        })
+    }
+
+    /// Only plain uploads of files and folders can go to a backend, so
+    /// anything arkv does over SFTP is refused before starting. Local
+    /// folders can be read back, so they take manifests and `--verify`.
+    fn check_backend(&self, local_paths: &[PathBuf]) -> Result<()> {
+        let mut supported = vec!["--if-exists"];
+        if self.destination.kind == DestinationKind::Local {
+            supported.extend(["--manifest", "--verify"]);
+        }
+        let mut unsupported = self.needs_sftp();
+        unsupported.retain(|name| !supported.contains(name));
+        if self.options.preserve {
+            unsupported.push("--preserve");
+        }
+        if !unsupported.is_empty() {
+            return Err(failure::fail(Failure::Config, format!(
+                "{}: {} can't be used with {}, which only takes plain uploads",
+                self.destination.name, unsupported.join(", "), self.destination.address()
+            )));
+        }
+        if let Some(path) = local_paths.iter().find(|path| !path.is_file() && !path.is_dir()) {
+            return Err(failure::fail(Failure::Config, format!(
+                "{}: {} is neither a file nor a folder, and only those can be uploaded to {}",
+                self.destination.name, path.display(), self.destination.address()
+            )));
+        }
+        Ok(())
+    }
+
+    /// Uploads files and folders to a destination that isn't an SFTP
+    /// server, naming and filtering them just as for SFTP.
+    fn upload_to_backend(&self, backend: &dyn Backend, local_paths: &[PathBuf], start_time: Instant, config: &Config) -> Result<TransferStats> {
+        for path in local_paths {
+            let file_name = path.file_name()
+                .with_context(|| format!("Cannot archive path without a name: {}", path.display()))?;
+            let name = self.upload_name(path).unwrap_or_else(|| PathBuf::from(file_name));
+            let remote_root = self.remote_root(file_name)?;
+            let remote_name = self.destination.sanitize.path(&name);
+            let pb = self.progress_bar(ProgressBar::new(0), progress::bytes_style());
+            let mut uploaded = Vec::new();
+
+            if path.is_file() {
+                pb.set_message(format!("Uploading {}", name.display()));
+                uploaded.extend(self.backend_file(backend, path, &remote_root.join(&remote_name), Bar::File(&pb))?);
+                pb.finish_with_message(format!("✓ Uploaded {}", name.display()));
+            } else {
+                self.backend_folder(backend, path, &remote_root, &remote_name, &pb, &mut uploaded)?;
             }
-            session.userauth_password(&self.destination.username, password)
-                .context("Password authentication failed")?;
-        } else {
-            if self.verbose {
-                eprintln!("Authenticating with SSH key: {} for user: {}", ssh_key_path, self.destination.username);
+
+            if self.options.verify {
+                self.verify_uploads(&Store::Local, &uploaded)?;
+            }
+            if self.options.manifest {
+                let remote_base = self.destination.remote_base();
+                let expanded_dir = remote_root.strip_prefix(&remote_base).unwrap_or(Path::new(""));
+                let name = expanded_dir.join(&remote_name);
+                self.write_manifest(&Store::Local, path, &remote_base, &name.to_string_lossy(), uploaded, config)?;
             }
-            session.userauth_pubkey_file(
-                &self.destination.username,
-                None,
-                Path::new(ssh_key_path),
-                None,
-            ).context("SSH key authentication failed")?;
         }
 
-        if !session.authenticated() {
-            anyhow::bail!("Authentication failed");
+        let mut warnings = Vec::new();
+        let mut pruned = 0;
+        let retention = self.destination.retention.as_ref()
+            .filter(|r| r.auto_prune && (r.keep_last.is_some() || r.keep_days.is_some()));
+        if let (DestinationKind::Local, Some(_)) = (self.destination.kind, retention) {
+            match prune::run(&Store::Local, &self.destination, false) {
+                Ok(removed) => pruned = removed.len() as u64,
+                Err(e) => warnings.push(format!("pruning old archives failed: {:#}", e)),
+            }
         }
-        
-        if self.verbose {
-            eprintln!("Successfully authenticated");
+        warnings.extend(self.copied_links_warning());
+
+        Ok(TransferStats {
+            bytes_transferred: self.throughput.total(),
+            bytes_compressed: None,
+            duration_secs: start_time.elapsed().as_secs_f64(),
+            unchanged: 0,
+            existing: self.existing.load(Ordering::Relaxed),
+            deleted: 0,
+            pruned,
+            special: self.special_count(),
+            warnings,
+            files: std::mem::take(&mut *self.timings.lock().unwrap()),
+        })
+    }
+
+    fn backend_folder(
+        &self,
+        backend: &dyn Backend,
+        path: &Path,
+        remote_root: &Path,
+        remote_name: &Path,
+        pb: &ProgressBar,
+        uploaded: &mut Vec<UploadedFile>,
+    ) -> Result<()> {
+        let mut remote_names = HashSet::new();
+        let mut work = Vec::new();
+        for entry in self.folder_files(path) {
+            let relative = entry.path().strip_prefix(path)
+                .context("Failed to compute relative path")?;
+            let remote_relative = remote_name.join(self.destination.sanitize.path(relative));
+            if !remote_names.insert(remote_relative.clone()) {
+                anyhow::bail!(
+                    "{} and another file both become {} after sanitizing",
+                    entry.path().display(), remote_relative.display()
+                );
+            }
+            work.push(FileJob {
+                local: entry.path().to_path_buf(),
+                remote: remote_root.join(&remote_relative),
+                original: None,
+                label: relative.display().to_string(),
+            });
         }
-        Ok(session)
+        let (mut work, copies) = hard_linked(work);
+        self.copy_links(&copies, "this destination type can't hard-link files");
+        work.extend(copies.into_iter().map(|(_, job)| job));
+        pb.set_length(work.iter().map(|job| fs::metadata(&job.local).map(|m| m.len()).unwrap_or(0)).sum());
+        let folder = FolderBar { pb, total: work.len(), done: AtomicUsize::new(0) };
+        for job in &work {
+            folder.uploading(&job.label);
+            uploaded.extend(self.backend_file(backend, &job.local, &job.remote, Bar::Folder(pb))?);
+            folder.done.fetch_add(1, Ordering::Relaxed);
+        }
+        pb.finish_with_message(format!("✓ Uploaded {} files", work.len()));
+        Ok(())
     }
 
-    fn upload_file(&self, sftp: &ssh2::Sftp, local_path: &Path, remote_path: &str) -> Result<u64> {
-        if self.verbose {
-            eprintln!("Uploading: {} -> {}", local_path.display(), remote_path);
+    /// Uploads one file through `backend`, returning it unless it was
+    /// skipped.
+    fn backend_file(&self, backend: &dyn Backend, local_path: &Path, logical_path: &Path, bar: Bar) -> Result<Option<UploadedFile>> {
+        let size = fs::metadata(local_path)
+            .with_context(|| format!("Failed to read {}", local_path.display()))?
+            .len();
+        bar.set_size(size);
+        let skip = |bar: Bar| {
+            bar.mark_done(size);
+            self.skipped_bytes.fetch_add(size, Ordering::Relaxed);
+        };
+
+        if let Some(run) = &self.options.run {
+            if run.completed(&self.destination.name, logical_path).is_some() {
+                debug!("Already uploaded earlier in this run: {}", logical_path.display());
+                skip(bar);
+                return Ok(None);
+            }
         }
-        
-        let remote_dir = Path::new(remote_path).parent()
-            .context("Invalid remote path")?;
-        
-        if self.verbose {
-            eprintln!("Ensuring remote directory exists: {}", remote_dir.display());
+        let Some(remote_path) = self.resolve_existing(|path| backend.exists(path), logical_path, logical_path.to_path_buf())? else {
+            skip(bar);
+            return Ok(None);
+        };
+        if let Some(parent) = remote_path.parent() {
+            backend.create_dir_all(parent)?;
         }
-        self.ensure_remote_dir(sftp, remote_dir)?;
 
-        if self.verbose {
-            eprintln!("Opening local file: {}", local_path.display());
+        debug!("Uploading: {} -> {}", local_path.display(), remote_path.display());
+        let started = Instant::now();
+        let bytes_read = Rc::new(Cell::new(0));
+        let mut reader = PacedReader {
+            inner: CountingReader {
+                inner: File::open(local_path).context("Failed to open local file")?,
+                count: bytes_read.clone(),
+                throughput: self.throughput.clone(),
+                pb: Some(bar.progress().clone()),
+            },
+            transferer: self,
+            hasher: self.hashing().then(Sha256::new),
+        };
+        backend.put(&remote_path, &mut reader, size)?;
+
+        let file = UploadedFile {
+            remote_path,
+            bytes_read: bytes_read.get(),
+            size,
+            sha256: reader.hasher.map(hex_digest),
+            original: None,
+            parts: Vec::new(),
+        };
+        self.record_timing(&file, started);
+        if let Some(run) = &self.options.run {
+            let done = CompletedFile { size, sha256: file.sha256.clone() };
+            run.record(&self.destination.name, logical_path, done, file.bytes_read, self.unrecorded_sent.swap(0, Ordering::Relaxed))?;
         }
-        let mut local_file = File::open(local_path)
-            .context("Failed to open local file")?;
-        
-        if self.verbose {
-            eprintln!("Creating remote file: {}", remote_path);
+        Ok(Some(file))
+    }
+
+    /// Whether this run can go through rsync. Whatever rsync can't do the
+    /// way arkv would is uploaded with SFTP instead, saying why.
+    fn rsync_usable(&self, session: &Session, local_paths: &[PathBuf]) -> bool {
+        let mut conflicting = self.remote_side_options();
+        conflicting.extend([
+            (!self.destination.bandwidth.is_empty(), "bandwidth"),
+            (!self.destination.sanitize.is_default(), "sanitize"),
+            (!self.destination.remote_encoding.is_default(), "remote_encoding"),
+            (self.options.relative_to.is_some(), "--files-from"),
+            (local_paths.iter().any(|path| is_device(path)), "device sources"),
+        ].into_iter().filter_map(|(used, name)| used.then_some(name)));
+
+        let reason = match conflicting.is_empty() {
+            true => rsync::unavailable(session, &self.destination),
+            false => Some(format!("{} can't be used with rsync", conflicting.join(", "))),
+        };
+        if let Some(reason) = &reason {
+            self.rsync_fallback(reason);
         }
-        let mut remote_file = sftp.create(Path::new(remote_path))
-            .context(format!("Failed to create remote file: {}", remote_path))?;
+        reason.is_none()
+    }
 
-        let mut buffer = vec![0; BUFFER_SIZE];
+    fn rsync_fallback(&self, reason: &str) {
+        progress::suspend(&self.options.progress, || eprintln!(
+            "⚠️  {}: uploading with SFTP instead of rsync: {}",
+            self.destination.name, reason
+        ));
+    }
+
+    /// Uploads with rsync over its own OpenSSH connection, so files that
+    /// are already on the server only have their changed blocks sent. The
+    /// SFTP session creates the remote folder and keeps the lock fresh.
+    fn upload_rsync(&self, session: &Session, sftp: &ssh2::Sftp, local_paths: &[PathBuf], config: &Config) -> Result<u64> {
         let mut total_bytes = 0u64;
-        loop {
-            let bytes_read = local_file.read(&mut buffer)
-                .context("Failed to read local file")?;
-            
-            if bytes_read == 0 {
-                break;
+        for (index, path) in local_paths.iter().enumerate() {
+            let file_name = path.file_name()
+                .with_context(|| format!("Cannot archive path without a name: {}", path.display()))?;
+            let name = file_name.to_string_lossy();
+            let source_dir = path.parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or(Path::new("."));
+            let files: Vec<PathBuf> = match path.is_file() {
+                true => vec![PathBuf::from(file_name)],
+                false => self.folder_files(path).iter()
+                    .filter_map(|entry| entry.path().strip_prefix(path).ok())
+                    .map(|relative| Path::new(file_name).join(relative))
+                    .collect(),
+            };
+            let remote_root = self.remote_root(file_name)?;
+            self.ensure_remote_dir(sftp, &remote_root)?;
+
+            let size = files.iter().map(|file| fs::metadata(source_dir.join(file)).map(|m| m.len()).unwrap_or(0)).sum();
+            let pb = self.progress_bar(ProgressBar::new(size), progress::bytes_style());
+            pb.set_message(format!("Uploading {} with rsync", name));
+
+            let started = Instant::now();
+            let mut done = 0;
+            let upload = rsync::Upload {
+                destination: &self.destination,
+                ssh_key_path: &config.ssh_key_path,
+                source_dir,
+                files,
+                remote_dir: &remote_root,
+                preserve: self.options.preserve,
+                connect_timeout: self.options.connect_timeout,
+                io_timeout: self.options.io_timeout,
+                address_family: self.options.address_family,
+            };
+            let outcome = upload.run(|bytes| {
+                self.throughput.add(bytes.saturating_sub(done));
+                done = done.max(bytes);
+                pb.set_position(done);
+                if let Some(lock) = self.lock.lock().unwrap().as_mut() {
+                    lock.refresh(sftp)?;
+                }
+                Ok(())
+            })?;
+            match outcome {
+                rsync::Outcome::Sent(sent) => {
+                    self.sent_bytes.fetch_add(sent, Ordering::Relaxed);
+                }
+                rsync::Outcome::NoConnection(reason) => {
+                    pb.finish_and_clear();
+                    self.rsync_fallback(&format!("rsync couldn't connect ({})", reason));
+                    return Ok(total_bytes + self.upload_all(session, sftp, &local_paths[index..], config)?);
+                }
             }
 
-            std::io::Write::write_all(&mut remote_file, &buffer[..bytes_read])
-                .context("Failed to write to remote file")?;
-            total_bytes += bytes_read as u64;
+            let file = UploadedFile {
+                remote_path: remote_root.join(file_name),
+                bytes_read: done,
+                size: done,
+                sha256: None,
+                original: None,
+                parts: Vec::new(),
+            };
+            self.record_timing(&file, started);
+            total_bytes += done;
+            pb.finish_with_message(format!("✓ Uploaded {}", name));
         }
-
         Ok(total_bytes)
     }
 
-    fn ensure_remote_dir(&self, sftp: &ssh2::Sftp, dir: &Path) -> Result<()> {
-        if self.verbose {
-            eprintln!("Checking if directory exists: {}", dir.display());
-        }
-        if sftp.stat(dir).is_ok() {
-            if self.verbose {
-                eprintln!("Directory already exists: {}", dir.display());
+    /// Probes the server's SFTP capabilities the first time a feature needs them.
+    fn capabilities(&self, session: &Session, sftp: &ssh2::Sftp) -> &Capabilities {
+        self.capabilities.get_or_init(|| {
+            let dir = self.existing_remote_base(sftp);
+            let caps = Capabilities::probe(session, sftp, &dir);
+            info!("Server capabilities: {}", caps.describe());
+            if !caps.rename_overwrite {
+                info!("The server can't rename over a file, so replaced files are deleted just before their new copy takes their place");
+            }
+            caps
+        })
+    }
+
+    /// The remote base, or the nearest folder above it that exists: where
+    /// the server is probed and its free space read before the first
+    /// upload creates the base.
+    fn existing_remote_base(&self, sftp: &ssh2::Sftp) -> PathBuf {
+        let base = self.destination.remote_base();
+        let base = self.wire(&base).unwrap_or(base);
+        remote::existing_dir(&base, |dir| sftp.stat(dir).is_ok_and(|stat| stat.is_dir()))
+    }
+
+    /// Fails before anything is sent when the sources are bigger than the
+    /// remote volume's free space, rather than with ENOSPC partway through.
+    /// Runs that may send less than that (sync, resume, compression) only
+    /// get a warning.
+    fn check_space(&self, session: &Session, sftp: &ssh2::Sftp, local_paths: &[PathBuf]) -> Result<()> {
+        let caps = self.capabilities(session, sftp);
+        let dir = self.existing_remote_base(sftp);
+        let usage = match remote::disk_usage(session, sftp, &dir, caps) {
+            Ok(usage) => usage,
+            Err(e) => {
+                debug!("Skipping the free space check: {:#}", e);
+                return Ok(());
             }
+        };
+
+        let needed = self.planned_bytes(local_paths);
+        debug!("Upload needs {} bytes; the remote volume has {} available", needed, usage.available_bytes);
+        if needed <= usage.available_bytes {
+            return Ok(());
+        }
+        let message = format!(
+            "{}: the upload is {:.2} GB, but {} only has {:.2} GB free",
+            self.destination.name,
+            needed as f64 / 1_073_741_824.0,
+            dir.display(),
+            usage.available_bytes as f64 / 1_073_741_824.0,
+        );
+        let sends_less = self.options.sync || self.options.incremental || self.options.dedup || self.resume_enabled()
+            || self.options.compress.is_some() || self.options.if_exists == IfExists::Skip;
+        if sends_less {
+            progress::suspend(&self.options.progress, || eprintln!("⚠️  {}; uploading anyway, since not all of it may be sent", message));
             return Ok(());
         }
+        anyhow::bail!("{}. Free up space, or pass --no-space-check to try anyway", message)
+    }
 
-        if let Some(parent) = dir.parent() {
-            if self.verbose {
-                eprintln!("Creating parent directory first: {}", parent.display());
-            }
-            self.ensure_remote_dir(sftp, parent)?;
+    fn check_quota(&self, session: &Session, sftp: &ssh2::Sftp, threshold: f64) -> Option<String> {
+        let caps = self.capabilities(session, sftp);
+        let usage = self.wire(&self.destination.remote_base())
+            .and_then(|dir| remote::disk_usage(session, sftp, &dir, caps));
+        let usage = match usage {
+            Ok(usage) => usage,
+            Err(e) => return Some(format!("Could not check remote disk usage: {:#}", e)),
+        };
+
+        let used = usage.used_percent();
+        info!("Remote volume is {:.1}% full ({} bytes available)", used, usage.available_bytes);
+        if used < threshold {
+            return None;
         }
+        Some(format!(
+            "Remote volume is {:.0}% full (warning threshold {:.0}%), {:.2} GB free of {:.2} GB",
+            used, threshold,
+            usage.available_bytes as f64 / 1_073_741_824.0,
+            usage.total_bytes as f64 / 1_073_741_824.0,
+        ))
+    }
+
+    /// When this run started, or the run it resumes.
+    fn run_started(&self) -> u64 {
+        self.options.run.as_ref().map(|run| run.started()).unwrap_or(self.started)
+    }
 
-        if self.verbose {
-            eprintln!("Creating directory: {}", dir.display());
+    /// The destination's remote path with its placeholders expanded for
+    /// the upload of `source_name`, as of the start of the run (so a
+    /// resumed run goes on in the same dated folder).
+    fn remote_root(&self, source_name: &OsStr) -> Result<PathBuf> {
+        let expanded = template::expand(&self.destination.remote_path, self.run_started(), &source_name.to_string_lossy())
+            .with_context(|| format!("Invalid remote_path for {}", self.destination.name))?;
+        Ok(PathBuf::from(expanded))
+    }
+
+    /// With `relative_to`, the path below it (or, outside it, the path
+    /// without its leading `/`), so the remote keeps the same layout.
+    fn upload_name(&self, path: &Path) -> Option<PathBuf> {
+        let base = self.options.relative_to.as_ref()?;
+        let relative = path.strip_prefix(base).unwrap_or(path);
+        let name: PathBuf = relative.components()
+            .filter(|c| matches!(c, std::path::Component::Normal(_)))
+            .collect();
+        (!name.as_os_str().is_empty()).then_some(name)
+    }
+
+    fn upload_all(&self, session: &Session, sftp: &ssh2::Sftp, local_paths: &[PathBuf], config: &Config) -> Result<u64> {
+        let mut total_bytes = 0u64;
+        for path in local_paths {
+            total_bytes += self.upload_path(session, sftp, path, config)?;
         }
-        sftp.mkdir(dir, 0o755)
-            .context(format!("Failed to create remote directory: {}", dir.display()))?;
-        if self.verbose {
-            eprintln!("Successfully created directory: {}", dir.display());
+        Ok(total_bytes)
+    }
+
+    fn upload_path(&self, session: &Session, sftp: &ssh2::Sftp, path: &Path, config: &Config) -> Result<u64> {
+        if self.options.dedup {
+            return self.upload_dedup(session, sftp, path, config);
         }
+        let file_name = path.file_name()
+            .with_context(|| format!("Cannot archive path without a name: {}", path.display()))?;
+        let name = self.upload_name(path).unwrap_or_else(|| PathBuf::from(file_name));
+        let name = name.as_os_str();
+        let mut total_bytes = 0u64;
+        let mut uploaded = Vec::new();
+        let remote_root = self.remote_root(file_name)?;
+        let mut remote_name = self.destination.sanitize.path(Path::new(name));
+        // Manifests are named from the folder placeholders expand below,
+        // so `arkv verify` finds them by e.g. `2026-10-14/photos`
+        let remote_base = self.destination.remote_base();
+        let expanded_dir = remote_root.strip_prefix(&remote_base).unwrap_or(Path::new("")).to_path_buf();
+        let changed = |local: &Path, remote: &Path| (local != remote).then(|| local.to_path_buf());
 
-        Ok(())
+        if path.is_file() {
+            let pb = self.progress_bar(ProgressBar::new(0), progress::bytes_style());
+            pb.set_message(format!("Uploading {}", name.to_string_lossy()));
+
+            let remote_file_path = remote_root.join(&remote_name);
+            let identical = match self.options.checksum {
+                true => self.identical_file(session, sftp, path, &remote_file_path, &KnownFiles::default())?,
+                false => None,
+            };
+            let mut file = match identical {
+                Some(file) => {
+                    Bar::File(&pb).set_size(file.size);
+                    Bar::File(&pb).mark_done(file.size);
+                    file
+                }
+                None => self.upload_file(sftp, path, &remote_file_path, Some(Bar::File(&pb)), config)?,
+            };
+            file.original = changed(Path::new(name), &remote_name);
+            total_bytes = file.bytes_read;
+            uploaded.push(file);
+            
+            pb.finish_with_message(format!("✓ Uploaded {}", name.to_string_lossy()));
+        } else if is_device(path) {
+            let pb = self.progress_bar(ProgressBar::new(0), progress::bytes_style());
+            pb.set_message(format!("Uploading {}", path.display()));
+
+            let remote_file_path = remote_root.join(&remote_name);
+            let mut file = self.upload_file(sftp, path, &remote_file_path, Some(Bar::File(&pb)), config)?;
+            file.original = changed(Path::new(name), &remote_name);
+            total_bytes = file.bytes_read;
+            uploaded.push(file);
+
+            pb.finish_with_message(format!("✓ Uploaded {}", path.display()));
+        } else if self.options.archive {
+            let remote_folder = remote_root.join(&remote_name);
+            let started = Instant::now();
+            let mut file = self.upload_archive(sftp, path, Path::new(name), &remote_folder)?;
+            self.record_timing(&file, started);
+            file.original = changed(Path::new(name), &remote_name);
+            total_bytes = file.bytes_read;
+            uploaded.push(file);
+        } else {
+            let files = self.folder_files(path);
+            let known = match self.options.checksum {
+                true => Some(self.known_files(&expanded_dir.join(&remote_name).to_string_lossy())?),
+                false => None,
+            };
+
+            // After a full archive, changed files go into a dated delta folder
+            let previous = match self.options.incremental {
+                true => manifest::previous_state(&self.destination.name, &expanded_dir.join(&remote_name).to_string_lossy())?,
+                false => None,
+            };
+            if previous.is_some() {
+                remote_name = PathBuf::from(manifest::delta_name(&remote_name.to_string_lossy()));
+                info!("Uploading changes since the last archive to {}", remote_name.display());
+            }
+
+            // Unchanged files are linked to the previous snapshot's copy
+            let mut previous_snapshot = None;
+            if self.options.snapshot {
+                if !self.capabilities(session, sftp).exec {
+                    return Err(failure::fail(Failure::Config, format!(
+                        "{}: --snapshot hard-links unchanged files with ln, which needs shell access this server doesn't allow",
+                        self.destination.name
+                    )));
+                }
+                let series = remote_name.to_string_lossy().into_owned();
+                let current = snapshot::name(&series, self.run_started());
+                let entries = sftp.readdir(&self.wire(&remote_root)?).unwrap_or_default().into_iter()
+                    .filter(|(_, stat)| stat.is_dir())
+                    .filter_map(|(path, _)| {
+                        let path = self.destination.remote_encoding.decode(&path);
+                        path.file_name().map(|name| name.to_string_lossy().into_owned())
+                    });
+                previous_snapshot = snapshot::previous(entries, &series, &current).map(PathBuf::from);
+                match &previous_snapshot {
+                    Some(previous) => info!("Snapshot {} links unchanged files to {}", current, previous.display()),
+                    None => info!("Snapshot {} is the first of {}", current, series),
+                }
+                remote_name = PathBuf::from(current);
+            }
+            let mut links = Vec::new();
+
+            let total_files = files.len();
+            
+            let pb = self.progress_bar(ProgressBar::new(0), progress::bytes_style());
+
+            let mut remote_names = HashSet::new();
+            let mut work = Vec::with_capacity(total_files);
+            for entry in files {
+                let file_path = entry.path();
+                let relative = file_path.strip_prefix(path)
+                    .context("Failed to compute relative path")?;
+                
+                let sanitized = self.destination.sanitize.path(relative);
+                if let Some(previous) = &previous {
+                    pb.set_message(format!("Comparing {}", relative.display()));
+                    if !changed_since(file_path, &sanitized, previous)? {
+                        continue;
+                    }
+                }
+                let remote_relative = remote_name.join(&sanitized);
+                if !remote_names.insert(remote_relative.clone()) {
+                    anyhow::bail!(
+                        "{} and another file both become {} after sanitizing",
+                        file_path.display(), remote_relative.display()
+                    );
+                }
+                if let Some(snapshot) = &previous_snapshot {
+                    pb.set_message(format!("Comparing {}", relative.display()));
+                    let existing = self.stored_path(&remote_root.join(snapshot).join(&sanitized));
+                    if let Some(mut file) = self.unchanged_file(sftp, file_path, &existing)? {
+                        file.remote_path = self.stored_path(&remote_root.join(&remote_relative));
+                        file.original = changed(&Path::new(name).join(relative), &remote_relative);
+                        links.push((self.wire(&existing)?, self.wire(&file.remote_path)?));
+                        uploaded.push(file);
+                        continue;
+                    }
+                }
+                if let Some(known) = &known {
+                    pb.set_message(format!("Comparing {}", relative.display()));
+                    if let Some(mut file) = self.identical_file(session, sftp, file_path, &remote_root.join(&remote_relative), known)? {
+                        file.original = changed(&Path::new(name).join(relative), &remote_relative);
+                        uploaded.push(file);
+                        continue;
+                    }
+                }
+                work.push(FileJob {
+                    local: file_path.to_path_buf(),
+                    remote: remote_root.join(&remote_relative),
+                    original: changed(&Path::new(name).join(relative), &remote_relative),
+                    label: relative.display().to_string(),
+                });
+            }
+
+            if previous.is_some() && work.is_empty() {
+                pb.finish_with_message("✓ No changes since the last archive");
+                return Ok(0);
+            }
+            if !links.is_empty() {
+                pb.set_message(format!("Linking {} unchanged files", links.len()));
+                remote::hard_link(session, &links)?;
+            }
+
+            // Further hard links to a file are sent once and linked to it
+            // on the server afterwards
+            let (mut work, mut duplicates) = hard_linked(work);
+            if !duplicates.is_empty() {
+                if let Some(why) = self.hard_links_unsupported(session, sftp) {
+                    self.copy_links(&duplicates, why);
+                    work.extend(duplicates.drain(..).map(|(_, job)| job));
+                }
+            }
+
+            let total_files = work.len();
+            // Sized up front so the bar and its ETA follow bytes, not files
+            pb.set_length(work.iter().map(|job| fs::metadata(&job.local).map(|m| m.len()).unwrap_or(0)).sum());
+            let folder = FolderBar { pb: &pb, total: total_files, done: AtomicUsize::new(0) };
+            let mut expected: HashSet<PathBuf> = match self.options.mirror {
+                true => work.iter().map(|job| self.stored_path(&job.remote)).collect(),
+                false => HashSet::new(),
+            };
+
+            if self.options.jobs > 1 && work.len() > 1 {
+                uploaded.extend(self.upload_parallel(sftp, work, &folder, config)?);
+                total_bytes = uploaded.iter().map(|f| f.bytes_read).sum();
+            } else {
+                for job in work {
+                    folder.uploading(&job.label);
+                    let mut file = self.upload_file(sftp, &job.local, &job.remote, Some(Bar::Folder(&pb)), config)?;
+                    file.original = job.original;
+                    total_bytes += file.bytes_read;
+                    uploaded.push(file);
+                    folder.done.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+
+            if !duplicates.is_empty() {
+                // A group whose first file wasn't sent has its next one
+                // sent instead, for the rest to link to
+                let remote_paths: HashSet<PathBuf> = uploaded.iter().map(|file| file.remote_path.clone()).collect();
+                let promoted;
+                (promoted, duplicates) = promote_unsent(duplicates, |first| remote_paths.contains(&self.stored_path(first)));
+                for job in promoted {
+                    folder.uploading(&job.label);
+                    let mut file = self.upload_file(sftp, &job.local, &job.remote, Some(Bar::Folder(&pb)), config)?;
+                    file.original = job.original;
+                    total_bytes += file.bytes_read;
+                    uploaded.push(file);
+                }
+
+                pb.set_message(format!("Linking {} hard-linked files", duplicates.len()));
+                let pairs = duplicates.iter()
+                    .map(|(first, job)| Ok((self.wire(&self.stored_path(first))?, self.wire(&self.stored_path(&job.remote))?)))
+                    .collect::<Result<Vec<_>>>()?;
+                remote::hard_link(session, &pairs)?;
+
+                // Listed like the file they link to, for the manifest and --mirror
+                let sent: HashMap<PathBuf, (u64, Option<String>)> = uploaded.iter()
+                    .map(|file| (file.remote_path.clone(), (file.size, file.sha256.clone())))
+                    .collect();
+                for (first, job) in &duplicates {
+                    let (size, sha256) = sent.get(&self.stored_path(first))
+                        .with_context(|| format!("{} was linked to {}, which wasn't uploaded", job.remote.display(), first.display()))?;
+                    uploaded.push(UploadedFile {
+                        remote_path: self.stored_path(&job.remote),
+                        bytes_read: 0,
+                        size: *size,
+                        sha256: sha256.clone(),
+                        original: job.original.clone(),
+                        parts: Vec::new(),
+                    });
+                }
+            }
+
+            let mut message = format!("✓ Uploaded {} files", total_files);
+            if !links.is_empty() {
+                message.push_str(&format!(", linked {} unchanged", links.len()));
+            }
+            if !duplicates.is_empty() {
+                message.push_str(&format!(", linked {} hard links", duplicates.len()));
+            }
+            pb.finish_with_message(message);
+
+            if self.options.mirror {
+                // Renamed copies and split parts count as the local file too
+                expected.extend(uploaded.iter().map(|file| file.remote_path.clone()));
+                self.mirror(sftp, path, &remote_root.join(&remote_name), &expected)?;
+            }
+        }
+
+        if self.destination.owner.is_some() || self.destination.group.is_some() {
+            let mut targets: Vec<PathBuf> = self.created_dirs.lock().unwrap().drain(..).collect();
+            for file in &uploaded {
+                targets.push(self.wire(&file.remote_path)?);
+            }
+            self.apply_ownership(session, sftp, &targets)?;
+        }
+
+        if self.options.verify {
+            self.verify_uploads(&Store::Sftp(session, sftp), &uploaded)?;
+        }
+
+        if self.options.manifest {
+            self.write_manifest(&Store::Sftp(session, sftp), path, &remote_base, &expanded_dir.join(&remote_name).to_string_lossy(), uploaded, config)?;
+        }
+
+        Ok(total_bytes)
+    }
+
+    /// `--dedup`: uploads the content of each file the pool doesn't have
+    /// yet to `objects/<sha256>`, then writes the upload's index where the
+    /// upload itself would go.
+    fn upload_dedup(&self, session: &Session, sftp: &ssh2::Sftp, path: &Path, config: &Config) -> Result<u64> {
+        let file_name = path.file_name()
+            .with_context(|| format!("Cannot archive path without a name: {}", path.display()))?;
+        if is_device(path) {
+            return Err(failure::fail(Failure::Config, format!(
+                "{}: --dedup stores files and folders, not devices like {}", self.destination.name, path.display()
+            )));
+        }
+        let name = self.upload_name(path).unwrap_or_else(|| PathBuf::from(file_name));
+        let remote_name = self.destination.sanitize.path(&name);
+        let upload = self.remote_root(file_name)?.join(&remote_name);
+        let base = self.destination.remote_base();
+
+        let sources: Vec<(PathBuf, PathBuf)> = match path.is_file() {
+            true => vec![(path.to_path_buf(), remote_name.clone())],
+            false => self.folder_files(path).into_iter()
+                .map(|entry| {
+                    let relative = entry.path().strip_prefix(path).unwrap_or(entry.path());
+                    (entry.path().to_path_buf(), remote_name.join(self.destination.sanitize.path(relative)))
+                })
+                .collect(),
+        };
+
+        let pb = self.progress_bar(ProgressBar::new(0), progress::bytes_style());
+
+        let mut entries = Vec::with_capacity(sources.len());
+        let mut queued = HashSet::new();
+        let mut work = Vec::new();
+        for (local, indexed) in sources {
+            pb.set_message(format!("Hashing {}", indexed.display()));
+            let size = fs::metadata(&local)
+                .with_context(|| format!("Failed to stat local file: {}", local.display()))?
+                .len();
+            let mut file = File::open(&local)
+                .with_context(|| format!("Failed to open local file: {}", local.display()))?;
+            let sha256 = hex_digest(hash_prefix(&mut file, size)?);
+            let object = dedup::object_path(&base, &sha256);
+            entries.push(IndexEntry { path: indexed.to_string_lossy().into_owned(), size, sha256: sha256.clone() });
+
+            let stored = sftp.stat(&self.wire(&object)?).ok().and_then(|stat| stat.size) == Some(size);
+            if stored || !queued.insert(sha256) {
+                debug!("Already in the pool: {}", indexed.display());
+                self.skipped_bytes.fetch_add(size, Ordering::Relaxed);
+                self.unchanged.fetch_add(1, Ordering::Relaxed);
+                continue;
+            }
+            work.push(FileJob {
+                label: indexed.display().to_string(),
+                local,
+                remote: object,
+                original: None,
+            });
+        }
+
+        let total_files = work.len();
+        pb.set_length(work.iter().map(|job| fs::metadata(&job.local).map(|m| m.len()).unwrap_or(0)).sum());
+        let folder = FolderBar { pb: &pb, total: total_files, done: AtomicUsize::new(0) };
+        let mut uploaded = Vec::with_capacity(total_files);
+        if self.options.jobs > 1 && work.len() > 1 {
+            uploaded = self.upload_parallel(sftp, work, &folder, config)?;
+        } else {
+            for job in work {
+                folder.uploading(&job.label);
+                uploaded.push(self.upload_file(sftp, &job.local, &job.remote, Some(Bar::Folder(&pb)), config)?);
+                folder.done.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        let total_bytes = uploaded.iter().map(|file| file.bytes_read).sum();
+        if self.options.verify {
+            self.verify_uploads(&Store::Sftp(session, sftp), &uploaded)?;
+        }
+
+        let stored = entries.len() - total_files;
+        let index = dedup::index_path(&upload);
+        let content = toml::to_string_pretty(&Index::new(path, &self.destination.name, entries))
+            .context("Failed to serialize the index")?;
+        info!("Writing index: {}", index.display());
+        self.ensure_remote_dir(sftp, index.parent().unwrap_or(Path::new("")))?;
+        Store::Sftp(session, sftp).write(&self.wire(&index)?, content.as_bytes())?;
+
+        pb.finish_with_message(format!("✓ Stored {} new files ({} already in the pool)", total_files, stored));
+        Ok(total_bytes)
+    }
+
+    /// Downloads what the `--dedup` index at `index` lists from the pool,
+    /// laid out like the upload it stands for.
+    fn download_index(&self, sftp: &ssh2::Sftp, index: &Path, local: &Path) -> Result<u64> {
+        let mut content = String::new();
+        sftp.open(&self.wire(index)?)
+            .with_context(|| format!("Failed to open remote file: {}", index.display()))?
+            .read_to_string(&mut content)
+            .with_context(|| format!("Failed to read remote file: {}", index.display()))?;
+        let index_entries: Index = toml::from_str(&content)
+            .with_context(|| format!("Failed to parse {}", index.display()))?;
+
+        let base = self.destination.remote_base();
+        let pb = self.progress_bar(ProgressBar::new(index_entries.files.len() as u64), progress::files_style());
+        // Decided once, as the first file creates `local`
+        let into_folder = local.is_dir();
+        let mut total_bytes = 0;
+        for entry in &index_entries.files {
+            // The first component is the upload's name, which `local` may replace
+            let path = Path::new(&entry.path);
+            let relative: PathBuf = path.components().skip(1).collect();
+            let name = path.components().next().map(|c| PathBuf::from(c.as_os_str())).unwrap_or_default();
+            if !path.components().all(|c| matches!(c, std::path::Component::Normal(_))) {
+                anyhow::bail!("{} lists a path outside the upload: {}", index.display(), entry.path);
+            }
+            let mut target = match into_folder {
+                true => local.join(name),
+                false => local.to_path_buf(),
+            };
+            if !relative.as_os_str().is_empty() {
+                target.push(&relative);
+            }
+            if !dedup::is_sha256(&entry.sha256) {
+                anyhow::bail!("{} lists {} with a malformed SHA-256: {}", index.display(), entry.path, entry.sha256);
+            }
+            pb.set_message(format!("Downloading {}", entry.path));
+            let object = dedup::object_path(&base, &entry.sha256);
+            total_bytes += self.download_file(sftp, &object, std::slice::from_ref(&object), &target, None)?;
+            pb.inc(1);
+        }
+        pb.finish_with_message(format!("✓ Downloaded {} files", index_entries.files.len()));
+        Ok(total_bytes)
+    }
+
+    /// The files the local manifests of the upload `name` list, by where
+    /// they are on the remote and by content.
+    fn known_files(&self, name: &str) -> Result<KnownFiles> {
+        let base = self.destination.remote_base();
+        let mut known = KnownFiles::default();
+        for entry in manifest::previous_state(&self.destination.name, name)?.unwrap_or_default().into_values() {
+            if !dedup::is_sha256(&entry.sha256) {
+                let manifest = manifest::local_manifest_path(&self.destination.name, name)?;
+                anyhow::bail!("The manifests at {} list {} with a malformed SHA-256: {}", manifest.display(), entry.path, entry.sha256);
+            }
+            let path = base.join(&entry.path);
+            known.sizes.insert(entry.size);
+            known.by_content.insert((entry.size, entry.sha256.to_ascii_lowercase()), path.clone());
+            known.by_path.insert(path, entry);
+        }
+        debug!("Earlier manifests of {} list {} files", name, known.by_path.len());
+        Ok(known)
+    }
+
+    /// With `--checksum`, the upload of `local` to `logical_path` when the
+    /// server already has its content: there, by the manifest or
+    /// `sha256sum`, or renamed elsewhere in `known`, which is then copied
+    /// on the server. `None` when it has to be sent.
+    fn identical_file(&self, session: &Session, sftp: &ssh2::Sftp, local: &Path, logical_path: &Path, known: &KnownFiles) -> Result<Option<UploadedFile>> {
+        let size = fs::metadata(local)
+            .with_context(|| format!("Failed to stat local file: {}", local.display()))?
+            .len();
+        let exec = self.capabilities(session, sftp).exec;
+        let wire = self.wire(logical_path)?;
+        let at_path = sftp.stat(&wire).ok().and_then(|stat| stat.size) == Some(size);
+        // Hashing is only worth it when something could match
+        let elsewhere = exec && known.sizes.contains(&size);
+        if !at_path && !elsewhere {
+            return Ok(None);
+        }
+        let mut file = File::open(local)
+            .with_context(|| format!("Failed to open local file: {}", local.display()))?;
+        let digest = hex_digest(hash_prefix(&mut file, size)?);
+        let identical = |path: &Path| UploadedFile {
+            remote_path: path.to_path_buf(),
+            bytes_read: 0,
+            size,
+            sha256: Some(digest.clone()),
+            original: None,
+            parts: Vec::new(),
+        };
+
+        if at_path {
+            let remote_digest = match known.by_path.get(logical_path).filter(|entry| entry.size == size) {
+                Some(entry) => Some(entry.sha256.to_ascii_lowercase()),
+                None if exec => remote::sha256(session, &wire)?,
+                None => None,
+            };
+            if remote_digest.as_deref() == Some(digest.as_str()) {
+                debug!("Same content, skipping: {}", local.display());
+                self.skipped_bytes.fetch_add(size, Ordering::Relaxed);
+                self.unchanged.fetch_add(1, Ordering::Relaxed);
+                return Ok(Some(identical(logical_path)));
+            }
+        }
+
+        let Some(source) = known.by_content.get(&(size, digest.clone())).filter(|source| *source != logical_path) else {
+            return Ok(None);
+        };
+        // The manifest can be older than what's there now
+        let source_wire = self.wire(source)?;
+        if !exec || sftp.stat(&source_wire).ok().and_then(|stat| stat.size) != Some(size) {
+            return Ok(None);
+        }
+        info!("{} has the same content as {}, copying it on the server", logical_path.display(), source.display());
+        remote::copy(session, &source_wire, &wire)?;
+        self.skipped_bytes.fetch_add(size, Ordering::Relaxed);
+        self.unchanged.fetch_add(1, Ordering::Relaxed);
+        Ok(Some(identical(logical_path)))
+    }
+
+    /// Files of a folder upload, after ignore files, `--include`/`--exclude`,
+    /// `--no-hidden` and `--max-depth`. Sockets, FIFOs and device nodes
+    /// in the folder are left out and noted for the summary.
+    fn folder_files(&self, root: &Path) -> Vec<ignore::DirEntry> {
+        let mut walk = WalkBuilder::new(root);
+        walk.standard_filters(false)
+            .hidden(!self.options.hidden)
+            .max_depth(self.options.max_depth);
+        if self.options.ignore_files {
+            walk.git_ignore(true)
+                .require_git(false)
+                .parents(true)
+                .add_custom_ignore_filename(IGNORE_FILE);
+        }
+
+        let filter = self.options.filter.clone();
+        let base = root.to_path_buf();
+        walk.filter_entry(move |e| e.depth() == 0 || !filter.excludes(e.path().strip_prefix(&base).unwrap_or(e.path())));
+
+        let (files, special): (Vec<_>, Vec<_>) = walk.build()
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_type().is_some_and(|t| !t.is_dir() && !t.is_symlink()))
+            .filter(|e| self.options.filter.includes(e.path().strip_prefix(root).unwrap_or(e.path())))
+            .partition(|e| e.file_type().is_some_and(|t| t.is_file()));
+
+        // Walked more than once per run, but counted once
+        let mut skipped = self.special.lock().unwrap();
+        for entry in special {
+            if skipped.insert(entry.path().to_path_buf()) {
+                let kind = entry.file_type().map_or("special file", special_kind);
+                info!("Skipping {}: {}", kind, entry.path().display());
+            }
+        }
+        files
+    }
+
+    /// Total source bytes behind `paths`, for progress reporting.
+    fn planned_bytes(&self, paths: &[PathBuf]) -> u64 {
+        paths.iter()
+            .map(|path| {
+                if path.is_file() {
+                    fs::metadata(path).map(|m| m.len()).unwrap_or(0)
+                } else if is_device(path) {
+                    self.options.size
+                        .or_else(|| File::open(path).and_then(|mut f| f.seek(SeekFrom::End(0))).ok())
+                        .unwrap_or(0)
+                } else {
+                    self.folder_files(path).iter()
+                        .filter_map(|e| e.metadata().ok())
+                        .map(|m| m.len())
+                        .sum()
+                }
+            })
+            .sum()
+    }
+
+    /// Uploads files over `--jobs` SFTP sessions at once: the existing one
+    /// plus a new connection per extra worker, all feeding the same bar.
+    fn upload_parallel(&self, sftp: &ssh2::Sftp, work: Vec<FileJob>, folder: &FolderBar, config: &Config) -> Result<Vec<UploadedFile>> {
+        let workers = self.options.jobs.min(work.len());
+        info!("Uploading {} files with {} workers", work.len(), workers);
+
+        let queue = Mutex::new(work.into_iter());
+        let uploaded = Mutex::new(Vec::new());
+        let failed = AtomicBool::new(false);
+
+        let worker = |sftp: &ssh2::Sftp| -> Result<()> {
+            while !failed.load(Ordering::Relaxed) {
+                let Some(job) = queue.lock().unwrap().next() else {
+                    break;
+                };
+                folder.uploading(&job.label);
+                let result = self.upload_file(sftp, &job.local, &job.remote, Some(Bar::Folder(folder.pb)), config);
+                let mut file = result.inspect_err(|_| failed.store(true, Ordering::Relaxed))?;
+                file.original = job.original;
+                uploaded.lock().unwrap().push(file);
+                folder.done.fetch_add(1, Ordering::Relaxed);
+            }
+            Ok(())
+        };
+
+        thread::scope(|scope| {
+            let handles: Vec<_> = (1..workers)
+                .map(|_| scope.spawn(|| {
+                    let (_session, sftp) = self.reconnect(config)
+                        .inspect_err(|_| failed.store(true, Ordering::Relaxed))?;
+                    worker(&sftp)
+                }))
+                .collect();
+            let mut result = worker(sftp);
+            for handle in handles {
+                let joined = handle.join()
+                    .unwrap_or_else(|_| Err(anyhow::anyhow!("Upload worker panicked")));
+                result = result.and(joined);
+            }
+            result
+        })?;
+
+        let mut uploaded = uploaded.into_inner().unwrap();
+        uploaded.sort_by(|a, b| a.remote_path.cmp(&b.remote_path));
+        Ok(uploaded)
+    }
+
+    fn apply_ownership(&self, session: &Session, sftp: &ssh2::Sftp, paths: &[PathBuf]) -> Result<()> {
+        info!("Setting ownership ({}:{}) on {} remote paths",
+            self.destination.owner.as_deref().unwrap_or(""),
+            self.destination.group.as_deref().unwrap_or(""),
+            paths.len());
+        let caps = self.capabilities(session, sftp);
+        remote::apply_ownership(
+            session,
+            sftp,
+            caps,
+            self.destination.owner.as_deref(),
+            self.destination.group.as_deref(),
+            self.destination.chown_sudo,
+            paths,
+        )
+    }
+
+    /// Hashes the files written in this session on the server and checks
+    /// they match what was sent. Files skipped as unchanged or finished in
+    /// an earlier session are left alone.
+    fn verify_uploads(&self, store: &Store, uploaded: &[UploadedFile]) -> Result<()> {
+        let files: Vec<(&Path, &str)> = uploaded.iter()
+            .filter(|file| file.bytes_read > 0)
+            .flat_map(|file| match file.parts.is_empty() {
+                true => vec![(file.remote_path.as_path(), file.sha256.as_deref())],
+                false => file.parts.iter().map(|p| (p.remote_path.as_path(), p.sha256.as_deref())).collect(),
+            })
+            .filter_map(|(path, sha256)| Some((path, sha256?)))
+            .collect();
+        if files.is_empty() {
+            return Ok(());
+        }
+        if let Store::Sftp(session, sftp) = store {
+            if !self.capabilities(session, sftp).exec {
+                anyhow::bail!("--verify needs to run sha256sum on the server, which doesn't allow shell commands");
+            }
+        }
+
+        for (path, expected) in files {
+            debug!("Verifying: {}", path.display());
+            let actual = store.sha256(&self.wire(path)?)?
+                .with_context(|| format!("Failed to checksum {} on the server", path.display()))?;
+            if !actual.eq_ignore_ascii_case(expected) {
+                return Err(failure::fail(Failure::Verify, format!(
+                    "Checksum mismatch for {}: sent {} but the server has {}", path.display(), expected, actual
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Deletes what is under `remote_folder` but not in `expected` (the
+    /// remote paths of the local folder's files), after asking unless
+    /// `--force` was given. Files the include/exclude patterns leave out,
+    /// and partial uploads, are kept.
+    fn mirror(&self, sftp: &ssh2::Sftp, local_folder: &Path, remote_folder: &Path, expected: &HashSet<PathBuf>) -> Result<()> {
+        if sftp.stat(&self.wire(remote_folder)?).is_err() {
+            return Ok(());
+        }
+        let kept_dirs: HashSet<&Path> = expected.iter()
+            .flat_map(|path| path.ancestors().skip(1))
+            .collect();
+        let mut doomed = Vec::new();
+        self.mirror_deletions(sftp, remote_folder, remote_folder, expected, &kept_dirs, &mut doomed)?;
+        if doomed.is_empty() {
+            return Ok(());
+        }
+
+        let files = doomed.iter().filter(|(_, is_dir)| !is_dir).count();
+        if !self.options.force {
+            if !prompt::allowed() {
+                anyhow::bail!("--mirror would delete {} remote files not in {}; pass --force to delete without asking",
+                    files, local_folder.display());
+            }
+            let confirmed = progress::suspend(&self.options.progress, || {
+                eprintln!("\n🪞 {}: not in {} any more:", self.destination.name, local_folder.display());
+                for (path, _) in doomed.iter().filter(|(_, is_dir)| !is_dir).take(MIRROR_PREVIEW) {
+                    eprintln!("   {}", path.strip_prefix(remote_folder).unwrap_or(path).display());
+                }
+                if files > MIRROR_PREVIEW {
+                    eprintln!("   ... and {} more", files - MIRROR_PREVIEW);
+                }
+                Confirm::new()
+                    .with_prompt(format!("Delete these {} remote files?", files))
+                    .default(false)
+                    .interact()
+            }).context("Failed to read answer")?;
+            if !confirmed {
+                anyhow::bail!("Mirror deletions on {} were not confirmed", self.destination.name);
+            }
+        }
+
+        for (path, is_dir) in &doomed {
+            debug!("Deleting: {}", path.display());
+            let wire = self.wire(path)?;
+            match is_dir {
+                true => sftp.rmdir(&wire),
+                false => sftp.unlink(&wire),
+            }.with_context(|| format!("Failed to delete {}", path.display()))?;
+        }
+        self.deleted.fetch_add(files as u64, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Collects what `mirror` deletes under `dir`, folders after their
+    /// contents. Returns whether everything in `dir` goes.
+    fn mirror_deletions(
+        &self,
+        sftp: &ssh2::Sftp,
+        root: &Path,
+        dir: &Path,
+        expected: &HashSet<PathBuf>,
+        kept_dirs: &HashSet<&Path>,
+        doomed: &mut Vec<(PathBuf, bool)>,
+    ) -> Result<bool> {
+        let entries = sftp.readdir(&self.wire(dir)?)
+            .with_context(|| format!("Failed to list remote folder: {}", dir.display()))?;
+
+        let mut emptied = true;
+        for (entry, stat) in entries {
+            let Some(name) = self.destination.remote_encoding.decode(&entry).file_name().map(PathBuf::from) else {
+                continue;
+            };
+            let path = dir.join(&name);
+            let relative = path.strip_prefix(root).unwrap_or(&path);
+            let filtered = self.options.filter.excludes(relative)
+                || (!stat.is_dir() && !self.options.filter.includes(relative));
+            let partial = name.to_string_lossy().ends_with(PARTIAL_SUFFIX);
+            let part_of_expected = split_base(&path).is_some_and(|(base, _)| expected.contains(&base));
+
+            if filtered || partial {
+                emptied = false;
+            } else if stat.is_dir() {
+                if self.mirror_deletions(sftp, root, &path, expected, kept_dirs, doomed)? && !kept_dirs.contains(path.as_path()) {
+                    doomed.push((path, true));
+                } else {
+                    emptied = false;
+                }
+            } else if expected.contains(&path) || part_of_expected {
+                emptied = false;
+            } else {
+                doomed.push((path, false));
+            }
+        }
+        Ok(emptied)
+    }
+
+    fn write_manifest(&self, store: &Store, source: &Path, root: &Path, name: &str, uploaded: Vec<UploadedFile>, config: &Config) -> Result<()> {
+        // A split file is listed part by part, as that's what is on the remote
+        let files = uploaded.into_iter()
+            .flat_map(|file| match file.parts.is_empty() {
+                true => vec![(file.remote_path, file.size, file.sha256, file.original)],
+                false => file.parts.into_iter()
+                    .map(|part| (part.remote_path, part.size, part.sha256, file.original.clone()))
+                    .collect(),
+            })
+            .filter_map(|(remote_path, size, sha256, original)| {
+                let path = remote_path.strip_prefix(root).ok()?;
+                Some(ManifestEntry {
+                    path: path.to_string_lossy().to_string(),
+                    size,
+                    sha256: sha256?,
+                    original: original.map(|p| p.to_string_lossy().to_string()),
+                })
+            })
+            .collect();
+
+        let remote_path = self.wire(&manifest::remote_manifest_path(&root.to_string_lossy(), name))?;
+        info!("Writing manifest: {}", remote_path.display());
+
+        let manifest = Manifest::new(source, &self.destination.name, files);
+        progress::suspend(&self.options.progress, || {
+            manifest::write(store, &manifest, &remote_path, &self.destination.name, name, self.options.sign, config)
+        })
+    }
+
+    /// Compares `local` with its remote copy: at `remote` (relative to the
+    /// destination's remote path, or absolute), or where uploading `local`
+    /// would put it. Files count as changed when the sizes or modification
+    /// times differ; with `checksum`, same-sized files with different times
+    /// are compared by content instead.
+    pub fn diff(&self, local: &Path, remote: Option<&str>, checksum: bool, config: &Config) -> Result<DiffReport> {
+        let session = self.connect(&config.ssh_key_path)?;
+        let sftp = session.sftp()
+            .context("Failed to initialize SFTP")?;
+
+        let file_name = local.file_name()
+            .with_context(|| format!("Cannot compare path without a name: {}", local.display()))?;
+        let remote_path = match remote {
+            Some(remote) => self.destination.remote_base().join(remote.trim_end_matches('/')),
+            None => {
+                let name = self.upload_name(local).unwrap_or_else(|| PathBuf::from(file_name));
+                self.remote_root(file_name)?.join(self.destination.sanitize.path(&name))
+            }
+        };
+        info!("Comparing {} with {}", local.display(), remote_path.display());
+
+        // Both sides by relative remote name; local files with their path
+        let mut locals = BTreeMap::new();
+        let mut remotes = BTreeMap::new();
+        if local.is_dir() {
+            for entry in self.folder_files(local) {
+                let relative = entry.path().strip_prefix(local).unwrap_or(entry.path());
+                let stored = self.stored_path(&self.destination.sanitize.path(relative));
+                locals.insert(stored, entry.path().to_path_buf());
+            }
+            if sftp.stat(&self.wire(&remote_path)?).is_ok() {
+                for (path, parts) in join_split_files(self.remote_files(&sftp, &remote_path)?) {
+                    if path.file_name().is_some_and(|n| n.to_string_lossy().ends_with(PARTIAL_SUFFIX)) {
+                        continue;
+                    }
+                    let relative = path.strip_prefix(&remote_path).unwrap_or(&path).to_path_buf();
+                    remotes.insert(relative, parts);
+                }
+            }
+        } else {
+            let stored = self.stored_path(&remote_path);
+            let name = PathBuf::from(stored.file_name().unwrap_or_default());
+            locals.insert(name.clone(), local.to_path_buf());
+            if sftp.stat(&self.wire(&stored)?).is_ok() {
+                remotes.insert(name, vec![stored]);
+            } else {
+                let (parts, _) = self.remote_parts(&sftp, &stored)?;
+                if !parts.is_empty() {
+                    remotes.insert(name, parts);
+                }
+            }
+        }
+
+        let mut report = DiffReport {
+            only_remote: remotes.keys()
+                .filter(|relative| !locals.contains_key(*relative))
+                .map(|relative| relative.to_string_lossy().into_owned())
+                .collect(),
+            ..Default::default()
+        };
+        for (relative, local_file) in &locals {
+            let label = relative.to_string_lossy().into_owned();
+            let Some(parts) = remotes.get(relative) else {
+                report.only_local.push(label);
+                continue;
+            };
+            let metadata = fs::metadata(local_file)
+                .with_context(|| format!("Failed to stat local file: {}", local_file.display()))?;
+
+            let mut remote_size = 0;
+            let mut remote_mtime = None;
+            for part in parts {
+                let stat = sftp.stat(&self.wire(part)?)
+                    .with_context(|| format!("Failed to stat remote file: {}", part.display()))?;
+                remote_size += stat.size.unwrap_or(0);
+                remote_mtime = remote_mtime.or(stat.mtime);
+            }
+
+            // Compressed and encrypted copies can only be told apart by time
+            let difference = if self.stored_as_is() && remote_size != metadata.len() {
+                Some("size")
+            } else if remote_mtime == unix_mtime(&metadata) {
+                None
+            } else if checksum && self.stored_as_is() {
+                let mut file = File::open(local_file)
+                    .with_context(|| format!("Failed to open local file: {}", local_file.display()))?;
+                let local_digest = hex_digest(hash_prefix(&mut file, metadata.len())?);
+                (self.remote_digest(&session, &sftp, parts)? != local_digest).then_some("content")
+            } else {
+                Some("mtime")
+            };
+            match difference {
+                Some(difference) => report.changed.push((label, difference)),
+                None => report.same += 1,
+            }
+        }
+        Ok(report)
+    }
+
+    /// SHA-256 of a remote file (stored as `parts`), computed on the
+    /// server when it can run commands, otherwise read over SFTP.
+    fn remote_digest(&self, session: &Session, sftp: &ssh2::Sftp, parts: &[PathBuf]) -> Result<String> {
+        if let [path] = parts {
+            if self.capabilities(session, sftp).exec {
+                if let Some(digest) = remote::sha256(session, &self.wire(path)?)? {
+                    return Ok(digest);
+                }
+            }
+        }
+        let wire: Vec<PathBuf> = parts.iter().map(|p| self.wire(p)).collect::<Result<_>>()?;
+        let mut reader = PartsReader { sftp, parts: parts.iter().zip(&wire).collect(), current: None };
+        let mut hasher = Sha256::new();
+        std::io::copy(&mut reader, &mut hasher)
+            .context("Failed to read remote file")?;
+        Ok(hex_digest(hasher))
+    }
+
+    /// Downloads `remote` (relative to the destination's remote path, or
+    /// absolute) to `local`. An existing local folder receives the item
+    /// under its remote name.
+    pub fn download(&self, remote: &str, local: &Path, config: &Config) -> Result<TransferStats> {
+        let started = history::now();
+        let start_time = Instant::now();
+        let result = self.run_download(remote, local, config);
+        self.record_history(Direction::Download, vec![PathBuf::from(remote)], started, start_time, &result);
+        result
+    }
+
+    /// Notes how long sending `file` took, for `--report`.
+    fn record_timing(&self, file: &UploadedFile, started: Instant) {
+        if file.bytes_read == 0 {
+            return;
+        }
+        let base = self.destination.remote_base();
+        self.timings.lock().unwrap().push(FileTiming {
+            path: file.remote_path.strip_prefix(&base).unwrap_or(&file.remote_path).to_path_buf(),
+            bytes: file.bytes_read,
+            duration_secs: started.elapsed().as_secs_f64(),
+        });
+    }
+
+    /// Adds the outcome of a transfer to the history journal. A journal
+    /// that can't be written never fails the transfer itself.
+    fn record_history(&self, direction: Direction, paths: Vec<PathBuf>, started: u64, start_time: Instant, result: &Result<TransferStats>) -> HistoryEntry {
+        let entry = HistoryEntry {
+            started,
+            direction,
+            destination: self.destination.name.clone(),
+            paths,
+            bytes: match result {
+                Ok(stats) => stats.bytes_transferred,
+                Err(_) => self.throughput.total(),
+            },
+            duration_secs: start_time.elapsed().as_secs_f64(),
+            error: result.as_ref().err().map(|e| format!("{:#}", e)),
+            run_id: self.options.run.as_ref().map(|run| run.id()),
+        };
+        match &entry.error {
+            None => info!("{:?} with {} done: {} bytes in {:.1}s", direction, entry.destination, entry.bytes, entry.duration_secs),
+            Some(error) => error!("{:?} with {} failed: {}", direction, entry.destination, error),
+        }
+        if let Err(e) = history::record(&entry) {
+            progress::suspend(&self.options.progress, || eprintln!("⚠️  {:#}", e));
+        }
+        entry
+    }
+
+    fn run_download(&self, remote: &str, local: &Path, config: &Config) -> Result<TransferStats> {
+        let start_time = Instant::now();
+
+        let session = self.connect(&config.ssh_key_path)?;
+        let sftp = session.sftp()
+            .context("Failed to initialize SFTP")?;
+
+        let remote_path = self.destination.remote_base().join(remote.trim_end_matches('/'));
+        let index = match remote.ends_with(dedup::INDEX_SUFFIX) {
+            true => Some(remote_path.clone()),
+            false => Some(dedup::index_path(&remote_path))
+                .filter(|index| {
+                    let missing = self.wire(&remote_path).is_ok_and(|wire| sftp.stat(&wire).is_err());
+                    missing && self.wire(index).is_ok_and(|wire| sftp.stat(&wire).is_ok())
+                }),
+        };
+        if let Some(index) = index {
+            let total_bytes = self.download_index(&sftp, &index, local)?;
+            return Ok(TransferStats {
+                bytes_transferred: total_bytes,
+                bytes_compressed: None,
+                duration_secs: start_time.elapsed().as_secs_f64(),
+                unchanged: 0,
+                existing: 0,
+                deleted: 0,
+                pruned: 0,
+                special: 0,
+                warnings: Vec::new(),
+                files: Vec::new(),
+            });
+        }
+        let stat = match sftp.stat(&self.wire(&remote_path)?) {
+            Ok(stat) => Some(stat),
+            // A file stored with --split only exists as its parts
+            Err(_) if sftp.stat(&self.wire(&part_path(&remote_path, 1))?).is_ok() => None,
+            Err(e) => return Err(e).with_context(|| format!("Remote path not found: {}", remote_path.display())),
+        };
+        let name = remote_path.file_name()
+            .with_context(|| format!("Cannot download path without a name: {}", remote_path.display()))?;
+        let target = if local.is_dir() { self.decrypted_path(&local.join(name)) } else { local.to_path_buf() };
+
+        let mut total_bytes = 0u64;
+        if stat.as_ref().is_some_and(|s| s.is_dir()) {
+            let files = join_split_files(self.remote_files(&sftp, &remote_path)?);
+            let pb = self.progress_bar(ProgressBar::new(files.len() as u64), progress::files_style());
+
+            fs::create_dir_all(&target)
+                .with_context(|| format!("Failed to create local folder: {}", target.display()))?;
+            for (file, parts) in &files {
+                let relative = file.strip_prefix(&remote_path)
+                    .context("Failed to compute relative path")?;
+                pb.set_message(format!("Downloading {}", relative.display()));
+                total_bytes += self.download_file(&sftp, file, parts, &self.decrypted_path(&target.join(relative)), None)?;
+                pb.inc(1);
+            }
+            pb.finish_with_message(format!("✓ Downloaded {} files", files.len()));
+        } else {
+            let (parts, size) = match stat {
+                Some(stat) => (vec![remote_path.clone()], stat.size.unwrap_or(0)),
+                None => self.remote_parts(&sftp, &remote_path)?,
+            };
+            if parts.len() > 1 {
+                info!("Joining {} parts: {}", parts.len(), remote_path.display());
+            }
+            let pb = self.progress_bar(ProgressBar::new(size), progress::bytes_style());
+            pb.set_message(format!("Downloading {}", name.to_string_lossy()));
+            total_bytes = self.download_file(&sftp, &remote_path, &parts, &target, Some(&pb))?;
+            pb.finish_with_message(format!("✓ Downloaded {}", name.to_string_lossy()));
+        }
+
+        Ok(TransferStats {
+            bytes_transferred: total_bytes,
+            bytes_compressed: None,
+            duration_secs: start_time.elapsed().as_secs_f64(),
+            unchanged: 0,
+            existing: 0,
+            deleted: 0,
+            pruned: 0,
+            special: 0,
+            warnings: Vec::new(),
+            files: Vec::new(),
+        })
+    }
+
+    /// Where a downloaded file goes locally: encrypted files lose their
+    /// `.age` or `.gpg` extension when they are decrypted.
+    fn decrypted_path(&self, local: &Path) -> PathBuf {
+        match self.options.decrypt_identity {
+            Some(_) if encryption::is_encrypted(local) => local.with_extension(""),
+            _ => local.to_path_buf(),
+        }
+    }
+
+    /// Every regular file below a remote folder, as logical paths.
+    fn remote_files(&self, sftp: &ssh2::Sftp, dir: &Path) -> Result<Vec<PathBuf>> {
+        let entries = sftp.readdir(&self.wire(dir)?)
+            .with_context(|| format!("Failed to list remote folder: {}", dir.display()))?;
+
+        let mut files = Vec::new();
+        for (path, stat) in entries {
+            let Some(name) = self.destination.remote_encoding.decode(&path).file_name().map(PathBuf::from) else {
+                continue;
+            };
+            let path = dir.join(name);
+            if stat.is_dir() {
+                files.extend(self.remote_files(sftp, &path)?);
+            } else if stat.is_file() {
+                files.push(path);
+            }
+        }
+        files.sort();
+        Ok(files)
+    }
+
+    /// The parts of a `--split` file, in order, and their total size.
+    fn remote_parts(&self, sftp: &ssh2::Sftp, path: &Path) -> Result<(Vec<PathBuf>, u64)> {
+        let mut parts = Vec::new();
+        let mut size = 0;
+        for number in 1.. {
+            let part = part_path(path, number);
+            let Ok(stat) = sftp.stat(&self.wire(&part)?) else {
+                break;
+            };
+            size += stat.size.unwrap_or(0);
+            parts.push(part);
+        }
+        Ok((parts, size))
+    }
+
+    /// Downloads the file `remote_path`, stored as `parts` (just itself
+    /// unless it was uploaded with `--split`), to `local_path`.
+    fn download_file(&self, sftp: &ssh2::Sftp, remote_path: &Path, parts: &[PathBuf], local_path: &Path, pb: Option<&ProgressBar>) -> Result<u64> {
+        info!("Downloading: {} -> {}", remote_path.display(), local_path.display());
+
+        let wire: Vec<PathBuf> = parts.iter().map(|p| self.wire(p)).collect::<Result<_>>()?;
+        let remote_file = PartsReader { sftp, parts: parts.iter().zip(&wire).collect(), current: None };
+        if let Some(parent) = local_path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create local folder: {}", parent.display()))?;
+        }
+        let mut local_file = File::create(local_path)
+            .with_context(|| format!("Failed to create local file: {}", local_path.display()))?;
+
+        let bytes_read = Rc::new(Cell::new(0u64));
+        let counted = CountingReader {
+            inner: remote_file,
+            count: bytes_read.clone(),
+            throughput: self.throughput.clone(),
+            pb: pb.cloned(),
+        };
+        let mut reader: Box<dyn Read> = match &self.options.decrypt_identity {
+            Some(identity) if encryption::is_encrypted(remote_path) => encryption::decrypting_reader(counted, remote_path, identity)
+                .with_context(|| format!("Failed to decrypt {}", remote_path.display()))?,
+            _ => Box::new(counted),
+        };
+
+        let mut buffer = vec![0; BUFFER_SIZE];
+        loop {
+            let chunk = reader.read(&mut buffer)
+                .with_context(|| format!("Failed to read remote file: {}", remote_path.display()))?;
+            if chunk == 0 {
+                break;
+            }
+            local_file.write_all(&buffer[..chunk])
+                .with_context(|| format!("Failed to write local file: {}", local_path.display()))?;
+        }
+
+        if let Some(mtime) = wire.last().and_then(|w| sftp.stat(w).ok()).and_then(|s| s.mtime) {
+            let _ = local_file.set_modified(std::time::UNIX_EPOCH + Duration::from_secs(mtime));
+        }
+        Ok(bytes_read.get())
+    }
+
+    /// Registers a bar with the shared multi-progress display, labeled with
+    /// this destination and showing its live throughput.
+    fn progress_bar(&self, pb: ProgressBar, style: ProgressStyle) -> ProgressBar {
+        let pb = self.options.progress.add(pb);
+        pb.set_style(progress::with_throughput(style, &self.throughput));
+        pb.set_prefix(self.destination.name.clone());
+        pb.enable_steady_tick(std::time::Duration::from_millis(100));
+        pb
+    }
+
+    pub fn connect(&self, ssh_key_path: &str) -> Result<Connection> {
+        if self.destination.kind != DestinationKind::Sftp {
+            return Err(failure::fail(Failure::Config, format!(
+                "{} ({}) isn't an SFTP server, which this needs",
+                self.destination.name, self.destination.address()
+            )));
+        }
+        if self.destination.gssapi {
+            return Err(failure::fail(Failure::Config, format!(
+                "{} logs in with GSSAPI through ssh, which only takes uploads; this needs a key or password login",
+                self.destination.name
+            )));
+        }
+        let transport = net::open_transport(&self.destination, self.options.address_family, self.options.connect_timeout).classify(Failure::Connect)?;
+
+        trace!("Creating SSH session");
+        let mut session = Session::new()
+            .context("Failed to create SSH session")?;
+        
+        session.set_tcp_stream(transport);
+        if self.options.fast_cipher {
+            self.prefer_fast_ciphers(&session)?;
+        }
+        trace!("Performing SSH handshake");
+        let handshake_start = Instant::now();
+        session.set_timeout(timeout_millis(self.options.connect_timeout));
+        session.handshake()
+            .context("SSH handshake failed")
+            .classify(Failure::Connect)?;
+        session.set_timeout(timeout_millis(self.options.io_timeout));
+        info!(
+            "Handshake completed in {:.0}ms (cipher: {}, mac: {})",
+            handshake_start.elapsed().as_secs_f64() * 1000.0,
+            session.methods(MethodType::CryptCs).unwrap_or("unknown"),
+            session.methods(MethodType::MacCs).unwrap_or("unknown"),
+        );
+        known_hosts::verify(&session, &self.destination, &self.options.progress).classify(Failure::HostKey)?;
+        self.authenticate(&session, ssh_key_path).classify(Failure::Auth)?;
+
+        info!("Successfully authenticated");
+        Ok(Connection::new(session, self.destination.keepalive_interval))
+    }
+
+    fn authenticate(&self, session: &Session, ssh_key_path: &str) -> Result<()> {
+        let methods = session.auth_methods(&self.destination.username)
+            .context("Failed to query authentication methods")?
+            .to_string();
+        trace!("Server accepts authentication methods: {}", methods);
+        if methods.split(',').all(|m| m.starts_with("gssapi")) {
+            anyhow::bail!(
+                "{} only accepts GSSAPI/Kerberos authentication ({}); set gssapi = true for {} to upload with your Kerberos tickets",
+                self.destination.host, methods, self.destination.name
+            );
+        }
+
+        let offers = |method: &str| methods.split(',').any(|m| m == method);
+        let result = match offers("keyboard-interactive") && !offers("publickey") && !offers("password") {
+            true => Ok(()),
+            false => self.log_in(session, ssh_key_path, &methods),
+        };
+
+        // Servers asking for a one-time code take it after the key or
+        // password (a partial success), or instead of them.
+        if !session.authenticated() {
+            let remaining = session.auth_methods(&self.destination.username).unwrap_or_default().to_string();
+            if remaining.split(',').any(|m| m == "keyboard-interactive") && (result.is_ok() || prompt::allowed()) {
+                if let Err(e) = &result {
+                    debug!("{:#}; trying keyboard-interactive", e);
+                }
+                info!("Authenticating with keyboard-interactive for user: {}", self.destination.username);
+                auth::userauth_keyboard_interactive(session, &self.destination.username, &self.options.progress)?;
+            } else {
+                result?;
+            }
+        }
+
+        if !session.authenticated() {
+            anyhow::bail!("Authentication failed");
+        }
+        Ok(())
+    }
+
+    /// Logs in with a password, ssh-agent or the key file, which may leave
+    /// the server asking for a second factor.
+    fn log_in(&self, session: &Session, ssh_key_path: &str, methods: &str) -> Result<()> {
+        let command = self.destination.password_command.as_deref();
+        let offers_key = methods.split(',').any(|m| m == "publickey") && Path::new(ssh_key_path).exists();
+        if let Some(ref password) = self.destination.password {
+            info!("Authenticating with password for user: {}", self.destination.username);
+            let account = format!("{}@{}", self.destination.username, self.destination.host);
+            session.userauth_password(&self.destination.username, &password.resolve(&account, &self.options.progress)?)
+                .context("Password authentication failed")?;
+        } else if let Some(command) = command.filter(|_| !offers_key) {
+            info!("Authenticating with password from password_command for user: {}", self.destination.username);
+            session.userauth_password(&self.destination.username, &secret::run_command(command, &self.options.progress)?)
+                .context("Password authentication failed")?;
+        } else if let Some(key) = auth::read_public_key(Path::new(ssh_key_path)).filter(|k| k.is_security_key()) {
+            info!("Authenticating with security key ({}) via ssh-agent for user: {}", key.key_type, self.destination.username);
+            auth::userauth_agent_key(session, &self.destination.username, &key, ssh_key_path, &self.options.progress)?;
+        } else if self.destination.ssh_agent.unwrap_or(true)
+            && methods.split(',').any(|m| m == "publickey")
+            && auth::userauth_agent(session, &self.destination.username)?
+        {
+            info!("Authenticated via ssh-agent for user: {}", self.destination.username);
+        } else {
+            info!("Authenticating with SSH key: {} for user: {}", ssh_key_path, self.destination.username);
+            let key_path = Path::new(ssh_key_path);
+            let username = &self.destination.username;
+            let unlocked = session.userauth_pubkey_file(username, None, key_path, None);
+            if unlocked.is_err() && auth::is_encrypted(key_path) {
+                info!("SSH key is passphrase-protected: {}", ssh_key_path);
+                if let Some(command) = command {
+                    let passphrase = secret::run_command(command, &self.options.progress)?;
+                    session.userauth_pubkey_file(username, None, key_path, Some(&passphrase))
+                        .context("SSH key authentication failed with the passphrase from password_command")?;
+                } else {
+                    auth::with_passphrase(ssh_key_path, &self.options.progress, |passphrase| {
+                        session.userauth_pubkey_file(username, None, key_path, Some(passphrase))
+                    }).context("SSH key authentication failed")?;
+                }
+            } else {
+                unlocked.context("SSH key authentication failed")?;
+            }
+        }
+        Ok(())
+    }
+
+    fn prefer_fast_ciphers(&self, session: &Session) -> Result<()> {
+        let hardware_aes = has_hardware_aes();
+        let candidates = if hardware_aes { AES_NI_CIPHERS } else { SOFTWARE_CIPHERS };
+
+        let supported = session.supported_algs(MethodType::CryptCs)
+            .context("Failed to query supported ciphers")?;
+        let prefs: Vec<&str> = candidates.iter()
+            .copied()
+            .filter(|cipher| supported.contains(cipher))
+            .collect();
+
+        trace!("Hardware AES: {}, preferring ciphers: {}",
+                if hardware_aes { "yes" } else { "no" }, prefs.join(","));
+        if prefs.is_empty() {
+            return Ok(());
+        }
+
+        let prefs = prefs.join(",");
+        session.method_pref(MethodType::CryptCs, &prefs)
+            .context("Failed to set client-to-server cipher preference")?;
+        session.method_pref(MethodType::CryptSc, &prefs)
+            .context("Failed to set server-to-client cipher preference")?;
+        Ok(())
+    }
+
+    /// The remote name of a file once compressed and encrypted, e.g. `a.txt.gz.age`.
+    fn stored_path(&self, remote_path: &Path) -> PathBuf {
+        let mut name = remote_path.as_os_str().to_owned();
+        if let Some(compression) = self.options.compress {
+            name.push(".");
+            name.push(compression.extension());
+        }
+        if let Some(encryption) = &self.options.encrypt {
+            name.push(".");
+            name.push(encryption.extension());
+        }
+        PathBuf::from(name)
+    }
+
+    /// Whether uploads are hashed as they are written, for the manifest or `--verify`.
+    fn hashing(&self) -> bool {
+        self.options.manifest || self.options.verify
+    }
+
+    /// Whether the remote copy is byte-for-byte the local file, so it can
+    /// be compared by size, appended to or split into ranges.
+    fn stored_as_is(&self) -> bool {
+        self.options.compress.is_none() && self.options.encrypt.is_none()
+    }
+
+    fn upload_file(&self, sftp: &ssh2::Sftp, local_path: &Path, logical_path: &Path, bar: Option<Bar>, config: &Config) -> Result<UploadedFile> {
+        let remote_path = self.stored_path(logical_path);
+
+        if self.options.sync {
+            if let Some(file) = self.unchanged_file(sftp, local_path, &remote_path)? {
+                if let Some(bar) = bar {
+                    let source_size = fs::metadata(local_path).map(|m| m.len()).unwrap_or(0);
+                    bar.set_size(source_size);
+                    bar.mark_done(source_size);
+                }
+                return Ok(file);
+            }
+        }
+
+        if let Some(run) = &self.options.run {
+            if let Some(done) = run.completed(&self.destination.name, &remote_path) {
+                debug!("Already uploaded earlier in this run: {}", remote_path.display());
+                let source_size = fs::metadata(local_path).map(|m| m.len()).unwrap_or(0);
+                if let Some(bar) = bar {
+                    bar.set_size(source_size);
+                    bar.mark_done(source_size);
+                }
+                self.skipped_bytes.fetch_add(source_size, Ordering::Relaxed);
+                return Ok(UploadedFile { remote_path, bytes_read: 0, size: done.size, sha256: done.sha256, original: None, parts: Vec::new() });
+            }
+
+            let large = fs::metadata(local_path)
+                .map(|m| !m.is_file() || m.len() >= CHECKPOINT_SIZE)
+                .unwrap_or(true);
+            if large {
+                run.checkpoint()?;
+            }
+        }
+
+        let Some(remote_path) = self.resolve_existing(|path| self.remote_exists(sftp, path), logical_path, remote_path)? else {
+            let source_size = fs::metadata(local_path).map(|m| m.len()).unwrap_or(0);
+            if let Some(bar) = bar {
+                bar.set_size(source_size);
+                bar.mark_done(source_size);
+            }
+            self.skipped_bytes.fetch_add(source_size, Ordering::Relaxed);
+            return Ok(UploadedFile { remote_path: logical_path.to_path_buf(), bytes_read: 0, size: 0, sha256: None, original: None, parts: Vec::new() });
+        };
+
+        let started = Instant::now();
+        let file = self.write_file(sftp, local_path, remote_path, bar, config)?;
+        self.record_timing(&file, started);
+        if self.options.sync || self.options.preserve || self.options.snapshot {
+            self.copy_metadata(sftp, local_path, &file.remote_path)?;
+        }
+        if let Some(run) = &self.options.run {
+            let done = CompletedFile { size: file.size, sha256: file.sha256.clone() };
+            run.record(&self.destination.name, &file.remote_path, done, file.bytes_read, self.unrecorded_sent.swap(0, Ordering::Relaxed))?;
+        }
+        Ok(file)
+    }
+
+    /// Applies `--if-exists` to an upload of `logical_path`, stored as
+    /// `remote_path`: where to write it, or `None` to skip it. `exists`
+    /// tells whether a remote path is taken. Resumed uploads leave
+    /// existing files to the resume logic.
+    fn resolve_existing(&self, exists: impl Fn(&Path) -> Result<bool>, logical_path: &Path, remote_path: PathBuf) -> Result<Option<PathBuf>> {
+        if self.options.if_exists == IfExists::Overwrite || self.resume_enabled() || !exists(&remote_path)? {
+            return Ok(Some(remote_path));
+        }
+        match self.options.if_exists {
+            IfExists::Overwrite => Ok(Some(remote_path)),
+            IfExists::Skip => {
+                debug!("Already exists, skipping: {}", remote_path.display());
+                self.existing.fetch_add(1, Ordering::Relaxed);
+                Ok(None)
+            }
+            IfExists::Fail => anyhow::bail!("Remote file already exists: {}", remote_path.display()),
+            IfExists::Rename => {
+                // A timestamped name can still be taken by an upload in the
+                // same minute; it is numbered then
+                let base = match self.options.rename_scheme {
+                    RenameScheme::Number => logical_path.to_path_buf(),
+                    RenameScheme::Timestamp => {
+                        let candidate = timestamped_path(logical_path);
+                        let stored = self.stored_path(&candidate);
+                        if !exists(&stored)? {
+                            debug!("{} exists, uploading as {}", remote_path.display(), stored.display());
+                            return Ok(Some(stored));
+                        }
+                        candidate
+                    }
+                };
+                for number in 1.. {
+                    let candidate = self.stored_path(&numbered_path(&base, number));
+                    if !exists(&candidate)? {
+                        debug!("{} exists, uploading as {}", remote_path.display(), candidate.display());
+                        return Ok(Some(candidate));
+                    }
+                }
+                unreachable!("ran out of numbers")
+            }
+        }
+    }
+
+    /// Renames a finished upload to its real name, replacing the old copy:
+    /// atomically where the server can rename over a file, otherwise by
+    /// removing the old copy first, so readers only ever see a complete
+    /// file or none.
+    fn move_into_place(&self, sftp: &ssh2::Sftp, from: &Path, to: &Path) -> Result<()> {
+        let (from_wire, to_wire) = (self.wire(from)?, self.wire(to)?);
+        // Servers the probe found can't are spared a rename bound to fail
+        let atomic = self.capabilities.get().is_none_or(|caps| caps.rename_overwrite);
+        let flags = RenameFlags::OVERWRITE | RenameFlags::ATOMIC | RenameFlags::NATIVE;
+        if atomic && sftp.rename(&from_wire, &to_wire, Some(flags)).is_ok() {
+            return Ok(());
+        }
+        // SFTP v3 servers won't rename over an existing file
+        let _ = sftp.unlink(&to_wire);
+        sftp.rename(&from_wire, &to_wire, None)
+            .with_context(|| format!("Failed to move finished upload into place: {}", to.display()))
+    }
+
+    /// Whether something is stored at `path`, whole or as `--split` parts.
+    fn remote_exists(&self, sftp: &ssh2::Sftp, path: &Path) -> Result<bool> {
+        Ok(sftp.stat(&self.wire(path)?).is_ok()
+            || (self.options.split.is_some() && sftp.stat(&self.wire(&part_path(path, 1))?).is_ok()))
+    }
+
+    /// In sync mode, a remote file with the local file's mtime (and size,
+    /// unless compressed) is taken to be up to date.
+    fn unchanged_file(&self, sftp: &ssh2::Sftp, local_path: &Path, remote_path: &Path) -> Result<Option<UploadedFile>> {
+        let local = fs::metadata(local_path)
+            .with_context(|| format!("Failed to stat local file: {}", local_path.display()))?;
+        if !local.is_file() {
+            return Ok(None);
+        }
+        let Ok(remote) = sftp.stat(&self.wire(remote_path)?) else {
+            return Ok(None);
+        };
+
+        let same_size = !self.stored_as_is() || remote.size == Some(local.len());
+        if !same_size || remote.mtime.is_none() || remote.mtime != unix_mtime(&local) {
+            return Ok(None);
+        }
+
+        debug!("Unchanged, skipping: {}", local_path.display());
+        self.skipped_bytes.fetch_add(local.len(), Ordering::Relaxed);
+        self.unchanged.fetch_add(1, Ordering::Relaxed);
+
+        // Compressed or encrypted copies can't be hashed without redoing it
+        let sha256 = match (self.options.manifest, self.stored_as_is()) {
+            (true, true) => {
+                let mut file = File::open(local_path).context("Failed to open local file")?;
+                Some(hex_digest(hash_prefix(&mut file, local.len())?))
+            }
+            _ => None,
+        };
+        Ok(Some(UploadedFile {
+            remote_path: remote_path.to_path_buf(),
+            bytes_read: 0,
+            size: remote.size.unwrap_or(0),
+            sha256,
+            original: None,
+            parts: Vec::new(),
+        }))
+    }
+
+    /// Gives the remote copy the local mtime, which is what sync compares,
+    /// and with `--preserve` the local permission bits too.
+    fn copy_metadata(&self, sftp: &ssh2::Sftp, local_path: &Path, remote_path: &Path) -> Result<()> {
+        let Some(local) = fs::metadata(local_path).ok().filter(|m| m.is_file()) else {
+            return Ok(());
+        };
+        let mtime = unix_mtime(&local);
+        let perm = self.options.preserve.then(|| local.permissions().mode() & 0o7777);
+        if self.options.preserve {
+            debug!("Preserving mode {:o} and mtime of {}", perm.unwrap_or(0), remote_path.display());
+        }
+        let stat = FileStat { size: None, uid: None, gid: None, perm, atime: mtime, mtime };
+        sftp.setstat(&self.wire(remote_path)?, stat)
+            .with_context(|| format!(
+                "Failed to set the mode and modification time of {} (the server may not support setstat, which --sync and --preserve need)",
+                remote_path.display()
+            ))
+    }
+
+    fn write_file(&self, sftp: &ssh2::Sftp, local_path: &Path, logical_path: PathBuf, bar: Option<Bar>, config: &Config) -> Result<UploadedFile> {
+        let remote_path = self.wire(&logical_path)?;
+        let remote_path = remote_path.as_path();
+
+        debug!("Uploading: {} -> {}", local_path.display(), logical_path.display());
+        
+        let remote_dir = logical_path.parent()
+            .context("Invalid remote path")?;
+        
+        trace!("Ensuring remote directory exists: {}", remote_dir.display());
+        self.ensure_remote_dir(sftp, remote_dir)?;
+
+        trace!("Opening local file: {}", local_path.display());
+        let mut local_file = File::open(local_path)
+            .context("Failed to open local file")?;
+        let source_size = self.source_size(&mut local_file)?;
+        if let Some(bar) = bar {
+            bar.set_size(source_size);
+        }
+        let pb = bar.map(Bar::progress);
+
+        let chunks = self.chunk_count(local_path, source_size);
+        if chunks > 1 {
+            return self.write_chunked(sftp, local_path, logical_path, source_size, chunks, pb, config);
+        }
+
+        if let Some(part_size) = self.options.split.filter(|&size| source_size > size) {
+            let mut sink = RemoteSink::split(self, sftp, &logical_path, part_size)?;
+            let bytes_read = Rc::new(Cell::new(0u64));
+            self.send(sftp, local_file, &mut sink, bytes_read.clone(), pb)?;
+            let (size, _, parts) = sink.finish()?;
+            return Ok(UploadedFile { remote_path: logical_path, bytes_read: bytes_read.get(), size, sha256: None, original: None, parts });
+        }
+
+        // An interrupted atomic upload left its data in the partial file
+        let write_path = match self.options.atomic {
+            true => partial_path(&logical_path),
+            false => logical_path.clone(),
+        };
+        let wire_path = self.wire(&write_path)?;
+        let offset = match self.resume_enabled() {
+            true if self.options.atomic && source_size > 0
+                && self.resume_offset(sftp, remote_path, &mut local_file, source_size)? == source_size => source_size,
+            true => self.resume_offset(sftp, &wire_path, &mut local_file, source_size)?,
+            false => 0,
+        };
+        if offset > 0 && offset == source_size {
+            debug!("Remote file already complete: {}", logical_path.display());
+            if sftp.stat(&wire_path).is_ok_and(|s| s.size == Some(source_size)) && write_path != logical_path {
+                self.move_into_place(sftp, &write_path, &logical_path)?;
+            }
+            if let Some(bar) = bar {
+                bar.mark_done(source_size);
+            }
+            self.skipped_bytes.fetch_add(source_size, Ordering::Relaxed);
+            let sha256 = match self.hashing() {
+                true => Some(hex_digest(hash_prefix(&mut local_file, source_size)?)),
+                false => None,
+            };
+            return Ok(UploadedFile { remote_path: logical_path, bytes_read: 0, size: source_size, sha256, original: None, parts: Vec::new() });
+        }
+
+        // A resumed upload only sends the tail, so the manifest hash has to
+        // start from the bytes already on the server.
+        let hasher = match (self.hashing(), offset) {
+            (false, _) => None,
+            (true, 0) => Some(Sha256::new()),
+            (true, _) => Some(hash_prefix(&mut local_file, offset)?),
+        };
+
+        self.skipped_bytes.fetch_add(offset, Ordering::Relaxed);
+        let remote_file = if offset > 0 {
+            debug!("Resuming remote file at byte {}: {}", offset, logical_path.display());
+            local_file.seek(SeekFrom::Start(offset))
+                .context("Failed to seek local file")?;
+            let mut file = sftp.open_mode(&wire_path, OpenFlags::WRITE, 0o644, OpenType::File)
+                .with_context(|| format!("Failed to reopen remote file: {}", write_path.display()))?;
+            file.seek(SeekFrom::Start(offset))
+                .context("Failed to seek remote file")?;
+            file
+        } else {
+            debug!("Creating remote file: {}", write_path.display());
+            sftp.create(&wire_path)
+                .context(format!("Failed to create remote file: {}", write_path.display()))?
+        };
+        if let Some(bar) = bar {
+            bar.mark_done(offset);
+        }
+
+        let bytes_read = Rc::new(Cell::new(0u64));
+        let mut sink = RemoteSink::new(remote_file, hasher);
+        self.send(sftp, local_file, &mut sink, bytes_read.clone(), pb)?;
+        let (written, sha256, _) = sink.finish()?;
+        if write_path != logical_path {
+            self.move_into_place(sftp, &write_path, &logical_path)?;
+        }
+
+        Ok(UploadedFile {
+            remote_path: logical_path,
+            bytes_read: bytes_read.get(),
+            size: offset + written,
+            sha256,
+            original: None,
+            parts: Vec::new(),
+        })
+    }
+
+    /// Copies `source` (compressed with `--compress`, then encrypted with
+    /// `--encrypt`) to `remote_file`, counting source bytes into
+    /// `bytes_read`. Returns the bytes written.
+    fn send(&self, sftp: &ssh2::Sftp, source: impl Read, sink: &mut RemoteSink, bytes_read: Rc<Cell<u64>>, pb: Option<&ProgressBar>) -> Result<()> {
+        let counted = CountingReader {
+            inner: source,
+            count: bytes_read,
+            throughput: self.throughput.clone(),
+            pb: pb.cloned(),
+        };
+        let mut reader: Box<dyn Read> = match self.options.compress {
+            Some(Compression::Gzip) => Box::new(GzEncoder::new(counted, flate2::Compression::default())),
+            Some(Compression::Zstd) => Box::new(zstd::stream::read::Encoder::new(counted, 0)
+                .context("Failed to start zstd compression")?),
+            None => Box::new(counted),
+        };
+
+        // The hash and size are of what lands remotely, after encryption
+        let mut output: Box<dyn FinishWrite> = match &self.options.encrypt {
+            Some(encryption) => encryption.wrap(Box::new(&mut *sink))?,
+            None => Box::new(Plain(&mut *sink)),
+        };
+
+        let mut buffer = vec![0; BUFFER_SIZE];
+        loop {
+            let chunk = reader.read(&mut buffer)
+                .context("Failed to read local file")?;
+            
+            if chunk == 0 {
+                break;
+            }
+
+            output.write_all(&buffer[..chunk])
+                .context("Failed to write to remote file")?;
+            self.after_write(sftp, chunk)?;
+        }
+        output.finish()
+            .context("Failed to write to remote file")
+    }
+
+    /// Streams a folder into a single tar file (`<name>.tar`, plus the
+    /// compression and encryption extensions) instead of uploading its files one by one.
+    /// The tarball is built on the fly, without a local temporary file.
+    fn upload_archive(&self, sftp: &ssh2::Sftp, folder: &Path, name: &Path, remote_path: &Path) -> Result<UploadedFile> {
+        let mut archive_name = remote_path.as_os_str().to_owned();
+        archive_name.push(".tar");
+        let logical_path = self.stored_path(Path::new(&archive_name));
+        let files = self.folder_files(folder);
+        let source_size: u64 = files.iter()
+            .filter_map(|e| e.metadata().ok())
+            .map(|m| m.len())
+            .sum();
+
+        if let Some(run) = &self.options.run {
+            if let Some(done) = run.completed(&self.destination.name, &logical_path) {
+                debug!("Already uploaded earlier in this run: {}", logical_path.display());
+                self.skipped_bytes.fetch_add(source_size, Ordering::Relaxed);
+                return Ok(UploadedFile { remote_path: logical_path, bytes_read: 0, size: done.size, sha256: done.sha256, original: None, parts: Vec::new() });
+            }
+            run.checkpoint()?;
+        }
+
+        let Some(logical_path) = self.resolve_existing(|path| self.remote_exists(sftp, path), Path::new(&archive_name), logical_path)? else {
+            self.skipped_bytes.fetch_add(source_size, Ordering::Relaxed);
+            return Ok(UploadedFile { remote_path: self.stored_path(Path::new(&archive_name)), bytes_read: 0, size: 0, sha256: None, original: None, parts: Vec::new() });
+        };
+
+        // Each file takes a 512-byte header and is padded to 512 bytes, and
+        // the archive ends with two empty blocks
+        let tar_size = files.iter()
+            .filter_map(|e| e.metadata().ok())
+            .map(|m| 512 + m.len().div_ceil(512) * 512)
+            .sum::<u64>() + 1024;
+        let pb = self.progress_bar(ProgressBar::new(tar_size), progress::bytes_style());
+        let count = files.len();
+        pb.set_message(format!("Archiving {} ({} files)", name.display(), count));
+
+        let remote_dir = logical_path.parent()
+            .context("Invalid remote path")?;
+        self.ensure_remote_dir(sftp, remote_dir)?;
+        info!("Creating remote archive: {}", logical_path.display());
+        let split = self.options.split.filter(|&size| tar_size > size);
+        let write_path = match self.options.atomic && split.is_none() {
+            true => partial_path(&logical_path),
+            false => logical_path.clone(),
+        };
+        let mut sink = match split {
+            Some(part_size) => RemoteSink::split(self, sftp, &logical_path, part_size)?,
+            None => {
+                let file = sftp.create(&self.wire(&write_path)?)
+                    .with_context(|| format!("Failed to create remote file: {}", write_path.display()))?;
+                RemoteSink::new(file, self.hashing().then(Sha256::new))
+            }
+        };
+
+        let (reader, writer) = std::io::pipe()
+            .context("Failed to create archive pipe")?;
+        let bytes_read = Rc::new(Cell::new(0u64));
+        let (sent, archived) = thread::scope(|scope| {
+            let tar = scope.spawn(move || -> Result<()> {
+                let mut builder = tar::Builder::new(writer);
+                for entry in &files {
+                    let relative = entry.path().strip_prefix(folder).unwrap_or(entry.path());
+                    builder.append_path_with_name(entry.path(), name.join(relative))
+                        .with_context(|| format!("Failed to add {} to the archive", entry.path().display()))?;
+                }
+                builder.into_inner().context("Failed to finish the archive")?;
+                Ok(())
+            });
+            let sent = self.send(sftp, reader, &mut sink, bytes_read.clone(), Some(&pb));
+            let archived = tar.join()
+                .unwrap_or_else(|_| Err(anyhow::anyhow!("Archive writer panicked")));
+            (sent, archived)
+        });
+        // A failed upload also breaks the pipe, so its error comes first
+        sent?;
+        archived?;
+        let (size, sha256, parts) = sink.finish()?;
+        if write_path != logical_path {
+            self.move_into_place(sftp, &write_path, &logical_path)?;
+        }
+
+        let file = UploadedFile {
+            remote_path: logical_path,
+            bytes_read: bytes_read.get(),
+            size,
+            sha256,
+            original: None,
+            parts,
+        };
+        if let Some(run) = &self.options.run {
+            let done = CompletedFile { size: file.size, sha256: file.sha256.clone() };
+            run.record(&self.destination.name, &file.remote_path, done, file.bytes_read, self.unrecorded_sent.swap(0, Ordering::Relaxed))?;
+        }
+        pb.finish_with_message(format!("✓ Archived {} files", count));
+        Ok(file)
+    }
+
+    /// Keeps the remote lock fresh and paces the upload to the bandwidth
+    /// schedule after each buffer written.
+    fn after_write(&self, sftp: &ssh2::Sftp, written: usize) -> Result<()> {
+        if let Some(lock) = self.lock.lock().unwrap().as_mut() {
+            lock.refresh(sftp)?;
+        }
+        self.pace(written);
+        Ok(())
+    }
+
+    /// Counts `written` bytes as sent and waits as long as the bandwidth
+    /// limit asks.
+    fn pace(&self, written: usize) {
+        self.sent_bytes.fetch_add(written as u64, Ordering::Relaxed);
+        self.unrecorded_sent.fetch_add(written as u64, Ordering::Relaxed);
+        if let Some(throttle) = self.throttle.lock().unwrap().as_mut() {
+            match throttle.pace(written as u64) {
+                Some(Some(limit)) => info!("Bandwidth limit now {}", progress::format_rate(limit as f64)),
+                Some(None) => info!("Bandwidth limit lifted"),
+                None => {}
+            }
+        }
+    }
+
+    /// How many ranges to split a file into. Compressed and encrypted
+    /// streams can't be split, and resumable uploads stay whole so a partial remote file
+    /// never has gaps. Files stored as `--split` parts are written in order.
+    fn chunk_count(&self, local_path: &Path, source_size: u64) -> u64 {
+        let splittable = self.options.chunks > 1
+            && self.stored_as_is()
+            && !self.resume_enabled()
+            && self.options.split.is_none_or(|size| source_size <= size)
+            && fs::metadata(local_path).is_ok_and(|m| m.is_file());
+        if !splittable {
+            return 1;
+        }
+        (self.options.chunks as u64).min(source_size / MIN_CHUNK_SIZE).max(1)
+    }
+
+    /// Uploads a large file as `chunks` ranges at once: the existing session
+    /// plus a new connection per extra range, each writing at its own offset
+    /// into a temporary remote file that is renamed into place at the end.
+    #[allow(clippy::too_many_arguments)]
+    fn write_chunked(&self, sftp: &ssh2::Sftp, local_path: &Path, logical_path: PathBuf, source_size: u64, chunks: u64, pb: Option<&ProgressBar>, config: &Config) -> Result<UploadedFile> {
+        let partial = partial_path(&logical_path);
+        let part_path = self.wire(&partial)?;
+
+        debug!("Uploading in {} chunks: {}", chunks, logical_path.display());
+        sftp.create(&part_path)
+            .with_context(|| format!("Failed to create remote file: {}", partial.display()))?;
+
+        let chunk_size = source_size.div_ceil(chunks);
+        let range = |i: u64| i * chunk_size..((i + 1) * chunk_size).min(source_size);
+        thread::scope(|scope| {
+            let handles: Vec<_> = (1..chunks)
+                .map(|i| {
+                    let part_path = &part_path;
+                    scope.spawn(move || {
+                        let (_session, sftp) = self.reconnect(config)?;
+                        self.write_range(&sftp, local_path, part_path, range(i), pb)
+                    })
+                })
+                .collect();
+            let mut result = self.write_range(sftp, local_path, &part_path, range(0), pb);
+            for handle in handles {
+                let joined = handle.join()
+                    .unwrap_or_else(|_| Err(anyhow::anyhow!("Upload worker panicked")));
+                result = result.and(joined);
+            }
+            result
+        })?;
+
+        self.move_into_place(sftp, &partial, &logical_path)?;
+
+        let sha256 = match self.hashing() {
+            true => {
+                let mut local_file = File::open(local_path)
+                    .context("Failed to open local file")?;
+                Some(hex_digest(hash_prefix(&mut local_file, source_size)?))
+            }
+            false => None,
+        };
+        Ok(UploadedFile { remote_path: logical_path, bytes_read: source_size, size: source_size, sha256, original: None, parts: Vec::new() })
+    }
+
+    /// Copies `range` of the local file to the same offsets of `remote_path`.
+    fn write_range(&self, sftp: &ssh2::Sftp, local_path: &Path, remote_path: &Path, range: Range<u64>, pb: Option<&ProgressBar>) -> Result<()> {
+        let mut local_file = File::open(local_path)
+            .context("Failed to open local file")?;
+        local_file.seek(SeekFrom::Start(range.start))
+            .context("Failed to seek local file")?;
+        let mut remote_file = sftp.open_mode(remote_path, OpenFlags::WRITE, 0o644, OpenType::File)
+            .with_context(|| format!("Failed to open remote file: {}", remote_path.display()))?;
+        remote_file.seek(SeekFrom::Start(range.start))
+            .context("Failed to seek remote file")?;
+
+        let bytes_read = Rc::new(Cell::new(0u64));
+        let mut reader = CountingReader {
+            inner: local_file.take(range.end - range.start),
+            count: bytes_read.clone(),
+            throughput: self.throughput.clone(),
+            pb: pb.cloned(),
+        };
+        let mut buffer = vec![0; BUFFER_SIZE];
+        loop {
+            let chunk = reader.read(&mut buffer)
+                .context("Failed to read local file")?;
+            if chunk == 0 {
+                break;
+            }
+            remote_file.write_all(&buffer[..chunk])
+                .context("Failed to write to remote file")?;
+            self.after_write(sftp, chunk)?;
+        }
+
+        if bytes_read.get() != range.end - range.start {
+            anyhow::bail!("Local file shrank during upload: {}", local_path.display());
+        }
+        Ok(())
+    }
+
+    /// Regular files report their size in metadata; block devices report
+    /// zero there, so their size comes from `--size` or seeking to the end.
+    fn source_size(&self, file: &mut File) -> Result<u64> {
+        let metadata = file.metadata()
+            .context("Failed to read local file metadata")?;
+        if metadata.is_file() {
+            return Ok(metadata.len());
+        }
+        if let Some(size) = self.options.size {
+            return Ok(size);
+        }
+
+        if metadata.file_type().is_block_device() {
+            let size = file.seek(SeekFrom::End(0))
+                .context("Failed to determine device size")?;
+            file.seek(SeekFrom::Start(0))
+                .context("Failed to rewind device")?;
+            return Ok(size);
+        }
+
+        Ok(0)
+    }
+
+    fn resume_offset(&self, sftp: &ssh2::Sftp, remote_path: &Path, local_file: &mut File, source_size: u64) -> Result<u64> {
+        let remote_size = match sftp.stat(remote_path) {
+            Ok(stat) => stat.size.unwrap_or(0),
+            Err(_) => return Ok(0),
+        };
+
+        if remote_size > source_size {
+            debug!("Remote file is larger than the source, restarting: {}", remote_path.display());
+            return Ok(0);
+        }
+
+        // Appending is only safe if the remote copy really is a prefix of
+        // this file, so compare the bytes just before the offset.
+        let check = remote_size.min(RESUME_CHECK_SIZE);
+        if check > 0 {
+            let start = remote_size - check;
+            let mut local_tail = vec![0; check as usize];
+            local_file.seek(SeekFrom::Start(start))
+                .and_then(|_| local_file.read_exact(&mut local_tail))
+                .context("Failed to read local file")?;
+
+            let mut remote_tail = vec![0; check as usize];
+            let matches = sftp.open(remote_path)
+                .map_err(std::io::Error::from)
+                .and_then(|mut remote| {
+                    remote.seek(SeekFrom::Start(start))?;
+                    remote.read_exact(&mut remote_tail)
+                })
+                .map(|_| remote_tail == local_tail)
+                .unwrap_or(false);
+            if !matches {
+                debug!("Remote file differs from the source, restarting: {}", remote_path.display());
+                return Ok(0);
+            }
+        }
+
+        Ok(remote_size)
+    }
+
+    fn ensure_remote_dir(&self, sftp: &ssh2::Sftp, dir: &Path) -> Result<()> {
+        trace!("Checking if directory exists: {}", dir.display());
+        let wire_dir = self.wire(dir)?;
+        if sftp.stat(&wire_dir).is_ok() {
+            trace!("Directory already exists: {}", dir.display());
+            return Ok(());
+        }
+
+        if let Some(parent) = dir.parent() {
+            trace!("Creating parent directory first: {}", parent.display());
+            self.ensure_remote_dir(sftp, parent)?;
+        }
+
+        debug!("Creating directory: {}", dir.display());
+        if let Err(e) = sftp.mkdir(&wire_dir, 0o755) {
+            // Another worker may have created it in the meantime
+            if sftp.stat(&wire_dir).is_ok_and(|s| s.is_dir()) {
+                return Ok(());
+            }
+            return Err(e).context(format!("Failed to create remote directory: {}", dir.display()));
+        }
+        self.created_dirs.lock().unwrap().push(wire_dir);
+        trace!("Successfully created directory: {}", dir.display());
+
+        Ok(())
+    }
+
+    /// Converts a remote path to the destination's filename encoding.
+    fn wire(&self, path: &Path) -> Result<PathBuf> {
+        self.destination.remote_encoding.encode(path)
+    }
+}
+
+fn unix_mtime(metadata: &fs::Metadata) -> Option<u64> {
+    metadata.modified().ok()?
+        .duration_since(std::time::UNIX_EPOCH).ok()
+        .map(|d| d.as_secs())
+}
+
+/// A timeout as libssh2 takes it: milliseconds, with 0 for none.
+pub fn timeout_millis(timeout: Option<Duration>) -> u32 {
+    timeout.map_or(0, |t| t.as_millis().clamp(1, u32::MAX as u128) as u32)
+}
+
+fn has_hardware_aes() -> bool {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        std::arch::is_x86_feature_detected!("aes")
+    }
+    #[cfg(target_arch = "aarch64")]
+    {
+        std::arch::is_aarch64_feature_detected!("aes")
+    }
+    #[cfg(not(any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64")))]
+    {
+        false
+    }
+}
+
+/// Resolves the input paths and drops any that are already covered by
+/// another input (`photos/` and `photos/2023/`), so every file is
+/// uploaded exactly once under its top-level input's name.
+pub fn dedupe_paths(paths: &[String], relative: bool) -> Result<Vec<PathBuf>> {
+    let mut resolved: Vec<(String, PathBuf)> = Vec::new();
+    for path in paths {
+        let canonical = fs::canonicalize(path)
+            .with_context(|| format!("Path does not exist: {}", path))?;
+        resolved.push((path.clone(), canonical));
+    }
+
+    let mut kept: Vec<PathBuf> = Vec::new();
+    for (original, canonical) in &resolved {
+        let covered_by = resolved.iter()
+            .find(|(_, other)| other != canonical && canonical.starts_with(other));
+
+        if let Some((ancestor, _)) = covered_by {
+            progress::suspend(progress::bars(), || {
+                eprintln!("ℹ️  Skipping {} (already included in {})", original, ancestor)
+            });
+        } else if !kept.contains(canonical) {
+            kept.push(canonical.clone());
+        }
+    }
+
+    // Relative uploads keep their folders, so only the full paths have to differ
+    let mut names = HashSet::new();
+    for path in kept.iter().filter(|_| !relative) {
+        if let Some(name) = path.file_name() {
+            if !names.insert(name.to_os_string()) {
+                anyhow::bail!(
+                    "Multiple inputs are named '{}' and would overwrite each other remotely",
+                    name.to_string_lossy()
+                );
+            }
+        }
+    }
+
+    Ok(kept)
+}
+
+/// Whether a file differs from its entry in the manifests of the last
+/// archive (`relative` is its remote path inside the upload).
+fn changed_since(local: &Path, relative: &Path, previous: &HashMap<String, ManifestEntry>) -> Result<bool> {
+    let Some(entry) = previous.get(relative.to_string_lossy().as_ref()) else {
+        return Ok(true);
+    };
+    let size = fs::metadata(local)
+        .with_context(|| format!("Failed to stat local file: {}", local.display()))?
+        .len();
+    if size != entry.size {
+        return Ok(true);
+    }
+    let mut file = File::open(local)
+        .with_context(|| format!("Failed to open local file: {}", local.display()))?;
+    Ok(hex_digest(hash_prefix(&mut file, size)?) != entry.sha256)
+}
+
+/// Hashes the first `len` bytes of `file`, leaving it positioned at `len`.
+fn hash_prefix(file: &mut File, len: u64) -> Result<Sha256> {
+    file.seek(SeekFrom::Start(0))
+        .context("Failed to seek local file")?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut Read::by_ref(file).take(len), &mut hasher)
+        .context("Failed to hash local file")?;
+    Ok(hasher)
+}
+
+/// Reads remote files one after another, opening each as it's reached.
+struct PartsReader<'a> {
+    sftp: &'a ssh2::Sftp,
+    /// Logical and wire path of the parts still to open.
+    parts: VecDeque<(&'a PathBuf, &'a PathBuf)>,
+    current: Option<ssh2::File>,
+}
+
+impl Read for PartsReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            if let Some(file) = self.current.as_mut() {
+                match file.read(buf)? {
+                    0 => self.current = None,
+                    n => return Ok(n),
+                }
+            }
+            let Some((path, wire)) = self.parts.pop_front() else {
+                return Ok(0);
+            };
+            let file = self.sftp.open(wire)
+                .map_err(|e| std::io::Error::other(format!("Failed to open remote file: {}: {}", path.display(), e)))?;
+            self.current = Some(file);
+        }
+    }
+}
+
+/// Pairs each remote file with what it is stored as, putting the parts
+/// of `--split` files together under the file's own name.
+fn join_split_files(files: Vec<PathBuf>) -> Vec<(PathBuf, Vec<PathBuf>)> {
+    let mut joined: BTreeMap<PathBuf, Vec<(usize, PathBuf)>> = BTreeMap::new();
+    for file in files {
+        match split_base(&file) {
+            Some((base, number)) => joined.entry(base).or_default().push((number, file)),
+            None => joined.entry(file.clone()).or_default().push((0, file)),
+        }
+    }
+    joined.into_iter()
+        .map(|(path, mut parts)| {
+            parts.sort();
+            (path, parts.into_iter().map(|(_, part)| part).collect())
+        })
+        .collect()
+}
+
+/// Joins the local parts of a `--split` file downloaded without arkv
+/// (`name.part0001`, ...) back into `name`, returning it, its size and
+/// the number of parts.
+pub fn join_parts(part: &Path) -> Result<(PathBuf, u64, usize)> {
+    let (base, _) = split_base(part)
+        .with_context(|| format!("Not a part file (name.part0001, ...): {}", part.display()))?;
+    if !part_path(&base, 1).exists() {
+        anyhow::bail!("First part not found: {}", part_path(&base, 1).display());
+    }
+
+    let tmp = base.with_file_name(format!(".{}.joining", base.file_name().unwrap_or_default().to_string_lossy()));
+    let mut output = File::create(&tmp)
+        .with_context(|| format!("Failed to create {}", tmp.display()))?;
+    let mut size = 0;
+    let mut count = 0;
+    for number in 1.. {
+        let path = part_path(&base, number);
+        let Ok(mut input) = File::open(&path) else {
+            break;
+        };
+        size += std::io::copy(&mut input, &mut output)
+            .with_context(|| format!("Failed to copy {}", path.display()))?;
+        count = number;
+    }
+    output.sync_all()
+        .with_context(|| format!("Failed to write {}", tmp.display()))?;
+    fs::rename(&tmp, &base)
+        .with_context(|| format!("Failed to create {}", base.display()))?;
+    Ok((base, size, count))
+}
+
+/// `path` with a number before its extension, as `--if-exists rename`
+/// names it: `photo.jpg` becomes `photo.1.jpg`.
+fn numbered_path(path: &Path, number: usize) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(extension) => format!("{}.{}.{}", stem, number, extension.to_string_lossy()),
+        None => format!("{}.{}", stem, number),
+    };
+    path.with_file_name(name)
+}
+
+/// `path` with the local time before its extension: `report-2026-10-14T10-33.pdf`.
+fn timestamped_path(path: &Path) -> PathBuf {
+    let stamp = unsafe {
+        let now = libc::time(std::ptr::null_mut());
+        let mut tm: libc::tm = std::mem::zeroed();
+        libc::localtime_r(&now, &mut tm);
+        format!("{:04}-{:02}-{:02}T{:02}-{:02}",
+            tm.tm_year + 1900, tm.tm_mon + 1, tm.tm_mday, tm.tm_hour, tm.tm_min)
+    };
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(extension) => format!("{}-{}.{}", stem, stamp, extension.to_string_lossy()),
+        None => format!("{}-{}", stem, stamp),
+    };
+    path.with_file_name(name)
+}
+
+/// Hidden name a file is written under until it is complete: `.name.arkv-partial`.
+pub fn partial_path(path: &Path) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!(".{}{}", name, PARTIAL_SUFFIX))
+}
+
+/// Remote name of part `number` (from 1) of a `--split` file: `name.part0001`.
+fn part_path(path: &Path, number: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".part{:04}", number));
+    PathBuf::from(name)
+}
+
+/// The file a part name like `name.part0001` belongs to, with its number.
+fn split_base(path: &Path) -> Option<(PathBuf, usize)> {
+    let name = path.to_str()?;
+    let (base, number) = name.rsplit_once(".part")?;
+    if base.is_empty() || number.len() < 4 || !number.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    Some((PathBuf::from(base), number.parse().ok()?))
+}
+
+/// Splits the files of `work` that are hard links to one before them
+/// off, each with the remote path of that first one.
+fn hard_linked(work: Vec<FileJob>) -> (Vec<FileJob>, Vec<(PathBuf, FileJob)>) {
+    let mut first: HashMap<(u64, u64), PathBuf> = HashMap::new();
+    let mut unique = Vec::with_capacity(work.len());
+    let mut links = Vec::new();
+    for job in work {
+        let Some(inode) = fs::symlink_metadata(&job.local).ok()
+            .filter(|m| m.nlink() > 1)
+            .map(|m| (m.dev(), m.ino()))
+        else {
+            unique.push(job);
+            continue;
+        };
+        match first.get(&inode) {
+            Some(remote) => links.push((remote.clone(), job)),
+            None => {
+                first.insert(inode, job.remote.clone());
+                unique.push(job);
+            }
+        }
+    }
+    (unique, links)
+}
+
+/// Splits the hard links of groups whose first file `sent` doesn't have
+/// off to be sent themselves: the first of those in each group, with the
+/// rest of it linked to that one instead.
+fn promote_unsent(links: Vec<(PathBuf, FileJob)>, sent: impl Fn(&Path) -> bool) -> (Vec<FileJob>, Vec<(PathBuf, FileJob)>) {
+    let mut promoted: HashMap<PathBuf, PathBuf> = HashMap::new();
+    let mut send = Vec::new();
+    let mut linked = Vec::with_capacity(links.len());
+    for (first, job) in links {
+        if let Some(target) = promoted.get(&first) {
+            linked.push((target.clone(), job));
+        } else if sent(&first) {
+            linked.push((first, job));
+        } else {
+            promoted.insert(first, job.remote.clone());
+            send.push(job);
+        }
+    }
+    (send, linked)
+}
+
+fn special_kind(file_type: std::fs::FileType) -> &'static str {
+    if file_type.is_socket() {
+        "socket"
+    } else if file_type.is_fifo() {
+        "FIFO"
+    } else if file_type.is_block_device() || file_type.is_char_device() {
+        "device node"
+    } else {
+        "special file"
+    }
+}
+
+fn hex_digest(hasher: Sha256) -> String {
+    hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn is_device(path: &Path) -> bool {
+    fs::metadata(path)
+        .map(|m| m.file_type().is_block_device() || m.file_type().is_char_device())
+        .unwrap_or(false)
+}
+
+/// The remote end of an upload: hashes and counts what is written, and
+/// with `--split` moves on to a new part file every `part_size` bytes.
+struct RemoteSink<'a> {
+    file: ssh2::File,
+    /// Hash of the current file (or part).
+    hasher: Option<Sha256>,
+    written: u64,
+    split: Option<Split<'a>>,
+}
+
+/// Progress of a `--split` upload through its part files.
+struct Split<'a> {
+    transferer: &'a Transferer,
+    sftp: &'a ssh2::Sftp,
+    /// Logical path of the whole file.
+    path: PathBuf,
+    part_size: u64,
+    part_written: u64,
+    parts: Vec<UploadedPart>,
+}
+
+impl<'a> RemoteSink<'a> {
+    fn new(file: ssh2::File, hasher: Option<Sha256>) -> Self {
+        Self { file, hasher, written: 0, split: None }
+    }
+
+    fn split(transferer: &'a Transferer, sftp: &'a ssh2::Sftp, path: &Path, part_size: u64) -> Result<Self> {
+        let split = Split {
+            transferer,
+            sftp,
+            path: path.to_path_buf(),
+            part_size,
+            part_written: 0,
+            parts: Vec::new(),
+        };
+        let file = split.create_part(1)?;
+        let hasher = transferer.hashing().then(Sha256::new);
+        Ok(Self { file, hasher, written: 0, split: Some(split) })
+    }
+
+    /// Returns the bytes written, the hash of a whole file, or the parts
+    /// of a split one (each with its own hash).
+    fn finish(self) -> Result<(u64, Option<String>, Vec<UploadedPart>)> {
+        let RemoteSink { file, hasher, written, split } = self;
+        // Closing the handle completes the last part before it is renamed
+        drop(file);
+        match split {
+            None => Ok((written, hasher.map(hex_digest), Vec::new())),
+            Some(mut split) => {
+                split.end_part(hasher)?;
+                split.remove_stale_parts()?;
+                Ok((written, None, split.parts))
+            }
+        }
+    }
+}
+
+impl Split<'_> {
+    /// Where part `number` is written, which is only its real name
+    /// without `--no-atomic` once it is complete.
+    fn write_path(&self, number: usize) -> PathBuf {
+        match self.transferer.options.atomic {
+            true => partial_path(&part_path(&self.path, number)),
+            false => part_path(&self.path, number),
+        }
+    }
+
+    fn create_part(&self, number: usize) -> Result<ssh2::File> {
+        let path = self.write_path(number);
+        debug!("Creating remote part: {}", path.display());
+        self.sftp.create(&self.transferer.wire(&path)?)
+            .with_context(|| format!("Failed to create remote file: {}", path.display()))
+    }
+
+    /// Records the part just written (its handle already closed).
+    fn end_part(&mut self, hasher: Option<Sha256>) -> Result<()> {
+        let number = self.parts.len() + 1;
+        let remote_path = part_path(&self.path, number);
+        let write_path = self.write_path(number);
+        if write_path != remote_path {
+            self.transferer.move_into_place(self.sftp, &write_path, &remote_path)?;
+        }
+        self.parts.push(UploadedPart {
+            remote_path,
+            size: self.part_written,
+            sha256: hasher.map(hex_digest),
+        });
+        self.part_written = 0;
+        Ok(())
+    }
+
+    /// Removes parts left after the last one by an earlier, bigger upload,
+    /// so they aren't joined onto this one.
+    fn remove_stale_parts(&self) -> Result<()> {
+        for number in self.parts.len() + 1.. {
+            let path = self.transferer.wire(&part_path(&self.path, number))?;
+            if self.sftp.stat(&path).is_err() {
+                break;
+            }
+            self.sftp.unlink(&path)
+                .with_context(|| format!("Failed to remove old part: {}", path.display()))?;
+        }
+        Ok(())
+    }
+}
+
+impl Write for RemoteSink<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut buf = buf;
+        if let Some(split) = self.split.as_mut() {
+            if split.part_written == split.part_size {
+                let io = |e: anyhow::Error| std::io::Error::other(format!("{:#}", e));
+                // Opening the next part closes the full one before it is renamed
+                self.file = split.create_part(split.parts.len() + 2).map_err(io)?;
+                let next = self.hasher.as_ref().map(|_| Sha256::new());
+                split.end_part(std::mem::replace(&mut self.hasher, next)).map_err(io)?;
+            }
+            let room = split.part_size - split.part_written;
+            buf = &buf[..buf.len().min(room as usize)];
+        }
+
+        let n = self.file.write(buf)?;
+        if let Some(hasher) = self.hasher.as_mut() {
+            hasher.update(&buf[..n]);
+        }
+        self.written += n as u64;
+        if let Some(split) = self.split.as_mut() {
+            split.part_written += n as u64;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Counts bytes read from the source before any compression, so stats and
+/// progress always reflect the size of the original data.
+struct CountingReader<R> {
+    inner: R,
+    count: Rc<Cell<u64>>,
+    throughput: Arc<Throughput>,
+    pb: Option<ProgressBar>,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count.set(self.count.get() + n as u64);
+        self.throughput.add(n as u64);
+        if let Some(pb) = &self.pb {
+            pb.inc(n as u64);
+        }
+        Ok(n)
+    }
+}
+
+/// Counts what is read as sent and keeps it within the bandwidth limit,
+/// for uploads whose writing is left to a `Backend`. Hashes it as well
+/// when a manifest or `--verify` needs the checksum.
+struct PacedReader<'a, R> {
+    inner: R,
+    transferer: &'a Transferer,
+    hasher: Option<Sha256>,
+}
+
+impl<R: Read> Read for PacedReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if let Some(hasher) = &mut self.hasher {
+            hasher.update(&buf[..n]);
+        }
+        self.transferer.pace(n);
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fresh folder under the system temp dir for one test.
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("arkv-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn paths(dir: &Path, names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| dir.join(name).to_string_lossy().into_owned()).collect()
+    }
+
+    #[test]
+    fn nested_inputs_are_uploaded_once() {
+        let dir = scratch("dedupe-nested");
+        fs::create_dir_all(dir.join("photos/2023/summer")).unwrap();
+        fs::create_dir_all(dir.join("music")).unwrap();
+
+        let kept = dedupe_paths(&paths(&dir, &["photos/2023/summer", "photos", "music", "photos/2023"]), false).unwrap();
+        let root = fs::canonicalize(&dir).unwrap();
+        assert_eq!(kept, vec![root.join("photos"), root.join("music")]);
+
+        // The same folder twice, once through `..`
+        let kept = dedupe_paths(&paths(&dir, &["music", "photos/../music"]), false).unwrap();
+        assert_eq!(kept, vec![root.join("music")]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn inputs_with_the_same_name_conflict_unless_relative() {
+        let dir = scratch("dedupe-names");
+        fs::create_dir_all(dir.join("a/docs")).unwrap();
+        fs::create_dir_all(dir.join("b/docs")).unwrap();
+
+        let inputs = paths(&dir, &["a/docs", "b/docs"]);
+        assert!(dedupe_paths(&inputs, false).is_err());
+        assert_eq!(dedupe_paths(&inputs, true).unwrap().len(), 2);
+        assert!(dedupe_paths(&paths(&dir, &["missing"]), false).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn hard_links_are_split_off_after_the_first() {
+        let dir = scratch("hard-links");
+        fs::write(dir.join("a"), "same").unwrap();
+        fs::hard_link(dir.join("a"), dir.join("b")).unwrap();
+        fs::hard_link(dir.join("a"), dir.join("c")).unwrap();
+        fs::write(dir.join("d"), "other").unwrap();
+
+        let work = ["a", "b", "d", "c"].iter()
+            .map(|name| FileJob {
+                local: dir.join(name),
+                remote: PathBuf::from("remote").join(name),
+                original: None,
+                label: name.to_string(),
+            })
+            .collect();
+        let (unique, links) = hard_linked(work);
+        let labels: Vec<&str> = unique.iter().map(|job| job.label.as_str()).collect();
+        assert_eq!(labels, ["a", "d"]);
+        let linked: Vec<(PathBuf, &str)> = links.iter().map(|(first, job)| (first.clone(), job.label.as_str())).collect();
+        assert_eq!(linked, [(PathBuf::from("remote/a"), "b"), (PathBuf::from("remote/a"), "c")]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn unsent_first_hard_link_hands_over_to_the_next() {
+        let job = |name: &str| FileJob {
+            local: PathBuf::from(name),
+            remote: PathBuf::from("remote").join(name),
+            original: None,
+            label: name.to_string(),
+        };
+        let links = vec![
+            (PathBuf::from("remote/a"), job("b")),
+            (PathBuf::from("remote/x"), job("y")),
+            (PathBuf::from("remote/a"), job("c")),
+            (PathBuf::from("remote/a"), job("d")),
+        ];
+        // a was skipped; x went up
+        let (send, linked) = promote_unsent(links, |first| first == Path::new("remote/x"));
+        let labels: Vec<&str> = send.iter().map(|job| job.label.as_str()).collect();
+        assert_eq!(labels, ["b"]);
+        let linked: Vec<(PathBuf, &str)> = linked.iter().map(|(first, job)| (first.clone(), job.label.as_str())).collect();
+        assert_eq!(linked, [
+            (PathBuf::from("remote/x"), "y"),
+            (PathBuf::from("remote/b"), "c"),
+            (PathBuf::from("remote/b"), "d"),
+        ]);
     }
 }
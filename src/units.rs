@@ -0,0 +1,23 @@
+use anyhow::{Context, Result};
+
+/// Parses a byte count such as `4096`, `512K`, `1.5G` or `32GiB`.
+/// Suffixes are binary multiples.
+pub fn parse_size(input: &str) -> Result<u64> {
+    let trimmed = input.trim();
+    let split = trimmed.find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(trimmed.len());
+    let (number, suffix) = trimmed.split_at(split);
+
+    let value: f64 = number.parse()
+        .with_context(|| format!("Invalid size: {}", input))?;
+    let multiplier: u64 = match suffix.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" | "KIB" => 1 << 10,
+        "M" | "MB" | "MIB" => 1 << 20,
+        "G" | "GB" | "GIB" => 1 << 30,
+        "T" | "TB" | "TIB" => 1 << 40,
+        other => anyhow::bail!("Unknown size suffix '{}' in: {}", other, input),
+    };
+
+    Ok((value * multiplier as f64) as u64)
+}
@@ -0,0 +1,100 @@
+use anyhow::{Context, Result};
+use argon2::Argon2;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use dialoguer::Password;
+use std::sync::Mutex;
+use crate::prompt;
+
+/// First line of an encrypted config file; the rest is base64 of
+/// salt, nonce and ciphertext.
+const HEADER: &str = "# arkv encrypted config v1";
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+/// Lets scripts and cron unlock an encrypted config without a prompt.
+pub const PASSPHRASE_ENV: &str = "ARKV_CONFIG_PASSPHRASE";
+
+/// Master passphrase once entered, so saving the config after loading it
+/// doesn't ask again.
+static PASSPHRASE: Mutex<Option<String>> = Mutex::new(None);
+
+pub fn is_encrypted(content: &str) -> bool {
+    content.starts_with(HEADER)
+}
+
+pub fn encrypt(plaintext: &str) -> Result<String> {
+    let passphrase = passphrase()?;
+    let mut salt = [0u8; SALT_LEN];
+    OsRng.fill_bytes(&mut salt);
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+
+    let cipher = ChaCha20Poly1305::new(&derive_key(&passphrase, &salt)?);
+    let ciphertext = cipher.encrypt(&nonce, plaintext.as_bytes())
+        .map_err(|_| anyhow::anyhow!("Failed to encrypt config"))?;
+
+    let mut data = salt.to_vec();
+    data.extend_from_slice(&nonce);
+    data.extend_from_slice(&ciphertext);
+    Ok(format!("{}\n{}\n", HEADER, STANDARD.encode(data)))
+}
+
+pub fn decrypt(content: &str) -> Result<String> {
+    let body: String = content.lines().skip(1).collect();
+    let data = STANDARD.decode(body.trim())
+        .context("Encrypted config is corrupt")?;
+    if data.len() < SALT_LEN + NONCE_LEN {
+        anyhow::bail!("Encrypted config is corrupt");
+    }
+    let (salt, rest) = data.split_at(SALT_LEN);
+    let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+
+    let passphrase = passphrase()?;
+    let cipher = ChaCha20Poly1305::new(&derive_key(&passphrase, salt)?);
+    let plaintext = match cipher.decrypt(Nonce::from_slice(nonce), ciphertext) {
+        Ok(plaintext) => plaintext,
+        Err(_) => {
+            *PASSPHRASE.lock().unwrap() = None;
+            anyhow::bail!("Wrong master passphrase for the arkv config");
+        }
+    };
+    String::from_utf8(plaintext).context("Encrypted config is corrupt")
+}
+
+/// Asks for a new master passphrase (twice) and uses it for the next save.
+pub fn set_new_passphrase() -> Result<()> {
+    let passphrase = Password::new()
+        .with_prompt("New master passphrase")
+        .with_confirmation("Repeat passphrase", "Passphrases don't match")
+        .interact()?;
+    *PASSPHRASE.lock().unwrap() = Some(passphrase);
+    Ok(())
+}
+
+fn passphrase() -> Result<String> {
+    let mut cached = PASSPHRASE.lock().unwrap();
+    if let Some(passphrase) = cached.as_ref() {
+        return Ok(passphrase.clone());
+    }
+
+    let passphrase = match std::env::var(PASSPHRASE_ENV) {
+        Ok(passphrase) => passphrase,
+        Err(_) if prompt::allowed() => Password::new()
+            .with_prompt("🔒 Master passphrase for the arkv config")
+            .interact()
+            .context("Failed to read passphrase")?,
+        Err(_) => anyhow::bail!("The arkv config is encrypted; set {} to unlock it", PASSPHRASE_ENV),
+    };
+    *cached = Some(passphrase.clone());
+    Ok(passphrase)
+}
+
+fn derive_key(passphrase: &str, salt: &[u8]) -> Result<Key> {
+    let mut key = Key::default();
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| anyhow::anyhow!("Failed to derive key from passphrase: {}", e))?;
+    Ok(key)
+}
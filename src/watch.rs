@@ -0,0 +1,96 @@
+use anyhow::{Context, Result};
+use notify::{EventKind, RecursiveMode, Watcher};
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use tracing::warn;
+use crate::config::{Config, Destination};
+use crate::progress;
+use crate::transfer::{TransferOptions, Transferer};
+
+/// Uploads files in `folder` to every destination as they are created or
+/// modified, until interrupted. Changes are batched until the folder has
+/// been quiet for `debounce`, so a file still being written (or a burst
+/// of imports) goes up once.
+pub fn run(config: &Config, folder: &Path, jobs: Vec<(Destination, TransferOptions)>, debounce: Duration) -> Result<()> {
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(tx)
+        .context("Failed to start the file watcher")?;
+    watcher.watch(folder, RecursiveMode::Recursive)
+        .with_context(|| format!("Failed to watch {}", folder.display()))?;
+
+    // Files land under the folder's name, as when uploading the whole folder
+    let base = folder.parent().unwrap_or(folder).to_path_buf();
+    println!("\n👀 Watching {} (Ctrl-C to stop)\n", folder.display());
+
+    loop {
+        let mut changed = BTreeSet::new();
+        let first = rx.recv().context("File watcher stopped")?;
+        collect(first, &mut changed);
+        while let Ok(event) = rx.recv_timeout(debounce) {
+            collect(event, &mut changed);
+        }
+
+        let files: Vec<PathBuf> = changed.into_iter().filter(|p| p.is_file()).collect();
+        if files.is_empty() {
+            continue;
+        }
+        println!("📤 {} changed file(s)", files.len());
+
+        thread::scope(|scope| {
+            for (destination, options) in &jobs {
+                let files: Vec<PathBuf> = files.iter()
+                    .filter(|path| wanted(options, folder, path))
+                    .cloned()
+                    .collect();
+                if files.is_empty() {
+                    continue;
+                }
+                let mut options = options.clone();
+                options.relative_to = Some(base.clone());
+                let transferer = Transferer::new(destination.clone(), options);
+                scope.spawn(move || {
+                    let result = transferer.transfer(&files, config);
+                    progress::suspend(progress::bars(), || match result {
+                        Ok(stats) => println!(
+                            "📊 {}: {} file(s), {:.2} MB in {:.1}s",
+                            destination.name,
+                            files.len(),
+                            stats.bytes_transferred as f64 / 1_048_576.0,
+                            stats.duration_secs,
+                        ),
+                        Err(e) => eprintln!("❌ {}: {:#}", destination.name, e),
+                    });
+                });
+            }
+        });
+        println!();
+    }
+}
+
+fn collect(event: notify::Result<notify::Event>, changed: &mut BTreeSet<PathBuf>) {
+    match event {
+        Ok(event) if matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) => {
+            changed.extend(event.paths);
+        }
+        Ok(_) => {}
+        Err(e) => warn!("File watcher error: {}", e),
+    }
+}
+
+/// Applies the folder-walk settings (`--include`/`--exclude`, hidden
+/// files) to a single changed file.
+fn wanted(options: &TransferOptions, folder: &Path, path: &Path) -> bool {
+    let Ok(relative) = path.strip_prefix(folder) else {
+        return false;
+    };
+    if !options.hidden && relative.components().any(|c| c.as_os_str().to_string_lossy().starts_with('.')) {
+        return false;
+    }
+    let excluded = relative.ancestors()
+        .filter(|a| !a.as_os_str().is_empty())
+        .any(|a| options.filter.excludes(a));
+    !excluded && options.filter.includes(relative)
+}
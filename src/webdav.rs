@@ -0,0 +1,121 @@
+use anyhow::{Context, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use indicatif::MultiProgress;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tracing::{debug, trace};
+use crate::backend::{uri_encode, Backend};
+use crate::config::Destination;
+use crate::failure::{self, Failure};
+use crate::secret::Password;
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct WebDavSettings {
+    /// The share's root, e.g. Nextcloud's
+    /// `https://cloud.example.com/remote.php/dav/files/me`.
+    pub url: String,
+    pub username: String,
+    /// An app password rather than the account's own, where the server
+    /// offers them.
+    pub password: Password,
+}
+
+/// A WebDAV share that uploads are written into, with folders created
+/// as they are needed.
+pub struct WebDav {
+    url: String,
+    authorization: String,
+    agent: ureq::Agent,
+    /// Folders known to exist, so each is only checked once per run.
+    folders: Mutex<HashSet<PathBuf>>,
+}
+
+impl WebDav {
+    pub fn open(destination: &Destination, progress: &MultiProgress, agent: ureq::Agent) -> Result<Self> {
+        let settings = destination.webdav.as_ref()
+            .with_context(|| format!("Destination '{}' has no [destinations.webdav] settings", destination.name))?;
+        let account = format!("{}@{}", settings.username, settings.url);
+        let password = settings.password.resolve(&account, progress)?;
+        Ok(WebDav {
+            url: settings.url.trim_end_matches('/').to_string(),
+            authorization: format!("Basic {}", STANDARD.encode(format!("{}:{}", settings.username, password))),
+            agent,
+            folders: Mutex::new(HashSet::new()),
+        })
+    }
+
+    fn request(&self, method: &str, path: &Path) -> ureq::Request {
+        let url = format!("{}/{}", self.url, uri_encode(path.to_string_lossy().trim_start_matches('/'), false));
+        trace!("WebDAV {} {}", method, url);
+        self.agent.request(method, &url).set("Authorization", &self.authorization)
+    }
+
+    /// Turns a failed request into an error saying what the server said.
+    fn error(&self, error: ureq::Error, path: &Path) -> anyhow::Error {
+        match error {
+            ureq::Error::Status(status, response) => {
+                let message = format!("{} refused {} ({} {})", self.url, path.display(), status, response.status_text());
+                match status {
+                    401 | 403 => failure::fail(Failure::Auth, message),
+                    _ => anyhow::Error::msg(message),
+                }
+            }
+            ureq::Error::Transport(transport) => failure::fail(Failure::Connect, format!(
+                "Failed to reach {}: {}", self.url, transport
+            )),
+        }
+    }
+}
+
+impl Backend for WebDav {
+    fn exists(&self, path: &Path) -> Result<bool> {
+        match self.request("HEAD", path).call() {
+            Ok(_) => Ok(true),
+            Err(ureq::Error::Status(404, _)) => Ok(false),
+            Err(e) => Err(self.error(e, path)),
+        }
+    }
+
+    fn is_dir(&self, dir: &Path) -> Result<bool> {
+        if self.folders.lock().unwrap().contains(dir) {
+            return Ok(true);
+        }
+        let found = match self.request("PROPFIND", dir).set("Depth", "0").call() {
+            Ok(_) => true,
+            Err(ureq::Error::Status(404, _)) => false,
+            Err(e) => return Err(self.error(e, dir)),
+        };
+        if found {
+            self.folders.lock().unwrap().insert(dir.to_path_buf());
+        }
+        Ok(found)
+    }
+
+    fn create_dir(&self, dir: &Path) -> Result<()> {
+        debug!("Creating directory: {}", dir.display());
+        match self.request("MKCOL", dir).call() {
+            // 405: another upload created it in the meantime
+            Ok(_) | Err(ureq::Error::Status(405, _)) => {}
+            Err(e) => return Err(self.error(e, dir)),
+        }
+        self.folders.lock().unwrap().insert(dir.to_path_buf());
+        Ok(())
+    }
+
+    fn put(&self, path: &Path, reader: &mut dyn Read, size: u64) -> Result<()> {
+        self.request("PUT", path)
+            .set("Content-Length", &size.to_string())
+            .send(reader.take(size))
+            .map_err(|e| self.error(e, path))?;
+        Ok(())
+    }
+
+    fn remove(&self, path: &Path) -> Result<()> {
+        self.request("DELETE", path).call().map_err(|e| self.error(e, path))?;
+        Ok(())
+    }
+}
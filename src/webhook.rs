@@ -0,0 +1,79 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::time::Duration;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+fn default_interval() -> u64 {
+    30
+}
+
+/// Periodic progress callbacks for live dashboards.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ProgressWebhook {
+    pub url: String,
+    /// Post at least this often while a destination is uploading.
+    #[serde(default = "default_interval")]
+    pub interval_secs: u64,
+    /// Also post each time progress crosses a multiple of this percentage.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub every_percent: Option<f64>,
+}
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RunStatus {
+    Running,
+    Finished,
+    Failed,
+}
+
+/// Body of each progress POST.
+#[derive(Debug, Serialize)]
+pub struct ProgressEvent<'a> {
+    pub run_id: &'a str,
+    pub destination: &'a str,
+    pub status: RunStatus,
+    pub bytes_done: u64,
+    pub bytes_total: u64,
+    pub percent: f64,
+    pub bytes_per_sec: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub eta_secs: Option<u64>,
+}
+
+/// Body of the POST to `webhook_url` when an upload ends.
+#[derive(Debug, Serialize)]
+pub struct CompletionEvent<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub run_id: Option<&'a str>,
+    pub destination: &'a str,
+    pub status: RunStatus,
+    pub paths: &'a [PathBuf],
+    pub bytes: u64,
+    pub duration_secs: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<&'a str>,
+}
+
+impl ProgressWebhook {
+    pub fn post(&self, event: &ProgressEvent) -> Result<()> {
+        post_json(&self.url, event).with_context(|| format!("Progress webhook {} failed", self.url))
+    }
+}
+
+impl CompletionEvent<'_> {
+    pub fn post(&self, url: &str) -> Result<()> {
+        post_json(url, self).with_context(|| format!("Webhook {} failed", url))
+    }
+}
+
+fn post_json(url: &str, body: &impl Serialize) -> Result<()> {
+    let body = serde_json::to_string(body).context("Failed to serialize webhook event")?;
+    ureq::post(url)
+        .timeout(TIMEOUT)
+        .set("Content-Type", "application/json")
+        .send_string(&body)?;
+    Ok(())
+}